@@ -0,0 +1,156 @@
+//! Derive macros for the [goldenscript](https://docs.rs/goldenscript) test
+//! framework. Use them via the `derive` feature of the `goldenscript` crate,
+//! rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::spanned::Spanned as _;
+use syn::{Data, DeriveInput, Error, Fields, LitStr};
+
+/// Derives `goldenscript::FromCommand` for an enum, mapping command names to
+/// variants and arguments to fields. See the `goldenscript::FromCommand`
+/// documentation for details.
+#[proc_macro_derive(FromCommand, attributes(command))]
+pub fn derive_from_command(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    from_command(input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Generates the FromCommand implementation.
+fn from_command(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let Data::Enum(data) = &input.data else {
+        return Err(Error::new(Span::call_site(), "FromCommand can only be derived for enums"));
+    };
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let mut names = Vec::new();
+    let mut arms = Vec::new();
+    for variant in &data.variants {
+        let mut name = snake_case(&variant.ident.to_string());
+        for attr in variant.attrs.iter().filter(|a| a.path().is_ident("command")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("name") {
+                    name = meta.value()?.parse::<LitStr>()?.value();
+                    return Ok(());
+                }
+                Err(meta.error("unknown variant attribute, expected name"))
+            })?;
+        }
+        if names.contains(&name) {
+            return Err(Error::new(variant.span(), format!("duplicate command name '{name}'")));
+        }
+
+        let variant_ident = &variant.ident;
+        let value = match &variant.fields {
+            Fields::Unit => quote! { Self::#variant_ident },
+            Fields::Unnamed(fields) => {
+                let mut values = Vec::new();
+                for (i, field) in fields.unnamed.iter().enumerate() {
+                    values.push(positional(&format!("argument {}", i + 1), &field.ty));
+                }
+                quote! { Self::#variant_ident(#(#values),*) }
+            }
+            Fields::Named(fields) => {
+                let mut values = Vec::new();
+                for field in &fields.named {
+                    let field_ident = field.ident.as_ref().expect("named field");
+                    let field_name = field_ident.to_string().trim_start_matches("r#").to_string();
+                    let mut is_positional = false;
+                    for attr in field.attrs.iter().filter(|a| a.path().is_ident("command")) {
+                        attr.parse_nested_meta(|meta| {
+                            if meta.path.is_ident("positional") {
+                                is_positional = true;
+                                return Ok(());
+                            }
+                            Err(meta.error("unknown field attribute, expected positional"))
+                        })?;
+                    }
+                    let value = match is_positional {
+                        true => positional(&field_name, &field.ty),
+                        false => keyed(&field_name, &field.ty),
+                    };
+                    values.push(quote! { #field_ident: #value });
+                }
+                quote! { Self::#variant_ident { #(#values),* } }
+            }
+        };
+        arms.push(quote! { #name => #value, });
+        names.push(name);
+    }
+
+    Ok(quote! {
+        impl #impl_generics ::goldenscript::FromCommand for #ident #ty_generics #where_clause {
+            fn from_command(
+                command: &::goldenscript::Command,
+            ) -> ::std::result::Result<Self, ::std::boxed::Box<dyn ::std::error::Error>> {
+                #[allow(unused_mut)]
+                let mut args = command.consume_args();
+                let value = match command.name.as_str() {
+                    #(#arms)*
+                    name => {
+                        return ::std::result::Result::Err(
+                            ::std::format!("unknown command '{name}'").into(),
+                        )
+                    }
+                };
+                args.reject_rest()?;
+                ::std::result::Result::Ok(value)
+            }
+
+            fn command_names() -> &'static [&'static str] {
+                &[#(#names),*]
+            }
+        }
+    })
+}
+
+/// Returns an expression that parses a positional argument with the given
+/// name. Option fields are optional, and Vec fields take the remaining
+/// positional arguments.
+fn positional(name: &str, ty: &syn::Type) -> proc_macro2::TokenStream {
+    match wrapper(ty) {
+        Some("Option") => quote! {
+            args.next_pos().map(|a| a.parse()).transpose()?
+        },
+        Some("Vec") => quote! {
+            args.rest_pos().iter().map(|a| a.parse()).collect::<::std::result::Result<_, _>>()?
+        },
+        _ => quote! { args.require_pos_parse(#name)? },
+    }
+}
+
+/// Returns an expression that parses a key/value argument with the given key.
+/// Option fields are optional, and Vec fields take comma-separated lists.
+fn keyed(key: &str, ty: &syn::Type) -> proc_macro2::TokenStream {
+    match wrapper(ty) {
+        Some("Option") => quote! { args.lookup_parse(#key)? },
+        Some("Vec") => quote! { args.lookup_list_parse(#key)?.unwrap_or_default() },
+        _ => quote! { args.require_key_parse(#key)? },
+    }
+}
+
+/// Returns the name of a type's Option or Vec wrapper, if any.
+fn wrapper(ty: &syn::Type) -> Option<&'static str> {
+    let syn::Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if !matches!(segment.arguments, syn::PathArguments::AngleBracketed(_)) {
+        return None;
+    }
+    ["Option", "Vec"].into_iter().find(|w| segment.ident == w)
+}
+
+/// Converts a CamelCase identifier to snake_case.
+fn snake_case(ident: &str) -> String {
+    let mut name = String::new();
+    for (i, c) in ident.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            name.push('_');
+        }
+        name.extend(c.to_lowercase());
+    }
+    name
+}
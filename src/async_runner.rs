@@ -0,0 +1,223 @@
+//! Async runners, enabled via the `tokio` crate feature.
+
+use std::error::Error;
+
+use crate::output::Output;
+use crate::{BlockInfo, Command, RunContext, RunOptions, Runner};
+
+/// Runs goldenscript commands asynchronously, returning their output. This is
+/// an async counterpart to [`Runner`], for testing async systems such as
+/// network servers, without having to call `block_on` in every command. Run
+/// scripts via [`run_async()`] or [`generate_async()`].
+///
+/// Commands are still run sequentially, one at a time, and the hooks are
+/// called as for [`Runner`].
+///
+/// ```
+/// # use std::error::Error;
+/// # use goldenscript::Command;
+/// struct Runner;
+///
+/// impl goldenscript::AsyncRunner for Runner {
+///     async fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+///         tokio::task::yield_now().await;
+///         Ok(command.name.clone())
+///     }
+/// }
+///
+/// #[tokio::main(flavor = "multi_thread")]
+/// async fn main() {
+///     let output = goldenscript::generate_async(&mut Runner, "foo\n---\n").await.unwrap();
+///     assert_eq!(output, "foo\n---\nfoo\n");
+/// }
+/// ```
+// The futures aren't required to be Send, since they're always polled on the
+// calling thread.
+#[allow(async_fn_in_trait)]
+pub trait AsyncRunner {
+    /// Runs a goldenscript command, returning its output, or an error if the
+    /// command fails. See [`Runner::run`].
+    ///
+    /// Either this or [`AsyncRunner::run_ctx`] must be implemented. The
+    /// default implementation returns an error.
+    async fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        Err(format!("AsyncRunner::run() not implemented for command '{}'", command.name).into())
+    }
+
+    /// Like [`AsyncRunner::run`], but also given the script's [`RunContext`],
+    /// and returns structured [`Output`]. See [`Runner::run_ctx`]. The default
+    /// implementation calls [`AsyncRunner::run`].
+    #[allow(unused_variables)]
+    async fn run_ctx(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        self.run(command).await.map(Output::Text)
+    }
+
+    /// Called at the start of a goldenscript. See [`Runner::start_script`].
+    #[allow(unused_variables)]
+    async fn start_script(&mut self, ctx: &mut RunContext) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Called at the end of a goldenscript. See [`Runner::end_script`].
+    #[allow(unused_variables)]
+    async fn end_script(&mut self, ctx: &mut RunContext) -> Result<String, Box<dyn Error>> {
+        Ok(String::new())
+    }
+
+    /// Called at the start of a block. See [`Runner::start_block`].
+    #[allow(unused_variables)]
+    async fn start_block(
+        &mut self,
+        block: &BlockInfo<'_>,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(String::new())
+    }
+
+    /// Called at the end of a block. See [`Runner::end_block`].
+    #[allow(unused_variables)]
+    async fn end_block(
+        &mut self,
+        block: &BlockInfo<'_>,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        Ok(Output::Text(String::new()))
+    }
+
+    /// Called at the start of a command. See [`Runner::start_command`].
+    #[allow(unused_variables)]
+    async fn start_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(String::new())
+    }
+
+    /// Called at the end of a command. See [`Runner::end_command`].
+    #[allow(unused_variables)]
+    async fn end_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(String::new())
+    }
+}
+
+/// Runs a goldenscript at the given path with an async runner. Otherwise
+/// identical to [`run()`](crate::run).
+///
+/// Must be called from a multi-threaded Tokio runtime, e.g. in a
+/// `#[tokio::test(flavor = "multi_thread")]` test, since the script itself is
+/// run on the current worker thread via [`tokio::task::block_in_place`].
+pub async fn run_async<R: AsyncRunner>(
+    runner: &mut R,
+    path: impl AsRef<std::path::Path>,
+) -> std::io::Result<()> {
+    run_async_with(runner, path, &RunOptions::default()).await
+}
+
+/// Runs a goldenscript at the given path with an async runner and the given
+/// options. Otherwise identical to [`run_async()`].
+pub async fn run_async_with<R: AsyncRunner>(
+    runner: &mut R,
+    path: impl AsRef<std::path::Path>,
+    options: &RunOptions,
+) -> std::io::Result<()> {
+    let mut runner = BlockOn::new(runner);
+    tokio::task::block_in_place(|| crate::run_with(&mut runner, path, options))
+}
+
+/// Generates output for a goldenscript input with an async runner, without
+/// comparing them. See [`run_async()`] for runtime requirements.
+pub async fn generate_async<R: AsyncRunner>(
+    runner: &mut R,
+    input: &str,
+) -> std::io::Result<String> {
+    generate_async_with(runner, input, &RunOptions::default()).await
+}
+
+/// Generates output for a goldenscript input with an async runner and the
+/// given options, without comparing them. See [`run_async()`] for runtime
+/// requirements.
+pub async fn generate_async_with<R: AsyncRunner>(
+    runner: &mut R,
+    input: &str,
+    options: &RunOptions,
+) -> std::io::Result<String> {
+    let mut runner = BlockOn::new(runner);
+    tokio::task::block_in_place(|| crate::generate_with(&mut runner, input, options))
+}
+
+/// Adapts an async runner to a runner, by blocking on its futures using the
+/// current Tokio runtime.
+struct BlockOn<'a, R> {
+    runner: &'a mut R,
+    handle: tokio::runtime::Handle,
+}
+
+impl<'a, R: AsyncRunner> BlockOn<'a, R> {
+    /// Creates a new adapter. Panics if not called within a Tokio runtime.
+    fn new(runner: &'a mut R) -> Self {
+        Self { runner, handle: tokio::runtime::Handle::current() }
+    }
+}
+
+impl<R: AsyncRunner> Runner for BlockOn<'_, R> {
+    fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        self.handle.block_on(self.runner.run(command))
+    }
+
+    fn run_ctx(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        self.handle.block_on(self.runner.run_ctx(command, ctx))
+    }
+
+    fn start_script(&mut self, ctx: &mut RunContext) -> Result<(), Box<dyn Error>> {
+        self.handle.block_on(self.runner.start_script(ctx))
+    }
+
+    fn end_script(&mut self, ctx: &mut RunContext) -> Result<String, Box<dyn Error>> {
+        self.handle.block_on(self.runner.end_script(ctx))
+    }
+
+    fn start_block(
+        &mut self,
+        block: &BlockInfo,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        self.handle.block_on(self.runner.start_block(block, ctx))
+    }
+
+    fn end_block(
+        &mut self,
+        block: &BlockInfo,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        self.handle.block_on(self.runner.end_block(block, ctx))
+    }
+
+    fn start_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        self.handle.block_on(self.runner.start_command(command, ctx))
+    }
+
+    fn end_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        self.handle.block_on(self.runner.end_command(command, ctx))
+    }
+}
@@ -0,0 +1,258 @@
+//! Baseline snapshots of generated goldenscript output, for finding the
+//! commits that changed a suite's output.
+//!
+//! A [`Baseline`] records a hash of the generated output of each script in a
+//! directory, which can be written to a manifest file:
+//!
+//! ```text
+//! 3a9c5d6e1f0b2c47  kv/get
+//! 81f0c2d9a3b4e5f6  kv/put
+//! ```
+//!
+//! To find the commit that changed the output of a suite, snapshot the
+//! baseline at a known good revision, and use [`bisect()`] in a test that is
+//! run by `git bisect run`. The test fails once the output of any script
+//! differs from the baseline, listing the changed scripts:
+//!
+//! ```no_run
+//! # struct Runner;
+//! # use goldenscript::Command;
+//! # use std::error::Error;
+//! # impl goldenscript::Runner for Runner {
+//! #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+//! # }
+//! # impl Runner { fn new() -> Self { Self } }
+//! use goldenscript::baseline::{self, Baseline};
+//!
+//! // Run with e.g. `cargo test --test baseline -- --ignored`.
+//! #[test]
+//! #[ignore]
+//! fn baseline() {
+//!     let options = goldenscript::RunOptions::new();
+//!     let current = Baseline::snapshot("tests/scripts", |_| Runner::new(), &options).unwrap();
+//!     if std::env::var("BASELINE_WRITE").is_ok() {
+//!         current.write("/tmp/baseline").unwrap();
+//!         return;
+//!     }
+//!     baseline::bisect("/tmp/baseline", &current).unwrap();
+//! }
+//! ```
+//!
+//! ```sh
+//! $ git checkout v1.0 && BASELINE_WRITE=1 cargo test --test baseline -- --ignored
+//! $ git bisect start HEAD v1.0
+//! $ git bisect run cargo test --test baseline -- --ignored
+//! ```
+//!
+//! Since the scripts are generated via [`generate_with()`](crate::generate_with),
+//! the golden files themselves are neither compared nor updated.
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use crate::{RunOptions, Runner};
+
+/// A baseline of generated script output hashes, by script name.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Baseline {
+    /// The output hash of each script, by name relative to the script
+    /// directory.
+    pub scripts: BTreeMap<String, u64>,
+}
+
+impl Baseline {
+    /// Snapshots the generated output of all scripts in the given directory
+    /// and its subdirectories, skipping hidden files. Each script is run with a
+    /// new runner from the given closure, which is given the script name.
+    /// Scripts that fail record a hash of the error instead.
+    pub fn snapshot<R, F>(
+        dir: impl AsRef<Path>,
+        mut new_runner: F,
+        options: &RunOptions,
+    ) -> Result<Self>
+    where
+        R: Runner,
+        F: FnMut(&str) -> R,
+    {
+        let mut scripts = BTreeMap::new();
+        for (name, path) in crate::util::find_scripts(dir.as_ref())? {
+            let input = crate::runner::read_script(&path, options)?;
+            let output = crate::generate_with(&mut new_runner(&name), &input, options)
+                .unwrap_or_else(|e| format!("error: {e}"));
+            scripts.insert(name, hash(output.as_bytes()));
+        }
+        Ok(Self { scripts })
+    }
+
+    /// Parses a baseline manifest, as written by [`write()`](Self::write).
+    /// Empty lines and lines beginning with # are ignored.
+    pub fn parse(manifest: &str) -> Result<Self> {
+        let mut scripts = BTreeMap::new();
+        for (i, line) in manifest.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line
+                .split_once("  ")
+                .and_then(|(hash, name)| Some((u64::from_str_radix(hash, 16).ok()?, name)));
+            let Some((hash, name)) = parsed else {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid baseline manifest at line {}: {line}", i + 1),
+                ));
+            };
+            scripts.insert(name.to_string(), hash);
+        }
+        Ok(Self { scripts })
+    }
+
+    /// Reads a baseline manifest file.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Writes the baseline to a manifest file, with one line per script
+    /// containing the hex-encoded hash and name.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, self.to_string())
+    }
+
+    /// Returns the scripts that changed between this baseline and a newer one,
+    /// ordered by name.
+    pub fn diff(&self, new: &Baseline) -> Vec<ScriptChange> {
+        let mut changes = Vec::new();
+        for (name, hash) in &self.scripts {
+            let kind = match new.scripts.get(name) {
+                Some(new_hash) if new_hash == hash => continue,
+                Some(_) => ChangeKind::Changed,
+                None => ChangeKind::Removed,
+            };
+            changes.push(ScriptChange { name: name.clone(), kind });
+        }
+        for name in new.scripts.keys().filter(|n| !self.scripts.contains_key(*n)) {
+            changes.push(ScriptChange { name: name.clone(), kind: ChangeKind::Added });
+        }
+        changes.sort_by(|a, b| a.name.cmp(&b.name));
+        changes
+    }
+}
+
+impl std::fmt::Display for Baseline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, hash) in &self.scripts {
+            writeln!(f, "{hash:016x}  {name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A script change between two baselines, see [`Baseline::diff`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScriptChange {
+    /// The script name.
+    pub name: String,
+    /// The kind of change.
+    pub kind: ChangeKind,
+}
+
+impl std::fmt::Display for ScriptChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.name)
+    }
+}
+
+/// The kind of a script change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// The script was added.
+    Added,
+    /// The script was removed.
+    Removed,
+    /// The script's output changed.
+    Changed,
+}
+
+impl std::fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Added => write!(f, "added"),
+            Self::Removed => write!(f, "removed"),
+            Self::Changed => write!(f, "changed"),
+        }
+    }
+}
+
+/// Compares the current baseline with the baseline manifest at the given
+/// path, for use with `git bisect run`. Errors if any scripts changed, listing
+/// them, which marks the revision as bad.
+pub fn bisect(manifest: impl AsRef<Path>, current: &Baseline) -> Result<()> {
+    let changes = Baseline::read(manifest)?.diff(current);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let lines: Vec<_> = changes.iter().map(|c| c.to_string()).collect();
+    Err(Error::new(
+        ErrorKind::Other,
+        format!(
+            "{} script{} changed since baseline:\n{}",
+            changes.len(),
+            if changes.len() == 1 { "" } else { "s" },
+            lines.join("\n")
+        ),
+    ))
+}
+
+/// Returns the 64-bit FNV-1a hash of the given data.
+fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, b| (hash ^ *b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests snapshots, manifests, diffs, and bisection.
+    #[test]
+    fn baseline() {
+        let dir =
+            std::env::temp_dir().join(format!("goldenscript-baseline-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("a"), "foo\n---\n").unwrap();
+        std::fs::write(dir.join("nested/b"), "bar\n---\n").unwrap();
+        std::fs::write(dir.join(".hidden"), "baz\n---\n").unwrap();
+
+        let options = RunOptions::new();
+        let new_runner = |suffix: &'static str| {
+            move |_: &str| crate::FnRunner::stateless(move |c| Ok(format!("{}{suffix}", c.name)))
+        };
+        let old = Baseline::snapshot(&dir, new_runner(""), &options).unwrap();
+        assert_eq!(old.scripts.keys().collect::<Vec<_>>(), vec!["a", "nested/b"]);
+        assert_eq!(Baseline::parse(&old.to_string()).unwrap(), old);
+        assert_eq!(old.diff(&old), vec![]);
+
+        let manifest = dir.join(".baseline");
+        old.write(&manifest).unwrap();
+        assert!(bisect(&manifest, &old).is_ok());
+
+        // Change the output of a, remove nested/b, and add c.
+        std::fs::remove_file(dir.join("nested/b")).unwrap();
+        std::fs::write(dir.join("c"), "bar\n---\n").unwrap();
+        let new = Baseline::snapshot(&dir, new_runner("!"), &options).unwrap();
+        assert_eq!(
+            old.diff(&new).iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+            vec!["changed: a", "added: c", "removed: nested/b"]
+        );
+        assert_eq!(
+            bisect(&manifest, &new).unwrap_err().to_string(),
+            "3 scripts changed since baseline:\nchanged: a\nadded: c\nremoved: nested/b"
+        );
+
+        assert_eq!(
+            Baseline::parse("# comment\n\nxyz a\n").unwrap_err().to_string(),
+            "invalid baseline manifest at line 3: xyz a"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! Manages a content-addressed fixture store for large goldenscript argument
+//! payloads. See the [`goldenscript::fixtures`] module.
+//!
+//! * `add FILE...`: adds the given files as fixtures, printing their references.
+//! * `gc PATH...`: removes fixtures not referenced by the scripts in the given
+//!   files or directories, printing the removed hashes.
+
+use std::error::Error;
+
+use goldenscript::fixtures::FixtureStore;
+
+const USAGE: &str = "usage: goldenscript-fixtures --dir DIR (add FILE... | gc PATH...)";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut dir = None;
+    let mut args = Vec::new();
+    let mut iter = std::env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--dir" => dir = Some(iter.next().ok_or(USAGE)?),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            arg if arg.starts_with('-') => {
+                return Err(format!("unknown option {arg}\n{USAGE}").into())
+            }
+            arg => args.push(arg.to_string()),
+        }
+    }
+    let (Some(dir), Some((command, paths))) = (dir, args.split_first()) else {
+        return Err(USAGE.into());
+    };
+    if paths.is_empty() {
+        return Err(USAGE.into());
+    }
+
+    let store = FixtureStore::new(dir);
+    match command.as_str() {
+        "add" => {
+            for path in paths {
+                let reference = store.add(&std::fs::read(path)?)?;
+                println!("{reference} {path}");
+            }
+        }
+        "gc" => {
+            for hash in store.gc(paths)? {
+                println!("removed {hash}");
+            }
+        }
+        command => return Err(format!("unknown command {command}\n{USAGE}").into()),
+    }
+    Ok(())
+}
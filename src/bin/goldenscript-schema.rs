@@ -0,0 +1,38 @@
+//! Infers a draft command schema from the goldenscripts in the given
+//! directories, and prints it as Rust code. See the [`goldenscript::schema`]
+//! module.
+
+use std::error::Error;
+
+use goldenscript::schema::Schema;
+
+const USAGE: &str = "usage: goldenscript-schema DIR...";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut dirs = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            arg if arg.starts_with('-') => {
+                return Err(format!("unknown option {arg}\n{USAGE}").into())
+            }
+            dir => dirs.push(dir.to_string()),
+        }
+    }
+    if dirs.is_empty() {
+        return Err(USAGE.into());
+    }
+
+    print!("{}", Schema::infer_dirs(&dirs)?.format_rust());
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! Prints statistics about the goldenscripts in the given directories, as a
+//! table or as JSON with --json. See the [`goldenscript::stats`] module.
+
+use std::error::Error;
+use std::path::Path;
+
+use goldenscript::stats;
+
+const USAGE: &str = "usage: goldenscript-stats [--json] DIR...";
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn Error>> {
+    let mut json = false;
+    let mut dirs = Vec::new();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return Ok(());
+            }
+            arg if arg.starts_with('-') => {
+                return Err(format!("unknown option {arg}\n{USAGE}").into())
+            }
+            dir => dirs.push(dir.to_string()),
+        }
+    }
+    if dirs.is_empty() {
+        return Err(USAGE.into());
+    }
+
+    // With multiple directories, prefix script names by their directory.
+    let mut scripts = Vec::new();
+    for dir in &dirs {
+        for (name, stats) in stats::scan_dir(Path::new(dir))? {
+            let name = match dirs.len() {
+                1 => name,
+                _ => Path::new(dir).join(name).to_string_lossy().to_string(),
+            };
+            scripts.push((name, stats));
+        }
+    }
+
+    match json {
+        true => print!("{}", stats::format_json(&scripts)),
+        false => print!("{}", stats::format_table(&scripts)),
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+//! A borrowed variant of the goldenscript syntax tree, returned by
+//! [`parse_borrowed()`](crate::parse_borrowed).
+//!
+//! Strings borrow from the input script where possible, and are only allocated
+//! when they differ from the input, e.g. quoted strings with escape sequences.
+//! This is useful for tools that parse many scripts without running them, such
+//! as linters or indexers. Use [`Command::into_owned`] to convert a command to
+//! a regular [`crate::Command`].
+//!
+//! The tree also records the byte spans of its syntax elements in the parsed
+//! input, e.g. for editor tooling such as the [`lsp`](crate::lsp) module.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ops::Range;
+
+/// A block, consisting of multiple commands.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Block<'a> {
+    /// The commands in the block.
+    pub commands: Vec<Command<'a>>,
+    /// Any block tags, given on a separate line before the commands. Tags
+    /// given as `key=value` are stored as a single `key=value` string.
+    pub tags: HashSet<Cow<'a, str>>,
+    /// The literal string of the input commands.
+    pub literal: &'a str,
+    /// The block's line number position in the script.
+    pub line_number: u32,
+    /// The byte span of the entire block, including the output and any
+    /// trailing blank line.
+    pub span: Range<usize>,
+    /// The byte spans of comments in the command section, excluding the
+    /// leading whitespace but including the # or // marker.
+    pub comment_spans: Vec<Range<usize>>,
+    /// The byte span of the --- separator, if any. It is only omitted for an
+    /// empty block at the end of the script.
+    pub separator_span: Option<Range<usize>>,
+    /// The byte span of the command output following the separator.
+    pub output_span: Range<usize>,
+    /// The byte spans of the block tags, in the given order, including any
+    /// values.
+    pub tag_spans: Vec<Range<usize>>,
+}
+
+impl Block<'_> {
+    /// Converts the block into an owned block.
+    pub(crate) fn into_owned(self) -> crate::command::Block {
+        crate::command::Block {
+            commands: self.commands.into_iter().map(Command::into_owned).collect(),
+            tags: self.tags.into_iter().map(Cow::into_owned).collect(),
+            literal: self.literal.to_string(),
+            line_number: self.line_number,
+        }
+    }
+}
+
+/// A command, see [`crate::Command`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Command<'a> {
+    /// The name of the command.
+    pub name: Cow<'a, str>,
+    /// The command's arguments, in the given order.
+    pub args: Vec<Argument<'a>>,
+    /// The command prefix, if given.
+    pub prefix: Option<Cow<'a, str>>,
+    /// Any command tags, if given. Tags given as `key=value` are stored as a
+    /// single `key=value` string.
+    pub tags: HashSet<Cow<'a, str>>,
+    /// Silences the output of this command.
+    pub silent: bool,
+    /// If true, the command is expected to fail with a panic or error.
+    pub fail: bool,
+    /// If true, the command is run in the background, given by a trailing &.
+    pub background: bool,
+    /// The command's line number position in the script.
+    pub line_number: u32,
+    /// If true, this is a `%` directive handled by goldenscript itself.
+    pub directive: bool,
+    /// The byte span of the command name, including any quotes. For
+    /// directives, this excludes the %. For > literal commands, this is the
+    /// rest of the line including any continuations.
+    pub name_span: Range<usize>,
+    /// The byte span of the prefix, if any.
+    pub prefix_span: Option<Range<usize>>,
+    /// The byte spans of the tags, in the given order, including any values.
+    pub tag_spans: Vec<Range<usize>>,
+}
+
+impl Command<'_> {
+    /// Converts the command into a borrowed command with owned strings, which
+    /// can outlive the input.
+    pub(crate) fn into_static(self) -> Command<'static> {
+        let owned = |s: Cow<str>| Cow::Owned(s.into_owned());
+        Command {
+            name: owned(self.name),
+            args: (self.args.into_iter())
+                .map(|arg| Argument {
+                    key: arg.key.map(owned),
+                    value: owned(arg.value),
+                    value_type: arg.value_type,
+                    key_span: arg.key_span,
+                    value_span: arg.value_span,
+                })
+                .collect(),
+            prefix: self.prefix.map(owned),
+            tags: self.tags.into_iter().map(owned).collect(),
+            silent: self.silent,
+            fail: self.fail,
+            background: self.background,
+            line_number: self.line_number,
+            directive: self.directive,
+            name_span: self.name_span,
+            prefix_span: self.prefix_span,
+            tag_spans: self.tag_spans,
+        }
+    }
+
+    /// Converts the command into an owned [`crate::Command`].
+    pub fn into_owned(self) -> crate::Command {
+        crate::Command {
+            name: self.name.into_owned(),
+            args: self.args.into_iter().map(Argument::into_owned).collect(),
+            prefix: self.prefix.map(Cow::into_owned),
+            tags: self.tags.into_iter().map(Cow::into_owned).collect(),
+            silent: self.silent,
+            fail: self.fail,
+            background: self.background,
+            line_number: self.line_number,
+            directive: self.directive,
+        }
+    }
+}
+
+/// A command argument, see [`crate::Argument`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Argument<'a> {
+    /// The argument key, for `key=value` style arguments.
+    pub key: Option<Cow<'a, str>>,
+    /// The argument value. Can be empty.
+    pub value: Cow<'a, str>,
+    /// The type of the value, as written in the script.
+    pub value_type: crate::ValueType,
+    /// The byte span of the key, if any.
+    pub key_span: Option<Range<usize>>,
+    /// The byte span of the value, including any quotes. For heredocs, this
+    /// is the body, excluding the terminator line.
+    pub value_span: Range<usize>,
+}
+
+impl Argument<'_> {
+    /// Converts the argument into an owned [`crate::Argument`].
+    pub fn into_owned(self) -> crate::Argument {
+        crate::Argument {
+            key: self.key.map(Cow::into_owned),
+            value: self.value.into_owned(),
+            value_type: self.value_type,
+        }
+    }
+}
@@ -7,12 +7,67 @@ use std::error::Error;
 pub(crate) struct Block {
     /// The commands in the block.
     pub commands: Vec<Command>,
+    /// The block tags.
+    pub tags: HashSet<String>,
     /// The literal string of the input commands. Used to generate the output.
     pub literal: String,
     /// The block's line number position in the script.
     pub line_number: u32,
 }
 
+/// Information about a block of commands, given to the
+/// [`Runner::start_block`](crate::Runner::start_block) and
+/// [`Runner::end_block`](crate::Runner::end_block) hooks, e.g. to reset state
+/// for blocks tagged `[isolated]` or to output a header.
+///
+/// Block tags are given on a separate line before the block's commands:
+///
+/// ```text
+/// [isolated]
+/// put foo=bar
+/// get foo
+/// ---
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct BlockInfo<'a> {
+    block: &'a Block,
+}
+
+impl<'a> BlockInfo<'a> {
+    /// Creates block info for the given block.
+    pub(crate) fn new(block: &'a Block) -> Self {
+        Self { block }
+    }
+
+    /// Returns the block's line number position in the script.
+    pub fn line_number(&self) -> u32 {
+        self.block.line_number
+    }
+
+    /// Returns the block's tags. Tags given as `key=value` are stored as a
+    /// single `key=value` string, see [`BlockInfo::tag_value`].
+    pub fn tags(&self) -> &'a HashSet<String> {
+        &self.block.tags
+    }
+
+    /// Returns true if the block has the given tag.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.block.tags.contains(tag)
+    }
+
+    /// Returns the value of a `key=value` block tag with the given key, if
+    /// any. If the key is given multiple times, an arbitrary value is returned.
+    pub fn tag_value(&self, key: &str) -> Option<&'a str> {
+        self.block.tags.iter().find_map(|tag| tag.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    /// Returns the commands in the block that are run by the runner, i.e.
+    /// excluding directives, in order.
+    pub fn commands(&self) -> impl Iterator<Item = &'a Command> {
+        self.block.commands.iter().filter(|command| !command.directive)
+    }
+}
+
 /// A command.
 #[derive(Clone, PartialEq)]
 #[non_exhaustive]
@@ -23,7 +78,8 @@ pub struct Command {
     pub args: Vec<Argument>,
     /// The command prefix, if given.
     pub prefix: Option<String>,
-    /// Any command tags, if given.
+    /// Any command tags, if given. Tags given as `key=value` are stored as a
+    /// single `key=value` string, see [`Command::tag_value`].
     pub tags: HashSet<String>,
     /// Silences the output of this command. This is handled automatically, the
     /// [`Runner`](crate::Runner) does not have to take this into account.
@@ -31,36 +87,116 @@ pub struct Command {
     /// If true, the command is expected to fail with a panic or error. If the
     /// command does not fail, the test fails.
     pub fail: bool,
+    /// If true, the command is run in the background via
+    /// [`Runner::spawn`](crate::Runner::spawn), given by a trailing `&`. Its
+    /// output is written by a later `_wait` command.
+    pub background: bool,
     /// The command's line number position in the script.
     pub line_number: u32,
+    /// If true, this is a `%` directive handled by goldenscript itself, and it
+    /// is never passed to the runner.
+    pub(crate) directive: bool,
 }
 
 impl std::fmt::Debug for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("Command")
-            .field("name", &self.name)
+        let mut f = f.debug_struct("Command");
+        f.field("name", &self.name)
             .field("args", &self.args)
             .field("prefix", &self.prefix)
             // Use a sorted BTreeSet for test determinism.
             .field("tags", &BTreeSet::from_iter(&self.tags))
             .field("silent", &self.silent)
-            .field("fail", &self.fail)
-            .field("line_number", &self.line_number)
-            .finish()
+            .field("fail", &self.fail);
+        // Only show background commands, to keep the common case concise.
+        if self.background {
+            f.field("background", &self.background);
+        }
+        f.field("line_number", &self.line_number).finish()
     }
 }
 
 impl Command {
+    /// Returns the value of a `key=value` tag with the given key, if any. If
+    /// the key is given multiple times, an arbitrary value is returned.
+    pub fn tag_value(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find_map(|tag| tag.strip_prefix(key)?.strip_prefix('='))
+    }
+
     /// Returns an argument consumer, for more convenient argument processing.
     /// Does not affect [`Command::args`].
     ///
     /// See the [module documentation](crate#argument-processing) for usage
     /// examples.
     pub fn consume_args(&self) -> ArgumentConsumer<'_> {
-        ArgumentConsumer::new(&self.args)
+        ArgumentConsumer::new(self)
     }
 }
 
+/// Parses a [`Command`] into a typed value, typically an enum with a variant
+/// per command. Requires the `derive` crate feature, and is usually derived
+/// for enums via `#[derive(FromCommand)]`, replacing hand-written command name
+/// matching and argument processing in [`Runner::run`](crate::Runner::run):
+///
+/// * Variants map to commands by their snake_case name, e.g. `PutAll` to
+///   `put_all`, or by `#[command(name = "...")]`.
+/// * Named fields map to key/value arguments by field name, or to positional
+///   arguments in order with `#[command(positional)]`.
+/// * Tuple fields map to positional arguments in order.
+/// * Values are parsed via [`FromStr`](std::str::FromStr). `Option` fields
+///   are optional, and `Vec` fields take the remaining positional arguments,
+///   or a comma-separated list for key/value arguments (e.g. `ids='1,2'`).
+/// * Unknown commands and arguments error.
+///
+/// ```
+/// use goldenscript::{Command, FromCommand};
+/// use std::error::Error;
+///
+/// #[derive(FromCommand)]
+/// enum KVCommand {
+///     /// get KEY
+///     Get(String),
+///     /// put KEY value=VALUE [ttl=SECONDS]
+///     Put {
+///         #[command(positional)]
+///         key: String,
+///         value: String,
+///         ttl: Option<u64>,
+///     },
+///     /// delete KEY...
+///     #[command(name = "delete")]
+///     Del(Vec<String>),
+/// }
+///
+/// struct Runner;
+///
+/// impl goldenscript::Runner for Runner {
+///     fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+///         Ok(match KVCommand::from_command(command)? {
+///             KVCommand::Get(key) => format!("get {key}"),
+///             KVCommand::Put { key, value, ttl } => format!("put {key}={value} {ttl:?}"),
+///             KVCommand::Del(keys) => format!("delete {}", keys.join(",")),
+///         })
+///     }
+///
+///     fn known_commands(&self) -> Option<Vec<&str>> {
+///         Some(KVCommand::command_names().to_vec())
+///     }
+/// }
+///
+/// let output = goldenscript::generate(&mut Runner, "put a value=1\ndelete a b\n---\n").unwrap();
+/// assert_eq!(output, "put a value=1\ndelete a b\n---\nput a=1 None\ndelete a,b\n");
+/// ```
+#[cfg(feature = "derive")]
+pub trait FromCommand: Sized {
+    /// Parses the command.
+    fn from_command(command: &Command) -> Result<Self, Box<dyn Error>>;
+
+    /// Returns the names of the parseable commands, e.g. for
+    /// [`Runner::known_commands`](crate::Runner::known_commands).
+    fn command_names() -> &'static [&'static str];
+}
+
 /// A command argument.
 #[derive(Clone, Debug, PartialEq)]
 #[non_exhaustive]
@@ -70,6 +206,43 @@ pub struct Argument {
     pub key: Option<String>,
     /// The argument value. Can be empty.
     pub value: String,
+    /// The type of the value, as written in the script.
+    pub value_type: ValueType,
+}
+
+/// The type of an argument value, as written in the script. Only unquoted
+/// values are typed, such that e.g. `count=10` is an integer but `count="10"`
+/// is a string. Heredocs and JSON literals are strings. The raw value is
+/// always available as [`Argument::value`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValueType {
+    /// A string, e.g. `foo` or `"10"`.
+    #[default]
+    String,
+    /// A 64-bit signed or unsigned integer, e.g. `10`.
+    Integer,
+    /// A floating point number, e.g. `1.5` or `1e3`.
+    Float,
+    /// A boolean, i.e. `true` or `false`.
+    Boolean,
+}
+
+impl ValueType {
+    /// Infers the type of an unquoted value.
+    pub(crate) fn infer(value: &str) -> Self {
+        if value.parse::<i64>().is_ok() || value.parse::<u64>().is_ok() {
+            return Self::Integer;
+        }
+        // Disallow inf and NaN, which are parseable as floats.
+        let digits = value.trim_start_matches(['-', '+']);
+        if digits.starts_with(|c: char| c.is_ascii_digit()) && value.parse::<f64>().is_ok() {
+            return Self::Float;
+        }
+        if value == "true" || value == "false" {
+            return Self::Boolean;
+        }
+        Self::String
+    }
 }
 
 impl Argument {
@@ -91,6 +264,34 @@ impl Argument {
     {
         self.value.parse().map_err(|e| format!("invalid argument '{}': {e}", self.value).into())
     }
+
+    /// Parses the argument value as a duration with a unit suffix, e.g.
+    /// `timeout=500ms`, see [`util::parse_duration()`](crate::util::parse_duration).
+    pub fn parse_duration(&self) -> Result<std::time::Duration, Box<dyn Error>> {
+        crate::util::parse_duration(&self.value).map_err(|e| self.context(e))
+    }
+
+    /// Parses the argument value as a byte size with an optional unit suffix,
+    /// e.g. `limit=4MB`, see [`util::parse_size()`](crate::util::parse_size).
+    pub fn parse_size(&self) -> Result<u64, Box<dyn Error>> {
+        crate::util::parse_size(&self.value).map_err(|e| self.context(e))
+    }
+
+    /// Adds the argument key to an error, if any.
+    fn context(&self, e: Box<dyn Error>) -> Box<dyn Error> {
+        match &self.key {
+            Some(key) => format!("invalid {key}: {e}").into(),
+            None => e,
+        }
+    }
+
+    /// Parses the argument value as JSON, typically given as a JSON object or
+    /// array literal. Requires the `serde_json` crate feature.
+    #[cfg(feature = "serde_json")]
+    pub fn json(&self) -> Result<serde_json::Value, Box<dyn Error>> {
+        serde_json::from_str(&self.value)
+            .map_err(|e| format!("invalid JSON argument '{}': {e}", self.value).into())
+    }
 }
 
 /// Helper for argument processing, by returning and removing arguments on
@@ -100,6 +301,29 @@ impl Argument {
 /// intended for out-of-order processing, unlike most iterators.
 pub struct ArgumentConsumer<'a> {
     args: VecDeque<&'a Argument>,
+    /// The command, for error messages.
+    command: &'a Command,
+    /// The source of values looked up via lookup_or(), by key.
+    sources: Vec<(String, ValueSource)>,
+}
+
+/// The source of an argument value looked up via
+/// [`ArgumentConsumer::lookup_or()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueSource {
+    /// The value was given in the script.
+    Script,
+    /// The value was not given in the script, and a default value was used.
+    Default,
+}
+
+impl std::fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Script => write!(f, "script"),
+            Self::Default => write!(f, "default"),
+        }
+    }
 }
 
 impl<'a> Iterator for ArgumentConsumer<'a> {
@@ -113,8 +337,20 @@ impl<'a> Iterator for ArgumentConsumer<'a> {
 
 impl<'a> ArgumentConsumer<'a> {
     /// Creates a new argument consumer.
-    fn new(args: &'a [Argument]) -> Self {
-        Self { args: VecDeque::from_iter(args.iter()) }
+    fn new(command: &'a Command) -> Self {
+        Self { args: VecDeque::from_iter(command.args.iter()), command, sources: Vec::new() }
+    }
+
+    /// Returns an error for the command, including its name and line number.
+    fn error(&self, message: impl std::fmt::Display) -> Box<dyn Error> {
+        let (name, line_number) = (&self.command.name, self.command.line_number);
+        format!("{message} for command '{name}' at line {line_number}").into()
+    }
+
+    /// Returns an error for an invalid argument value, including the argument
+    /// name and the command's name and line number.
+    fn invalid(&self, name: &str, value: &str, e: impl std::fmt::Display) -> Box<dyn Error> {
+        self.error(format!("invalid {name} '{value}': {e}"))
     }
 
     /// Looks up and removes a key/value argument by key. If multiple arguments
@@ -127,7 +363,8 @@ impl<'a> ArgumentConsumer<'a> {
         arg
     }
 
-    /// Looks up and parses a key/value argument by key, removing it. If parsing
+    /// Looks up and parses a key/value argument by key, removing it. Parse errors
+    /// include the key and the command's name and line number. If parsing
     /// errors, the argument is not removed.
     pub fn lookup_parse<T>(&mut self, key: &str) -> Result<Option<T>, Box<dyn Error>>
     where
@@ -139,7 +376,7 @@ impl<'a> ArgumentConsumer<'a> {
             .iter()
             .rev()
             .find(|a| a.key.as_deref() == Some(key))
-            .map(|a| a.parse())
+            .map(|a| a.value.parse().map_err(|e| self.invalid(key, &a.value, e)))
             .transpose()?;
         if value.is_some() {
             self.args.retain(|a| a.key.as_deref() != Some(key))
@@ -147,6 +384,116 @@ impl<'a> ArgumentConsumer<'a> {
         Ok(value)
     }
 
+    /// Looks up a key/value argument by key and parses it as a comma-separated
+    /// list of T, removing it. Elements are trimmed of surrounding whitespace,
+    /// and an empty value yields an empty list. Since unquoted strings can't
+    /// contain commas, the value must be quoted, e.g. `ids='1,2,3'`. Parse errors
+    /// include the key, the element, and the command's name and line number. If
+    /// parsing errors, the argument is not removed.
+    pub fn lookup_list_parse<T>(&mut self, key: &str) -> Result<Option<Vec<T>>, Box<dyn Error>>
+    where
+        T: std::str::FromStr,
+        <T as std::str::FromStr>::Err: std::fmt::Display,
+    {
+        let Some(arg) = self.args.iter().rev().find(|a| a.key.as_deref() == Some(key)) else {
+            return Ok(None);
+        };
+        let mut list = Vec::new();
+        if !arg.value.trim().is_empty() {
+            for element in arg.value.split(',').map(|e| e.trim()) {
+                let value = element
+                    .parse()
+                    .map_err(|e| self.error(format!("invalid {key} element '{element}': {e}")))?;
+                list.push(value);
+            }
+        }
+        self.args.retain(|a| a.key.as_deref() != Some(key));
+        Ok(Some(list))
+    }
+
+    /// Looks up and parses a key/value argument by key like
+    /// [`lookup_parse()`](Self::lookup_parse), removing it, or returns the given
+    /// default if it's not given. Records whether the value came from the
+    /// script or the default, see [`sources()`](Self::sources).
+    pub fn lookup_or<T>(&mut self, key: &str, default: T) -> Result<T, Box<dyn Error>>
+    where
+        T: std::str::FromStr,
+        <T as std::str::FromStr>::Err: std::fmt::Display,
+    {
+        let (value, source) = match self.lookup_parse(key)? {
+            Some(value) => (value, ValueSource::Script),
+            None => (default, ValueSource::Default),
+        };
+        self.sources.push((key.to_string(), source));
+        Ok(value)
+    }
+
+    /// Returns the keys looked up via [`lookup_or()`](Self::lookup_or) and
+    /// whether their values came from the script or a default, in lookup order.
+    /// Runners can include this in their output, e.g. to show reviewers which
+    /// behaviors a script pinned and which it inherited from defaults.
+    pub fn sources(&self) -> &[(String, ValueSource)] {
+        &self.sources
+    }
+
+    /// Looks up a key/value argument by key and validates that its value is one
+    /// of the given allowed values, removing it. The error lists the allowed
+    /// values, and includes the key and the command's name and line number. If
+    /// validation fails, the argument is not removed.
+    pub fn lookup_one_of(
+        &mut self,
+        key: &str,
+        allowed: &[&str],
+    ) -> Result<Option<&'a str>, Box<dyn Error>> {
+        let Some(arg) = self.args.iter().rev().find(|a| a.key.as_deref() == Some(key)) else {
+            return Ok(None);
+        };
+        if !allowed.contains(&arg.value.as_str()) {
+            return Err(self.error(format!(
+                "invalid {key} '{}', expected one of: {}",
+                arg.value,
+                allowed.join(", ")
+            )));
+        }
+        let value = arg.value.as_str();
+        self.args.retain(|a| a.key.as_deref() != Some(key));
+        Ok(Some(value))
+    }
+
+    /// Deserializes all remaining arguments into a T, removing them. Requires
+    /// the `serde` crate feature.
+    ///
+    /// Key/value arguments are mapped onto struct fields by key, parsing the
+    /// value as the field type, and comma-separated values as sequences (e.g.
+    /// `ids='1,2,3'`). Positional arguments are mapped onto an `args` field as
+    /// a sequence, typically a Vec or tuple, or onto T itself if it's a Vec or
+    /// tuple. Arguments without a corresponding field are rejected, and fields
+    /// must use `#[serde(default)]` or Option to be optional. If
+    /// deserialization errors, no arguments are removed.
+    ///
+    /// ```
+    /// # use goldenscript::Command;
+    /// #[derive(serde::Deserialize)]
+    /// struct Send {
+    ///     args: (String, u32),
+    ///     #[serde(default)]
+    ///     retry: bool,
+    ///     timeout: Option<u64>,
+    /// }
+    /// # fn run(command: &Command) -> Result<(), Box<dyn std::error::Error>> {
+    /// // send foo 1 retry=true
+    /// let send: Send = command.consume_args().deserialize()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: serde::de::DeserializeOwned>(&mut self) -> Result<T, Box<dyn Error>> {
+        let args = Vec::from_iter(self.args.iter().copied());
+        let value = T::deserialize(crate::de::ArgumentsDeserializer::new(args))?;
+        self.args.clear();
+        Ok(value)
+    }
+
     /// Returns and removes the next key/value argument, if any.
     pub fn next_key(&mut self) -> Option<&'a Argument> {
         self.args.iter().position(|a| a.key.is_some()).map(|i| self.args.remove(i).unwrap())
@@ -157,12 +504,82 @@ impl<'a> ArgumentConsumer<'a> {
         self.args.iter().position(|a| a.key.is_none()).map(|i| self.args.remove(i).unwrap())
     }
 
-    /// Rejects any remaining arguments with an error.
+    /// Looks up and removes a positional argument with the given value as a
+    /// boolean flag, returning true if it was given, e.g. `take_flag("verbose")`
+    /// for `cmd verbose`. If the flag is given multiple times, all are removed.
+    pub fn take_flag(&mut self, name: &str) -> bool {
+        let len = self.args.len();
+        self.args.retain(|a| a.key.is_some() || a.value != name);
+        self.args.len() < len
+    }
+
+    /// Returns and removes the next positional argument, or errors with the
+    /// given argument name and the command's name and line number if it isn't
+    /// given, e.g. "key not given for command 'get' at line 3".
+    pub fn require_pos(&mut self, name: &str) -> Result<&'a Argument, Box<dyn Error>> {
+        self.next_pos().ok_or_else(|| self.error(format!("{name} not given")))
+    }
+
+    /// Returns, removes, and parses the next positional argument like
+    /// [`require_pos()`](Self::require_pos). Parse errors also include the
+    /// argument name and the command's name and line number.
+    pub fn require_pos_parse<T>(&mut self, name: &str) -> Result<T, Box<dyn Error>>
+    where
+        T: std::str::FromStr,
+        <T as std::str::FromStr>::Err: std::fmt::Display,
+    {
+        let arg = self.require_pos(name)?;
+        arg.value.parse().map_err(|e| self.invalid(name, &arg.value, e))
+    }
+
+    /// Looks up and removes a key/value argument by key like
+    /// [`lookup()`](Self::lookup), or errors with the key and the command's name
+    /// and line number if it isn't given.
+    pub fn require_key(&mut self, key: &str) -> Result<&'a Argument, Box<dyn Error>> {
+        self.lookup(key).ok_or_else(|| self.error(format!("{key} not given")))
+    }
+
+    /// Looks up, removes, and parses a key/value argument by key like
+    /// [`require_key()`](Self::require_key). Parse errors also include the key
+    /// and the command's name and line number. If parsing errors, the argument
+    /// is not removed.
+    pub fn require_key_parse<T>(&mut self, key: &str) -> Result<T, Box<dyn Error>>
+    where
+        T: std::str::FromStr,
+        <T as std::str::FromStr>::Err: std::fmt::Display,
+    {
+        let Some(arg) = self.args.iter().rev().find(|a| a.key.as_deref() == Some(key)) else {
+            return Err(self.error(format!("{key} not given")));
+        };
+        let value = arg.value.parse().map_err(|e| self.invalid(key, &arg.value, e))?;
+        self.args.retain(|a| a.key.as_deref() != Some(key));
+        Ok(value)
+    }
+
+    /// Rejects any remaining arguments with an error, listing all of them.
     pub fn reject_rest(&self) -> Result<(), Box<dyn Error>> {
-        if let Some(arg) = self.args.front() {
-            return Err(format!("invalid argument '{}'", arg.name()).into());
+        match self.args.len() {
+            0 => Ok(()),
+            1 => Err(format!("invalid argument '{}'", self.args[0].name()).into()),
+            _ => {
+                let names: Vec<_> = self.args.iter().map(|a| format!("'{}'", a.name())).collect();
+                Err(format!("invalid arguments {}", names.join(", ")).into())
+            }
         }
-        Ok(())
+    }
+
+    /// Rejects any remaining arguments with an error, using the given closure
+    /// to generate an error message for each argument. The messages are
+    /// joined by "; ".
+    pub fn reject_rest_with<F>(&self, f: F) -> Result<(), Box<dyn Error>>
+    where
+        F: Fn(&Argument) -> String,
+    {
+        if self.args.is_empty() {
+            return Ok(());
+        }
+        let messages: Vec<_> = self.args.iter().map(|a| f(a)).collect();
+        Err(messages.join("; ").into())
     }
 
     /// Returns and removes all remaining arguments.
@@ -196,10 +613,14 @@ mod tests {
     /// Constructs an Argument from a string value or key => value.
     macro_rules! arg {
         ($value:expr) => {
-            Argument { key: None, value: $value.to_string() }
+            Argument { key: None, value: $value.to_string(), value_type: ValueType::String }
         };
         ($key:expr => $value:expr) => {
-            Argument { key: Some($key.to_string()), value: $value.to_string() }
+            Argument {
+                key: Some($key.to_string()),
+                value: $value.to_string(),
+                value_type: ValueType::String,
+            }
         };
     }
 
@@ -243,6 +664,73 @@ mod tests {
         );
     }
 
+    /// Tests Argument.parse_duration() and parse_size().
+    #[test]
+    fn argument_parse_duration_size() {
+        use std::time::Duration;
+
+        assert_eq!(arg!("500ms").parse_duration().unwrap(), Duration::from_millis(500));
+        assert_eq!(arg!("timeout" => "30s").parse_duration().unwrap(), Duration::from_secs(30));
+        assert_eq!(arg!("30").parse_duration().unwrap_err().to_string(), "invalid duration '30'");
+        assert_eq!(
+            arg!("timeout" => "30").parse_duration().unwrap_err().to_string(),
+            "invalid timeout: invalid duration '30'"
+        );
+
+        assert_eq!(arg!("4KiB").parse_size().unwrap(), 4096);
+        assert_eq!(arg!("limit" => "4MB").parse_size().unwrap(), 4_000_000);
+        assert_eq!(
+            arg!("limit" => "4XB").parse_size().unwrap_err().to_string(),
+            "invalid limit: invalid size '4XB'"
+        );
+    }
+
+    /// Tests Argument.json().
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn argument_json() {
+        let cmd = cmd!(r#"cmd {"a": [1, true, null]} key=["x"] str='{"b": "c"}'"#);
+        assert_eq!(cmd.args[0].json().unwrap(), serde_json::json!({"a": [1, true, null]}));
+        assert_eq!(cmd.args[1].json().unwrap(), serde_json::json!(["x"]));
+        assert_eq!(cmd.args[2].json().unwrap(), serde_json::json!({"b": "c"}));
+        assert_eq!(
+            arg!("foo").json().unwrap_err().to_string(),
+            "invalid JSON argument 'foo': expected ident at line 1 column 2"
+        );
+    }
+
+    /// Tests that the parser infers value types of unquoted values.
+    #[test]
+    fn argument_value_type() {
+        use ValueType::*;
+
+        let cmd = cmd!(
+            r#"cmd 10 "10" 1.5 1e3 1. true 'false' inf NaN 1.2.3 18446744073709551615 k= x=<<EOF"#
+                .to_string()
+                + "\nEOF"
+        );
+        let types: Vec<_> = cmd.args.iter().map(|a| a.value_type).collect();
+        assert_eq!(
+            types,
+            vec![
+                Integer, String, Float, Float, Float, Boolean, String, String, String, String,
+                Integer, String, String
+            ]
+        );
+    }
+
+    /// Tests Command.tag_value().
+    #[test]
+    fn command_tag_value() {
+        let cmd = cmd!("cmd [tag key=value empty= wrap=10]");
+        assert_eq!(cmd.tag_value("key"), Some("value"));
+        assert_eq!(cmd.tag_value("empty"), Some(""));
+        assert_eq!(cmd.tag_value("wrap"), Some("10"));
+        assert_eq!(cmd.tag_value("tag"), None);
+        assert_eq!(cmd.tag_value("ke"), None);
+        assert_eq!(cmd.tag_value("unknown"), None);
+    }
+
     /// Tests Command.consume_args(). ArgumentConsumer is tested separately.
     #[test]
     fn command_consume_args() {
@@ -299,10 +787,42 @@ mod tests {
         // lookup_parse() does not remove arguments on parse errors, even with
         // duplicate keys.
         let mut args = cmd.consume_args();
-        assert!(args.lookup_parse::<bool>("key").is_err());
+        assert_eq!(
+            args.lookup_parse::<bool>("key").unwrap_err().to_string(),
+            "invalid key '2': provided string was not `true` or `false` for command 'cmd' at line 1"
+        );
         assert_eq!(args.rest(), vec![&cmd.args[0], &cmd.args[1], &cmd.args[2], &cmd.args[3]]);
     }
 
+    /// Tests ArgumentConsumer.lookup_list_parse().
+    #[test]
+    fn argument_consumer_lookup_list_parse() {
+        let cmd = cmd!("cmd ids='1, 2,3' empty='' one=1 bad='1,x' ids=4");
+
+        // lookup_list_parse() returns None on unknown keys.
+        let mut args = cmd.consume_args();
+        assert_eq!(args.lookup_list_parse::<i64>("unknown").unwrap(), None);
+        assert_eq!(args.rest().len(), 5);
+
+        // lookup_list_parse() splits, trims, and parses the last value, and
+        // removes all duplicate keys.
+        let mut args = cmd.consume_args();
+        assert_eq!(args.lookup_list_parse::<i64>("ids").unwrap(), Some(vec![4]));
+        assert_eq!(args.rest(), vec![&cmd.args[1], &cmd.args[2], &cmd.args[3]]);
+
+        let cmd = cmd!("cmd ids='1, 2,3' empty='' bad='1,x'");
+        let mut args = cmd.consume_args();
+        assert_eq!(args.lookup_list_parse::<i64>("ids").unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(args.lookup_list_parse::<i64>("empty").unwrap(), Some(vec![]));
+
+        // Parse errors name the key and element, and don't remove the argument.
+        assert_eq!(
+            args.lookup_list_parse::<i64>("bad").unwrap_err().to_string(),
+            "invalid bad element 'x': invalid digit found in string for command 'cmd' at line 1"
+        );
+        assert_eq!(args.rest(), vec![&cmd.args[2]]);
+    }
+
     /// Tests ArgumentConsumer.next(), next_pos(), and next_key().
     #[test]
     fn argument_consumer_next() {
@@ -338,6 +858,177 @@ mod tests {
         assert!(args.rest().is_empty());
     }
 
+    /// Tests ArgumentConsumer.lookup_or() and sources().
+    #[test]
+    fn argument_consumer_lookup_or() {
+        let cmd = cmd!("cmd foo=1 bar=x");
+        let mut args = cmd.consume_args();
+
+        // Given values are parsed and removed, missing values use the default.
+        assert_eq!(args.lookup_or("foo", 7).unwrap(), 1);
+        assert_eq!(args.lookup_or("baz", 7).unwrap(), 7);
+        assert_eq!(
+            args.sources(),
+            &[("foo".to_string(), ValueSource::Script), ("baz".to_string(), ValueSource::Default)]
+        );
+
+        // Parse errors are returned without recording a source or removing the
+        // argument.
+        assert_eq!(
+            args.lookup_or("bar", 7).unwrap_err().to_string(),
+            "invalid bar 'x': invalid digit found in string for command 'cmd' at line 1"
+        );
+        assert_eq!(args.sources().len(), 2);
+        assert_eq!(args.rest(), vec![&cmd.args[1]]);
+    }
+
+    /// Tests ArgumentConsumer.lookup_one_of().
+    #[test]
+    fn argument_consumer_lookup_one_of() {
+        let allowed = &["sync", "async"];
+        let cmd = cmd!("cmd mode=foo mode=async other=sync");
+
+        // Missing key returns None.
+        let mut args = cmd.consume_args();
+        assert_eq!(args.lookup_one_of("unknown", allowed).unwrap(), None);
+
+        // Allowed value returns the last value and removes all.
+        assert_eq!(args.lookup_one_of("mode", allowed).unwrap(), Some("async"));
+        assert_eq!(args.rest(), vec![&cmd.args[2]]);
+
+        // Disallowed value errors and lists the allowed values, without
+        // removing the argument.
+        let cmd = cmd!("cmd mode=foo");
+        let mut args = cmd.consume_args();
+        assert_eq!(
+            args.lookup_one_of("mode", allowed).unwrap_err().to_string(),
+            "invalid mode 'foo', expected one of: sync, async for command 'cmd' at line 1"
+        );
+        assert_eq!(args.rest(), vec![&cmd.args[0]]);
+    }
+
+    /// Tests ArgumentConsumer.deserialize().
+    #[cfg(feature = "serde")]
+    #[test]
+    fn argument_consumer_deserialize() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        enum Mode {
+            Fast,
+            Safe,
+        }
+
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Args {
+            #[serde(default)]
+            args: Vec<String>,
+            id: u64,
+            ratio: Option<f64>,
+            ids: Vec<u32>,
+            mode: Mode,
+            #[serde(default)]
+            verbose: bool,
+        }
+
+        let cmd = cmd!("cmd foo id=1 ids='1, 2,3' mode=safe bar id=7 ratio=0.5");
+        let mut args = cmd.consume_args();
+        assert_eq!(
+            args.deserialize::<Args>().unwrap(),
+            Args {
+                args: vec!["foo".into(), "bar".into()],
+                id: 7,
+                ratio: Some(0.5),
+                ids: vec![1, 2, 3],
+                mode: Mode::Safe,
+                verbose: false,
+            }
+        );
+        assert!(args.rest().is_empty());
+
+        // Positional arguments can be deserialized as tuples and Vecs, and
+        // errors keep the arguments.
+        let cmd = cmd!("cmd foo 1 2");
+        let mut args = cmd.consume_args();
+        assert_eq!(
+            args.deserialize::<(String, u8)>().unwrap_err().to_string(),
+            "invalid length 3, expected 2 elements in sequence"
+        );
+        assert_eq!(
+            args.deserialize::<Vec<u8>>().unwrap_err().to_string(),
+            "invalid argument 'foo': invalid digit found in string"
+        );
+        assert_eq!(args.deserialize::<(String, u8, u8)>().unwrap(), ("foo".into(), 1, 2));
+        assert!(args.rest().is_empty());
+
+        // Errors.
+        for (input, error) in [
+            ("cmd id=x ids= mode=fast", "invalid id 'x': invalid digit found in string"),
+            ("cmd id=1 ids=1 mode=slow", "unknown variant `slow`, expected `fast` or `safe`"),
+            ("cmd id=1 mode=fast", "missing field `ids`"),
+            ("cmd id=1 ids= mode=fast foo=bar", "invalid argument 'foo'"),
+            (
+                "cmd id=1 ids= mode=fast verbose=yes",
+                "invalid verbose 'yes': provided string was not `true` or `false`",
+            ),
+        ] {
+            let cmd = cmd!(input);
+            let result = cmd.consume_args().deserialize::<Args>();
+            assert_eq!(result.unwrap_err().to_string(), error, "{input}");
+        }
+        let cmd = cmd!("cmd foo key=value");
+        let result = cmd.consume_args().deserialize::<Vec<String>>();
+        assert_eq!(result.unwrap_err().to_string(), "invalid argument 'key'");
+    }
+
+    /// Tests ArgumentConsumer.take_flag().
+    #[test]
+    fn argument_consumer_take_flag() {
+        let cmd = cmd!("cmd foo verbose verbose=false force verbose");
+
+        let mut args = cmd.consume_args();
+        assert!(args.take_flag("verbose"));
+        assert!(!args.take_flag("verbose"));
+        assert!(!args.take_flag("dry_run"));
+        assert!(args.take_flag("force"));
+        assert_eq!(args.rest(), vec![&cmd.args[0], &cmd.args[2]]);
+    }
+
+    /// Tests ArgumentConsumer.require_pos(), require_key(), and their _parse()
+    /// variants.
+    #[test]
+    fn argument_consumer_require() {
+        let cmd = cmd!("cmd foo 7 key=value n=x n=3");
+
+        let mut args = cmd.consume_args();
+        assert_eq!(args.require_pos("name").unwrap(), &cmd.args[0]);
+        assert_eq!(args.require_pos_parse::<u64>("id").unwrap(), 7);
+        assert_eq!(
+            args.require_pos("name").unwrap_err().to_string(),
+            "name not given for command 'cmd' at line 1"
+        );
+        assert_eq!(args.require_key("key").unwrap(), &cmd.args[2]);
+        assert_eq!(
+            args.require_key("key").unwrap_err().to_string(),
+            "key not given for command 'cmd' at line 1"
+        );
+        assert_eq!(args.require_key_parse::<u64>("n").unwrap(), 3);
+        assert!(args.rest().is_empty());
+
+        let mut args = cmd.consume_args();
+        assert_eq!(
+            args.require_pos_parse::<u64>("id").unwrap_err().to_string(),
+            "invalid id 'foo': invalid digit found in string for command 'cmd' at line 1"
+        );
+        assert_eq!(
+            args.require_key_parse::<bool>("key").unwrap_err().to_string(),
+            concat!(
+                "invalid key 'value': provided string was not `true` or `false` ",
+                "for command 'cmd' at line 1"
+            )
+        );
+        assert_eq!(args.rest().len(), 4); // key is not removed on errors
+    }
+
     /// Tests ArgumentConsumer.reject_rest().
     #[test]
     fn argument_consumer_reject_rest() {
@@ -356,6 +1047,30 @@ mod tests {
         let mut args = cmd.consume_args();
         assert_eq!(args.reject_rest().unwrap_err().to_string(), "invalid argument 'key'");
         assert!(!args.rest().is_empty());
+
+        // Multiple arguments are all reported.
+        let cmd = cmd!("cmd foo key=value bar");
+        let args = cmd.consume_args();
+        assert_eq!(
+            args.reject_rest().unwrap_err().to_string(),
+            "invalid arguments 'foo', 'key', 'bar'"
+        );
+    }
+
+    /// Tests ArgumentConsumer.reject_rest_with().
+    #[test]
+    fn argument_consumer_reject_rest_with() {
+        let f = |arg: &Argument| format!("unknown {}", arg.name());
+
+        // Empty args return Ok.
+        let cmd = cmd!("cmd");
+        assert!(cmd.consume_args().reject_rest_with(f).is_ok());
+
+        // All arguments are reported with custom messages.
+        let cmd = cmd!("cmd foo key=value");
+        let mut args = cmd.consume_args();
+        assert_eq!(args.reject_rest_with(f).unwrap_err().to_string(), "unknown foo; unknown key");
+        assert_eq!(args.rest().len(), 2);
     }
 
     /// Tests ArgumentConsumer.rest(), rest_pos() and rest_key().
@@ -0,0 +1,278 @@
+//! Conformance checks for runner implementations.
+//!
+//! [`check()`] exercises a runner with a standard battery of inputs, and
+//! reports any deviations from well-behaved runner behavior. This helps
+//! runner authors catch problems that scripts rarely exercise, such as panics
+//! on missing or unusual arguments, or unknown commands that are silently
+//! accepted. For example:
+//!
+//! ```
+//! # use std::error::Error;
+//! # use goldenscript::Command;
+//! struct Runner;
+//!
+//! impl goldenscript::Runner for Runner {
+//!     fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+//!         match command.name.as_str() {
+//!             "echo" => Ok(command.args.iter().map(|a| a.value.clone()).collect()),
+//!             name => Err(format!("unknown command {name}").into()),
+//!         }
+//!     }
+//! }
+//!
+//! let deviations = goldenscript::conformance::check_with(&mut Runner, &["echo"]);
+//! assert_eq!(deviations, vec![]);
+//! ```
+//!
+//! The following checks are run:
+//!
+//! * `hooks`: the script and block hooks succeed on a fresh runner.
+//! * `unknown command`: a command the runner can't know errors.
+//! * `empty args`: each known command handles missing arguments without
+//!   panicking.
+//! * `unicode args`: each known command handles Unicode arguments without
+//!   panicking.
+//!
+//! All checks also verify that errors have a message, and that panics have a
+//! string message, since goldenscript can't otherwise output them for commands
+//! expected to fail with `!`.
+//!
+//! Since commands are run for real, use a runner with disposable state. Panics
+//! are caught, but are still printed by the panic hook.
+
+use std::collections::HashSet;
+use std::error::Error;
+
+use crate::command::Block;
+use crate::{Argument, BlockInfo, Command, RunContext, Runner, ValueType};
+
+/// The name of a command that no runner should recognize.
+const UNKNOWN_COMMAND: &str = "_goldenscript_conformance_unknown";
+
+/// A deviation from well-behaved runner behavior, found by [`check()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Deviation {
+    /// The name of the check that found the deviation, e.g. `unknown command`.
+    pub check: &'static str,
+    /// A description of the deviation.
+    pub message: String,
+}
+
+impl std::fmt::Display for Deviation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.check, self.message)
+    }
+}
+
+/// Checks the runner for conformance, returning any deviations. The
+/// per-command checks are run for the commands given by
+/// [`Runner::known_commands`], if any.
+pub fn check<R: Runner>(runner: &mut R) -> Vec<Deviation> {
+    let commands: Vec<String> =
+        (runner.known_commands().into_iter().flatten()).map(|name| name.to_string()).collect();
+    check_with(runner, &commands)
+}
+
+/// Like [`check()`], but runs the per-command checks for the given command
+/// names, e.g. for runners that don't implement [`Runner::known_commands`].
+pub fn check_with<R: Runner>(runner: &mut R, commands: &[impl AsRef<str>]) -> Vec<Deviation> {
+    let mut deviations = Vec::new();
+    let mut deviation = |check, message| deviations.push(Deviation { check, message });
+
+    // The hooks should succeed on a fresh runner, without any commands.
+    let block = Block {
+        commands: Vec::new(),
+        tags: HashSet::new(),
+        literal: String::new(),
+        line_number: 1,
+    };
+    let block = BlockInfo::new(&block);
+    let mut ctx = RunContext::new();
+    for (hook, result) in [
+        ("start_script", call(|| runner.start_script(&mut ctx).map(|_| String::new()))),
+        ("start_block", call(|| runner.start_block(&block, &mut ctx))),
+        ("end_block", call(|| runner.end_block(&block, &mut ctx).map(|o| o.to_string()))),
+        ("end_script", call(|| runner.end_script(&mut ctx))),
+    ] {
+        if let Err(message) = result {
+            deviation("hooks", format!("{hook} failed: {message}"));
+        }
+    }
+
+    // Unknown commands should error.
+    let unknown = command(UNKNOWN_COMMAND, Vec::new());
+    match run(runner, &unknown, &mut ctx) {
+        Outcome::Ok(output) => deviation(
+            "unknown command",
+            format!("command '{UNKNOWN_COMMAND}' succeeded with output {output:?}"),
+        ),
+        Outcome::Err => {}
+        Outcome::Deviation(message) => deviation("unknown command", message),
+    }
+
+    // Known commands shouldn't panic on missing or Unicode arguments.
+    for name in commands.iter().map(|name| name.as_ref()) {
+        let empty = command(name, Vec::new());
+        if let Outcome::Deviation(message) = run(runner, &empty, &mut ctx) {
+            deviation("empty args", message);
+        }
+
+        let unicode = command(
+            name,
+            vec![
+                argument(None, "🦀"),
+                argument(None, "日本語"),
+                argument(Some("ключ"), "ünïcödé\u{200b}"),
+            ],
+        );
+        if let Outcome::Deviation(message) = run(runner, &unicode, &mut ctx) {
+            deviation("unicode args", message);
+        }
+    }
+
+    deviations
+}
+
+/// The outcome of running a command.
+enum Outcome {
+    /// The command succeeded with the given output.
+    Ok(String),
+    /// The command errored in a well-behaved way.
+    Err,
+    /// The command misbehaved, as described by the message.
+    Deviation(String),
+}
+
+/// Runs a command, catching panics.
+fn run<R: Runner>(runner: &mut R, command: &Command, ctx: &mut RunContext) -> Outcome {
+    let describe = |message: String| format!("command '{}' {message}", command.name);
+    match call(|| crate::runner::run_ctx(runner, command, ctx)) {
+        Ok(output) => Outcome::Ok(output),
+        Err(Failure::Error(message)) if message.is_empty() => {
+            Outcome::Deviation(describe("errored with an empty message".to_string()))
+        }
+        Err(Failure::Error(_)) => Outcome::Err,
+        Err(failure) => Outcome::Deviation(describe(failure.to_string())),
+    }
+}
+
+/// A failed call.
+enum Failure {
+    /// The call returned an error with the given message.
+    Error(String),
+    /// The call panicked with the given message, if it was a string.
+    Panic(Option<String>),
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Error(message) => write!(f, "{message}"),
+            Self::Panic(Some(message)) => write!(f, "panicked: {message}"),
+            Self::Panic(None) => write!(f, "panicked with a non-string payload"),
+        }
+    }
+}
+
+/// Calls a runner method, catching errors and panics.
+fn call(f: impl FnOnce() -> Result<String, Box<dyn Error>>) -> Result<String, Failure> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) => Err(Failure::Error(e.to_string())),
+        Err(panic) => Err(Failure::Panic(
+            (panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                .or_else(|| panic.downcast_ref::<String>().cloned()),
+        )),
+    }
+}
+
+/// Creates a command with the given name and arguments.
+fn command(name: &str, args: Vec<Argument>) -> Command {
+    Command {
+        name: name.to_string(),
+        args,
+        prefix: None,
+        tags: HashSet::new(),
+        silent: false,
+        fail: false,
+        background: false,
+        line_number: 1,
+        directive: false,
+    }
+}
+
+/// Creates a string argument.
+fn argument(key: Option<&str>, value: &str) -> Argument {
+    Argument {
+        key: key.map(|key| key.to_string()),
+        value: value.to_string(),
+        value_type: ValueType::String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::Output;
+
+    /// A misbehaving runner: it accepts unknown commands, panics on missing or
+    /// non-ASCII arguments, and errors without a message.
+    struct Misbehaving;
+
+    impl Runner for Misbehaving {
+        fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+            match command.name.as_str() {
+                "get" => Ok(command.args[0].value.clone()),
+                "put" if command.args.iter().any(|a| !a.value.is_ascii()) => {
+                    std::panic::panic_any(42)
+                }
+                "put" => Err("".into()),
+                _ => Ok("ok".to_string()),
+            }
+        }
+
+        fn known_commands(&self) -> Option<Vec<&str>> {
+            Some(vec!["get", "put"])
+        }
+
+        fn end_block(
+            &mut self,
+            _: &BlockInfo,
+            _: &mut RunContext,
+        ) -> Result<Output, Box<dyn Error>> {
+            Err("no block".into())
+        }
+    }
+
+    /// Tests that deviations are reported.
+    #[test]
+    fn check() {
+        let deviations: Vec<_> =
+            super::check(&mut Misbehaving).into_iter().map(|d| d.to_string()).collect();
+        assert_eq!(
+            deviations,
+            vec![
+                "hooks: end_block failed: no block".to_string(),
+                format!(
+                    "unknown command: command '{UNKNOWN_COMMAND}' succeeded with output \"ok\""
+                ),
+                concat!(
+                    "empty args: command 'get' panicked: ",
+                    "index out of bounds: the len is 0 but the index is 0"
+                )
+                .to_string(),
+                "empty args: command 'put' errored with an empty message".to_string(),
+                "unicode args: command 'put' panicked with a non-string payload".to_string(),
+            ]
+        );
+    }
+
+    /// Tests that a well-behaved runner has no deviations.
+    #[test]
+    fn check_ok() {
+        let mut runner = crate::CommandRegistry::new(()).register("get", |command, _| {
+            Ok(command.consume_args().require_pos("key")?.value.clone())
+        });
+        assert_eq!(check_with(&mut runner, &["get"]), vec![]);
+    }
+}
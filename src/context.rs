@@ -0,0 +1,653 @@
+use crate::resources::Reservation;
+use crate::shared::AnySharedFixture;
+use crate::ScriptMetadata;
+
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Context for a goldenscript run, passed to [`Runner::run_ctx`](crate::Runner::run_ctx).
+/// A new context is created for each goldenscript.
+///
+/// The context provides a deterministic pseudo-random number generator, seeded
+/// via the `%seed` directive (0 by default), which can be used e.g. to shuffle
+/// inputs while keeping the output stable across runs. It also holds data
+/// generated by the `%gen` directive, a [`CancellationToken`] for the
+/// script, a sequence counter via [`RunContext::next_seq`], the script's
+/// scratch directory, if any, any [`SharedFixture`](crate::SharedFixture), a
+/// [`VirtualClock`], script variables and environment variables set via the
+/// `_set` and `_env` built-in commands, and typed [`Extensions`] for arbitrary
+/// per-script state.
+pub struct RunContext {
+    /// The seed of the random number generator.
+    seed: u64,
+    /// The random number generator state.
+    rng: SplitMix64,
+    /// Data generated by %gen directives, by name.
+    generated: HashMap<String, String>,
+    /// The script's cancellation token.
+    cancellation_token: CancellationToken,
+    /// The last sequence number returned by next_seq() in the current block.
+    seq: u64,
+    /// The script's scratch directory, if any.
+    scratch_dir: Option<PathBuf>,
+    /// The execution trace, if tracing is enabled.
+    trace: Option<String>,
+    /// The seeds chosen by %seed auto directives, by line number.
+    auto_seeds: Vec<(u32, u64)>,
+    /// Script variables set via the _set built-in command.
+    vars: HashMap<String, String>,
+    /// Script environment variables set via the _env built-in command.
+    env: BTreeMap<String, String>,
+    /// Checkpoints saved via the _checkpoint built-in command, by name.
+    checkpoints: HashMap<String, Checkpoint>,
+    /// The script's virtual clock.
+    clock: VirtualClock,
+    /// The script's unique namespace, for shared fixtures.
+    namespace: String,
+    /// Shared fixtures registered via RunOptions::shared_fixture().
+    shared_fixtures: Vec<AnySharedFixture>,
+    /// Resources reserved via %resources, if any.
+    reservation: Option<Reservation>,
+    /// Arbitrary per-script state, by type.
+    extensions: Extensions,
+    /// The halt reason, if the script was halted via %halt or halt().
+    halted: Option<String>,
+    /// The script's front matter metadata.
+    metadata: ScriptMetadata,
+    /// The script's command prefixes, mapped to the number of commands.
+    prefixes: BTreeMap<String, usize>,
+}
+
+impl RunContext {
+    /// Creates a new run context.
+    pub(crate) fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self {
+            seed: 0,
+            rng: SplitMix64(0),
+            generated: HashMap::new(),
+            cancellation_token: CancellationToken::new(),
+            seq: 0,
+            scratch_dir: None,
+            trace: None,
+            auto_seeds: Vec::new(),
+            vars: HashMap::new(),
+            env: BTreeMap::new(),
+            checkpoints: HashMap::new(),
+            clock: VirtualClock::new(),
+            namespace: format!("script_{}", NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+            shared_fixtures: Vec::new(),
+            reservation: None,
+            extensions: Extensions::new(),
+            halted: None,
+            metadata: ScriptMetadata::default(),
+            prefixes: BTreeMap::new(),
+        }
+    }
+
+    /// Creates a new run context that records an execution trace, see
+    /// [`RunOptions::trace_dir`](crate::RunOptions::trace_dir).
+    pub(crate) fn with_trace() -> Self {
+        Self { trace: Some(String::new()), ..Self::new() }
+    }
+
+    /// Appends a line to the execution trace, if enabled.
+    pub(crate) fn trace(&mut self, line: std::fmt::Arguments) {
+        if let Some(trace) = self.trace.as_mut() {
+            writeln!(trace, "{line}").expect("write failed");
+        }
+    }
+
+    /// Takes the execution trace, if enabled.
+    pub(crate) fn take_trace(&mut self) -> Option<String> {
+        self.trace.take()
+    }
+
+    /// Returns the script's cancellation token. It is cancelled when the token
+    /// given via [`RunOptions::cancellation_token`](crate::RunOptions::cancellation_token)
+    /// is cancelled, e.g. by a ctrl-c handler, or when the script exceeds its
+    /// `%limits max_runtime`. Runners can check it to abort long-running
+    /// operations, and the script fails before running further commands.
+    pub fn cancellation_token(&self) -> &CancellationToken {
+        &self.cancellation_token
+    }
+
+    /// Sets the script's cancellation token.
+    pub(crate) fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation_token = token;
+    }
+
+    /// Returns data generated by a `%gen` directive with the given name, if
+    /// any.
+    pub fn generated(&self, name: &str) -> Option<&str> {
+        self.generated.get(name).map(|data| data.as_str())
+    }
+
+    /// Stores generated data with the given name, replacing any existing data.
+    pub(crate) fn set_generated(&mut self, name: String, data: String) {
+        self.generated.insert(name, data);
+    }
+
+    /// Returns the current random seed, as set by the last `%seed` directive.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Reseeds the random number generator.
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = SplitMix64(seed);
+    }
+
+    /// Records the seed chosen by a %seed auto directive at the given line.
+    pub(crate) fn record_auto_seed(&mut self, line_number: u32, seed: u64) {
+        self.auto_seeds.push((line_number, seed));
+    }
+
+    /// Returns the seeds chosen by %seed auto directives, by line number.
+    pub(crate) fn auto_seeds(&self) -> &[(u32, u64)] {
+        &self.auto_seeds
+    }
+
+    /// Returns the script's scratch directory, if enabled via
+    /// [`RunOptions::scratch_dir`](crate::RunOptions::scratch_dir). It is
+    /// empty when the script starts, and is removed after it has run.
+    pub fn scratch_dir(&self) -> Option<&Path> {
+        self.scratch_dir.as_deref()
+    }
+
+    /// Sets the script's scratch directory.
+    pub(crate) fn set_scratch_dir(&mut self, path: PathBuf) {
+        self.scratch_dir = Some(path);
+    }
+
+    /// Returns the script's metadata, given as front matter at the start of
+    /// the script. Empty if the script has no front matter.
+    pub fn metadata(&self) -> &ScriptMetadata {
+        &self.metadata
+    }
+
+    /// Sets the script's metadata.
+    pub(crate) fn set_metadata(&mut self, metadata: ScriptMetadata) {
+        self.metadata = metadata;
+    }
+
+    /// Returns all distinct command prefixes in the script, mapped to the
+    /// number of commands using them. Used e.g. by runners simulating multiple
+    /// clients or nodes to verify in [`Runner::end_script`](crate::Runner::end_script)
+    /// that all of them were properly shut down.
+    pub fn prefixes(&self) -> &BTreeMap<String, usize> {
+        &self.prefixes
+    }
+
+    /// Sets the script's command prefixes.
+    pub(crate) fn set_prefixes(&mut self, prefixes: BTreeMap<String, usize>) {
+        self.prefixes = prefixes;
+    }
+
+    /// Returns the value of a script variable set via the optional `_set`
+    /// built-in command, if any. See
+    /// [`RunOptions::builtin_commands`](crate::RunOptions::builtin_commands).
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|value| value.as_str())
+    }
+
+    /// Returns the script's environment variables, set via the `%env`
+    /// directive or the optional `_env` built-in command. These don't affect
+    /// the process environment, but the runner can e.g. pass them to
+    /// subprocesses that it spawns. See
+    /// [`RunOptions::builtin_commands`](crate::RunOptions::builtin_commands).
+    pub fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    /// Returns the script's extensions, holding arbitrary per-script state by
+    /// type. These are shared by the runner and its hooks, e.g. to keep temp
+    /// directories, ports, or captured values for the duration of the script.
+    /// They're dropped when the script completes, and aren't checkpointed.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Returns the script's extensions mutably, see
+    /// [`RunContext::extensions`].
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// Halts the script after the current command, e.g. when the runner
+    /// detects that an environment feature required by later blocks is
+    /// missing. Like the `%halt` directive, the rest of the block produces no
+    /// output, and the remaining blocks are kept verbatim without running
+    /// them. The reason may be empty.
+    pub fn halt(&mut self, reason: impl Into<String>) {
+        self.halted = Some(reason.into());
+    }
+
+    /// Returns the halt reason, if the script was halted via `%halt` or
+    /// [`RunContext::halt`].
+    pub fn halted(&self) -> Option<&str> {
+        self.halted.as_deref()
+    }
+
+    /// Sets or unsets a script variable.
+    pub(crate) fn set_var(&mut self, name: String, value: Option<String>) {
+        match value {
+            Some(value) => self.vars.insert(name, value),
+            None => self.vars.remove(&name),
+        };
+    }
+
+    /// Sets or unsets a script environment variable.
+    pub(crate) fn set_env(&mut self, name: String, value: Option<String>) {
+        match value {
+            Some(value) => self.env.insert(name, value),
+            None => self.env.remove(&name),
+        };
+    }
+
+    /// Saves a checkpoint of the random number generator, virtual clock, and
+    /// variables with the given name, replacing any existing one.
+    pub(crate) fn checkpoint(&mut self, name: &str) {
+        let checkpoint = self.snapshot();
+        self.checkpoints.insert(name.to_string(), checkpoint);
+    }
+
+    /// Returns a snapshot of the random number generator, virtual clock, and
+    /// variables, e.g. for a cached %setup section.
+    pub(crate) fn snapshot(&self) -> Checkpoint {
+        Checkpoint {
+            seed: self.seed,
+            rng: self.rng.0,
+            time: self.clock.now(),
+            vars: self.vars.clone(),
+            env: self.env.clone(),
+        }
+    }
+
+    /// Restores a snapshot taken via snapshot().
+    pub(crate) fn restore_snapshot(&mut self, checkpoint: &Checkpoint) {
+        self.seed = checkpoint.seed;
+        self.rng = SplitMix64(checkpoint.rng);
+        self.clock.set(checkpoint.time);
+        self.vars.clone_from(&checkpoint.vars);
+        self.env.clone_from(&checkpoint.env);
+    }
+
+    /// Returns true if a checkpoint with the given name exists.
+    pub(crate) fn has_checkpoint(&self, name: &str) -> bool {
+        self.checkpoints.contains_key(name)
+    }
+
+    /// Restores a checkpoint with the given name, if it exists.
+    pub(crate) fn restore(&mut self, name: &str) {
+        if let Some(checkpoint) = self.checkpoints.get(name).cloned() {
+            self.restore_snapshot(&checkpoint);
+        }
+    }
+
+    /// Returns the script's virtual clock, which starts at 0 and is only
+    /// advanced explicitly, by the built-in `_advance_time` command or the
+    /// runner itself. The built-in `_now` command outputs its current time.
+    pub fn clock(&self) -> &VirtualClock {
+        &self.clock
+    }
+
+    /// Returns the script's unique namespace, e.g. `script_7`, which is also
+    /// given to the [`SharedFixture`](crate::SharedFixture) script hooks. It
+    /// only contains ASCII letters, digits, and underscores, so it can be used
+    /// e.g. as a database name. It varies between runs, so it shouldn't be
+    /// included in command output.
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    /// Returns the shared fixture of type T, setting it up if it isn't already.
+    /// The first time a script fetches it, the fixture's
+    /// [`SharedFixture::on_start_script`](crate::SharedFixture::on_start_script)
+    /// hook is called with the script's namespace. Errors if no fixture of
+    /// type T is registered via
+    /// [`RunOptions::shared_fixture`](crate::RunOptions::shared_fixture), or
+    /// if setup fails.
+    pub fn shared_fixture<T: Send + Sync + 'static>(&self) -> Result<Arc<T>, Box<dyn Error>> {
+        let type_id = std::any::TypeId::of::<T>();
+        let Some(fixture) = self.shared_fixtures.iter().find(|f| f.0.value_type() == type_id)
+        else {
+            let name = std::any::type_name::<T>();
+            return Err(format!("no shared fixture of type {name} registered").into());
+        };
+        let value = fixture.0.get(&self.namespace)?;
+        Ok(value.downcast().expect("invalid shared fixture type"))
+    }
+
+    /// Registers the script as a user of the given shared fixtures, which must
+    /// be released via release_shared_fixtures().
+    pub(crate) fn acquire_shared_fixtures(&mut self, fixtures: &[AnySharedFixture]) {
+        for fixture in fixtures {
+            fixture.0.acquire();
+        }
+        self.shared_fixtures = fixtures.to_vec();
+    }
+
+    /// Releases the script's shared fixtures, returning the first error.
+    pub(crate) fn release_shared_fixtures(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut result = Ok(());
+        for fixture in std::mem::take(&mut self.shared_fixtures) {
+            result = result.and(fixture.0.release(Some(&self.namespace)));
+        }
+        result
+    }
+
+    /// Holds the script's reserved resources until released.
+    pub(crate) fn set_reservation(&mut self, reservation: Reservation) {
+        self.reservation = Some(reservation);
+    }
+
+    /// Releases the script's reserved resources, if any.
+    pub(crate) fn release_reservation(&mut self) {
+        self.reservation = None;
+    }
+
+    /// Returns the next sequence number, starting at 1 in each block. Useful
+    /// to label emitted events with stable numbers, without the runner having
+    /// to manage and reset its own counter. See also
+    /// [`output::numbered`](crate::output::numbered).
+    pub fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    /// Resets the sequence counter, at the start of a block.
+    pub(crate) fn reset_seq(&mut self) {
+        self.seq = 0;
+    }
+
+    /// Returns a pseudo-random u64, determined by the seed.
+    pub fn random(&mut self) -> u64 {
+        self.rng.next()
+    }
+
+    /// Returns a pseudo-random number in the range 0..n, determined by the
+    /// seed. Panics if n is 0.
+    pub fn random_below(&mut self, n: u64) -> u64 {
+        assert!(n > 0, "random_below(0) is an empty range");
+        self.random() % n
+    }
+
+    /// Shuffles the given items in place, using a pseudo-random permutation
+    /// determined by the seed.
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        // Fisher-Yates shuffle.
+        for i in (1..items.len()).rev() {
+            let j = self.random_below(i as u64 + 1) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+/// A token for cooperative cancellation, e.g. of long-running commands. It can
+/// be cloned and sent to other threads, and all clones share the same state.
+/// Cancellation can't be undone.
+///
+/// A token is also cancelled when its parent token is cancelled (if any), or
+/// when its deadline passes (if any).
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    /// Set when the token is cancelled.
+    cancelled: Arc<AtomicBool>,
+    /// The parent token, if any.
+    parent: Option<Arc<CancellationToken>>,
+    /// The token's deadline, if any.
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// Creates a new, uncancelled token.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the token, and any child tokens.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst)
+    }
+
+    /// Returns true if the token, or its parent, has been cancelled, or its
+    /// deadline has passed.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+            || self.parent.as_ref().is_some_and(|parent| parent.is_cancelled())
+    }
+
+    /// Returns an error if the token has been cancelled, for convenient use
+    /// with `?` in a [`Runner`](crate::Runner).
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        match self.is_cancelled() {
+            true => Err("cancelled".into()),
+            false => Ok(()),
+        }
+    }
+
+    /// Creates a child token, which is cancelled when this token is cancelled,
+    /// but can also be cancelled independently.
+    pub fn child(&self) -> Self {
+        Self { cancelled: Arc::default(), parent: Some(Arc::new(self.clone())), deadline: None }
+    }
+
+    /// Returns the token's own cancellation flag, e.g. to set from a signal
+    /// handler.
+    #[cfg(feature = "signals")]
+    pub(crate) fn flag(&self) -> &Arc<AtomicBool> {
+        &self.cancelled
+    }
+
+    /// Creates a child token which is also cancelled at the given deadline.
+    pub(crate) fn child_with_deadline(&self, deadline: Instant) -> Self {
+        Self { deadline: Some(deadline), ..self.child() }
+    }
+}
+
+/// A typed map of per-script state, holding at most one value of each type,
+/// see [`RunContext::extensions`]. Use a newtype to store several values of
+/// the same underlying type.
+///
+/// ```
+/// # use goldenscript::Extensions;
+/// struct Port(u16);
+///
+/// let mut extensions = Extensions::new();
+/// extensions.insert(Port(8080));
+/// assert_eq!(extensions.get::<Port>().map(|p| p.0), Some(8080));
+/// extensions.get_or_insert_with(Vec::<String>::new).push("captured".into());
+/// ```
+#[derive(Default)]
+pub struct Extensions(HashMap<TypeId, Box<dyn Any + Send + Sync>>);
+
+impl Extensions {
+    /// Creates an empty extensions map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Inserts a value, returning the previous value of the same type, if any.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        let prev = self.0.insert(TypeId::of::<T>(), Box::new(value))?;
+        prev.downcast().ok().map(|prev| *prev)
+    }
+
+    /// Returns the value of the given type, if any.
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.0.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    /// Returns the value of the given type mutably, if any.
+    pub fn get_mut<T: Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.0.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    /// Returns the value of the given type mutably, inserting it via the given
+    /// function if it doesn't exist.
+    pub fn get_or_insert_with<T, F>(&mut self, f: F) -> &mut T
+    where
+        T: Send + Sync + 'static,
+        F: FnOnce() -> T,
+    {
+        let value = self.0.entry(TypeId::of::<T>()).or_insert_with(|| Box::new(f()));
+        value.downcast_mut().expect("invalid extension type")
+    }
+
+    /// Removes and returns the value of the given type, if any.
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.0.remove(&TypeId::of::<T>())?.downcast().ok().map(|value| *value)
+    }
+
+    /// Returns true if a value of the given type exists.
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.0.contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl std::fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Extensions({} values)", self.0.len())
+    }
+}
+
+/// A checkpoint of the context state, see RunContext::checkpoint().
+#[derive(Clone, Debug)]
+pub(crate) struct Checkpoint {
+    /// The random seed.
+    seed: u64,
+    /// The random number generator state.
+    rng: u64,
+    /// The virtual clock time.
+    time: Duration,
+    /// The script variables.
+    vars: HashMap<String, String>,
+    /// The script environment variables.
+    env: BTreeMap<String, String>,
+}
+
+/// A virtual clock, for deterministic testing of time-dependent behavior such
+/// as TTLs, leases, and timeouts. It only advances when explicitly told to,
+/// e.g. by the built-in `_advance_time` command. It can be cloned and sent to
+/// other threads, e.g. to a clock abstraction in the system under test, and
+/// all clones share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualClock {
+    /// The current time since the start of the clock, in nanoseconds.
+    nanos: Arc<AtomicU64>,
+}
+
+impl VirtualClock {
+    /// Creates a new virtual clock, starting at 0.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the current time, since the start of the clock.
+    pub fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+
+    /// Advances the clock by the given duration, returning the new time. Time
+    /// saturates at u64::MAX nanoseconds (about 584 years).
+    pub fn advance(&self, duration: Duration) -> Duration {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let prev = self.nanos.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |now| {
+            Some(now.saturating_add(nanos))
+        });
+        Duration::from_nanos(prev.expect("can't fail").saturating_add(nanos))
+    }
+
+    /// Sets the current time, e.g. when restoring a checkpoint.
+    pub(crate) fn set(&self, time: Duration) {
+        let nanos = u64::try_from(time.as_nanos()).unwrap_or(u64::MAX);
+        self.nanos.store(nanos, Ordering::SeqCst);
+    }
+}
+
+/// A SplitMix64 pseudo-random number generator. It is simple, fast, and
+/// deterministic across platforms, which is all we need for test inputs. Not
+/// suitable for cryptographic use.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that shuffling is deterministic for a given seed.
+    #[test]
+    fn shuffle() {
+        let mut ctx = RunContext::new();
+        let mut a: Vec<u32> = (0..10).collect();
+        ctx.shuffle(&mut a);
+        assert_ne!(a, (0..10).collect::<Vec<_>>());
+
+        // Reseeding with the same seed yields the same permutation.
+        ctx.set_seed(0);
+        let mut b: Vec<u32> = (0..10).collect();
+        ctx.shuffle(&mut b);
+        assert_eq!(a, b);
+
+        // A different seed yields a different permutation.
+        ctx.set_seed(1);
+        let mut c: Vec<u32> = (0..10).collect();
+        ctx.shuffle(&mut c);
+        assert_ne!(a, c);
+
+        // Empty and single-item slices are fine.
+        ctx.shuffle::<u32>(&mut []);
+        ctx.shuffle(&mut [1]);
+    }
+
+    /// Tests CancellationToken cancellation, including clones, children, and
+    /// deadlines.
+    #[test]
+    fn cancellation_token() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+        let child = token.child();
+        let sibling = token.child();
+        assert!(!token.is_cancelled());
+        assert!(token.check().is_ok());
+
+        // Cancelling a child doesn't affect the parent or siblings.
+        child.cancel();
+        assert!(child.is_cancelled());
+        assert!(!token.is_cancelled());
+        assert!(!sibling.is_cancelled());
+
+        // Cancelling the parent cancels clones and children.
+        token.cancel();
+        assert!(clone.is_cancelled());
+        assert!(sibling.is_cancelled());
+        assert_eq!(sibling.check().unwrap_err().to_string(), "cancelled");
+
+        // Deadlines cancel the token once passed.
+        let parent = CancellationToken::new();
+        let now = Instant::now();
+        assert!(parent.child_with_deadline(now).is_cancelled());
+        let deadline = parent.child_with_deadline(now + std::time::Duration::from_secs(60));
+        assert!(!deadline.is_cancelled());
+        parent.cancel();
+        assert!(deadline.is_cancelled());
+    }
+}
@@ -0,0 +1,90 @@
+//! Test data generators, for creating data of controlled shapes without
+//! embedding large literals in goldenscripts. The data is pseudo-random, but
+//! deterministic for a given [`RunContext`] seed (see the `%seed` directive).
+//!
+//! Data can also be generated via the `%gen` directive, see the
+//! [module documentation](crate#directives).
+
+use crate::RunContext;
+
+/// Words used to generate lorem ipsum text, separated by spaces.
+const LOREM_WORDS: &str = "lorem ipsum dolor sit amet consectetur adipiscing elit sed do eiusmod \
+    tempor incididunt ut labore et dolore magna aliqua enim ad minim veniam quis nostrud \
+    exercitation ullamco laboris nisi aliquip ex ea commodo consequat duis aute irure in \
+    reprehenderit voluptate velit esse cillum eu fugiat nulla pariatur excepteur sint occaecat \
+    cupidatat non proident sunt culpa qui officia deserunt mollit anim id est laborum";
+
+/// Characters used to generate keys.
+const KEY_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Characters used to generate payloads.
+const PAYLOAD_CHARS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Generates the given number of space-separated lorem ipsum words.
+pub fn lorem(ctx: &mut RunContext, words: usize) -> String {
+    let vocabulary: Vec<&str> = LOREM_WORDS.split(' ').collect();
+    (0..words).map(|_| *pick(ctx, &vocabulary)).collect::<Vec<_>>().join(" ")
+}
+
+/// Generates a key of the given length, consisting of lowercase ASCII letters
+/// and digits.
+pub fn key(ctx: &mut RunContext, len: usize) -> String {
+    (0..len).map(|_| *pick(ctx, KEY_CHARS) as char).collect()
+}
+
+/// Generates a payload of the given size in bytes, consisting of ASCII letters
+/// and digits. Use [`checksum()`] to verify its contents in output.
+pub fn payload(ctx: &mut RunContext, size: usize) -> String {
+    (0..size).map(|_| *pick(ctx, PAYLOAD_CHARS) as char).collect()
+}
+
+/// Computes a 32-bit FNV-1a checksum of the given data. This is not a
+/// cryptographic hash, but is stable across platforms and releases, and short
+/// enough to include in output.
+pub fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, b| (hash ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+/// Picks a pseudo-random item from the given slice.
+fn pick<'a, T>(ctx: &mut RunContext, items: &'a [T]) -> &'a T {
+    &items[ctx.random_below(items.len() as u64) as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that generators are deterministic for a given seed, and produce
+    /// data of the requested size.
+    #[test]
+    fn generators() {
+        let mut ctx = RunContext::new();
+        let lorem_text = lorem(&mut ctx, 5);
+        let key_text = key(&mut ctx, 8);
+        let payload_text = payload(&mut ctx, 100);
+
+        assert_eq!(lorem_text.split(' ').count(), 5);
+        assert!(lorem_text.split(' ').all(|w| LOREM_WORDS.split(' ').any(|l| l == w)));
+        assert_eq!(key_text.len(), 8);
+        assert!(key_text.bytes().all(|c| KEY_CHARS.contains(&c)));
+        assert_eq!(payload_text.len(), 100);
+        assert!(payload_text.bytes().all(|c| PAYLOAD_CHARS.contains(&c)));
+
+        ctx.set_seed(0);
+        assert_eq!(lorem(&mut ctx, 5), lorem_text);
+        assert_eq!(key(&mut ctx, 8), key_text);
+        assert_eq!(payload(&mut ctx, 100), payload_text);
+
+        assert_eq!(lorem(&mut ctx, 0), "");
+        assert_eq!(key(&mut ctx, 0), "");
+        assert_eq!(payload(&mut ctx, 0), "");
+    }
+
+    /// Tests checksum() against known FNV-1a values.
+    #[test]
+    fn checksum_fnv1a() {
+        assert_eq!(checksum(b""), 0x811c9dc5);
+        assert_eq!(checksum(b"a"), 0xe40c292c);
+        assert_eq!(checksum(b"foobar"), 0xbf9cf968);
+    }
+}
@@ -0,0 +1,316 @@
+//! Serde deserialization of command arguments, via
+//! [`ArgumentConsumer::deserialize`](crate::ArgumentConsumer::deserialize).
+
+use serde::de::{self, IntoDeserializer as _, Visitor};
+
+use crate::Argument;
+
+/// The struct field that positional arguments are deserialized into.
+pub(crate) const POSITIONAL_FIELD: &str = "args";
+
+/// A deserialization error.
+#[derive(Debug)]
+pub(crate) struct Error(String);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+/// Deserializes a set of arguments. Structs and maps take key/value arguments
+/// by key and positional arguments as a sequence in the `args` field, while
+/// sequences and tuples take positional arguments only. Unknown struct fields
+/// are rejected.
+pub(crate) struct ArgumentsDeserializer<'a> {
+    args: Vec<&'a Argument>,
+}
+
+impl<'a> ArgumentsDeserializer<'a> {
+    /// Creates a deserializer for the given arguments.
+    pub(crate) fn new(args: Vec<&'a Argument>) -> Self {
+        Self { args }
+    }
+
+    /// Returns the key/value arguments, with the last value for duplicate keys
+    /// as for ArgumentConsumer::lookup(), along with any positional arguments
+    /// under the positional field.
+    fn entries(&self) -> Vec<(&'a str, Entry<'a>)> {
+        let mut entries: Vec<(&str, Entry)> = Vec::new();
+        for arg in &self.args {
+            let Some(key) = arg.key.as_deref() else {
+                continue;
+            };
+            entries.retain(|(k, _)| *k != key);
+            entries.push((key, Entry::Value(arg)));
+        }
+        let positional: Vec<_> = self.args.iter().copied().filter(|a| a.key.is_none()).collect();
+        if !positional.is_empty() {
+            entries.push((POSITIONAL_FIELD, Entry::Positional(positional)));
+        }
+        entries
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ArgumentsDeserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(EntriesAccess { entries: self.entries().into_iter(), next: None })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        for arg in &self.args {
+            let known = fields.contains(&arg.key.as_deref().unwrap_or(POSITIONAL_FIELD));
+            if !known {
+                return Err(Error(format!("invalid argument '{}'", arg.name())));
+            }
+        }
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if let Some(arg) = self.args.iter().find(|a| a.key.is_some()) {
+            return Err(Error(format!("invalid argument '{}'", arg.name())));
+        }
+        PositionalDeserializer(self.args).deserialize_any(visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct enum identifier
+        ignored_any
+    }
+}
+
+/// A struct or map entry: either a key/value argument or the positional
+/// arguments.
+enum Entry<'a> {
+    Value(&'a Argument),
+    Positional(Vec<&'a Argument>),
+}
+
+/// Deserializes positional arguments as a sequence.
+struct PositionalDeserializer<'a>(Vec<&'a Argument>);
+
+impl<'de, 'a> de::Deserializer<'de> for PositionalDeserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visit_seq(self.0.into_iter().map(ValueDeserializer::from).collect(), visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct enum
+        identifier ignored_any
+    }
+}
+
+/// Visits a sequence of values, erroring if the visitor doesn't consume all of
+/// them, e.g. for tuples.
+fn visit_seq<'de, V: Visitor<'de>>(
+    values: Vec<ValueDeserializer>,
+    visitor: V,
+) -> Result<V::Value, Error> {
+    let mut seq = de::value::SeqDeserializer::new(values.into_iter());
+    let value = visitor.visit_seq(&mut seq)?;
+    seq.end()?;
+    Ok(value)
+}
+
+/// Map access for struct and map entries.
+struct EntriesAccess<'a> {
+    entries: std::vec::IntoIter<(&'a str, Entry<'a>)>,
+    next: Option<Entry<'a>>,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for EntriesAccess<'a> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let Some((key, entry)) = self.entries.next() else {
+            return Ok(None);
+        };
+        self.next = Some(entry);
+        seed.deserialize(key.into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        match self.next.take().expect("value without key") {
+            Entry::Value(arg) => seed.deserialize(ValueDeserializer::from(arg)),
+            Entry::Positional(args) => seed.deserialize(PositionalDeserializer(args)),
+        }
+    }
+}
+
+/// Deserializes an argument value, parsing it as the requested type.
+/// Sequences are given as comma-separated values, as for
+/// ArgumentConsumer::lookup_list_parse().
+struct ValueDeserializer<'a> {
+    /// The argument key, if any, for error messages.
+    key: Option<&'a str>,
+    value: &'a str,
+}
+
+impl<'a> From<&'a Argument> for ValueDeserializer<'a> {
+    fn from(arg: &'a Argument) -> Self {
+        Self { key: arg.key.as_deref(), value: &arg.value }
+    }
+}
+
+impl<'de, 'a> de::IntoDeserializer<'de, Error> for ValueDeserializer<'a> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl<'a> ValueDeserializer<'a> {
+    /// Parses the value as a T, with the argument key in error messages.
+    fn parse<T>(&self) -> Result<T, Error>
+    where
+        T: std::str::FromStr,
+        <T as std::str::FromStr>::Err: std::fmt::Display,
+    {
+        self.value.parse().map_err(|e| match self.key {
+            Some(key) => Error(format!("invalid {key} '{}': {e}", self.value)),
+            None => Error(format!("invalid argument '{}': {e}", self.value)),
+        })
+    }
+}
+
+macro_rules! deserialize_parse {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.value)
+    }
+
+    deserialize_parse! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_i128 => visit_i128,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_u128 => visit_u128,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut elements = Vec::new();
+        if !self.value.trim().is_empty() {
+            elements = self
+                .value
+                .split(',')
+                .map(|e| ValueDeserializer { key: self.key, value: e.trim() })
+                .collect();
+        }
+        visit_seq(elements, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_enum(self.value.into_deserializer())
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit_struct map struct identifier ignored_any
+    }
+}
@@ -0,0 +1,338 @@
+//! Classification of changes between old and new goldenscript output, to help
+//! reviewers of large golden file regenerations focus on semantically
+//! meaningful changes.
+//!
+//! When a goldenscript's output doesn't match, [`run()`](crate::run) prints a
+//! [`Summary`] of the changed lines by [`ChangeClass`] before the diff, e.g.:
+//!
+//! ```text
+//! 5 changed lines: 3 numeric, 2 reordered
+//! ```
+
+use std::collections::HashMap;
+
+/// The maximum number of line pairs to compare when diffing, beyond which
+/// the differing lines are compared as a single change to bound the cost.
+const MAX_DIFF_CELLS: usize = 10_000_000;
+
+/// A class of changed line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ChangeClass {
+    /// Only numbers changed, e.g. `count: 3` to `count: 4`.
+    Numeric,
+    /// The line was moved, i.e. removed in one place and added in another.
+    Reordered,
+    /// A new error or panic message, e.g. `Error: not found`.
+    Error,
+    /// Only whitespace changed.
+    Whitespace,
+    /// Any other change, including added or removed lines.
+    Other,
+}
+
+impl std::fmt::Display for ChangeClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Numeric => write!(f, "numeric"),
+            Self::Reordered => write!(f, "reordered"),
+            Self::Error => write!(f, "error"),
+            Self::Whitespace => write!(f, "whitespace-only"),
+            Self::Other => write!(f, "other"),
+        }
+    }
+}
+
+/// A changed line, or pair of old and new lines.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change<'a> {
+    /// The change class.
+    pub class: ChangeClass,
+    /// The old line and its 1-based line number, if any.
+    pub old: Option<(usize, &'a str)>,
+    /// The new line and its 1-based line number, if any.
+    pub new: Option<(usize, &'a str)>,
+}
+
+/// Classifies the changed lines between the old and new text. A changed line
+/// is paired with the corresponding line in the other text where possible, and
+/// counted as a single change.
+pub fn classify<'a>(old: &'a str, new: &'a str) -> Vec<Change<'a>> {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // Find the hunks of removed and added lines.
+    let hunks = hunks(&old, &new);
+
+    // Lines that were removed in one place and added in another were moved. In
+    // case of duplicates, each removed line matches one added line.
+    let mut removed: HashMap<&str, usize> = HashMap::new();
+    for i in hunks.iter().flat_map(|(o, _)| o.clone()) {
+        *removed.entry(old[i]).or_default() += 1;
+    }
+    let mut moved: HashMap<&str, usize> = HashMap::new();
+    for j in hunks.iter().flat_map(|(_, n)| n.clone()) {
+        if let Some(count) = removed.get_mut(new[j]).filter(|c| **c > 0) {
+            *count -= 1;
+            *moved.entry(new[j]).or_default() += 1;
+        }
+    }
+    let mut moved_old = moved.clone();
+
+    let mut changes = Vec::new();
+    for (old_range, new_range) in hunks {
+        // Extract moved lines first, then pair up the remaining lines.
+        let mut old_lines = Vec::new();
+        for i in old_range {
+            match moved_old.get_mut(old[i]).filter(|c| **c > 0) {
+                Some(count) => *count -= 1,
+                None => old_lines.push((i + 1, old[i])),
+            }
+        }
+        let mut new_lines = Vec::new();
+        for j in new_range {
+            match moved.get_mut(new[j]).filter(|c| **c > 0) {
+                Some(count) => {
+                    *count -= 1;
+                    let class = ChangeClass::Reordered;
+                    changes.push(Change { class, old: None, new: Some((j + 1, new[j])) });
+                }
+                None => new_lines.push((j + 1, new[j])),
+            }
+        }
+        for i in 0..old_lines.len().max(new_lines.len()) {
+            let (old, new) = (old_lines.get(i).copied(), new_lines.get(i).copied());
+            let class = classify_line(old.map(|(_, l)| l), new.map(|(_, l)| l));
+            changes.push(Change { class, old, new });
+        }
+    }
+    changes
+}
+
+/// Classifies a pair of changed lines, either of which may be missing.
+fn classify_line(old: Option<&str>, new: Option<&str>) -> ChangeClass {
+    if new.is_some_and(is_error) && !old.is_some_and(is_error) {
+        return ChangeClass::Error;
+    }
+    let (Some(old), Some(new)) = (old, new) else {
+        return ChangeClass::Other;
+    };
+    if old.split_whitespace().eq(new.split_whitespace()) {
+        return ChangeClass::Whitespace;
+    }
+    if strip_numbers(old) == strip_numbers(new) {
+        return ChangeClass::Numeric;
+    }
+    if is_error(new) {
+        return ChangeClass::Error;
+    }
+    ChangeClass::Other
+}
+
+/// Returns true if the line is an error or panic message, as output for failed
+/// commands, optionally with a command prefix.
+fn is_error(line: &str) -> bool {
+    let line = line.split_once(": ").map_or(line, |(prefix, rest)| match prefix {
+        "Error" | "Panic" => line,
+        _ => rest,
+    });
+    line.starts_with("Error: ") || line.starts_with("Panic: ")
+}
+
+/// Replaces all numbers in the line with a single #, including signs and
+/// decimals.
+fn strip_numbers(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        let sign = (c == '-' || c == '+') && chars.peek().is_some_and(|c| c.is_ascii_digit());
+        if !c.is_ascii_digit() && !sign {
+            result.push(c);
+            continue;
+        }
+        while chars.peek().is_some_and(|c| c.is_ascii_digit() || *c == '.') {
+            chars.next();
+        }
+        result.push('#');
+    }
+    result
+}
+
+/// Renders a line diff between the old and new text, prefixing removed lines
+/// with `- `, added lines with `+ `, and unchanged lines with two spaces. If
+/// context is false, unchanged lines are omitted.
+pub(crate) fn render(old: &str, new: &str, eol: &str, context: bool) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+    let mut output = String::new();
+    let mut push = |prefix: &str, line: &str| {
+        if prefix == "  " && !context {
+            return;
+        }
+        output.push_str(format!("{prefix}{line}").trim_end());
+        output.push_str(eol);
+    };
+    let (mut i, mut j) = (0, 0);
+    for (old_range, new_range) in hunks(&old, &new) {
+        for line in &old[i..old_range.start] {
+            push("  ", line);
+        }
+        for line in &old[old_range.clone()] {
+            push("- ", line);
+        }
+        for line in &new[new_range.clone()] {
+            push("+ ", line);
+        }
+        (i, j) = (old_range.end, new_range.end);
+    }
+    debug_assert_eq!(old.len() - i, new.len() - j);
+    for line in &old[i..] {
+        push("  ", line);
+    }
+    output
+}
+
+/// Returns the hunks of differing lines between the old and new lines, as
+/// pairs of removed old line ranges and added new line ranges, using a longest
+/// common subsequence.
+fn hunks(old: &[&str], new: &[&str]) -> Vec<(std::ops::Range<usize>, std::ops::Range<usize>)> {
+    // Skip the common prefix and suffix, which is typically most of the text.
+    let prefix = old.iter().zip(new).take_while(|(o, n)| o == n).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(o, n)| o == n)
+        .count();
+    let (old_mid, new_mid) = (&old[prefix..old.len() - suffix], &new[prefix..new.len() - suffix]);
+    let (n, m) = (old_mid.len(), new_mid.len());
+    if n == 0 && m == 0 {
+        return Vec::new();
+    }
+    if n * m > MAX_DIFF_CELLS {
+        return vec![(prefix..prefix + n, prefix..prefix + m)];
+    }
+
+    // lcs[i][j] is the LCS length of old_mid[i..] and new_mid[j..].
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = match old_mid[i] == new_mid[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    // Walk the LCS table, collecting runs of removed and added lines.
+    let mut hunks = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let (mut hunk_i, mut hunk_j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old_mid[i] == new_mid[j] {
+            if (hunk_i, hunk_j) != (i, j) {
+                hunks.push((prefix + hunk_i..prefix + i, prefix + hunk_j..prefix + j));
+            }
+            (i, j) = (i + 1, j + 1);
+            (hunk_i, hunk_j) = (i, j);
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    if (hunk_i, hunk_j) != (n, m) {
+        hunks.push((prefix + hunk_i..prefix + n, prefix + hunk_j..prefix + m));
+    }
+    hunks
+}
+
+/// A summary of changed lines by class.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// The number of changes per class, in class order. Excludes classes
+    /// without changes.
+    pub counts: Vec<(ChangeClass, usize)>,
+}
+
+impl Summary {
+    /// Summarizes the changed lines between the old and new text.
+    pub fn new(old: &str, new: &str) -> Self {
+        Self::from_changes(&classify(old, new))
+    }
+
+    /// Summarizes the given changes.
+    pub fn from_changes(changes: &[Change]) -> Self {
+        let mut counts: Vec<(ChangeClass, usize)> = Vec::new();
+        for change in changes {
+            match counts.iter_mut().find(|(class, _)| *class == change.class) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((change.class, 1)),
+            }
+        }
+        counts.sort();
+        Self { counts }
+    }
+
+    /// Returns the total number of changes.
+    pub fn total(&self) -> usize {
+        self.counts.iter().map(|(_, count)| count).sum()
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.total();
+        write!(f, "{total} changed line{}", if total == 1 { "" } else { "s" })?;
+        for (i, (class, count)) in self.counts.iter().enumerate() {
+            write!(f, "{} {count} {class}", if i == 0 { ":" } else { "," })?;
+        }
+        Ok(())
+    }
+}
+
+/// Panics with a change summary and a line diff of the old and new text of the
+/// given script.
+pub(crate) fn panic_diff(name: &str, old: &str, new: &str) -> ! {
+    let diff = render(old, new, "\n", true);
+    panic!("{name}: output differs, {}:\n{diff}", Summary::new(old, new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ChangeClass::*;
+    use super::*;
+
+    /// Tests classification of changed lines.
+    #[test]
+    fn classify_lines() {
+        let old = "a\nb: 1\nc\nd\ne  f\ng\nh\n";
+        let new = "a\nb: 2\nd\ne f\nc\nx: Error: not found\ng2\nadded\n";
+        let classes: Vec<_> = classify(old, new).into_iter().map(|c| c.class).collect();
+        assert_eq!(classes, vec![Numeric, Reordered, Whitespace, Error, Other, Other]);
+
+        let changes = classify(old, new);
+        assert_eq!(changes[0].old, Some((2, "b: 1")));
+        assert_eq!(changes[0].new, Some((2, "b: 2")));
+        assert_eq!(changes[1].old, None);
+        assert_eq!(changes[1].new, Some((5, "c")));
+        assert_eq!(changes[5].old, None);
+        assert_eq!(changes[5].new, Some((8, "added")));
+
+        assert!(classify(old, old).is_empty());
+    }
+
+    /// Tests number stripping, including signs and decimals.
+    #[test]
+    fn strip_numbers() {
+        assert_eq!(super::strip_numbers("a=1 b=-2.5 c=+3 d-4 e"), "a=# b=# c=# d# e");
+    }
+
+    /// Tests summaries.
+    #[test]
+    fn summary() {
+        let summary = Summary::new("a\nb\nc 1\n", "b\na\nc 2\nd\n");
+        assert_eq!(summary.to_string(), "3 changed lines: 1 numeric, 1 reordered, 1 other");
+        assert_eq!(Summary::new("a\n", "b\n").to_string(), "1 changed line: 1 other");
+        assert_eq!(Summary::new("a\n", "a\n").to_string(), "0 changed lines");
+    }
+}
@@ -0,0 +1,295 @@
+//! A content-addressed fixture store for large argument payloads. Requires the
+//! `fixtures` crate feature.
+//!
+//! Rather than inlining multi-megabyte payloads in scripts, they can be stored
+//! as files in a fixtures directory, named by the SHA-256 hash of their
+//! content, and referenced in scripts by hash:
+//!
+//! ```text
+//! put key=foo payload=@sha256:2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae
+//! ---
+//! ok
+//! ```
+//!
+//! References can be abbreviated to a unique hash prefix of at least 8
+//! characters, e.g. `@sha256:2c26b46b`. With
+//! [`RunOptions::fixtures`](crate::RunOptions::fixtures), argument values
+//! that are fixture references are replaced with the fixture content before
+//! the commands are run, so runners receive the payload as a regular argument.
+//! Fixture contents are verified against their hash when read.
+//!
+//! Fixtures can be added and garbage-collected with the `goldenscript-fixtures`
+//! command-line tool, which also requires the `cli` crate feature:
+//!
+//! ```sh
+//! $ goldenscript-fixtures --dir tests/fixtures add payload.json
+//! @sha256:2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae payload.json
+//! $ goldenscript-fixtures --dir tests/fixtures gc tests/scripts
+//! ```
+
+use std::collections::BTreeSet;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest as _, Sha256};
+
+/// The prefix of fixture references.
+const PREFIX: &str = "@sha256:";
+
+/// The minimum length of an abbreviated fixture hash.
+const MIN_HASH_LEN: usize = 8;
+
+/// A content-addressed fixture store, backed by a directory where each fixture
+/// is stored in a file named by the hex-encoded SHA-256 hash of its content.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FixtureStore {
+    dir: PathBuf,
+}
+
+impl FixtureStore {
+    /// Creates a fixture store in the given directory. The directory is
+    /// created when the first fixture is added.
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Returns the fixture directory.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Adds a fixture, returning its reference, e.g. `@sha256:2c26b4…`. Adding
+    /// an existing fixture is a noop.
+    pub fn add(&self, data: &[u8]) -> Result<String> {
+        let hash = hash(data);
+        let path = self.dir.join(&hash);
+        if !path.exists() {
+            std::fs::create_dir_all(&self.dir)?;
+            // Write to a temporary file and rename it, to avoid leaving
+            // partial fixtures behind.
+            let tmp = self.dir.join(format!(".{hash}.tmp"));
+            std::fs::write(&tmp, data)?;
+            std::fs::rename(&tmp, &path)?;
+        }
+        Ok(format!("{PREFIX}{hash}"))
+    }
+
+    /// Returns the content of the given fixture reference, e.g.
+    /// `@sha256:2c26b4…`, which may use an abbreviated hash. Errors if the
+    /// fixture doesn't exist, the abbreviation is ambiguous, or the content
+    /// doesn't match the hash.
+    pub fn get(&self, reference: &str) -> Result<Vec<u8>> {
+        let Some(prefix) = parse_reference(reference) else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid fixture reference '{reference}'"),
+            ));
+        };
+        let matches: Vec<_> =
+            self.hashes()?.into_iter().filter(|h| h.starts_with(prefix)).collect();
+        let hash = match matches.as_slice() {
+            [hash] => hash,
+            matches => {
+                let kind = if matches.is_empty() { "unknown" } else { "ambiguous" };
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("{kind} fixture '{reference}' in {}", self.dir.display()),
+                ));
+            }
+        };
+        let data = std::fs::read(self.dir.join(hash))?;
+        if self::hash(&data) != *hash {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("fixture '{reference}' content doesn't match its hash"),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Returns the content of the given fixture reference as a string, like
+    /// [`get()`](Self::get). Errors if it isn't valid UTF-8.
+    pub fn get_string(&self, reference: &str) -> Result<String> {
+        String::from_utf8(self.get(reference)?).map_err(|_| {
+            Error::new(ErrorKind::InvalidData, format!("fixture '{reference}' is not valid UTF-8"))
+        })
+    }
+
+    /// Returns the hashes of all fixtures in the store, in sorted order.
+    pub fn hashes(&self) -> Result<BTreeSet<String>> {
+        let mut hashes = BTreeSet::new();
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(hashes),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if name.len() == 64 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+                hashes.insert(name);
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Removes fixtures that aren't referenced by any of the given scripts,
+    /// which may be files or directories that are scanned recursively.
+    /// Returns the hashes of the removed fixtures.
+    pub fn gc<P: AsRef<Path>>(&self, scripts: impl IntoIterator<Item = P>) -> Result<Vec<String>> {
+        let mut references = BTreeSet::new();
+        for path in scripts {
+            collect_references(path.as_ref(), &mut references)?;
+        }
+        let mut removed = Vec::new();
+        for hash in self.hashes()? {
+            if !references.iter().any(|r| hash.starts_with(r.as_str())) {
+                std::fs::remove_file(self.dir.join(&hash))?;
+                removed.push(hash);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Returns true if the given value is a fixture reference, e.g.
+/// `@sha256:2c26b4…`.
+pub fn is_reference(value: &str) -> bool {
+    parse_reference(value).is_some()
+}
+
+/// Parses a fixture reference, returning the (possibly abbreviated) hash.
+fn parse_reference(value: &str) -> Option<&str> {
+    let hash = value.strip_prefix(PREFIX)?;
+    let valid = (MIN_HASH_LEN..=64).contains(&hash.len())
+        && hash.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+    valid.then_some(hash)
+}
+
+/// Returns the hex-encoded SHA-256 hash of the given data.
+fn hash(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Collects fixture hash references from a script file, or recursively from
+/// the scripts in a directory.
+fn collect_references(path: &Path, references: &mut BTreeSet<String>) -> Result<()> {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            collect_references(&entry?.path(), references)?;
+        }
+        return Ok(());
+    }
+    let Ok(script) = std::fs::read_to_string(path) else {
+        return Ok(()); // skip non-UTF-8 files
+    };
+    for (i, _) in script.match_indices(PREFIX) {
+        let hash = &script[i + PREFIX.len()..];
+        let len = hash.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(hash.len());
+        if len >= MIN_HASH_LEN {
+            references.insert(hash[..len].to_string());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-256 hash of "foo".
+    const FOO: &str = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+
+    /// Returns a fixture store in a fresh temporary directory.
+    fn temp_store(name: &str) -> FixtureStore {
+        let dir = std::env::temp_dir()
+            .join(format!("goldenscript-fixtures-{name}-{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        FixtureStore::new(dir)
+    }
+
+    /// Tests adding and getting fixtures, including abbreviated references.
+    #[test]
+    fn add_get() {
+        let store = temp_store("add_get");
+        assert!(store.hashes().unwrap().is_empty());
+
+        let reference = store.add(b"foo").unwrap();
+        assert_eq!(reference, format!("@sha256:{FOO}"));
+        assert_eq!(store.add(b"foo").unwrap(), reference);
+        assert_eq!(store.hashes().unwrap(), [FOO.to_string()].into());
+
+        assert_eq!(store.get(&reference).unwrap(), b"foo");
+        assert_eq!(store.get_string("@sha256:2c26b46b").unwrap(), "foo");
+
+        assert_eq!(
+            store.get("@sha256:2c26").unwrap_err().to_string(),
+            "invalid fixture reference '@sha256:2c26'"
+        );
+        assert_eq!(store.get("@sha256:00000000").unwrap_err().kind(), ErrorKind::NotFound);
+
+        // Corrupted fixtures error.
+        std::fs::write(store.dir().join(FOO), "bar").unwrap();
+        assert_eq!(
+            store.get(&reference).unwrap_err().to_string(),
+            format!("fixture '{reference}' content doesn't match its hash")
+        );
+        std::fs::remove_dir_all(store.dir()).unwrap();
+    }
+
+    /// Tests garbage collection of unreferenced fixtures.
+    #[test]
+    fn gc() {
+        let store = temp_store("gc");
+        let foo = store.add(b"foo").unwrap();
+        let bar = store.add(b"bar").unwrap();
+        let baz = store.add(b"baz").unwrap();
+
+        let scripts = store.dir().join("scripts");
+        std::fs::create_dir_all(scripts.join("nested")).unwrap();
+        std::fs::write(scripts.join("a"), format!("put a={foo}\n---\nok\n")).unwrap();
+        std::fs::write(scripts.join("nested/b"), format!("put b={}\n---\nok\n", &bar[..16]))
+            .unwrap();
+
+        let removed = store.gc([&scripts]).unwrap();
+        assert_eq!(removed, vec![baz.strip_prefix(PREFIX).unwrap().to_string()]);
+        assert_eq!(store.hashes().unwrap().len(), 2);
+        assert!(store.get(&foo).is_ok());
+        assert!(store.get(&bar).is_ok());
+        std::fs::remove_dir_all(store.dir()).unwrap();
+    }
+
+    /// Tests reference parsing.
+    #[test]
+    fn reference() {
+        assert!(is_reference(&format!("@sha256:{FOO}")));
+        assert!(is_reference("@sha256:2c26b46b"));
+        assert!(!is_reference("@sha256:2c26b46"));
+        assert!(!is_reference("@sha256:2C26B46B"));
+        assert!(!is_reference(&format!("@sha256:{FOO}0")));
+        assert!(!is_reference("sha256:2c26b46b"));
+    }
+
+    /// Tests that RunOptions::fixtures() resolves fixture arguments.
+    #[test]
+    fn run_options() {
+        let store = temp_store("run_options");
+        let reference = store.add(b"foo").unwrap();
+        let options = crate::RunOptions::new().fixtures(store.clone());
+
+        let mut runner = crate::FnRunner::stateless(|command: &crate::Command| {
+            Ok(command.args.iter().map(|a| a.value.as_str()).collect::<Vec<_>>().join(" "))
+        });
+        let input = format!("put a={reference} b=@sha256:2c26b46b c='{reference}' d=@foo\n---\n");
+        assert_eq!(
+            crate::generate_with(&mut runner, &input, &options).unwrap(),
+            format!("{input}foo foo foo @foo\n")
+        );
+
+        let input = "put @sha256:00000000\n---\n";
+        assert_eq!(
+            crate::generate_with(&mut runner, input, &options).unwrap_err().to_string(),
+            format!("line 1: unknown fixture '@sha256:00000000' in {}", store.dir().display())
+        );
+        std::fs::remove_dir_all(store.dir()).unwrap();
+    }
+}
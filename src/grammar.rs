@@ -0,0 +1,374 @@
+//! Syntax highlighting grammars for goldenscripts, for use in editors.
+//!
+//! The grammars are generated from the regular expressions below, which are
+//! tested against the parser to keep them in sync with its actual behavior.
+//! They are line-based approximations, so e.g. line continuations aren't
+//! highlighted exactly like the parser interprets them.
+
+use std::fmt::Write as _;
+
+use crate::util::json_quote;
+
+/// The grammar scope name.
+const SCOPE: &str = "source.goldenscript";
+
+/// An unquoted string, see the parser's unquoted_string().
+const UNQUOTED: &str = r"(?:[-+]?[a-zA-Z0-9_]|@)[a-zA-Z0-9_\-./@*]*";
+
+/// An unquoted argument value, see the parser's unquoted_value().
+const UNQUOTED_VALUE: &str = r"(?:[-+]?[a-zA-Z0-9_]|@)(?:[a-zA-Z0-9_\-./@*]|:+[a-zA-Z0-9_\-./@*])*";
+
+/// A string escape sequence, see the parser's escape_sequence().
+const ESCAPE: &str = r#"\\(?:['"\\0nrt]|x[0-9a-fA-F]{2}|u\{[0-9a-fA-F]{1,6}\})"#;
+
+/// A line comment.
+const COMMENT: &str = r"(?:#|//).*$";
+
+/// A highlighting rule, either matching a single pattern or a begin/end region.
+struct Rule {
+    /// The rule's repository name.
+    id: &'static str,
+    /// The scope of the entire match or region, if any.
+    name: Option<&'static str>,
+    /// The scope of the region contents, if any.
+    content_name: Option<&'static str>,
+    /// The match pattern, or the begin and end patterns of a region.
+    pattern: Pattern,
+    /// The scopes of capture groups. For regions, these apply to the begin
+    /// pattern.
+    captures: &'static [(u32, &'static str)],
+    /// The scopes of the end pattern's capture groups, for regions.
+    end_captures: &'static [(u32, &'static str)],
+    /// Rules to include within a region, by repository name.
+    include: &'static [&'static str],
+}
+
+/// A rule pattern.
+enum Pattern {
+    Match(String),
+    Region(String, String),
+}
+
+/// Returns a pattern for a single-line string of any kind, in the order tried
+/// by the parser's string(). Raw strings must come before unquoted strings,
+/// since a bare r is an unquoted string, and triple-quoted strings before
+/// double-quoted strings.
+fn string() -> String {
+    format!(r#"(?:r'[^']*'|r"[^"]*"|{UNQUOTED}|'(?:[^'\\]|\\.)*'|""".*?"""|"(?:[^"\\]|\\.)*")"#)
+}
+
+/// Returns the grammar's rules, in priority order.
+fn rules() -> Vec<Rule> {
+    use Pattern::*;
+
+    let string = string();
+
+    // The start of a command line: silencing, prefix, tags, fail marker, and
+    // either a > literal command or the command name.
+    let command = format!(
+        r"^(\()?\s*(?:({string})(:)\s*)?(?:(\[[^\]]*\])\s*)?(!)?\s*(?:(>)\s*(.*)$|({string}))"
+    );
+
+    vec![
+        Rule {
+            id: "comment",
+            name: None,
+            content_name: None,
+            pattern: Match(format!(r"^\s*({COMMENT})")),
+            captures: &[(1, "comment.line.goldenscript")],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "output",
+            name: None,
+            content_name: Some("meta.output.goldenscript"),
+            pattern: Region(r"^(---)\r?$".to_string(), r"^\r?$".to_string()),
+            captures: &[(1, "keyword.operator.separator.goldenscript")],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "directive",
+            name: None,
+            content_name: None,
+            pattern: Match(format!(r"^(%)({string})")),
+            captures: &[
+                (1, "keyword.control.directive.goldenscript"),
+                (2, "keyword.control.directive.goldenscript"),
+            ],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "command",
+            name: None,
+            content_name: None,
+            pattern: Match(command),
+            captures: &[
+                (1, "punctuation.section.parens.goldenscript"),
+                (2, "entity.name.namespace.prefix.goldenscript"),
+                (3, "punctuation.separator.prefix.goldenscript"),
+                (4, "entity.name.tag.goldenscript"),
+                (5, "keyword.operator.fail.goldenscript"),
+                (6, "keyword.operator.literal.goldenscript"),
+                (7, "entity.name.function.goldenscript"),
+                (8, "entity.name.function.goldenscript"),
+            ],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "heredoc",
+            name: Some("string.unquoted.heredoc.goldenscript"),
+            content_name: None,
+            pattern: Region(r"<<([a-zA-Z0-9_]+)".to_string(), r"^(\1)\s*$".to_string()),
+            captures: &[(1, "keyword.operator.heredoc.goldenscript")],
+            end_captures: &[(1, "keyword.operator.heredoc.goldenscript")],
+            include: &[],
+        },
+        Rule {
+            id: "key",
+            name: None,
+            content_name: None,
+            pattern: Match(format!(r"({string})(=)")),
+            captures: &[
+                (1, "variable.parameter.goldenscript"),
+                (2, "keyword.operator.assignment.goldenscript"),
+            ],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "tags",
+            name: Some("entity.name.tag.goldenscript"),
+            content_name: None,
+            pattern: Match(r"\[[^\]]*\]".to_string()),
+            captures: &[],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "raw-string",
+            name: Some("string.quoted.raw.goldenscript"),
+            content_name: None,
+            pattern: Match(r#"r'[^']*'|r"[^"]*""#.to_string()),
+            captures: &[],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "unquoted-string",
+            name: Some("string.unquoted.goldenscript"),
+            content_name: None,
+            pattern: Match(UNQUOTED_VALUE.to_string()),
+            captures: &[],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "triple-quoted-string",
+            name: Some("string.quoted.triple.goldenscript"),
+            content_name: None,
+            pattern: Region(r#"""""#.to_string(), r#"""""#.to_string()),
+            captures: &[],
+            end_captures: &[],
+            include: &["escape"],
+        },
+        Rule {
+            id: "single-quoted-string",
+            name: Some("string.quoted.single.goldenscript"),
+            content_name: None,
+            pattern: Region("'".to_string(), "'".to_string()),
+            captures: &[],
+            end_captures: &[],
+            include: &["escape"],
+        },
+        Rule {
+            id: "double-quoted-string",
+            name: Some("string.quoted.double.goldenscript"),
+            content_name: None,
+            pattern: Region("\"".to_string(), "\"".to_string()),
+            captures: &[],
+            end_captures: &[],
+            include: &["escape"],
+        },
+        Rule {
+            id: "trailing-comment",
+            name: Some("comment.line.goldenscript"),
+            content_name: None,
+            pattern: Match(COMMENT.to_string()),
+            captures: &[],
+            end_captures: &[],
+            include: &[],
+        },
+        Rule {
+            id: "line-continuation",
+            name: Some("constant.character.escape.line-continuation.goldenscript"),
+            content_name: None,
+            pattern: Match(r"\\$".to_string()),
+            captures: &[],
+            end_captures: &[],
+            include: &[],
+        },
+    ]
+}
+
+/// Returns a TextMate grammar for goldenscripts as JSON, with scope name
+/// `source.goldenscript`. It can be used e.g. in VS Code extensions, Sublime
+/// Text, or other editors that support TextMate grammars.
+pub fn textmate() -> String {
+    let rules = rules();
+    let mut json = String::new();
+    json.push_str("{\n");
+    writeln!(json, r#"  "name": "Goldenscript","#).unwrap();
+    writeln!(json, r#"  "scopeName": {},"#, json_quote(SCOPE)).unwrap();
+    json.push_str("  \"patterns\": [\n");
+    let patterns = rules.iter().map(|r| format!("    {{ \"include\": \"#{}\" }}", r.id));
+    json.push_str(&patterns.collect::<Vec<_>>().join(",\n"));
+    json.push_str("\n  ],\n");
+    json.push_str("  \"repository\": {\n");
+
+    let mut entries = Vec::new();
+    for rule in &rules {
+        let mut fields = Vec::new();
+        if let Some(name) = rule.name {
+            fields.push(format!("\"name\": {}", json_quote(name)));
+        }
+        if let Some(content_name) = rule.content_name {
+            fields.push(format!("\"contentName\": {}", json_quote(content_name)));
+        }
+        match &rule.pattern {
+            Pattern::Match(pattern) => {
+                fields.push(format!("\"match\": {}", json_quote(pattern)));
+                fields.extend(captures(rule.captures).map(|c| format!("\"captures\": {c}")));
+            }
+            Pattern::Region(begin, end) => {
+                fields.push(format!("\"begin\": {}", json_quote(begin)));
+                fields.push(format!("\"end\": {}", json_quote(end)));
+                fields.extend(captures(rule.captures).map(|c| format!("\"beginCaptures\": {c}")));
+                fields.extend(captures(rule.end_captures).map(|c| format!("\"endCaptures\": {c}")));
+            }
+        }
+        if !rule.include.is_empty() {
+            let include = rule.include.iter().map(|id| format!("{{ \"include\": \"#{id}\" }}"));
+            fields.push(format!("\"patterns\": [{}]", include.collect::<Vec<_>>().join(", ")));
+        }
+        entries.push(format!(
+            "    {}: {{\n      {}\n    }}",
+            json_quote(rule.id),
+            fields.join(",\n      ")
+        ));
+    }
+    entries.push(format!(
+        "    \"escape\": {{\n      \"name\": {},\n      \"match\": {}\n    }}",
+        json_quote("constant.character.escape.goldenscript"),
+        json_quote(ESCAPE)
+    ));
+    json.push_str(&entries.join(",\n"));
+    json.push_str("\n  }\n}\n");
+    json
+}
+
+/// Formats capture group scopes as a JSON object, if any.
+fn captures(captures: &[(u32, &str)]) -> Option<String> {
+    if captures.is_empty() {
+        return None;
+    }
+    let captures =
+        captures.iter().map(|(i, name)| format!("\"{i}\": {{ \"name\": {} }}", json_quote(name)));
+    Some(format!("{{ {} }}", captures.collect::<Vec<_>>().join(", ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    /// Returns the match pattern of the given rule.
+    fn pattern(id: &str) -> Regex {
+        let rule = rules().into_iter().find(|r| r.id == id).unwrap();
+        let Pattern::Match(pattern) = rule.pattern else { panic!("{id} is not a match rule") };
+        Regex::new(&pattern).unwrap()
+    }
+
+    /// Tests that all patterns are valid regular expressions. Backreferences
+    /// aren't supported by the regex crate, so they're replaced.
+    #[test]
+    fn patterns_compile() {
+        for rule in rules() {
+            let patterns = match rule.pattern {
+                Pattern::Match(pattern) => vec![pattern],
+                Pattern::Region(begin, end) => vec![begin, end.replace(r"\1", "x")],
+            };
+            for pattern in patterns {
+                Regex::new(&pattern).unwrap_or_else(|e| panic!("{}: {e}", rule.id));
+            }
+        }
+        Regex::new(ESCAPE).unwrap();
+    }
+
+    /// Tests that unquoted strings and argument values match the parser, for
+    /// all ASCII characters and a few Unicode characters.
+    #[test]
+    fn unquoted_matches_parser() {
+        let unquoted = Regex::new(&format!("^{UNQUOTED}$")).unwrap();
+        let unquoted_value = Regex::new(&format!("^{UNQUOTED_VALUE}$")).unwrap();
+        let chars = (' '..='~').chain(['é', '😀', '\t']);
+        for c in chars {
+            for s in [format!("a{c}b"), format!("{c}a"), format!("a{c}"), format!("a{c}{c}b")] {
+                let parsed = crate::parse_borrowed(&format!("{s}\n---\n"))
+                    .is_ok_and(|blocks| blocks[0].commands[0].name == s);
+                assert_eq!(unquoted.is_match(&s), parsed, "{s:?}");
+
+                let parsed =
+                    crate::parse_borrowed(&format!("command {s}\n---\n")).is_ok_and(|blocks| {
+                        blocks[0].commands[0].args.first().is_some_and(|a| a.value == s)
+                    });
+                assert_eq!(unquoted_value.is_match(&s), parsed, "{s:?}");
+            }
+        }
+    }
+
+    /// Tests that the command and directive patterns capture the same names and
+    /// prefixes as the parser, for all commands in the test scripts.
+    #[test]
+    fn commands_match_parser() {
+        let command = pattern("command");
+        let directive = pattern("directive");
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+        let mut count = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let input = std::fs::read_to_string(entry.unwrap().path()).unwrap();
+            for cmd in crate::parse_borrowed(&input).unwrap().iter().flat_map(|b| &b.commands) {
+                let line_start = input[..cmd.name_span.start].rfind('\n').map_or(0, |i| i + 1);
+                let line_end =
+                    input[line_start..].find('\n').map_or(input.len(), |i| line_start + i);
+                let line = input[line_start..line_end].trim_end_matches('\r');
+
+                // Only compare the first line of multi-line names.
+                let mut name = &input[cmd.name_span.clone()];
+                if name.contains('\n') {
+                    if !line.contains('>') {
+                        continue; // multi-line string
+                    }
+                    name = name.lines().next().unwrap();
+                }
+
+                let captures = match cmd.directive {
+                    true => directive.captures(line).map(|c| (c.get(2), None)),
+                    false => command.captures(line).map(|c| (c.get(7).or(c.get(8)), c.get(2))),
+                };
+                let (captured_name, captured_prefix) = captures.expect(line);
+                assert_eq!(captured_name.map(|m| m.as_str()), Some(name), "{line}");
+                assert_eq!(
+                    captured_prefix.map(|m| m.as_str()),
+                    cmd.prefix_span.clone().map(|s| &input[s]),
+                    "{line}"
+                );
+                count += 1;
+            }
+        }
+        assert!(count > 100, "too few commands: {count}");
+    }
+}
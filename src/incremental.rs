@@ -0,0 +1,256 @@
+//! Incremental parsing of goldenscripts, e.g. for editors and file watchers.
+//!
+//! A [`Script`] keeps the script text along with its parsed blocks. When the
+//! text is edited via [`Script::edit`], only the blocks affected by the edit
+//! are reparsed, and the remaining blocks are reused (with adjusted positions).
+
+use crate::parser::{format_error, parse_block, parse_first_block};
+use crate::Command;
+
+use std::ops::Range;
+
+/// An incrementally parsed goldenscript.
+#[derive(Clone, Debug)]
+pub struct Script {
+    /// The script text.
+    text: String,
+    /// The parsed blocks, in order. Their ranges cover the entire text.
+    blocks: Vec<ScriptBlock>,
+    /// If true, the last edit failed to parse, and the blocks are empty. The
+    /// next edit will reparse the entire script.
+    failed: bool,
+}
+
+/// A parsed block in a [`Script`].
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct ScriptBlock {
+    /// The byte range of the block in the script text, including the output
+    /// and any trailing blank line.
+    pub range: Range<usize>,
+    /// The block's line number position in the script.
+    pub line_number: u32,
+    /// The commands in the block, including directives.
+    pub commands: Vec<Command>,
+}
+
+impl Script {
+    /// Parses a script.
+    pub fn parse(text: impl Into<String>) -> std::io::Result<Self> {
+        let mut script = Self { text: text.into(), blocks: Vec::new(), failed: false };
+        script.blocks = script.parse_from(0, 1, |_| false)?.0;
+        Ok(script)
+    }
+
+    /// Returns the script text.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the parsed blocks. Empty if the last edit failed to parse.
+    pub fn blocks(&self) -> &[ScriptBlock] {
+        &self.blocks
+    }
+
+    /// Replaces the given byte range of the text with the replacement, and
+    /// reparses the affected blocks. Returns the index range of the reparsed
+    /// blocks in [`Script::blocks`]; other blocks are unchanged, except for
+    /// their positions.
+    ///
+    /// The text is updated even if it fails to parse, in which case the error
+    /// is returned and the blocks are cleared until a later edit succeeds.
+    pub fn edit(
+        &mut self,
+        range: Range<usize>,
+        replacement: &str,
+    ) -> std::io::Result<Range<usize>> {
+        if range.start > range.end
+            || !self.text.is_char_boundary(range.start)
+            || !self.text.is_char_boundary(range.end)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid edit range {range:?}"),
+            ));
+        }
+        let removed_lines = count_lines(&self.text[range.clone()]);
+        self.text.replace_range(range.clone(), replacement);
+
+        // If the previous edit failed, reparse the entire script.
+        if self.failed {
+            return self.reparse_all();
+        }
+
+        // Find the first block affected by the edit. Since a block's parse may
+        // depend on the end of the text, an edit at the end affects the last
+        // block.
+        let first = match self.blocks.iter().position(|b| b.range.end > range.start) {
+            Some(i) => i,
+            None => self.blocks.len().saturating_sub(1),
+        };
+        let (start, line_number) = match self.blocks.get(first) {
+            Some(block) => (block.range.start, block.line_number),
+            None => (0, 1),
+        };
+
+        // Reparse blocks until we reach an unchanged block boundary after the
+        // edit, i.e. the end of an old block, at which point the rest of the
+        // blocks can be reused.
+        let delta = replacement.len() as isize - range.len() as isize;
+        let edit_end = range.start + replacement.len();
+        let old_ends: Vec<usize> = self.blocks[first..].iter().map(|b| b.range.end).collect();
+        let result = self.parse_from(start, line_number, |pos| {
+            pos >= edit_end && old_ends.contains(&pos.wrapping_add_signed(-delta))
+        });
+        let (blocks, end) = match result {
+            Ok(result) => result,
+            Err(_) => return self.reparse_all(), // for accurate error positions
+        };
+
+        // Splice in the reparsed blocks, and shift the positions of the reused
+        // blocks.
+        let reused = match end {
+            Some(end) => {
+                first
+                    + 1
+                    + old_ends.iter().position(|e| *e == end.wrapping_add_signed(-delta)).unwrap()
+            }
+            None => self.blocks.len(),
+        };
+        let line_delta = count_lines(replacement) as i64 - removed_lines as i64;
+        let mut tail = self.blocks.split_off(reused);
+        for block in &mut tail {
+            block.range.start = block.range.start.wrapping_add_signed(delta);
+            block.range.end = block.range.end.wrapping_add_signed(delta);
+            block.line_number = (block.line_number as i64 + line_delta) as u32;
+            for command in &mut block.commands {
+                command.line_number = (command.line_number as i64 + line_delta) as u32;
+            }
+        }
+        let reparsed = first..first + blocks.len();
+        self.blocks.truncate(first);
+        self.blocks.extend(blocks);
+        self.blocks.extend(tail);
+        Ok(reparsed)
+    }
+
+    /// Reparses the entire script, returning the range of all blocks.
+    fn reparse_all(&mut self) -> std::io::Result<Range<usize>> {
+        self.blocks.clear();
+        self.failed = true;
+        self.blocks = self.parse_from(0, 1, |_| false)?.0;
+        self.failed = false;
+        Ok(0..self.blocks.len())
+    }
+
+    /// Parses blocks starting at the given byte position and line number,
+    /// until the end of the text or until stop returns true for the end
+    /// position of a block. Returns the blocks and the stop position, if any.
+    fn parse_from(
+        &self,
+        mut pos: usize,
+        mut line_number: u32,
+        stop: impl Fn(usize) -> bool,
+    ) -> std::io::Result<(Vec<ScriptBlock>, Option<usize>)> {
+        let mut blocks = Vec::new();
+        while pos < self.text.len() {
+            let input = &self.text[pos..];
+            let (len, block) = match pos {
+                0 => parse_first_block(input),
+                _ => parse_block(input),
+            }
+            .map_err(format_error)?;
+            let commands = block
+                .commands
+                .into_iter()
+                .map(|c| {
+                    let mut command = c.into_owned();
+                    command.line_number += line_number - 1;
+                    command
+                })
+                .collect();
+            blocks.push(ScriptBlock { range: pos..pos + len, line_number, commands });
+            line_number += count_lines(&input[..len]);
+            pos += len;
+            if stop(pos) {
+                return Ok((blocks, Some(pos)));
+            }
+        }
+        Ok((blocks, None))
+    }
+}
+
+/// Counts the number of line feeds in the given string.
+fn count_lines(s: &str) -> u32 {
+    s.bytes().filter(|b| *b == b'\n').count() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests that incremental edits at every position yield the same blocks as
+    /// parsing the edited script from scratch.
+    #[test]
+    fn edit_matches_full_parse() {
+        let text =
+            "# comment\na\nb arg\n---\nout\n\n%seed 1\nc [tag]\n---\nx\ny\n\nd\n---\n\n# end\n";
+        let replacements = ["", "x", "\n", "\n\n", "---\n", "e\n---\nok\n\n", "# c\n"];
+        for start in 0..=text.len() {
+            for end in start..=(start + 3).min(text.len()) {
+                for replacement in replacements {
+                    let mut expect = text.to_string();
+                    expect.replace_range(start..end, replacement);
+
+                    let mut script = Script::parse(text).unwrap();
+                    let result = script.edit(start..end, replacement);
+                    assert_eq!(script.text(), expect);
+                    match Script::parse(expect.as_str()) {
+                        Ok(full) => {
+                            assert!(result.is_ok(), "{start}..{end} {replacement:?}");
+                            assert_eq!(
+                                script.blocks(),
+                                full.blocks(),
+                                "{start}..{end} {replacement:?}"
+                            );
+                        }
+                        Err(e) => {
+                            assert_eq!(result.unwrap_err().to_string(), e.to_string());
+                            assert!(script.blocks().is_empty());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tests that only affected blocks are reparsed, and that a failed edit
+    /// can be recovered by a later edit.
+    #[test]
+    fn edit_reparsed() {
+        let text = "a\n---\nok\n\nb\n---\nok\n\nc\n---\nok\n";
+        let mut script = Script::parse(text).unwrap();
+        assert_eq!(script.blocks().len(), 3);
+
+        // Editing a command name only reparses its block.
+        assert_eq!(script.edit(10..11, "bb").unwrap(), 1..2);
+        assert_eq!(script.blocks()[1].commands[0].name, "bb");
+        assert_eq!(script.blocks()[2].range, 21..30);
+
+        // Adding a block in the middle shifts line numbers of later blocks.
+        assert_eq!(script.edit(21..21, "x\n---\nok\n\n").unwrap(), 2..4);
+        assert_eq!(script.blocks()[3].line_number, 13);
+        assert_eq!(script.blocks()[3].commands[0].line_number, 13);
+
+        // Removing a separator fails, and is recovered by a later edit.
+        assert!(script.edit(33..37, "").is_err());
+        assert!(script.blocks().is_empty());
+        assert_eq!(script.edit(33..33, "---\n").unwrap(), 0..4);
+        assert_eq!(script.blocks().len(), 4);
+
+        // Invalid ranges error without changing the script.
+        assert!(script.edit(1000..1000, "").is_err());
+        assert!(script.edit(0..1000, "").is_err());
+        assert_eq!(script.blocks().len(), 4);
+    }
+}
@@ -0,0 +1,77 @@
+//! Integration with the [`insta`](https://docs.rs/insta/latest/insta/) snapshot
+//! testing library, enabled via the `insta` crate feature.
+//!
+//! Instead of writing the output back to the goldenscript file, the generated
+//! output is recorded as an insta snapshot. Changes can then be reviewed and
+//! accepted with the usual insta tooling, e.g. `cargo insta review`, rather
+//! than `UPDATE_GOLDENFILES=1`. The script files themselves are left untouched.
+//!
+//! Use the [`assert_insta_snapshot!`](crate::assert_insta_snapshot) macro,
+//! which must be called from the test itself such that insta can resolve the
+//! crate's workspace. For a script at `tests/scripts/foo`, the snapshot is
+//! stored as `tests/scripts/snapshots/foo.snap`:
+//!
+//! ```no_run
+//! # struct Runner;
+//! # use goldenscript::Command;
+//! # use std::error::Error;
+//! # impl goldenscript::Runner for Runner {
+//! #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+//! # }
+//! #[test]
+//! fn test() {
+//!     goldenscript::assert_insta_snapshot!(&mut Runner, "tests/scripts/foo");
+//! }
+//! ```
+
+use crate::{generate_with, RunOptions, Runner};
+
+use std::path::Path;
+
+#[doc(hidden)]
+pub use ::insta as __insta;
+
+/// Asserts that the output of running the goldenscript at the given path
+/// matches its insta snapshot. Takes a runner, a path, and optionally
+/// [`RunOptions`](crate::RunOptions). Panics on errors or mismatches, like
+/// [`insta::assert_snapshot!`](https://docs.rs/insta/latest/insta/macro.assert_snapshot.html).
+#[macro_export]
+macro_rules! assert_insta_snapshot {
+    ($runner:expr, $path:expr $(,)?) => {
+        $crate::assert_insta_snapshot!($runner, $path, &$crate::RunOptions::default())
+    };
+    ($runner:expr, $path:expr, $options:expr $(,)?) => {{
+        let path: &::std::path::Path = ::std::convert::AsRef::as_ref(&$path);
+        let (name, output, settings) = match $crate::insta::prepare($runner, path, $options) {
+            Ok(prepared) => prepared,
+            Err(err) => panic!("goldenscript {} failed: {err}", path.display()),
+        };
+        settings.bind(|| $crate::insta::__insta::assert_snapshot!(name, output));
+    }};
+}
+
+/// Runs the goldenscript at the given path, returning the snapshot name, the
+/// generated output, and the insta settings to assert the snapshot with. Used
+/// by [`assert_insta_snapshot!`](crate::assert_insta_snapshot).
+#[doc(hidden)]
+pub fn prepare<R: Runner>(
+    runner: &mut R,
+    path: &Path,
+    options: &RunOptions,
+) -> std::io::Result<(String, String, ::insta::Settings)> {
+    let invalid_path = || {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid path '{path:?}'"))
+    };
+    let dir = path.parent().ok_or_else(invalid_path)?;
+    let name = path.file_name().ok_or_else(invalid_path)?.to_string_lossy().to_string();
+
+    let input = crate::runner::read_script(path, options)?;
+    let output = generate_with(runner, &input, options)?;
+
+    let mut settings = ::insta::Settings::clone_current();
+    settings.set_snapshot_path(std::env::current_dir()?.join(dir).join("snapshots"));
+    settings.set_prepend_module_to_snapshot(false);
+    settings.set_omit_expression(true);
+    settings.set_input_file(path);
+    Ok((name, output, settings))
+}
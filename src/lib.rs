@@ -32,7 +32,10 @@
 //! ```
 //!
 //! The files are then verified by inspection and checked in to version control.
-//! Tests will fail with a diff if they don't match the expected output.
+//! Tests will fail with a diff if they don't match the expected output. The
+//! diff is preceded by a summary of the changed lines by class (e.g. numeric
+//! or whitespace-only changes), see the [`diff`] module. A [`ReviewPolicy`]
+//! can be used to reject specific block updates when regenerating.
 //!
 //! This approach is particularly useful when testing complex stateful systems,
 //! such as database operations, network protocols, or language parsing. It can
@@ -40,9 +43,8 @@
 //! scripting and recording these interactions often yields much better test
 //! coverage at a fraction of the cost.
 //!
-//! Internally, the
-//! [`goldenfile`](https://docs.rs/goldenfile/latest/goldenfile/) crate is used
-//! to manage golden files.
+//! Golden files are read and written via a pluggable [`storage::Storage`]
+//! backend, which defaults to the filesystem.
 //!
 //! # Examples
 //!
@@ -183,6 +185,57 @@
 //! output 2
 //! ```
 //!
+//! A block can be given tags on separate lines before its first command, using
+//! the same syntax as command tags. These are passed to the block hooks via
+//! [`BlockInfo`], e.g. to reset state for blocks tagged `[isolated]`:
+//!
+//! ```text
+//! [isolated]
+//! command
+//! ---
+//! output
+//! ```
+//!
+//! Blocks tagged `[unordered]` compare their output as an unordered set of
+//! lines, for commands whose output order is inherently unstable, e.g. set
+//! listings or cluster membership. If the output has the same lines as the
+//! existing output in any order (including duplicates), the existing output is
+//! kept as-is, otherwise the new output is written.
+//!
+//! Blocks tagged `[concurrent]` run their commands concurrently, e.g. for
+//! stress tests. Each command is spawned on a separate thread via
+//! [`Runner::spawn`] as for background commands, and all of them are joined at
+//! the end of the block, writing their output in input order. Directives are
+//! run as they're reached. The runner must implement [`Runner::spawn`] and
+//! enable [`Capability::Spawn`], otherwise scripts with `[concurrent]` blocks
+//! error before running anything. Commands with `[after=@LABEL]` dependencies are
+//! spawned once the commands they depend on have completed, while independent
+//! commands run concurrently.
+//!
+//! Blocks tagged `[diff]` output the lines that changed relative to the
+//! previous block's full output, prefixed by `- ` or `+ `, instead of the full
+//! output, or `(no changes)` if it's the same. This is useful e.g. for scripts
+//! that test incremental state transitions, where full dumps are redundant.
+//!
+//! ```text
+//! dump
+//! ---
+//! a=1
+//! b=2
+//!
+//! [diff]
+//! set b=3
+//! dump
+//! ---
+//! - b=2
+//! + b=3
+//! ```
+//!
+//! Blocks tagged `[skip]` or `[skip=REASON]` are parsed but not run, and keep
+//! their existing output verbatim, e.g. to temporarily quarantine a failing
+//! block without deleting its expected output. Skipped blocks are reported to
+//! the [`RunOptions::reporter`], if any, as [`Notice::BlockSkipped`].
+//!
 //! ## Commands
 //!
 //! A [`Command`] must have a command name, which can be any arbitrary
@@ -248,23 +301,74 @@
 //!     prefix: Panic: bar
 //!     ```
 //!
+//! * [**Background:**](Command::background) if `&` follows the command, it is
+//!   run in the background on a separate thread via [`Runner::spawn`], and the
+//!   script continues with later commands. The built-in `_wait` command waits
+//!   for all background commands, and outputs their results in launch order.
+//!   This allows scripting blocking operations that are unblocked by later
+//!   commands. All background commands must be waited for.
+//!
+//!     ```text
+//!     client1: lock key &
+//!     client2: unlock key
+//!     _wait
+//!     ---
+//!     client2: unlock ok
+//!     client1: lock ok
+//!     ```
+//!
 //! * [**Tags:**](Command::tags) an optional comma- or space-separated list of
 //!   tags (strings) enclosed in [] before or after the command and arguments.
 //!   This can be used by the runner e.g. to modify the execution of a command.
+//!   Tags can also be given as `key=value`, see [`Command::tag_value`].
+//!   Commands with certain tags, e.g. `[slow]`, can be skipped via
+//!   [`RunOptions::skip_tags`].
 //!
 //!     ```text
 //!     command [tag]
 //!     command arg key=value [a,b c]
+//!     command [key=value]
 //!     [tag] command
 //!     prefix:[tag]!> command arg
 //!     ---
 //!     ```
 //!
+//!   Some tags are interpreted by goldenscript itself:
+//!
+//!   * `[wrap=WIDTH]`: hard-wraps output lines longer than WIDTH characters,
+//!     see [`RunOptions::wrap`].
+//!   * `[budget=DURATION]`: fails if the command takes longer than DURATION
+//!     (e.g. `50ms`), see [`RunOptions::budget_breach`].
+//!   * `[timeout=DURATION]`: fails if the command hasn't completed within
+//!     DURATION (e.g. `5s`), see [`RunOptions::command_timeout`].
+//!   * `[retry=N]`: retries the command up to N times while it errors, or while
+//!     its output doesn't match its existing golden output (the lines following
+//!     the preceding commands' output), e.g. to test eventually consistent
+//!     systems. Combine with `[backoff=DURATION]` to wait between attempts,
+//!     doubling the wait after each retry. The last attempt's output is used.
+//!   * `[repeat=N]`: runs the command N times, e.g. for idempotency checks. If
+//!     the outputs are identical, they're collapsed into a single output,
+//!     otherwise each output's lines are prefixed by the repetition number,
+//!     e.g. `2: `. Can also be given as a block tag, repeating the block's
+//!     commands.
+//!   * `[unix]`, `[windows]`, `[cfg:NAME]`: skips the command unless running
+//!     on a Unix or Windows platform, or the condition is enabled (see
+//!     [`RunOptions::cfg`]), e.g. for path-sensitive commands. Skipped
+//!     commands output `(skipped)`. Can also be given as block tags, skipping
+//!     all of the block's commands.
+//!   * `[@LABEL]`: labels the command, for use with `after=@LABEL`.
+//!   * `[after=@LABEL]`: runs the command after the labeled command in the
+//!     same block. If any command in a block declares a dependency, the block's
+//!     commands are run in dependency order, batching independent commands
+//!     (see [`Runner::run_batch`]) which the runner may run concurrently. In
+//!     `[concurrent]` blocks, independent commands are spawned concurrently.
+//!     Directives run after all preceding and before all following commands.
+//!     The output is always written in declaration order.
+//!
 //!  * **Literal:** if `>` precedes the command, the entire rest of the line is
 //!    taken to be the command name (except leading whitespace). Arguments,
 //!    tags, comments, and any other special characters are ignored and used
-//!    as-is. As a special case (currently only with `>`), lines can span
-//!    multiple lines by ending the line with \.
+//!    as-is. The name can span multiple lines by ending the line with \.
 //!
 //!    ```text
 //!    > a long command name including key=value, [tags], # a comment and exclamation!
@@ -278,12 +382,201 @@
 //!    ---
 //!    ```
 //!
+//! Long commands can be continued on the next line by ending the line with `\`
+//! between the command name, arguments, and tags. The command's line number is
+//! that of its first line.
+//!
+//! ```text
+//! command arg1 arg2 \
+//!     key=value [tag]
+//! ---
+//! ```
+//!
+//! A few built-in commands, beginning with `_`, are handled by goldenscript
+//! rather than the runner. Besides `_wait` (see
+//! background commands above), `_advance_time DURATION` advances the script's
+//! [`VirtualClock`] (available to the runner via [`RunContext::clock`]), and
+//! `_now` outputs its current time. This allows deterministic testing of e.g.
+//! TTLs and leases, where the system under test reads the virtual clock.
+//! `_checkpoint NAME` saves a named checkpoint of the runner's state via
+//! [`Runner::checkpoint`], and `_restore NAME` restores it via
+//! [`Runner::restore`], e.g. to run divergent scenarios after a common setup.
+//! Further optional built-ins such as `_sleep` and `_skip` can be enabled via
+//! [`RunOptions::builtin_commands`].
+//!
+//! ```text
+//! put key=foo ttl=10s
+//! _advance_time 11s
+//! _now
+//! get key=foo
+//! ---
+//! 11s
+//! not found
+//! ```
+//!
+//! ## Directives
+//!
+//! Lines beginning with `%` are directives, which are handled by goldenscript
+//! itself rather than the runner. They take arguments like commands, but can't
+//! have prefixes, tags, silencing, or failures. The following directives are
+//! supported:
+//!
+//! * `%seed SEED`: seeds the [`RunContext`] random number generator with the
+//!   given integer, e.g. to shuffle inputs via [`RunContext::shuffle`]. The
+//!   initial seed is 0, unless set via [`RunOptions::seed`] or the
+//!   `GOLDENSCRIPT_SEED` environment variable, in which case failures report
+//!   it for reproduction.
+//!
+//!   `%seed auto` uses a random seed for each run (or `GOLDENSCRIPT_SEED` if
+//!   set), e.g. for exploratory testing of scripts whose output doesn't depend
+//!   on the seed. The chosen seed is reported to the [`RunOptions::reporter`],
+//!   if any, as [`Notice::AutoSeed`], and included in failures. When
+//!   recording with `UPDATE_GOLDENFILES=1`, the directive is pinned to the
+//!   chosen seed, e.g. `%seed 4711`, making the run reproducible.
+//!
+//! * `%gen NAME KIND [ARGS...]`: generates pseudo-random data via [`datagen`],
+//!   which the runner can fetch via [`RunContext::generated`]. Outputs the
+//!   data size and checksum. The kind can be `key [len=8]`,
+//!   `lorem [words=10]`, or `payload size=SIZE` (e.g. `4096` or `4KiB`).
+//!
+//! * `%limits [max_commands=N] [max_runtime=DURATION]`: sets resource limits
+//!   for the script, erroring if it runs more than the given number of commands
+//!   (excluding directives) or for longer than the given duration (e.g. `30s`,
+//!   with unit `ms`, `s`, `m`, or `h`). Must be given before any commands.
+//!   Once the runtime is exceeded, the [`RunContext::cancellation_token`] is
+//!   cancelled, allowing runners to abort long-running commands.
+//!
+//! * `%resources [NAME=AMOUNT...] [NAME...]`: declares resources used by the
+//!   script, e.g. `%resources cpu=4 net-port`, where a bare name uses 1. With
+//!   [`RunOptions::resource_limit`], the script waits until the resources are
+//!   available before running, limiting the total used by concurrently running
+//!   scripts. Must be given before any commands.
+//!
+//! * `%expect-fail`: expects the block to fail. Any command in the block may
+//!   fail, with its error or panic output as for `!`, and at least one must
+//!   fail. Useful for blocks dedicated to error paths.
+//!
+//! * `%sleep DURATION`: pauses the script for the given duration (e.g. `50ms`),
+//!   or until it's cancelled. Requires [`Capability::Sleep`].
+//!
+//! * `%env [KEY=VALUE...] [KEY...]`: sets the given script environment
+//!   variables, available to the runner via [`RunContext::env`], and unsets
+//!   the given bare keys. They don't affect the process environment, and are
+//!   dropped at the end of the script. Requires [`Capability::Env`].
+//!
+//! * `%runner NAME`: selects the runner for the script from a
+//!   [`RunnerRegistry`] when run via [`run_dir()`], allowing a directory to mix
+//!   scripts for different runners. Must be given before any commands, and is
+//!   ignored when the script is run directly.
+//!
+//! * `%setup [verbose]` and `%teardown [verbose]`: mark the first and last
+//!   block as the script's setup and teardown sections, e.g. to create and
+//!   remove shared state. The directive must be the first command in the
+//!   block. The sections' command output is silenced, unless `verbose` is
+//!   given. The teardown section is also run if the script fails before it,
+//!   in which case its output is ignored, and its failures are reported to the
+//!   [`RunOptions::reporter`], if any, as [`Notice::TeardownFailed`].
+//!   Identical setup sections across scripts can be cached, see
+//!   [`RunOptions::cache_setup`].
+//!
+//!   ```text
+//!   %setup
+//!   create table
+//!   ---
+//!   ok
+//!
+//!   %teardown
+//!   drop table
+//!   ---
+//!   ok
+//!   ```
+//!
+//! * `%branch NAME`: starts a named branch, which runs until the next branch or
+//!   the end of the script. Each branch continues from the state at the first
+//!   branch, restored via [`Runner::checkpoint`] and [`Runner::restore`] with
+//!   the name `%branch`, such that a single script can test every outcome of
+//!   a common scenario. The directive must be the first command in the block.
+//!
+//!   ```text
+//!   begin
+//!   put foo=bar
+//!   ---
+//!   ok
+//!
+//!   %branch commit
+//!   commit
+//!   get foo
+//!   ---
+//!   bar
+//!
+//!   %branch rollback
+//!   rollback
+//!   get foo
+//!   ---
+//!   not found
+//!   ```
+//!
+//! * `%halt [REASON...]`: halts the script, e.g. when later blocks depend on
+//!   an environment feature that's missing. The rest of the block produces
+//!   no output, and the remaining blocks are kept verbatim without running
+//!   them, except for any `%teardown` section which is run with its output
+//!   ignored. The number of blocks not executed is reported to the
+//!   [`RunOptions::reporter`], if any, as [`Notice::ScriptHalted`].
+//!   Runners can also halt the script at runtime via [`RunContext::halt`].
+//!
+//! * `%template NAME = "COMMAND"`: defines a command template, which is
+//!   invoked as `@NAME KEY=VALUE...` in later commands. `${KEY}` placeholders
+//!   in the command are replaced by the invocation's arguments, and the
+//!   invocation's prefix, tags, silencing, and failure marker are added to the
+//!   command. Templates are expanded when the script is parsed, erroring on
+//!   missing or unknown placeholders. For example:
+//!
+//!   ```text
+//!   %template put_kv = "put key=${key} value=${value}"
+//!   @put_kv key=a value=b
+//!   ---
+//!   ```
+//!
+//! * `%alias NAME=COMMAND...`: defines command aliases for the rest of the
+//!   script, e.g. to abbreviate long command names with common arguments.
+//!   Commands named `NAME` are replaced by the alias' command, with the
+//!   invocation's arguments appended. Like templates, aliases are expanded
+//!   when the script is parsed, so the runner only sees the full command, and
+//!   the invocation's prefix, tags, silencing, and failure marker are added
+//!   to it. For example:
+//!
+//!   ```text
+//!   %alias g=get s="scan reverse=true"
+//!   g foo
+//!   s prefix=a
+//!   ---
+//!   ```
+//!
+//! * `%end-script`: holds the output of [`Runner::end_script`], e.g. a
+//!   final state summary, in a trailing block. This block is added, updated,
+//!   or removed automatically, and must be the last block.
+//!
+//! Directives that can affect the runner require it to opt in via
+//! [`Runner::capabilities`], and otherwise error.
+//!
+//! ```text
+//! %seed 7
+//! %gen value payload size=4096
+//! put foo value
+//! ---
+//! value: 4096 bytes, checksum c4e85c56
+//! put ok
+//! ```
+//!
 //! ## Output
 //!
 //! The command output following a `---` separator can contain any arbitrary
 //! Unicode string until an empty line (or end of file). If the command output
 //! contains empty lines, the entire output will automatically be prefixed with
 //! `> `. If no commands in a block yield any output, it defaults to "ok".
+//! Raw control characters can optionally be escaped or rejected via
+//! [`RunOptions::control_chars`], and binary output can be rendered as a
+//! hexdump or rejected via [`RunOptions::binary_output`].
 //!
 //! ```text
 //! echo "output 1"
@@ -316,11 +609,30 @@
 //! ---
 //! ```
 //!
+//! ## Front Matter
+//!
+//! A script can begin with front matter: `key: value` lines between two `---`
+//! lines, before the first block. It's parsed into a [`ScriptMetadata`],
+//! available via [`RunContext::metadata`] e.g. in [`Runner::start_script`] to
+//! configure the system under test per script. It's kept verbatim in the output.
+//!
+//! ```text
+//! ---
+//! engine: memory
+//! ---
+//!
+//! command
+//! ---
+//! ```
+//!
 //! ## Strings
 //!
 //! Unquoted strings can only contain alphanumeric ASCII characters
-//! `[a-zA-Z0-9]` and a handful of special characters: `_ - . / @`
-//! (only `_` at the start of a string).
+//! `[a-zA-Z0-9]` and a handful of special characters: `_ - . / @ *` (only `_`
+//! and `@` at the start of a string). They can also start with a `-` or `+`
+//! sign, e.g. `-5`. Unquoted argument values and tags can also contain `:` but
+//! not end with it, e.g. `range=1:10`. Prefixes, command names, and argument
+//! keys can't, so `prefix:command` is a command with a prefix.
 //!
 //! Strings can be quoted using `"` or `'`, in which case they can contain
 //! arbitrary Unicode characters. `\` is used as an escape character, both to
@@ -329,10 +641,47 @@
 //! represent arbitrary hexadecimal bytes (e.g. `\x7a`) and `\u{}` can be used
 //! to represent arbitrary Unicode characters (e.g. `\u{1f44b}`)
 //!
+//! Triple-quoted strings use `"""`, and can span multiple lines and contain
+//! unescaped quotes. They respect the same escape sequences as quoted strings,
+//! and end at the first `"""`.
+//!
+//! Raw strings are prefixed by `r`, e.g. `r"C:\path"` or `r'\d+'`. They take
+//! `\` literally and don't process escape sequences, but can't contain their
+//! own quote character.
+//!
 //! ```text
 //! string
 //! "string with spaces and \"quotes\""
 //! '字符串'
+//! r"C:\Windows\System32"
+//! """SELECT *
+//! FROM "table""""
+//! ---
+//! ```
+//!
+//! Multi-line argument values can be given as heredocs, using `<<` followed by
+//! a terminator. The value consists of the following lines, taken literally, up
+//! to a line containing only the terminator. It does not include a trailing
+//! newline. Multiple heredocs on a line are read in order.
+//!
+//! ```text
+//! insert key=<<EOF
+//! {
+//!     "value": 1
+//! }
+//! EOF
+//! ---
+//! ```
+//!
+//! Argument values can also be given as inline JSON object or array literals,
+//! which can span multiple lines. They are validated by the parser and passed
+//! to the runner verbatim. With the `serde_json` crate feature,
+//! [`Argument::json()`] parses them as structured values. Arrays are only
+//! allowed as `key=value` values, since they would otherwise be ambiguous with
+//! tags.
+//!
+//! ```text
+//! configure {"name": "test"} config={"retries": 3, "hosts": ["a", "b"]}
 //! ---
 //! ```
 //!
@@ -362,6 +711,28 @@
 //! }
 //! ```
 //!
+//! Trivial runners can also be given as a closure via [`run_fn()`] or
+//! [`FnRunner::stateless()`], or as a closure over some state with optional
+//! hooks via [`FnRunner::new()`]. A [`CommandRegistry`] dispatches commands to
+//! handler closures by name, with shared state. Runners implementing
+//! [`Default`] can be constructed by [`run_default()`]:
+//!
+//! ```no_run
+//! # #[derive(Default)]
+//! # struct Runner;
+//! # use goldenscript::Command;
+//! # use std::error::Error;
+//! # impl goldenscript::Runner for Runner {
+//! #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+//! # }
+//! goldenscript::run_fn("tests/scripts/echo", |command| Ok(command.name.clone()))?;
+//! goldenscript::run_default::<Runner>("tests/scripts/test")?;
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! Runners can be checked for common misbehavior, such as panicking on missing
+//! arguments or accepting unknown commands, via the [`conformance`] module.
+//!
 //! ## Argument Processing
 //!
 //! Arguments can be processed manually via [`Command::args`], or using the
@@ -381,7 +752,7 @@
 //!     /// Implement a send command, which sends a string message to a list
 //!     /// of nodes, optionally retrying.
 //!     ///
-//!     /// send [retry=BOOL] MESSAGE ID...
+//!     /// send [retry] MESSAGE ID...
 //!     ///
 //!     /// Example: send foo 1 2 3
 //!     fn run(&mut self, command: &goldenscript::Command) -> Result<String, Box<dyn Error>> {
@@ -391,8 +762,12 @@
 //!
 //!         let mut args = command.consume_args();
 //!
+//!         // An optional retry flag can be given as a bare positional argument.
+//!         // Take it before processing the other positional arguments.
+//!         let retry = args.take_flag("retry");
+//!
 //!         // The first positional argument is a required string message.
-//!         let message = &args.next_pos().ok_or("message not given")?.value;
+//!         let message = &args.require_pos("message")?.value;
 //!
 //!         // The remaining positional arguments are numeric node IDs.
 //!         let ids: Vec<u32> = args.rest_pos().iter().map(|a| a.parse()).collect::<Result<_, _>>()?;
@@ -400,9 +775,6 @@
 //!             return Err("no node IDs given".into())
 //!         }
 //!
-//!         // An optional retry=bool key/value argument can also be given.
-//!         let retry: bool = args.lookup_parse("retry")?.unwrap_or(false);
-//!
 //!         // Any other arguments that haven't been processed above should error.
 //!         args.reject_rest()?;
 //!
@@ -412,6 +784,18 @@
 //! }
 //! ```
 //!
+//! With the `serde` crate feature, the arguments can instead be deserialized
+//! into a struct via [`ArgumentConsumer::deserialize`], which also rejects
+//! unknown arguments.
+//!
+//! With the `derive` crate feature, commands and their arguments can be parsed
+//! into an enum with a variant per command via `#[derive(FromCommand)]`, see
+//! [`FromCommand`].
+//!
+//! The parser also records the type of unquoted argument values in
+//! [`Argument::value_type`], e.g. to distinguish `count=10` (an integer) from
+//! `count="10"` (a string), see [`ValueType`].
+//!
 //! ## Managing State
 //!
 //! The runner is free to manage internal state as desired. If it is stateful,
@@ -423,6 +807,13 @@
 //! Initial state setup should generally be done via explicit setup commands, to
 //! make it more discoverable.
 //!
+//! Goldenscript also provides a [`RunContext`] for each script, which runners
+//! can access by also implementing [`Runner::run_ctx`]. It contains e.g. a
+//! random number generator seeded by the [`%seed`](#directives) directive,
+//! which can be used to shuffle inputs in a reproducible way, and typed
+//! [`Extensions`] for per-script state such as temp directories or ports,
+//! shared between the runner and its hooks, which are all given the context.
+//!
 //! ## Running All Scripts in a Directory
 //!
 //! External crates can be used to automatically generate and run individual
@@ -446,19 +837,143 @@
 //! }
 //! ```
 //!
+//! Alternatively, [`run_dir()`] runs all scripts in a directory with runners
+//! selected via `%runner`. With the `manifest` crate feature, a
+//! `goldenscripts.toml` manifest in the directory can declare defaults for the
+//! suite, such as the runner, output wrapping, and normalizers.
+//!
+//! Large end-to-end scenarios can be split into several scripts that run in
+//! order against a single runner instance via [`run_suite()`], given a
+//! `.suite` file listing them. Each script keeps its own golden output.
+//!
+//! With the `signals` crate feature, [`RunOptions::cancel_on_signal`] shuts
+//! down directory and suite runs gracefully on SIGINT or SIGTERM: no further
+//! scripts are started, the in-flight script runs its `%teardown` section and
+//! [`Runner::end_script`] hook, and the run fails reporting the completed
+//! scripts.
+//!
+//! Scripts can also be embedded into the test binary via `include_str!` and
+//! run via [`run_str()`], e.g. to run tests without the script files after
+//! `cargo publish`. With `UPDATE_GOLDENFILES=1`, the output is written to the
+//! given [`UpdateTarget`], typically the embedded source file.
+//!
+//! Scripts can be stored elsewhere than on the filesystem, e.g. in memory or
+//! in a remote object store, via a [`storage::Storage`] backend given to
+//! [`RunOptions::storage`].
+//!
+//! ## Snapshot Scripts
+//!
+//! For systems whose natural input is a single document, e.g. a SQL file or
+//! a config file, [`run_snapshot()`] passes a script's entire input to
+//! [`Runner::run_raw`] instead of parsing it as commands, and records the
+//! output after a `---` separator line. See [`generate_snapshot()`] for the
+//! format.
+//!
+//! ## Insta Snapshots
+//!
+//! With the `insta` crate feature, outputs can instead be recorded as
+//! [`insta`](https://docs.rs/insta/latest/insta/) snapshots and reviewed with
+//! `cargo insta review`. See the [`insta`](crate::insta) module for details.
+//!
 //! ## Hooks
 //!
 //! Runners have various hooks that will be called during script execution:
 //! [`Runner::start_script`], [`Runner::end_script`], [`Runner::start_block`],
 //! [`Runner::end_block`], [`Runner::start_command`], and
 //! [`Runner::end_command`]. These can be used e.g. for initial setup, invariant
-//! assertions, or to output the current state.
+//! assertions, or to output the current state. The block hooks are given a
+//! [`BlockInfo`] with the block's line number, tags, and commands.
+//!
+//! Each hook is also given the script's [`RunContext`], which provides e.g.
+//! the script's front matter metadata via [`RunContext::metadata`], and all
+//! command prefixes seen in the script via [`RunContext::prefixes`], e.g. to
+//! verify in [`Runner::end_script`] that all simulated clients or nodes were
+//! properly shut down.
+//!
+//! [`Runner::check_invariants`] is called after each command's
+//! [`Runner::end_command`] hook. Either can return an [`InvariantViolation`],
+//! which is rendered below the command's output with a clear marker and an
+//! optional diff of the expected and actual state, rather than aborting the
+//! script.
+//!
+//! [`Runner::process_output`] is called with each command's output, and can be
+//! used to centrally redact nondeterministic output such as timestamps or
+//! temporary paths.
+//!
+//! ## Async Runners
+//!
+//! With the `tokio` crate feature, async systems can be tested by implementing
+//! [`AsyncRunner`] and running scripts via [`run_async()`].
+//!
+//! ## Structured Output
+//!
+//! [`Runner::run_ctx`] and [`Runner::end_block`] return structured
+//! [`Output`](output::Output), which can be text or e.g. named sections or a
+//! table. goldenscript renders these in a consistent format, which keeps
+//! complex state dumps organized and diff-stable across runners.
 
 #![warn(clippy::all)]
+// Errors are constructed via io::Error::new(ErrorKind::Other, ...) throughout,
+// and doc examples show #[test] functions as they'd be written in a crate.
+#![allow(clippy::io_other_error, clippy::test_attr_in_doctest)]
 
+#[cfg(feature = "tokio")]
+mod async_runner;
+pub mod baseline;
+pub mod borrowed;
 mod command;
+pub mod conformance;
+mod context;
+pub mod datagen;
+#[cfg(feature = "serde")]
+mod de;
+pub mod diff;
+#[cfg(feature = "fixtures")]
+pub mod fixtures;
+pub mod grammar;
+pub mod incremental;
+#[cfg(feature = "insta")]
+pub mod insta;
+#[cfg(feature = "lsp")]
+pub mod lsp;
+#[cfg(feature = "manifest")]
+pub mod manifest;
+mod metadata;
+mod options;
+pub mod output;
 mod parser;
+mod resources;
 mod runner;
+pub mod schema;
+mod setup_cache;
+mod shared;
+pub mod stats;
+pub mod storage;
+pub mod util;
 
-pub use command::{Argument, ArgumentConsumer, Command};
-pub use runner::{generate, run, Runner};
+#[cfg(feature = "tokio")]
+pub use async_runner::{
+    generate_async, generate_async_with, run_async, run_async_with, AsyncRunner,
+};
+#[cfg(feature = "derive")]
+pub use command::FromCommand;
+pub use command::{Argument, ArgumentConsumer, BlockInfo, Command, ValueSource, ValueType};
+pub use context::{CancellationToken, Extensions, RunContext, VirtualClock};
+#[cfg(feature = "derive")]
+pub use goldenscript_derive::FromCommand;
+pub use metadata::ScriptMetadata;
+#[cfg(feature = "regex")]
+pub use options::Scope;
+pub use options::{
+    BinaryOutput, Bom, BudgetBreach, ControlChars, FinalNewline, InvalidUtf8, RunOptions,
+    ScratchDir,
+};
+pub use parser::parse_borrowed;
+pub use runner::{
+    check_commands, check_commands_with, generate, generate_snapshot, generate_with, run,
+    run_default, run_dir, run_dir_with, run_fn, run_snapshot, run_snapshot_with, run_str,
+    run_str_with, run_suite, run_suite_with, run_with, Capability, CommandRegistry, FnRunner,
+    InvariantViolation, Notice, PrefixRouter, Reporter, ReviewPolicy, Runner, RunnerRegistry,
+    Spawned, UnknownCommand, UpdateTarget, Validator,
+};
+pub use shared::SharedFixture;
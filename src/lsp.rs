@@ -0,0 +1,471 @@
+//! Editor support for goldenscripts, following the Language Server Protocol
+//! (LSP). Requires the `lsp` feature.
+//!
+//! A [`Document`] parses a script and provides diagnostics, block navigation,
+//! folding ranges, and semantic tokens for it. Positions use the LSP
+//! conventions, i.e. 0-based lines and UTF-16 character offsets, such that they
+//! map directly onto the protocol types. The protocol transport itself is left
+//! to the language server, e.g. one built with the `lsp-server` or `tower-lsp`
+//! crates, which allows projects to include their own runner's commands in the
+//! diagnostics:
+//!
+//! ```
+//! # use goldenscript::lsp::{Document, Position};
+//! let document = Document::new("put foo=bar\n---\nok\n\nget foo\n---\nbar\n");
+//! assert!(document.diagnostics(Some(&["get", "put"])).is_empty());
+//!
+//! let block = document.block_at(Position { line: 5, character: 0 }).unwrap();
+//! assert_eq!(block.name, "get foo");
+//! assert_eq!(block.range.start, Position { line: 4, character: 0 });
+//! ```
+
+use crate::borrowed;
+use crate::parser::{format_error, parse};
+use crate::runner::DIRECTIVES;
+
+use std::ops::Range as ByteRange;
+
+/// A parsed goldenscript document.
+pub struct Document<'a> {
+    /// The document text.
+    text: &'a str,
+    /// The byte offsets of the start of each line.
+    line_starts: Vec<usize>,
+    /// The parsed blocks, or a parse error diagnostic.
+    blocks: Result<Vec<borrowed::Block<'a>>, Diagnostic>,
+}
+
+/// A position in a document, as a 0-based line and UTF-16 character offset.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub line: u32,
+    pub character: u32,
+}
+
+/// A range in a document, with an exclusive end position.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// A diagnostic severity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The script can't be parsed or run.
+    Error,
+    /// The script can be parsed, but likely fails when run.
+    Warning,
+}
+
+/// A document diagnostic, e.g. a parse error or unknown command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub range: Range,
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// A block in a document, e.g. for document symbols or go-to-block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    /// The block name, i.e. its first command line.
+    pub name: String,
+    /// The range of the entire block, including its output but excluding the
+    /// trailing blank line.
+    pub range: Range,
+    /// The range of the block's first command name.
+    pub selection_range: Range,
+}
+
+/// A foldable line range, inclusive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FoldingRange {
+    pub start_line: u32,
+    pub end_line: u32,
+}
+
+/// A semantic token type. The token legend is given by
+/// [`SemanticTokenType::LEGEND`], using standard LSP token type names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SemanticTokenType {
+    /// A # or // comment.
+    Comment,
+    /// A % directive name, including the %.
+    Directive,
+    /// A command name.
+    Command,
+    /// A command prefix.
+    Prefix,
+    /// A command tag.
+    Tag,
+    /// An argument key.
+    Key,
+    /// An argument value.
+    Value,
+    /// The --- separator.
+    Separator,
+}
+
+impl SemanticTokenType {
+    /// The token type legend, in index order.
+    pub const LEGEND: [SemanticTokenType; 8] = [
+        Self::Comment,
+        Self::Directive,
+        Self::Command,
+        Self::Prefix,
+        Self::Tag,
+        Self::Key,
+        Self::Value,
+        Self::Separator,
+    ];
+
+    /// Returns the token type's index in the legend.
+    pub fn index(self) -> u32 {
+        Self::LEGEND.iter().position(|t| *t == self).unwrap() as u32
+    }
+
+    /// Returns the standard LSP token type name.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Comment => "comment",
+            Self::Directive => "macro",
+            Self::Command => "function",
+            Self::Prefix => "namespace",
+            Self::Tag => "decorator",
+            Self::Key => "parameter",
+            Self::Value => "string",
+            Self::Separator => "operator",
+        }
+    }
+}
+
+/// A semantic token. Tokens never span multiple lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub line: u32,
+    /// The UTF-16 character offset of the token start.
+    pub start: u32,
+    /// The UTF-16 length of the token.
+    pub length: u32,
+    pub token_type: SemanticTokenType,
+}
+
+impl<'a> Document<'a> {
+    /// Parses a document. Parse errors are returned via
+    /// [`Document::diagnostics`].
+    pub fn new(text: &'a str) -> Self {
+        let line_starts =
+            std::iter::once(0).chain(text.match_indices('\n').map(|(i, _)| i + 1)).collect();
+        let mut document = Self { text, line_starts, blocks: Ok(Vec::new()) };
+        document.blocks = parse(text).map_err(|e| {
+            let start = e.input.location_offset();
+            let end = start + e.input.find(['\r', '\n']).unwrap_or(e.input.len());
+            let message = format_error(e).to_string();
+            Diagnostic {
+                range: document.range(start..end),
+                severity: Severity::Error,
+                message: message.lines().next().unwrap_or_default().to_string(),
+            }
+        });
+        document
+    }
+
+    /// Returns diagnostics for the document. If the document fails to parse,
+    /// this is the parse error. Otherwise, if known commands are given (e.g.
+    /// from [`Runner::known_commands`](crate::Runner::known_commands)), unknown
+    /// commands are reported as warnings, as are unknown directives.
+    pub fn diagnostics(&self, known_commands: Option<&[&str]>) -> Vec<Diagnostic> {
+        let blocks = match &self.blocks {
+            Ok(blocks) => blocks,
+            Err(diagnostic) => return vec![diagnostic.clone()],
+        };
+        let mut diagnostics = Vec::new();
+        for command in blocks.iter().flat_map(|b| &b.commands) {
+            let name = command.name.as_ref();
+            let message = match command.directive {
+                true if !DIRECTIVES.contains(&name) => format!("unknown directive %{name}"),
+                false if known_commands.is_some_and(|k| !k.contains(&name)) => {
+                    format!("unknown command '{name}'")
+                }
+                _ => continue,
+            };
+            diagnostics.push(Diagnostic {
+                range: self.range(command.name_span.clone()),
+                severity: Severity::Warning,
+                message,
+            })
+        }
+        diagnostics
+    }
+
+    /// Returns the document's blocks. Empty if the document fails to parse.
+    pub fn blocks(&self) -> Vec<Block> {
+        self.parsed_blocks()
+            .iter()
+            .filter(|b| !b.commands.is_empty())
+            .map(|b| self.block(b))
+            .collect()
+    }
+
+    /// Returns the block containing the given position, if any. This includes
+    /// the block's output, so it can be used to go from the output to the
+    /// commands that produced it.
+    pub fn block_at(&self, position: Position) -> Option<Block> {
+        let offset = self.offset(position);
+        self.parsed_blocks()
+            .iter()
+            .filter(|b| !b.commands.is_empty())
+            .find(|b| b.span.start <= offset && offset < b.span.end)
+            .map(|b| self.block(b))
+    }
+
+    /// Returns folding ranges for multi-line blocks.
+    pub fn folding_ranges(&self) -> Vec<FoldingRange> {
+        self.blocks()
+            .into_iter()
+            .map(|b| FoldingRange { start_line: b.range.start.line, end_line: b.range.end.line })
+            .filter(|r| r.end_line > r.start_line)
+            .collect()
+    }
+
+    /// Returns the document's semantic tokens, in position order. Tokens that
+    /// span multiple lines (e.g. multi-line strings) are split into one token
+    /// per line. Empty if the document fails to parse.
+    pub fn semantic_tokens(&self) -> Vec<SemanticToken> {
+        use SemanticTokenType::*;
+
+        let mut spans = Vec::new();
+        for block in self.parsed_blocks() {
+            spans.extend(block.comment_spans.iter().map(|s| (s.clone(), Comment)));
+            spans.extend(block.separator_span.clone().map(|s| (s, Separator)));
+            spans.extend(block.tag_spans.iter().map(|s| (s.clone(), Tag)));
+            for command in &block.commands {
+                match command.directive {
+                    true => {
+                        spans.push((command.name_span.start - 1..command.name_span.end, Directive))
+                    }
+                    false => spans.push((command.name_span.clone(), Command)),
+                }
+                spans.extend(command.prefix_span.clone().map(|s| (s, Prefix)));
+                spans.extend(command.tag_spans.iter().map(|s| (s.clone(), Tag)));
+                for arg in &command.args {
+                    spans.extend(arg.key_span.clone().map(|s| (s, Key)));
+                    spans.push((arg.value_span.clone(), Value));
+                }
+            }
+        }
+        spans.sort_by_key(|(span, _)| span.start);
+
+        let mut tokens = Vec::new();
+        for (span, token_type) in spans {
+            let mut start = span.start;
+            for line in self.text[span].split_inclusive('\n') {
+                let content = line.trim_end_matches(['\r', '\n']);
+                if !content.is_empty() {
+                    let position = self.position(start);
+                    let length = content.encode_utf16().count() as u32;
+                    tokens.push(SemanticToken {
+                        line: position.line,
+                        start: position.character,
+                        length,
+                        token_type,
+                    });
+                }
+                start += line.len();
+            }
+        }
+        tokens
+    }
+
+    /// Returns the parsed blocks, or an empty slice on parse errors.
+    fn parsed_blocks(&self) -> &[borrowed::Block<'a>] {
+        self.blocks.as_deref().unwrap_or_default()
+    }
+
+    /// Converts a parsed block to a document block.
+    fn block(&self, block: &borrowed::Block) -> Block {
+        // The first command is the block name, up to the end of its line.
+        let first = &block.commands[0];
+        let line = self.position(first.name_span.start).line as usize;
+        let line_end = self.line_starts.get(line + 1).copied().unwrap_or(self.text.len());
+        let mut name = self.text[self.line_starts[line]..line_end].trim();
+        if let Some((command, _)) = name.split_once(" #").or_else(|| name.split_once(" //")) {
+            name = command.trim_end();
+        }
+
+        // The block ends after the output, or the separator if it's empty.
+        let end = match &block.separator_span {
+            Some(separator) if block.output_span.is_empty() => separator.end,
+            Some(_) => block.output_span.end,
+            None => block.span.end,
+        };
+        Block {
+            name: name.to_string(),
+            range: self.range(block.span.start..end),
+            selection_range: self.range(first.name_span.clone()),
+        }
+    }
+
+    /// Converts a byte offset to a position.
+    fn position(&self, offset: usize) -> Position {
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        let character = self.text[line_start..offset].encode_utf16().count();
+        Position { line: line as u32, character: character as u32 }
+    }
+
+    /// Converts a byte range to a range.
+    fn range(&self, range: ByteRange<usize>) -> Range {
+        Range { start: self.position(range.start), end: self.position(range.end) }
+    }
+
+    /// Converts a position to a byte offset, clamped to the line and text.
+    fn offset(&self, position: Position) -> usize {
+        let Some(line_start) = self.line_starts.get(position.line as usize).copied() else {
+            return self.text.len();
+        };
+        let line = self.text[line_start..].split_inclusive('\n').next().unwrap_or_default();
+        let mut character = 0;
+        for (i, c) in line.char_indices() {
+            if character >= position.character as usize || c == '\n' {
+                return line_start + i;
+            }
+            character += c.len_utf16();
+        }
+        line_start + line.len()
+    }
+}
+
+/// Encodes semantic tokens using the LSP relative encoding, i.e. five integers
+/// per token: the line delta, start character delta (relative to the previous
+/// token if on the same line), length, token type index, and modifiers (none).
+/// Tokens must be in position order.
+pub fn encode_semantic_tokens(tokens: &[SemanticToken]) -> Vec<u32> {
+    let mut data = Vec::with_capacity(tokens.len() * 5);
+    let (mut line, mut start) = (0, 0);
+    for token in tokens {
+        if token.line != line {
+            start = 0;
+        }
+        data.extend([
+            token.line - line,
+            token.start - start,
+            token.length,
+            token.token_type.index(),
+            0,
+        ]);
+        (line, start) = (token.line, token.start);
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SemanticTokenType::*;
+    use super::*;
+
+    /// Shorthand for constructing a range.
+    fn range(start: (u32, u32), end: (u32, u32)) -> Range {
+        Range {
+            start: Position { line: start.0, character: start.1 },
+            end: Position { line: end.0, character: end.1 },
+        }
+    }
+
+    /// Tests diagnostics for parse errors and unknown commands.
+    #[test]
+    fn diagnostics() {
+        let document = Document::new("a\n---\nok\n\nb x\n---\n\n%foo\n---\n");
+        assert_eq!(
+            document.diagnostics(None),
+            vec![Diagnostic {
+                range: range((7, 1), (7, 4)),
+                severity: Severity::Warning,
+                message: "unknown directive %foo".to_string(),
+            }]
+        );
+        let diagnostics = document.diagnostics(Some(&["a"]));
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].range, range((4, 0), (4, 1)));
+        assert_eq!(diagnostics[0].message, "unknown command 'b'");
+
+        let document = Document::new("a\n---\nok\n\nb 'x\n---\n");
+        let diagnostics = document.diagnostics(None);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+        assert_eq!(diagnostics[0].range, range((4, 2), (4, 4)));
+        assert!(diagnostics[0].message.starts_with("parse error at line 5 column 3"));
+        assert!(document.blocks().is_empty());
+        assert!(document.semantic_tokens().is_empty());
+    }
+
+    /// Tests blocks, block lookup, and folding ranges.
+    #[test]
+    fn blocks() {
+        let text = "# comment\na # trailing\nb\n---\nok\n\n\n(c '😀')\n---\n\n# end\n";
+        let document = Document::new(text);
+        let blocks = document.blocks();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].name, "a");
+        assert_eq!(blocks[0].range, range((0, 0), (4, 2)));
+        assert_eq!(blocks[0].selection_range, range((1, 0), (1, 1)));
+        assert_eq!(blocks[1].name, "(c '😀')");
+        assert_eq!(blocks[1].range, range((6, 0), (8, 3)));
+
+        let at = |line, character| document.block_at(Position { line, character });
+        assert_eq!(at(4, 1).map(|b| b.name), Some("a".to_string()));
+        assert_eq!(at(7, 10).map(|b| b.name), Some("(c '😀')".to_string()));
+        assert_eq!(at(10, 0), None);
+        assert_eq!(at(100, 0), None);
+
+        assert_eq!(
+            document.folding_ranges(),
+            vec![
+                FoldingRange { start_line: 0, end_line: 4 },
+                FoldingRange { start_line: 6, end_line: 8 },
+            ]
+        );
+    }
+
+    /// Tests semantic tokens, including UTF-16 offsets and multi-line tokens.
+    #[test]
+    fn semantic_tokens() {
+        let text = "%seed 1\np: [t] '😀' k='v' <<EOF # c\nx\ny\nEOF\n---\nok\n";
+        let tokens: Vec<_> = Document::new(text)
+            .semantic_tokens()
+            .into_iter()
+            .map(|t| (t.line, t.start, t.length, t.token_type))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (0, 0, 5, Directive),
+                (0, 6, 1, Value),
+                (1, 0, 1, Prefix),
+                (1, 4, 1, Tag),
+                (1, 7, 4, Command),
+                (1, 12, 1, Key),
+                (1, 14, 3, Value),
+                (1, 24, 3, Comment),
+                (2, 0, 1, Value),
+                (3, 0, 1, Value),
+                (5, 0, 3, Separator),
+            ]
+        );
+
+        // Block tags.
+        let tokens: Vec<_> = Document::new("[b k=v]\na\n---\n")
+            .semantic_tokens()
+            .into_iter()
+            .map(|t| (t.line, t.start, t.length, t.token_type))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![(0, 1, 1, Tag), (0, 3, 3, Tag), (1, 0, 1, Command), (2, 0, 3, Separator)]
+        );
+
+        let encoded = encode_semantic_tokens(&Document::new("a b\n---\n").semantic_tokens());
+        assert_eq!(encoded, vec![0, 0, 1, 2, 0, 0, 2, 1, 6, 0, 1, 0, 3, 7, 0]);
+    }
+}
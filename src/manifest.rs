@@ -0,0 +1,169 @@
+//! Suite manifests, declaring per-suite defaults alongside the scripts.
+//! Requires the `manifest` crate feature.
+//!
+//! A scripts directory can contain a `goldenscripts.toml` manifest, which is
+//! loaded by [`run_dir()`](crate::run_dir) and
+//! [`run_dir_with()`](crate::run_dir_with). This keeps per-suite configuration
+//! with the scripts rather than in Rust code. For example:
+//!
+//! ```toml
+//! # The runner for scripts without a %runner directive.
+//! runner = "kv"
+//! # Hard-wraps output lines longer than 100 characters.
+//! wrap = 100
+//! # The timeout for commands without a [timeout] tag.
+//! command_timeout = "5s"
+//! # Scripts that are expected to fail, relative to the directory.
+//! expect_fail = ["broken/script"]
+//!
+//! # Output normalizers, applied in order.
+//! [[normalize]]
+//! regex = '\d{4}-\d{2}-\d{2}'
+//! replacement = "<date>"
+//! ```
+//!
+//! Manifest settings are defaults: options given via [`RunOptions`] take
+//! precedence, while expected failures and normalizers are added to any given
+//! via [`RunOptions`]. Unknown keys are rejected. The manifest file is not
+//! considered a script.
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{util, RunOptions};
+
+/// The manifest file name.
+pub const MANIFEST_FILE: &str = util::MANIFEST_FILE;
+
+/// A suite manifest, see the [module documentation](self).
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    /// The runner for scripts without a %runner directive.
+    runner: Option<String>,
+    /// The output wrap width.
+    wrap: Option<usize>,
+    /// The default command timeout.
+    command_timeout: Option<Duration>,
+    /// Scripts that are expected to fail.
+    expect_fail: Vec<PathBuf>,
+    /// Output normalizers, as regexes and replacements.
+    normalize: Vec<(regex::Regex, String)>,
+}
+
+impl Manifest {
+    /// Parses a manifest from a TOML string.
+    pub fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        let table: toml::Table = s.parse()?;
+        let mut manifest = Self::default();
+        for (key, value) in table {
+            let invalid = || format!("invalid {key}: {value}");
+            match key.as_str() {
+                "runner" => manifest.runner = Some(value.as_str().ok_or_else(invalid)?.into()),
+                "wrap" => {
+                    let wrap = value.as_integer().ok_or_else(invalid)?;
+                    manifest.wrap = Some(wrap.try_into().map_err(|_| invalid())?);
+                }
+                "command_timeout" => {
+                    let timeout = value.as_str().ok_or_else(invalid)?;
+                    manifest.command_timeout = Some(util::parse_duration(timeout)?);
+                }
+                "expect_fail" => {
+                    for path in value.as_array().ok_or_else(invalid)? {
+                        manifest.expect_fail.push(path.as_str().ok_or_else(invalid)?.into());
+                    }
+                }
+                "normalize" => {
+                    for normalizer in value.as_array().ok_or_else(invalid)? {
+                        let normalizer = normalizer.as_table().ok_or_else(invalid)?;
+                        let field = |name| normalizer.get(name).and_then(|v| v.as_str());
+                        let regex = field("regex").ok_or("normalize: regex not given")?;
+                        let replacement = field("replacement").unwrap_or_default();
+                        if let Some(key) = normalizer
+                            .keys()
+                            .find(|k| !["regex", "replacement"].contains(&k.as_str()))
+                        {
+                            return Err(format!("normalize: unknown key '{key}'").into());
+                        }
+                        manifest.normalize.push((regex::Regex::new(regex)?, replacement.into()));
+                    }
+                }
+                key => return Err(format!("unknown key '{key}'").into()),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Loads the manifest in the given directory, if any.
+    pub fn load(dir: impl AsRef<Path>) -> std::io::Result<Option<Self>> {
+        let path = dir.as_ref().join(MANIFEST_FILE);
+        let s = match std::fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Self::parse(&s).map(Some).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {e}", path.display()))
+        })
+    }
+
+    /// Returns the runner for scripts without a `%runner` directive, if any.
+    pub fn runner(&self) -> Option<&str> {
+        self.runner.as_deref()
+    }
+
+    /// Applies the manifest to the given options, as defaults.
+    pub fn apply(&self, mut options: RunOptions) -> RunOptions {
+        options.wrap = options.wrap.or(self.wrap.filter(|w| *w > 0));
+        options.command_timeout = options.command_timeout.or(self.command_timeout);
+        let mut options = options.expect_fail(&self.expect_fail);
+        for (regex, replacement) in &self.normalize {
+            options = options.normalize(regex.clone(), replacement);
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests manifest parsing and application.
+    #[test]
+    fn parse() {
+        let manifest = Manifest::parse(
+            r#"
+            runner = "kv"
+            wrap = 10
+            command_timeout = "5s"
+            expect_fail = ["broken"]
+
+            [[normalize]]
+            regex = '\d+'
+            replacement = "N"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(manifest.runner(), Some("kv"));
+
+        // Options given in code take precedence.
+        let options = manifest.apply(RunOptions::new().wrap(20));
+        assert_eq!(options.wrap, Some(20));
+        assert_eq!(options.command_timeout, Some(Duration::from_secs(5)));
+        assert!(options.expects_failure(Path::new("dir/broken")));
+        assert_eq!(options.normalizers.len(), 1);
+        assert_eq!(manifest.apply(RunOptions::new()).wrap, Some(10));
+
+        // Invalid manifests error.
+        for (input, error) in [
+            ("separator = '==='", "unknown key 'separator'"),
+            ("wrap = 'wide'", "invalid wrap: \"wide\""),
+            ("command_timeout = '5'", "invalid duration '5'"),
+            ("expect_fail = [1]", "invalid expect_fail: [1]"),
+            ("[[normalize]]\nreplacement = 'x'", "normalize: regex not given"),
+            ("[[normalize]]\nregex = 'x'\nfoo = 1", "normalize: unknown key 'foo'"),
+        ] {
+            assert_eq!(Manifest::parse(input).unwrap_err().to_string(), error, "{input}");
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+/// Script metadata, given as front matter at the start of the script. It's
+/// available via [`RunContext::metadata`](crate::RunContext::metadata), e.g.
+/// in [`Runner::start_script`](crate::Runner::start_script).
+///
+/// Front matter is delimited by `---` lines, and contains `key: value` lines.
+/// Blank lines and `#` comments are ignored, and keys must be unique. Values
+/// are trimmed, and otherwise uninterpreted. For example:
+///
+/// ```text
+/// ---
+/// engine: memory
+/// # Seed for the random number generator.
+/// seed: 42
+/// ---
+///
+/// put foo=bar
+/// ---
+/// ok
+/// ```
+///
+/// The front matter is retained verbatim in the output.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScriptMetadata(BTreeMap<String, String>);
+
+impl ScriptMetadata {
+    /// Parses metadata from the front matter at the start of the given script,
+    /// if any. The front matter has already been validated by the parser, but
+    /// the lines haven't.
+    pub(crate) fn parse(input: &str) -> std::io::Result<Self> {
+        let mut metadata = Self::default();
+        let mut lines = input.lines().enumerate();
+        if lines.next().map(|(_, line)| line) != Some("---") {
+            return Ok(metadata);
+        }
+        for (i, line) in lines {
+            if line == "---" {
+                break;
+            }
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error =
+                |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+            let Some((key, value)) = line.split_once(':').filter(|(k, _)| !k.trim().is_empty())
+            else {
+                let line_number = i + 1;
+                return Err(error(format!(
+                    "invalid front matter at line {line_number}: expected 'key: value'"
+                )));
+            };
+            let key = key.trim();
+            if metadata.0.insert(key.to_string(), value.trim().to_string()).is_some() {
+                let line_number = i + 1;
+                return Err(error(format!(
+                    "duplicate front matter key '{key}' at line {line_number}"
+                )));
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// Returns the value of the given key, if any.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(|v| v.as_str())
+    }
+
+    /// Parses the value of the given key as a T using core::str::parse(), if
+    /// any. Like [`Argument::parse`](crate::Argument::parse), returns an
+    /// improved error message as a boxed error.
+    pub fn parse_value<T>(&self, key: &str) -> Result<Option<T>, Box<dyn Error>>
+    where
+        T: std::str::FromStr,
+        <T as std::str::FromStr>::Err: std::fmt::Display,
+    {
+        let Some(value) = self.get(key) else { return Ok(None) };
+        value.parse().map(Some).map_err(|e| format!("invalid {key} '{value}': {e}").into())
+    }
+
+    /// Iterates over the keys and values, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Returns true if there is no metadata.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
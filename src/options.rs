@@ -0,0 +1,846 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::resources::ResourcePool;
+use crate::setup_cache::SetupCache;
+use crate::shared::AnySharedFixture;
+use crate::storage::{FileStorage, Storage};
+use crate::{CancellationToken, Notice, Reporter, ReviewPolicy, SharedFixture, Validator};
+
+/// Options for running goldenscripts, used with [`run_with()`](crate::run_with)
+/// and [`generate_with()`](crate::generate_with). Configured via builder
+/// methods, e.g.:
+///
+/// ```no_run
+/// # struct Runner;
+/// # use goldenscript::Command;
+/// # use std::error::Error;
+/// # impl goldenscript::Runner for Runner {
+/// #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+/// # }
+/// let options = goldenscript::RunOptions::new().wrap(100);
+/// goldenscript::run_with(&mut Runner, "tests/scripts/test", &options)
+/// # .unwrap()
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Hard-wraps command output lines longer than this width.
+    pub(crate) wrap: Option<usize>,
+    /// The policy for control characters in output.
+    pub(crate) control_chars: ControlChars,
+    /// The policy for binary command output.
+    pub(crate) binary_output: BinaryOutput,
+    /// The maximum size of a command's output, in bytes.
+    pub(crate) max_output_size: Option<usize>,
+    /// The cancellation token for the run.
+    pub(crate) cancellation_token: CancellationToken,
+    /// Whether to cancel the run on SIGINT or SIGTERM.
+    #[cfg(feature = "signals")]
+    pub(crate) cancel_on_signal: bool,
+    /// The policy for commands exceeding their [budget] tag.
+    pub(crate) budget_breach: BudgetBreach,
+    /// The fraction by which commands may exceed their [budget] tag.
+    pub(crate) budget_tolerance: f64,
+    /// The default timeout for commands without a [timeout] tag.
+    pub(crate) command_timeout: Option<Duration>,
+    /// The fixture store to resolve fixture references from.
+    #[cfg(feature = "fixtures")]
+    pub(crate) fixtures: Option<crate::fixtures::FixtureStore>,
+    /// The command schema to validate commands against.
+    pub(crate) schema: Option<crate::schema::Schema>,
+    /// Output normalizers, applied in order.
+    #[cfg(feature = "regex")]
+    pub(crate) normalizers: Vec<Normalizer>,
+    /// Block validators, run in order.
+    pub(crate) validators: Vec<SharedValidator>,
+    /// The review policy for golden output updates, if any.
+    pub(crate) review_policy: Option<SharedReviewPolicy>,
+    /// The reporter for notices about the run, if any.
+    pub(crate) reporter: Option<SharedReporter>,
+    /// Script paths that are expected to fail.
+    pub(crate) expect_fail: Vec<PathBuf>,
+    /// Whether to drop the output of block and command hooks.
+    pub(crate) suppress_hook_output: bool,
+    /// The policy for per-script scratch directories.
+    pub(crate) scratch_dir: ScratchDir,
+    /// The directory to write execution traces of failed scripts to.
+    pub(crate) trace_dir: Option<PathBuf>,
+    /// The policy for final newlines in generated files.
+    pub(crate) final_newline: FinalNewline,
+    /// The policy for byte order marks in script files.
+    pub(crate) bom: Bom,
+    /// The policy for invalid UTF-8 in script files.
+    pub(crate) invalid_utf8: InvalidUtf8,
+    /// The golden file storage.
+    pub(crate) storage: SharedStorage,
+    /// Shared fixtures, available to runners via the run context.
+    pub(crate) shared_fixtures: Vec<AnySharedFixture>,
+    /// The cache of %setup sections, if enabled.
+    pub(crate) setup_cache: Option<SetupCache>,
+    /// Whether to enable the optional built-in commands.
+    pub(crate) builtin_commands: bool,
+    /// Whether to pin %seed auto directives to the chosen seed in the output.
+    pub(crate) pin_auto_seeds: bool,
+    /// The initial random seed of scripts.
+    pub(crate) seed: Option<u64>,
+    /// Limits for resources declared via %resources, by name.
+    pub(crate) resource_limits: BTreeMap<String, u64>,
+    /// The resources reserved by running scripts, shared by clones.
+    pub(crate) resource_pool: ResourcePool,
+    /// If given, only run commands with these names.
+    pub(crate) only_commands: Option<BTreeSet<String>>,
+    /// Skip commands with any of these tags.
+    pub(crate) skip_tags: BTreeSet<String>,
+    /// Enabled conditions for [cfg:NAME] tags.
+    pub(crate) cfgs: BTreeSet<String>,
+}
+
+impl RunOptions {
+    /// Creates a new set of default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hard-wraps command output lines longer than the given width (in
+    /// characters), splitting them into chunks of the given width where all
+    /// but the last chunk end with a `\` continuation marker. This keeps
+    /// golden files with very long output lines reviewable. Can be overridden
+    /// for individual commands with a `[wrap=WIDTH]` tag, where 0 disables
+    /// wrapping.
+    pub fn wrap(mut self, width: usize) -> Self {
+        self.wrap = Some(width).filter(|w| *w > 0);
+        self
+    }
+
+    /// Sets the policy for raw control characters in command and block hook
+    /// output, other than newlines and tabs (and carriage returns in \r\n line
+    /// endings). Such characters can break text tooling and diffs of golden
+    /// files. Defaults to [`ControlChars::Allow`].
+    ///
+    /// Output is always valid UTF-8, since it is given as a Rust [`String`].
+    pub fn control_chars(mut self, policy: ControlChars) -> Self {
+        self.control_chars = policy;
+        self
+    }
+
+    /// Sets the policy for command output that looks like binary data, as
+    /// determined by [`output::is_binary`](crate::output::is_binary). This
+    /// typically happens when a runner accidentally returns raw bytes as a
+    /// string, which can corrupt golden files. Defaults to
+    /// [`BinaryOutput::Allow`].
+    pub fn binary_output(mut self, policy: BinaryOutput) -> Self {
+        self.binary_output = policy;
+        self
+    }
+
+    /// Errors if a command's output exceeds the given size in bytes, e.g. to
+    /// detect runaway output. Unlimited by default.
+    pub fn max_output_size(mut self, bytes: usize) -> Self {
+        self.max_output_size = Some(bytes);
+        self
+    }
+
+    /// Sets a cancellation token for the run, e.g. one that is cancelled by a
+    /// ctrl-c handler or on test suite shutdown. Each script gets a child token
+    /// via [`RunContext::cancellation_token`](crate::RunContext::cancellation_token),
+    /// which runners can check to abort long-running commands. Once cancelled,
+    /// scripts run any pending `%teardown` section and call the
+    /// [`Runner::end_script`](crate::Runner::end_script) hook to clean up, and
+    /// fail before running further commands, reporting the number of completed
+    /// blocks. [`run_dir_with()`](crate::run_dir_with) and
+    /// [`run_suite_with()`](crate::run_suite_with) don't start further scripts,
+    /// and report the scripts that weren't completed to the
+    /// [`RunOptions::reporter`], if any, as
+    /// [`Notice::ScriptNotCompleted`](crate::Notice::ScriptNotCompleted).
+    ///
+    /// For example, a test harness can cancel the token from a SIGINT handler
+    /// to shut down gracefully when interrupted, see
+    /// [`RunOptions::cancel_on_signal`].
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = token;
+        self
+    }
+
+    /// Cancels the run's cancellation token on SIGINT or SIGTERM while
+    /// running a directory or suite via [`run_dir_with()`](crate::run_dir_with)
+    /// or [`run_suite_with()`](crate::run_suite_with), shutting down gracefully
+    /// as described in [`RunOptions::cancellation_token`]. A second signal
+    /// terminates the process immediately. Requires the `signals` crate
+    /// feature.
+    #[cfg(feature = "signals")]
+    pub fn cancel_on_signal(mut self, enabled: bool) -> Self {
+        self.cancel_on_signal = enabled;
+        self
+    }
+
+    /// Sets the policy for commands that take longer than their duration
+    /// budget, given as a `[budget=DURATION]` tag (e.g. `[budget=50ms]`). This
+    /// allows lightweight performance regression testing in existing scripts.
+    /// The duration includes the command's start_command() and end_command()
+    /// hooks, and commands with a budget are never batched. Defaults to
+    /// [`BudgetBreach::Error`].
+    pub fn budget_breach(mut self, policy: BudgetBreach) -> Self {
+        self.budget_breach = policy;
+        self
+    }
+
+    /// Allows commands to exceed their `[budget=DURATION]` tag by the given
+    /// fraction of the budget, e.g. 0.5 for 50%, to reduce flakiness from
+    /// timing noise. Negative values are treated as 0. Defaults to 0.
+    pub fn budget_tolerance(mut self, tolerance: f64) -> Self {
+        self.budget_tolerance = tolerance.max(0.0);
+        self
+    }
+
+    /// Sets a timeout for each command, which fails the script if a command
+    /// takes longer. Individual commands can set their own timeout via a
+    /// `[timeout=DURATION]` tag (e.g. `[timeout=5s]`), overriding this one.
+    /// The duration includes the command's start_command() and end_command()
+    /// hooks. Commands with a `[timeout]` tag are never batched, while batches
+    /// have the combined timeout of their commands.
+    ///
+    /// Commands can't be interrupted, so the script's
+    /// [`RunContext::cancellation_token`](crate::RunContext::cancellation_token)
+    /// is cancelled when the timeout expires, and the script fails with a
+    /// [`TimedOut`](std::io::ErrorKind::TimedOut) error once the command
+    /// returns. Runners should check the token in long-running commands. With
+    /// a [`RunOptions::reporter`], a single watchdog thread per script reports
+    /// commands that exceed their timeout, and again if they still haven't
+    /// returned after a grace period of the same duration, as
+    /// [`Notice::CommandTimedOut`](crate::Notice::CommandTimedOut).
+    pub fn command_timeout(mut self, timeout: Duration) -> Self {
+        self.command_timeout = Some(timeout);
+        self
+    }
+
+    /// Resolves argument values that are fixture references (e.g.
+    /// `payload=@sha256:2c26b4…`) from the given fixture store, replacing them
+    /// with the fixture content before running the script. Errors if a fixture
+    /// doesn't exist or isn't valid UTF-8. See the [`fixtures`](crate::fixtures)
+    /// module. Requires the `fixtures` crate feature.
+    #[cfg(feature = "fixtures")]
+    pub fn fixtures(mut self, store: crate::fixtures::FixtureStore) -> Self {
+        self.fixtures = Some(store);
+        self
+    }
+
+    /// Validates all commands against the given schema before running the
+    /// script, erroring with all invalid commands and their line numbers. See
+    /// the [`schema`](crate::schema) module.
+    pub fn schema(mut self, schema: crate::schema::Schema) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Adds a validator, which is given each block's input and output after
+    /// it has run, failing the script on errors. Validators run in the order
+    /// they were added, and are shared between clones of the options.
+    ///
+    /// ```
+    /// # use std::error::Error;
+    /// struct NoErrors;
+    ///
+    /// impl goldenscript::Validator for NoErrors {
+    ///     fn validate_block(&mut self, _: &str, output: &str) -> Result<(), Box<dyn Error>> {
+    ///         match output.contains("Error:") {
+    ///             true => Err("unexpected error".into()),
+    ///             false => Ok(()),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let options = goldenscript::RunOptions::new().validator(NoErrors);
+    /// ```
+    pub fn validator(mut self, validator: impl Validator + Send + 'static) -> Self {
+        self.validators.push(SharedValidator(Arc::new(Mutex::new(validator))));
+        self
+    }
+
+    /// Sets a review policy, which is given each block's old and new output
+    /// when recording goldenfiles via `UPDATE_GOLDENFILES=1`, and can reject
+    /// the update. Rejected blocks keep their old output, and are reported to
+    /// the [`RunOptions::reporter`], if any. The policy is shared between clones of the options.
+    ///
+    /// ```
+    /// struct KeepErrors;
+    ///
+    /// impl goldenscript::ReviewPolicy for KeepErrors {
+    ///     fn review_block(&mut self, _: &str, old: &str, new: &str) -> Result<(), String> {
+    ///         let errors = |s: &str| s.lines().filter(|l| l.starts_with("Error:")).count();
+    ///         match errors(old) == errors(new) {
+    ///             true => Ok(()),
+    ///             false => Err("error lines changed".to_string()),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let options = goldenscript::RunOptions::new().review_policy(KeepErrors);
+    /// ```
+    pub fn review_policy(mut self, policy: impl ReviewPolicy + Send + 'static) -> Self {
+        self.review_policy = Some(SharedReviewPolicy(Arc::new(Mutex::new(policy))));
+        self
+    }
+
+    /// Sets a reporter, which receives notices about the run that don't
+    /// affect its result, e.g. skipped blocks, replacing any existing
+    /// reporter. It's shared between clones of the options. Without a
+    /// reporter, notices are discarded.
+    ///
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let notices = Arc::new(Mutex::new(Vec::new()));
+    /// let options = goldenscript::RunOptions::new().reporter({
+    ///     let notices = notices.clone();
+    ///     move |notice: &goldenscript::Notice| notices.lock().unwrap().push(notice.clone())
+    /// });
+    /// ```
+    pub fn reporter(mut self, reporter: impl Reporter + Send + 'static) -> Self {
+        self.reporter = Some(SharedReporter(Arc::new(Mutex::new(reporter))));
+        self
+    }
+
+    /// Reports a notice to the reporter, if any.
+    pub(crate) fn report(&self, notice: Notice) {
+        if let Some(reporter) = &self.reporter {
+            reporter.report(&notice);
+        }
+    }
+
+    /// Marks the scripts at the given paths as expected to fail, e.g. known
+    /// broken scripts during an incremental migration. When run via
+    /// [`run_with()`](crate::run_with) or [`run_dir_with()`](crate::run_dir_with),
+    /// these report an expected failure to the [`reporter`](Self::reporter)
+    /// instead of failing, if they
+    /// error, panic, or their output differs. They are never updated with
+    /// `UPDATE_GOLDENFILES=1`, and error if they pass, so they can be removed
+    /// from the list once fixed. A path matches any script path it's a suffix
+    /// of, e.g. `scripts/foo` matches `tests/scripts/foo`.
+    ///
+    /// ```
+    /// let options = goldenscript::RunOptions::new()
+    ///     .expect_fail(["tests/scripts/old_format", "tests/scripts/pending"]);
+    /// ```
+    pub fn expect_fail(mut self, paths: impl IntoIterator<Item = impl AsRef<Path>>) -> Self {
+        self.expect_fail.extend(paths.into_iter().map(|path| path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Drops the output of the [`Runner::start_block`], [`Runner::end_block`],
+    /// [`Runner::start_command`], and [`Runner::end_command`] hooks, which are
+    /// still called. Useful for suites where hook output is verbose
+    /// diagnostics rather than part of the tested behavior, e.g. only enabling
+    /// it for explicit debug runs:
+    ///
+    /// ```
+    /// let debug = std::env::var("GOLDENSCRIPT_DEBUG").is_ok();
+    /// let options = goldenscript::RunOptions::new().suppress_hook_output(!debug);
+    /// ```
+    ///
+    /// [`Runner::start_block`]: crate::Runner::start_block
+    /// [`Runner::end_block`]: crate::Runner::end_block
+    /// [`Runner::start_command`]: crate::Runner::start_command
+    /// [`Runner::end_command`]: crate::Runner::end_command
+    pub fn suppress_hook_output(mut self, suppress: bool) -> Self {
+        self.suppress_hook_output = suppress;
+        self
+    }
+
+    /// Sets the policy for per-script scratch directories. If enabled, a new
+    /// temporary directory is created for each script, available to the
+    /// runner via [`RunContext::scratch_dir`](crate::RunContext::scratch_dir),
+    /// and removed after the script has run. Useful e.g. for storage engines
+    /// that need a data directory. Defaults to [`ScratchDir::None`].
+    pub fn scratch_dir(mut self, policy: ScratchDir) -> Self {
+        self.scratch_dir = policy;
+        self
+    }
+
+    /// Registers a shared fixture, e.g. a test cluster, which runners can fetch
+    /// via [`RunContext::shared_fixture`](crate::RunContext::shared_fixture).
+    /// It's set up on first use and torn down after the last script using it
+    /// completes, see [`SharedFixture`]. If multiple fixtures of the same type
+    /// are registered, the first one is used.
+    pub fn shared_fixture<T: Send + Sync + 'static>(mut self, fixture: &SharedFixture<T>) -> Self {
+        self.shared_fixtures.push(AnySharedFixture(Arc::new(fixture.clone())));
+        self
+    }
+
+    /// Caches the runner state after each `%setup` section, via
+    /// [`Runner::serialize_state`](crate::Runner::serialize_state), and
+    /// restores it via [`Runner::restore_state`](crate::Runner::restore_state)
+    /// instead of running identical `%setup` sections in later scripts. This
+    /// avoids repeating expensive setup across a suite. The cache is shared
+    /// between clones of the options, e.g. across
+    /// [`run_dir_with()`](crate::run_dir_with) calls.
+    ///
+    /// Sections are identical if they have the same text (including comments)
+    /// and random seed. The block hooks aren't called for a restored section,
+    /// and its output is taken from the cache. The [`RunContext`](crate::RunContext)
+    /// random number generator, virtual clock, and variables are also cached.
+    /// Sections containing other directives or background commands aren't
+    /// cached. Disabled by default.
+    pub fn cache_setup(mut self, enable: bool) -> Self {
+        self.setup_cache = enable.then(SetupCache::default);
+        self
+    }
+
+    /// Enables a set of optional built-in commands, which are handled by
+    /// goldenscript instead of being passed to the runner:
+    ///
+    /// * `_comment [ARGS...]`: does nothing, ignoring its arguments.
+    /// * `_env [KEY=VALUE...] [KEY...]`: sets the given script environment
+    ///   variables, which the runner can fetch via
+    ///   [`RunContext::env`](crate::RunContext::env) e.g. to pass to
+    ///   subprocesses, and unsets the given bare keys. Unlike `%env`, this
+    ///   doesn't change the process environment. Requires
+    ///   [`Capability::Env`](crate::Capability::Env).
+    /// * `_set [KEY=VALUE...] [KEY...]`: sets the given script variables, which
+    ///   the runner can fetch via [`RunContext::var`](crate::RunContext::var),
+    ///   and unsets the given bare keys.
+    /// * `_skip [REASON...]`: skips the remaining commands in the block,
+    ///   outputting `skipped: REASON`.
+    /// * `_sleep DURATION`: pauses the script for the given duration (e.g.
+    ///   `50ms`), or until it's cancelled.
+    ///
+    /// Disabled by default, passing these commands to the runner.
+    pub fn builtin_commands(mut self, enable: bool) -> Self {
+        self.builtin_commands = enable;
+        self
+    }
+
+    /// Sets the initial seed of each script's random number generator (see
+    /// [`RunContext::random`](crate::RunContext::random)), instead of 0. This
+    /// can also be set via the `GOLDENSCRIPT_SEED` environment variable, which
+    /// takes precedence, e.g. to run randomized scripts with different seeds
+    /// in CI. `%seed` directives in scripts reseed the generator as usual. When
+    /// given, script errors and panics include the seed, so they can be
+    /// reproduced, and differing output is reported to the
+    /// [`RunOptions::reporter`], if any, as
+    /// [`Notice::OutputDiffers`](crate::Notice::OutputDiffers).
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Replaces `%seed auto` directives with the seed that was chosen for them
+    /// in the generated output, e.g. `%seed 4711`, such that the seed becomes
+    /// a reproducible part of the script when it's written back. Always
+    /// enabled when recording goldenfiles via `UPDATE_GOLDENFILES=1`.
+    pub fn pin_auto_seeds(mut self, pin: bool) -> Self {
+        self.pin_auto_seeds = pin;
+        self
+    }
+
+    /// Only runs commands with the given names, e.g. to iterate on a single
+    /// command's behavior in large mixed scripts without editing them. Other
+    /// commands aren't run, and output `(skipped)` instead, while directives
+    /// and built-in commands run as usual. This can also be set as a
+    /// comma-separated list via the `GOLDENSCRIPT_ONLY_COMMANDS` environment
+    /// variable, which takes precedence.
+    ///
+    /// The skipped output differs from the golden output, so this shouldn't be
+    /// used when recording goldenfiles via `UPDATE_GOLDENFILES=1`.
+    ///
+    /// ```
+    /// let options = goldenscript::RunOptions::new().only_commands(&["get", "scan"]);
+    /// ```
+    pub fn only_commands<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.only_commands = Some(names.into_iter().map(|n| n.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Skips commands with any of the given tags, e.g. `[slow]` or `[flaky]`,
+    /// including commands in blocks with these tags. Skipped commands aren't
+    /// run, and output `(skipped)` instead, while directives and built-in
+    /// commands run as usual. This can also be set as a comma-separated list
+    /// via the `GOLDENSCRIPT_SKIP_TAGS` environment variable, which takes
+    /// precedence.
+    ///
+    /// ```
+    /// let options = goldenscript::RunOptions::new().skip_tags(["slow", "flaky"]);
+    /// ```
+    pub fn skip_tags<I, S>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.skip_tags = tags.into_iter().map(|t| t.as_ref().to_string()).collect();
+        self
+    }
+
+    /// Enables the given condition for `[cfg:NAME]` tags, e.g. to run commands
+    /// tagged `[cfg:feature-x]` only when a crate feature is enabled. The
+    /// current OS (e.g. `linux`) and OS family (e.g. `unix`) are always
+    /// enabled. Can be called multiple times.
+    ///
+    /// ```
+    /// let options = goldenscript::RunOptions::new().cfg("feature-x");
+    /// ```
+    pub fn cfg(mut self, name: impl Into<String>) -> Self {
+        self.cfgs.insert(name.into());
+        self
+    }
+
+    /// Returns true if the given condition is enabled, see [`RunOptions::cfg`].
+    pub(crate) fn cfg_enabled(&self, name: &str) -> bool {
+        name == std::env::consts::OS || name == std::env::consts::FAMILY || self.cfgs.contains(name)
+    }
+
+    /// Limits the total amount of the given resource reserved by concurrently
+    /// running scripts, across all threads using these options or clones of
+    /// them (e.g. tests run in parallel). Scripts run with independently
+    /// created options don't share reservations. Scripts declare the resources they use via e.g.
+    /// `%resources cpu=4 net-port`, where a bare name reserves 1, and wait for
+    /// them to become available before running. This prevents heavy scripts
+    /// from oversubscribing e.g. CI machines, while light scripts fill the
+    /// gaps. Resources without a limit are unlimited, and scripts requesting
+    /// more than the limit wait for exclusive use of the resource.
+    ///
+    /// ```
+    /// let options = goldenscript::RunOptions::new()
+    ///     .resource_limit("cpu", 8)
+    ///     .resource_limit("net-port", 4);
+    /// ```
+    pub fn resource_limit(mut self, name: impl Into<String>, limit: u64) -> Self {
+        self.resource_limits.insert(name.into(), limit);
+        self
+    }
+
+    /// Writes an execution trace of failed scripts to the given directory, as
+    /// `<script>.trace` (e.g. `foo.trace` for `tests/scripts/foo`). The trace
+    /// lists each hook and command call in order, with their raw output before
+    /// normalization, prefixing, and other processing, and command timings.
+    /// This helps debug e.g. output processing issues without rerunning slow
+    /// scripts. A script fails if it errors, panics, or its output differs
+    /// (unless updated via `UPDATE_GOLDENFILES=1`), and a stale trace is
+    /// removed when it passes. Only used by [`run_with()`](crate::run_with)
+    /// and [`run_dir_with()`](crate::run_dir_with).
+    ///
+    /// ```
+    /// let options = goldenscript::RunOptions::new().trace_dir("target/goldenscript-traces");
+    /// ```
+    pub fn trace_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.trace_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the policy for final newlines in generated files, to avoid churn
+    /// from editors that add or remove them. Defaults to
+    /// [`FinalNewline::Keep`].
+    pub fn final_newline(mut self, policy: FinalNewline) -> Self {
+        self.final_newline = policy;
+        self
+    }
+
+    /// Sets the policy for scripts starting with a UTF-8 byte order mark (BOM),
+    /// as added by some editors. Defaults to [`Bom::Error`].
+    pub fn bom(mut self, policy: Bom) -> Self {
+        self.bom = policy;
+        self
+    }
+
+    /// Sets the policy for script files containing invalid UTF-8. Only used
+    /// by [`run_with()`](crate::run_with) and
+    /// [`run_dir_with()`](crate::run_dir_with), which read the files. Defaults
+    /// to [`InvalidUtf8::Error`].
+    pub fn invalid_utf8(mut self, policy: InvalidUtf8) -> Self {
+        self.invalid_utf8 = policy;
+        self
+    }
+
+    /// Sets the storage backend that scripts are read from and updated output
+    /// is written to. See the [`storage`](crate::storage) module. The storage
+    /// is shared between clones of the options. Defaults to [`FileStorage`].
+    ///
+    /// ```
+    /// let storage = goldenscript::storage::MemoryStorage::new();
+    /// let options = goldenscript::RunOptions::new().storage(storage.clone());
+    /// ```
+    pub fn storage(mut self, storage: impl Storage + 'static) -> Self {
+        self.storage = SharedStorage(Arc::new(storage));
+        self
+    }
+
+    /// Returns true if the script at the given path is expected to fail.
+    pub(crate) fn expects_failure(&self, path: &Path) -> bool {
+        self.expect_fail.iter().any(|expect| path.ends_with(expect))
+    }
+
+    /// Normalizes command output by replacing all matches of the given regex
+    /// with the replacement, which can reference capture groups as e.g. `$1`.
+    /// Useful to scrub nondeterministic output like timestamps or temporary
+    /// paths. Normalizers are applied in the order they're added, before
+    /// other output processing. Requires the `regex` crate feature.
+    ///
+    /// ```
+    /// let options = goldenscript::RunOptions::new()
+    ///     .normalize(regex::Regex::new(r"\d{4}-\d{2}-\d{2}").unwrap(), "<date>");
+    /// ```
+    #[cfg(feature = "regex")]
+    pub fn normalize(mut self, regex: regex::Regex, replacement: &str) -> Self {
+        self.normalizers.push(Normalizer { scope: None, regex, replacement: replacement.into() });
+        self
+    }
+
+    /// Like [`normalize()`](Self::normalize), but only normalizes the output of
+    /// commands matching the given scope: a command name (e.g. `"status"`), or
+    /// a [`Scope`] for a command prefix or tag. Avoids mangling legitimate
+    /// output of unrelated commands. Requires the `regex` crate feature.
+    ///
+    /// ```
+    /// use goldenscript::Scope;
+    /// use regex::Regex;
+    /// let options = goldenscript::RunOptions::new()
+    ///     .normalize_for("status", Regex::new(r"uptime=\d+").unwrap(), "uptime=<n>")
+    ///     .normalize_for(Scope::Tag("tmp".into()), Regex::new(r"/tmp/\S+").unwrap(), "<tmp>");
+    /// ```
+    #[cfg(feature = "regex")]
+    pub fn normalize_for(
+        mut self,
+        scope: impl Into<Scope>,
+        regex: regex::Regex,
+        replacement: &str,
+    ) -> Self {
+        let scope = Some(scope.into());
+        self.normalizers.push(Normalizer { scope, regex, replacement: replacement.into() });
+        self
+    }
+}
+
+/// A block validator, shared between clones of the options.
+#[derive(Clone)]
+pub(crate) struct SharedValidator(Arc<Mutex<dyn Validator + Send>>);
+
+impl SharedValidator {
+    /// Validates a block, see [`Validator::validate_block`].
+    pub(crate) fn validate_block(&self, input: &str, output: &str) -> Result<(), Box<dyn Error>> {
+        // A panicking validator is reported by the caller, so ignore poisoning.
+        let mut validator = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        validator.validate_block(input, output)
+    }
+}
+
+impl std::fmt::Debug for SharedValidator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Validator")
+    }
+}
+
+/// A review policy, shared between clones of the options.
+#[derive(Clone)]
+pub(crate) struct SharedReviewPolicy(Arc<Mutex<dyn ReviewPolicy + Send>>);
+
+impl SharedReviewPolicy {
+    /// Reviews a block update, see [`ReviewPolicy::review_block`].
+    pub(crate) fn review_block(&self, input: &str, old: &str, new: &str) -> Result<(), String> {
+        // A panicking policy is reported by the caller, so ignore poisoning.
+        let mut policy = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        policy.review_block(input, old, new)
+    }
+}
+
+impl std::fmt::Debug for SharedReviewPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ReviewPolicy")
+    }
+}
+
+/// A notice reporter, shared between clones of the options.
+#[derive(Clone)]
+pub(crate) struct SharedReporter(Arc<Mutex<dyn Reporter + Send>>);
+
+impl SharedReporter {
+    /// Reports a notice, see [`Reporter::report`].
+    pub(crate) fn report(&self, notice: &Notice) {
+        // A panicking reporter is reported by the caller, so ignore poisoning.
+        let mut reporter = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        reporter.report(notice)
+    }
+}
+
+impl std::fmt::Debug for SharedReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Reporter")
+    }
+}
+
+/// A golden file storage backend, shared between clones of the options.
+#[derive(Clone)]
+pub(crate) struct SharedStorage(Arc<dyn Storage>);
+
+impl std::ops::Deref for SharedStorage {
+    type Target = dyn Storage;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl Default for SharedStorage {
+    fn default() -> Self {
+        Self(Arc::new(FileStorage))
+    }
+}
+
+impl std::fmt::Debug for SharedStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Storage")
+    }
+}
+
+/// The commands an output normalizer applies to, see
+/// [`RunOptions::normalize_for`]. A string converts to a command name scope.
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Commands with the given name.
+    Command(String),
+    /// Commands with the given prefix, e.g. `node1` for `node1: get a`.
+    Prefix(String),
+    /// Commands with the given tag, e.g. `tmp` for `get a [tmp]`.
+    Tag(String),
+}
+
+#[cfg(feature = "regex")]
+impl Scope {
+    /// Returns true if the scope matches the given command.
+    fn matches(&self, command: &crate::Command) -> bool {
+        match self {
+            Self::Command(name) => command.name == *name,
+            Self::Prefix(prefix) => command.prefix.as_ref() == Some(prefix),
+            Self::Tag(tag) => command.tags.contains(tag),
+        }
+    }
+}
+
+#[cfg(feature = "regex")]
+impl From<&str> for Scope {
+    fn from(name: &str) -> Self {
+        Self::Command(name.to_string())
+    }
+}
+
+/// An output normalizer, see [`RunOptions::normalize`].
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+pub(crate) struct Normalizer {
+    /// The commands to normalize, or None for all commands.
+    scope: Option<Scope>,
+    regex: regex::Regex,
+    replacement: String,
+}
+
+#[cfg(feature = "regex")]
+impl Normalizer {
+    /// Normalizes the given command output, if the command is in scope.
+    pub(crate) fn apply(&self, command: &crate::Command, output: String) -> String {
+        if self.scope.as_ref().is_some_and(|scope| !scope.matches(command)) {
+            return output;
+        }
+        match self.regex.replace_all(&output, &self.replacement) {
+            std::borrow::Cow::Borrowed(_) => output,
+            std::borrow::Cow::Owned(output) => output,
+        }
+    }
+}
+
+/// A policy for control characters in output, see [`RunOptions::control_chars`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ControlChars {
+    /// Allow control characters, writing them as-is to the golden file.
+    #[default]
+    Allow,
+    /// Escape control characters as `\xNN`, where NN is the hexadecimal
+    /// Unicode code point.
+    Escape,
+    /// Error on control characters, failing the script.
+    Error,
+}
+
+/// A policy for binary command output, see [`RunOptions::binary_output`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BinaryOutput {
+    /// Allow binary output, writing it as-is to the golden file.
+    #[default]
+    Allow,
+    /// Render binary output as a hexdump, via
+    /// [`output::hexdump`](crate::output::hexdump).
+    Hexdump,
+    /// Error on binary output, failing the script.
+    Error,
+}
+
+/// A policy for per-script scratch directories, see [`RunOptions::scratch_dir`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ScratchDir {
+    /// Don't create a scratch directory.
+    #[default]
+    None,
+    /// Create a scratch directory, and remove it after the script has run.
+    Remove,
+    /// Create a scratch directory, and remove it after the script has run,
+    /// unless the script errors or panics. The path of a kept directory is
+    /// reported to the [`RunOptions::reporter`], if any, for inspection.
+    KeepOnFailure,
+}
+
+/// A policy for final newlines in generated files, see
+/// [`RunOptions::final_newline`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FinalNewline {
+    /// Keep the file as generated. It ends with a newline, unless the script
+    /// ends with a comment without one.
+    #[default]
+    Keep,
+    /// Always end the file with a newline, unless it's empty.
+    Always,
+    /// Never end the file with a newline, removing any trailing newlines.
+    Never,
+}
+
+/// A policy for UTF-8 byte order marks (BOMs) at the start of scripts, see
+/// [`RunOptions::bom`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Bom {
+    /// Error on a BOM, failing the script.
+    #[default]
+    Error,
+    /// Ignore a BOM, and remove it from the generated file.
+    Strip,
+    /// Ignore a BOM, and keep it in the generated file.
+    Preserve,
+}
+
+/// A policy for invalid UTF-8 in script files, see
+/// [`RunOptions::invalid_utf8`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Error on invalid UTF-8, failing the script with the byte offset.
+    #[default]
+    Error,
+    /// Replace invalid UTF-8 sequences with the U+FFFD replacement character,
+    /// which is written to the generated file.
+    Replace,
+}
+
+/// A policy for commands exceeding their `[budget=DURATION]` tag, see
+/// [`RunOptions::budget_breach`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BudgetBreach {
+    /// Error on budget breaches, failing the script.
+    #[default]
+    Error,
+    /// Report a warning to the [`RunOptions::reporter`], if any, on budget
+    /// breaches, but don't fail.
+    Warn,
+    /// Ignore budget breaches, e.g. for unoptimized builds.
+    Ignore,
+}
@@ -0,0 +1,287 @@
+//! Helpers for formatting command output.
+
+use std::fmt::Write as _;
+
+/// Structured command or block output, rendered by goldenscript in a
+/// consistent format. This keeps e.g. complex per-block state dumps organized
+/// and diff-stable across runners. Returned by [`Runner::run_ctx`] and
+/// [`Runner::end_block`], or rendered to a string via [`Display`].
+///
+/// ```
+/// use goldenscript::output::Output;
+///
+/// let output = Output::Sections(vec![
+///     ("nodes".into(), "n1\nn2\n".into()),
+///     ("leader".into(), "n1\n".into()),
+/// ]);
+/// assert_eq!(output.to_string(), "leader:\n  n1\nnodes:\n  n1\n  n2\n");
+///
+/// let output = Output::Table {
+///     header: vec!["key".into(), "value".into()],
+///     rows: vec![vec!["a".into(), "1".into()], vec!["bcd".into(), "2".into()]],
+/// };
+/// assert_eq!(output.to_string(), "key  value\n---  -----\na    1\nbcd  2\n");
+/// ```
+///
+/// [`Runner::run_ctx`]: crate::Runner::run_ctx
+/// [`Runner::end_block`]: crate::Runner::end_block
+/// [`Display`]: std::fmt::Display
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Output {
+    /// Plain text, output verbatim.
+    Text(String),
+    /// Named sections, ordered by name. Each section is output as a `name:`
+    /// header followed by its contents, indented by two spaces.
+    Sections(Vec<(String, String)>),
+    /// A table with a header row, output as left-aligned columns separated by
+    /// two spaces, with a dashed line below the header. Rows are output in the
+    /// given order, and may have fewer or more cells than the header.
+    Table { header: Vec<String>, rows: Vec<Vec<String>> },
+    /// A JSON value, pretty-printed. Object keys are ordered by key (unless
+    /// serde_json's `preserve_order` feature is enabled). Requires the
+    /// `serde_json` crate feature.
+    #[cfg(feature = "serde_json")]
+    Json(serde_json::Value),
+}
+
+impl From<String> for Output {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+impl From<&str> for Output {
+    fn from(text: &str) -> Self {
+        Self::Text(text.to_string())
+    }
+}
+
+impl std::fmt::Display for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Text(text) => write!(f, "{text}"),
+
+            Self::Sections(sections) => {
+                let mut sections: Vec<_> = sections.iter().collect();
+                sections.sort_by(|a, b| a.0.cmp(&b.0));
+                for (name, contents) in sections {
+                    writeln!(f, "{name}:")?;
+                    for line in contents.lines() {
+                        match line.is_empty() {
+                            true => writeln!(f)?,
+                            false => writeln!(f, "  {line}")?,
+                        }
+                    }
+                }
+                Ok(())
+            }
+
+            Self::Table { header, rows } => {
+                let columns = rows.iter().map(|row| row.len()).chain([header.len()]).max();
+                let mut widths = vec![0; columns.unwrap_or_default()];
+                for row in rows.iter().chain([header]) {
+                    for (width, cell) in widths.iter_mut().zip(row) {
+                        *width = (*width).max(cell.chars().count());
+                    }
+                }
+                let dashes: Vec<_> = header.iter().map(|h| "-".repeat(h.chars().count())).collect();
+                for row in [header, &dashes].into_iter().chain(rows) {
+                    let mut line = String::new();
+                    for (cell, width) in row.iter().zip(&widths) {
+                        write!(line, "{cell:width$}  ").unwrap();
+                    }
+                    writeln!(f, "{}", line.trim_end())?;
+                }
+                Ok(())
+            }
+
+            #[cfg(feature = "serde_json")]
+            Self::Json(value) => writeln!(f, "{value:#}"),
+        }
+    }
+}
+
+/// Renders the given bytes as a canonical hexdump, similar to `hexdump -C`,
+/// with 16 bytes per line: the offset, the bytes in hex, and the printable
+/// ASCII characters (others are shown as `.`). Useful for binary output.
+///
+/// ```
+/// assert_eq!(
+///     goldenscript::output::hexdump(b"hello\0world"),
+///     "00000000  68 65 6c 6c 6f 00 77 6f  72 6c 64                 |hello.world|\n"
+/// );
+/// ```
+pub fn hexdump(data: &[u8]) -> String {
+    let mut output = String::new();
+    for (i, chunk) in data.chunks(16).enumerate() {
+        write!(output, "{:08x} ", i * 16).unwrap();
+        for j in 0..16 {
+            if j % 8 == 0 {
+                output.push(' ');
+            }
+            match chunk.get(j) {
+                Some(b) => write!(output, "{b:02x} ").unwrap(),
+                None => output.push_str("   "),
+            }
+        }
+        output.push_str(" |");
+        output.extend(chunk.iter().map(|&b| match b {
+            0x20..=0x7e => b as char,
+            _ => '.',
+        }));
+        output.push_str("|\n");
+    }
+    output
+}
+
+/// Returns true if the given output looks like binary data rather than text.
+/// This is a heuristic: the output is considered binary if it contains NUL
+/// characters, or if more than 10% of its characters are control characters
+/// (other than whitespace) or Unicode replacement characters (as produced e.g.
+/// by [`String::from_utf8_lossy`] for invalid UTF-8).
+pub fn is_binary(output: &str) -> bool {
+    let mut total = 0;
+    let mut suspicious = 0;
+    for c in output.chars() {
+        match c {
+            '\0' => return true,
+            '\n' | '\r' | '\t' => {}
+            '\u{fffd}' => suspicious += 1,
+            c if c.is_control() => suspicious += 1,
+            _ => {}
+        }
+        total += 1;
+    }
+    suspicious * 10 > total
+}
+
+/// Formats the given lines as a numbered list, starting at 1, with a trailing
+/// newline. Numbers aren't padded, so adding lines doesn't change existing
+/// ones. See also [`RunContext::next_seq`](crate::RunContext::next_seq).
+///
+/// ```
+/// assert_eq!(goldenscript::output::numbered(["put a", "get a"]), "1. put a\n2. get a\n");
+/// ```
+pub fn numbered(lines: impl IntoIterator<Item = impl std::fmt::Display>) -> String {
+    let mut output = String::new();
+    for (i, line) in lines.into_iter().enumerate() {
+        writeln!(output, "{}. {line}", i + 1).unwrap();
+    }
+    output
+}
+
+/// Sorts the lines of the given output, for deterministic output where the
+/// line order is arbitrary, e.g. when iterating over a HashMap. Preserves a
+/// trailing newline, if any.
+///
+/// ```
+/// assert_eq!(goldenscript::output::sorted_lines("b\nc\na\n"), "a\nb\nc\n");
+/// ```
+pub fn sorted_lines(output: &str) -> String {
+    let mut lines: Vec<_> = output.lines().collect();
+    lines.sort();
+    let mut sorted = lines.join("\n");
+    if output.ends_with('\n') {
+        sorted.push('\n');
+    }
+    sorted
+}
+
+/// Formats a map's entries, ordered by key, for deterministic output
+/// regardless of the map's iteration order. Takes any iterator of key/value
+/// pairs, e.g. a `&HashMap`, and uses the same format as a BTreeMap's Debug
+/// implementation.
+///
+/// ```
+/// let map = std::collections::HashMap::from([("b", 2), ("a", 1)]);
+/// assert_eq!(goldenscript::output::sorted_debug_map(&map), r#"{"a": 1, "b": 2}"#);
+/// ```
+pub fn sorted_debug_map<K, V>(map: impl IntoIterator<Item = (K, V)>) -> String
+where
+    K: Ord + std::fmt::Debug,
+    V: std::fmt::Debug,
+{
+    format!("{:?}", map.into_iter().collect::<std::collections::BTreeMap<K, V>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests Output rendering.
+    #[test]
+    fn output_render() {
+        let s = |s: &str| s.to_string();
+
+        assert_eq!(Output::from("a\nb").to_string(), "a\nb");
+
+        let sections =
+            Output::Sections(vec![(s("b"), s("x\n\ny")), (s("a"), s("")), (s("c"), s("z"))]);
+        assert_eq!(sections.to_string(), "a:\nb:\n  x\n\n  y\nc:\n  z\n");
+        assert_eq!(Output::Sections(vec![]).to_string(), "");
+
+        let table = Output::Table {
+            header: vec![s("id"), s("name")],
+            rows: vec![vec![s("1"), s("åsa"), s("extra")], vec![s("22")], vec![]],
+        };
+        assert_eq!(table.to_string(), "id  name\n--  ----\n1   åsa   extra\n22\n\n");
+    }
+
+    /// Tests Output rendering of JSON values.
+    #[cfg(feature = "serde_json")]
+    #[test]
+    fn output_render_json() {
+        let json = Output::Json(serde_json::json!({"b": [1, null], "a": "x"}));
+        assert_eq!(json.to_string(), "{\n  \"a\": \"x\",\n  \"b\": [\n    1,\n    null\n  ]\n}\n");
+    }
+
+    /// Tests hexdump().
+    #[test]
+    fn hexdump_lines() {
+        assert_eq!(hexdump(b""), "");
+        assert_eq!(
+            hexdump(b"0123456789abcdef\x00\x01\xff"),
+            "00000000  30 31 32 33 34 35 36 37  38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+             00000010  00 01 ff                                          |...|\n"
+        );
+    }
+
+    /// Tests is_binary().
+    #[test]
+    fn is_binary_heuristic() {
+        assert!(!is_binary(""));
+        assert!(!is_binary("plain text\nwith\ttabs\r\n"));
+        assert!(!is_binary("输出 🚀"));
+        assert!(!is_binary("one bell \x07 in a long enough line"));
+        assert!(is_binary("a\0b"));
+        assert!(is_binary("\x01\x02\x03 text"));
+        assert!(is_binary(&String::from_utf8_lossy(&[0xff, 0xfe, 0x80, b'a', 0x90])));
+    }
+
+    /// Tests numbered().
+    #[test]
+    fn numbered_lines() {
+        assert_eq!(numbered(Vec::<String>::new()), "");
+        assert_eq!(numbered(1..=10).lines().last(), Some("10. 10"));
+    }
+
+    /// Tests sorted_lines().
+    #[test]
+    fn sorted_lines_order() {
+        assert_eq!(sorted_lines(""), "");
+        assert_eq!(sorted_lines("b\na\nc"), "a\nb\nc");
+        assert_eq!(sorted_lines("b\n\na\nb\n"), "\na\nb\nb\n");
+    }
+
+    /// Tests sorted_debug_map() with a HashMap, which has random iteration
+    /// order.
+    #[test]
+    fn sorted_debug_map_order() {
+        let map: std::collections::HashMap<_, _> = (0..10).map(|i| (i, i.to_string())).collect();
+        let expect = (0..10).map(|i| format!("{i}: \"{i}\"")).collect::<Vec<_>>().join(", ");
+        assert_eq!(sorted_debug_map(&map), format!("{{{expect}}}"));
+        assert_eq!(sorted_debug_map([(2, 'b'), (1, 'a')]), "{1: 'a', 2: 'b'}");
+        assert_eq!(sorted_debug_map(Vec::<(u8, u8)>::new()), "{}");
+    }
+}
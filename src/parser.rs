@@ -1,16 +1,20 @@
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 
-use crate::command::{Argument, Block, Command};
+use crate::borrowed::{Argument, Block, Command};
+use crate::ValueType;
 
 use nom::branch::alt;
-use nom::bytes::complete::{escaped_transform, is_not, tag, take, take_while_m_n};
+use nom::bytes::complete::{escaped_transform, is_not, tag, take, take_until, take_while_m_n};
 use nom::character::complete::{
-    alphanumeric1, anychar, char, line_ending, not_line_ending, one_of, space0, space1,
+    alphanumeric1, anychar, char, digit0, digit1, line_ending, multispace0, none_of,
+    not_line_ending, one_of, space0, space1,
 };
-use nom::combinator::{consumed, eof, map_res, opt, peek, recognize, value, verify};
+use nom::combinator::{consumed, cut, eof, map_res, not, opt, peek, recognize, value, verify};
 use nom::error::ErrorKind;
-use nom::multi::{many0, many0_count, many_till, separated_list1};
-use nom::sequence::{delimited, pair, preceded, separated_pair, terminated};
+use nom::multi::{many0, many0_count, many1_count, many_till, separated_list1};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::Finish as _;
 
 /// A string input span, annotated with location information.
@@ -22,30 +26,110 @@ type IResult<'a, O> = nom::IResult<Span<'a>, O>;
 /// A Span parse error.
 type Error<'a> = nom::error::Error<Span<'a>>;
 
-/// Parses the given goldenscript string into a list of command blocks.
-pub(crate) fn parse(input: &str) -> Result<Vec<Block>, Error> {
+/// Parses the given goldenscript string into a list of command blocks, which
+/// borrow from the input where possible.
+pub(crate) fn parse(input: &str) -> Result<Vec<Block<'_>>, Error<'_>> {
     blocks(Span::new(input)).finish().map(|(_, blocks)| blocks)
 }
 
+/// Parses a single block at the start of the given input, returning the number
+/// of bytes consumed and the block. Line numbers are relative to the input.
+pub(crate) fn parse_block(input: &str) -> Result<(usize, Block<'_>), Error<'_>> {
+    block(Span::new(input)).finish().map(|(rest, block)| (input.len() - rest.len(), block))
+}
+
+/// Like parse_block(), but for the first block of a script, which may be
+/// preceded by front matter.
+pub(crate) fn parse_first_block(input: &str) -> Result<(usize, Block<'_>), Error<'_>> {
+    first_block(Span::new(input)).finish().map(|(rest, block)| (input.len() - rest.len(), block))
+}
+
+/// Parses a goldenscript without running it, returning its blocks of commands.
+/// Strings borrow from the input where possible, avoiding allocations. This is
+/// useful for tools that process many scripts, e.g. linters or indexers. Parse
+/// errors are returned as [`std::io::ErrorKind::InvalidInput`].
+pub fn parse_borrowed(input: &str) -> std::io::Result<Vec<Block<'_>>> {
+    parse(input).map_err(format_error)
+}
+
+/// Converts a parse error into an I/O error with a descriptive message,
+/// pointing to the error location.
+pub(crate) fn format_error(e: Error<'_>) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!(
+            "parse error at line {} column {} for {:?}:\n{}\n{}^",
+            e.input.location_line(),
+            e.input.get_column(),
+            e.code,
+            String::from_utf8_lossy(e.input.get_line_beginning()),
+            ' '.to_string().repeat(e.input.get_utf8_column() - 1)
+        ),
+    )
+}
+
 /// Parses a command, for use in tests.
 #[cfg(test)]
-pub(crate) fn parse_command(input: &str) -> Result<Command, Error> {
-    command(Span::new(input)).finish().map(|(_, cmd)| cmd)
+pub(crate) fn parse_command(input: &str) -> Result<crate::Command, Error<'_>> {
+    command(Span::new(input)).finish().map(|(_, (cmd, _))| cmd.into_owned())
 }
 
 /// Parses a list of blocks until EOF.
 fn blocks(input: Span) -> IResult<Vec<Block>> {
-    let (input, (blocks, _)) = many_till(block, eof)(input)?;
+    if input.is_empty() {
+        return Ok((input, Vec::new()));
+    }
+    let (input, first) = first_block(input)?;
+    let (input, (mut blocks, _)) = many_till(block, eof)(input)?;
+    blocks.insert(0, first);
     Ok((input, blocks))
 }
 
+/// Parses the first block of a script, which may be preceded by front matter
+/// (see ScriptMetadata). Like leading comments, the front matter is included
+/// in the block's literal and span.
+fn first_block(input: Span) -> IResult<Block> {
+    let script = *input.fragment();
+    let (input, front_matter) = opt(recognize(front_matter))(input)?;
+    let (input, mut block) = block(input)?;
+    if let Some(front_matter) = front_matter {
+        block.literal = &script[..front_matter.len() + block.literal.len()];
+        block.line_number = front_matter.location_line();
+        block.span.start = front_matter.location_offset();
+    }
+    Ok((input, block))
+}
+
+/// Parses front matter, consisting of a --- line, any number of lines, and a
+/// closing --- line. The lines are parsed by ScriptMetadata.
+fn front_matter(input: Span) -> IResult<()> {
+    let line = terminated(not_line_ending, line_ending);
+    let delimiter = |input| terminated(tag("---"), alt((line_ending, eof)))(input);
+    let (input, _) = terminated(tag("---"), line_ending)(input)?;
+    let (input, _) = many_till(line, delimiter)(input)?;
+    Ok((input, ()))
+}
+
 /// Parses a single block, consisting of a set of commands, a --- separator, and
 /// the command output.
 fn block(input: Span) -> IResult<Block> {
     // Parse the command section, preserving the literal for output.
     let line_number = input.location_line();
-    let (input, (literal, commands)) = consumed(commands)(input)?;
-    let block = Block { literal: literal.to_string(), commands, line_number };
+    let start = input.location_offset();
+    let (input, (literal, (commands, tags, comment_spans))) = consumed(commands)(input)?;
+    let end = input.location_offset();
+    let (tag_spans, tags) = tags.into_iter().unzip();
+    let mut block = Block {
+        literal: literal.fragment(),
+        commands,
+        tags,
+        line_number,
+        span: start..end,
+        comment_spans,
+        separator_span: None,
+        output_span: end..end,
+        tag_spans,
+    };
 
     // If there were no commands, and we're at the end of the input, preserve
     // the literal as an empty block for output.
@@ -55,106 +139,629 @@ fn block(input: Span) -> IResult<Block> {
 
     // Parse the separator. There must be one.
     let (input, _) = separator(input)?;
+    block.separator_span = Some(end..end + 3);
 
-    // Parse and skip the output section.
-    let (input, _) = output(input)?;
+    // Parse and skip the output section, excluding trailing line endings from
+    // its span.
+    let (input, output) = output(input)?;
+    let output_start = output.location_offset();
+    let output_len = output.trim_end_matches(['\r', '\n']).len();
+    block.output_span = output_start..output_start + output_len;
+    block.span.end = input.location_offset();
 
     Ok((input, block))
 }
 
+/// The block tags and their spans, in the given order.
+type BlockTags<'a> = Vec<(Range<usize>, Cow<'a, str>)>;
+
 /// Parses the command section of a block. This consists of lines that are
 /// either empty/blank, commands, or comments, up to the separator or EOF.
-fn commands(mut input: Span) -> IResult<Vec<Command>> {
+/// Before the first command, lines consisting only of a tag list give block
+/// tags. Also returns the block tags and the spans of any comments.
+fn commands(mut input: Span) -> IResult<(Vec<Command>, BlockTags, Vec<Range<usize>>)> {
     let mut commands = Vec::new();
+    let mut tags = Vec::new();
+    let mut comments = Vec::new();
     loop {
         // Skip empty/comment lines.
-        if let (i, Some(_)) = opt(empty_or_comment_line)(input)? {
+        if let (i, Some(comment)) = opt(empty_or_comment_line)(input)? {
+            comments.extend(comment.as_ref().map(span_range));
             input = i;
             continue;
         }
 
         // Detect premature EOF. This case must be handled by the caller.
         if input.is_empty() {
-            return Ok((input, commands));
+            return Ok((input, (commands, tags, comments)));
         }
 
         // If we hit a separator and we've seen at least 1 command, we're done.
         // Otherwise, we want to error while attempting to parse the command.
         if let (_, Some(_)) = peek(opt(separator))(input)? {
             if !commands.is_empty() {
-                return Ok((input, commands));
+                return Ok((input, (commands, tags, comments)));
             }
         }
 
-        // Parse a command.
-        let (i, command) = command(input)?;
+        // Parse block tags before the first command.
+        if commands.is_empty() {
+            if let (i, Some((block_tags, comment))) = opt(block_tags)(input)? {
+                tags.extend(block_tags);
+                comments.extend(comment.as_ref().map(span_range));
+                input = i;
+                continue;
+            }
+        }
+
+        // Parse a directive or command.
+        let (i, (command, comment)) = match input.starts_with('%') {
+            true => directive(input)?,
+            false => command(input)?,
+        };
         commands.push(command);
+        comments.extend(comment);
         input = i;
     }
 }
 
 /// Parses a single command, consisting of a command name and optionally a set
 /// of arguments (with or without values), prefix, and silencing parentheses.
-/// Consumes the entire line, including any whitespace and comments at the end.
-fn command(input: Span) -> IResult<Command> {
+/// Consumes the entire line, including any whitespace and comments at the end,
+/// and also returns the span of a trailing comment.
+fn command(input: Span) -> IResult<(Command, Option<Range<usize>>)> {
     // Look for a silencing (.
     let (input, maybe_silent) = opt(terminated(char('('), space0))(input)?;
     let silent = maybe_silent.is_some();
 
     // The prefix, tags, and fail marker.
-    let mut tags = HashSet::new();
-    let (input, prefix) = opt(terminated(string, pair(tag(":"), space0)))(input)?;
+    let (input, maybe_prefix) = opt(terminated(consumed(string), pair(tag(":"), space0)))(input)?;
+    let (prefix_span, prefix) = maybe_prefix.map(|(s, p)| (span_range(&s), p)).unzip();
     let (input, maybe_tags) = opt(delimited(space0, taglist, space0))(input)?;
-    tags.extend(maybe_tags.unwrap_or_default());
+    let (mut tag_spans, mut tags): (Vec<_>, HashSet<_>) =
+        maybe_tags.unwrap_or_default().into_iter().unzip();
     let (input, maybe_fail) = opt(terminated(char('!'), space0))(input)?;
     let fail = maybe_fail.is_some();
 
     // A > takes the rest of the line as the literal command name. It allows
     // line continuation with \ to escape the newline.
-    // TODO: generalize line continuation for all commands.
     let (input, maybe_literal) = opt(terminated(tag(">"), space0))(input)?;
     if maybe_literal.is_some() {
         let line_number = input.location_line();
-        let (input, name) = line_continuation(input)?;
-        let args = Vec::new();
-        return Ok((input, Command { name, args, tags, prefix, silent, fail, line_number }));
+        let (input, (literal, name)) = consumed(line_continuation)(input)?;
+        let start = literal.location_offset();
+        let name_span = start..start + literal.trim_end_matches(['\r', '\n']).len();
+        let command = Command {
+            name,
+            args: Vec::new(),
+            tags,
+            prefix,
+            silent,
+            fail,
+            background: false,
+            line_number,
+            directive: false,
+            name_span,
+            prefix_span,
+            tag_spans,
+        };
+        return Ok((input, (command, None)));
     }
 
     // The command itself, and any trailing tags.
     let line_number = input.location_line();
-    let (input, name) = string(input)?;
-    let (input, args) = many0(preceded(space1, argument))(input)?;
-    let (mut input, maybe_tags) = opt(preceded(space1, taglist))(input)?;
-    tags.extend(maybe_tags.unwrap_or_default());
+    let (input, (name_span, name)) = consumed(string)(input)?;
+    let (input, args) = many0(preceded(token_space, argument))(input)?;
+    let (input, maybe_tags) = opt(preceded(token_space, taglist))(input)?;
+    for (span, tag) in maybe_tags.unwrap_or_default() {
+        tag_spans.push(span);
+        tags.insert(tag);
+    }
+
+    // A trailing & runs the command in the background.
+    let (mut input, maybe_background) = opt(preceded(opt(token_space), char('&')))(input)?;
+    let background = maybe_background.is_some();
 
     // If silenced, look for the closing brace.
     if silent {
-        (input, _) = preceded(space0, char(')'))(input)?;
+        (input, _) = preceded(opt(token_space), char(')'))(input)?;
     }
 
-    // Ignore trailing whitespace and comments on this line.
-    let (input, _) = space0(input)?;
-    let (input, _) = opt(comment)(input)?;
+    // Ignore trailing whitespace and comments on this line. This may include
+    // line continuations, such that parse errors are reported on the continued
+    // line.
+    let (input, _) = opt(token_space)(input)?;
+    let (input, comment) = opt(comment)(input)?;
+    let (input, _) = line_ending(input)?;
+
+    // Parse any heredoc argument values following the command line.
+    let (input, args) = heredoc_bodies(input, args)?;
+
+    let command = Command {
+        name,
+        args,
+        tags,
+        prefix,
+        silent,
+        fail,
+        background,
+        line_number,
+        directive: false,
+        name_span: span_range(&name_span),
+        prefix_span,
+        tag_spans,
+    };
+    Ok((input, (command, comment.as_ref().map(span_range))))
+}
+
+/// Parses a % directive, consisting of a directive name and optionally a set of
+/// arguments. Directives can't have prefixes, tags, silencing, or failures.
+/// Consumes the entire line, including any whitespace and comments at the end,
+/// and also returns the span of a trailing comment.
+fn directive(input: Span) -> IResult<(Command, Option<Range<usize>>)> {
+    let line_number = input.location_line();
+    let (input, (name_span, name)) = preceded(char('%'), consumed(string))(input)?;
+
+    // %template NAME = "COMMAND" allows whitespace around =, for readability.
+    let (input, template) = match name.as_ref() {
+        "template" => opt(preceded(token_space, template_definition))(input)?,
+        _ => (input, None),
+    };
+    let (input, mut args) = many0(preceded(token_space, argument))(input)?;
+    args.splice(0..0, template.map(|arg| (arg, None)));
+
+    // Ignore trailing whitespace (including line continuations) and comments.
+    let (input, _) = opt(token_space)(input)?;
+    let (input, comment) = opt(comment)(input)?;
     let (input, _) = line_ending(input)?;
 
-    Ok((input, Command { name, args, tags, prefix, silent, fail, line_number }))
+    // Parse any heredoc argument values following the directive line.
+    let (input, args) = heredoc_bodies(input, args)?;
+
+    let command = Command {
+        name,
+        args,
+        tags: HashSet::new(),
+        prefix: None,
+        silent: false,
+        fail: false,
+        background: false,
+        line_number,
+        directive: true,
+        name_span: span_range(&name_span),
+        prefix_span: None,
+        tag_spans: Vec::new(),
+    };
+    Ok((input, (command, comment.as_ref().map(span_range))))
+}
+
+/// Parses a %template definition NAME = "COMMAND", as a key=value argument.
+fn template_definition(input: Span) -> IResult<Argument> {
+    let (input, ((key_span, key), _, (value_span, value))) =
+        tuple((consumed(string), delimited(space0, char('='), space0), consumed(string)))(input)?;
+    let arg = Argument {
+        key: Some(key),
+        value,
+        value_type: ValueType::String,
+        key_span: Some(span_range(&key_span)),
+        value_span: span_range(&value_span),
+    };
+    Ok((input, arg))
+}
+
+/// Expands command templates defined via %template NAME = "COMMAND" directives
+/// in the given blocks. Commands named @NAME are replaced by the template's
+/// command, with ${KEY} placeholders replaced by the invocation's KEY=VALUE
+/// arguments. Templates are only available after they're defined, and
+/// commands named @NAME without a template are left as-is.
+///
+/// Also expands command aliases defined via %alias NAME=COMMAND directives.
+/// Commands named NAME are replaced by the alias' command, with the
+/// invocation's arguments appended to the alias' arguments.
+///
+/// The invocation's prefix, tags, silencing, failure, and background markers
+/// are merged into the expanded command, which has the invocation's line
+/// number and name span. Errors on invalid templates or aliases, and on
+/// missing or unknown placeholders.
+pub(crate) fn expand_templates(blocks: &mut [Block]) -> std::io::Result<()> {
+    let error = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+
+    let mut templates: HashMap<String, (String, Vec<String>)> = HashMap::new();
+    let mut aliases: HashMap<String, Command<'static>> = HashMap::new();
+    for command in blocks.iter_mut().flat_map(|block| &mut block.commands) {
+        // Record alias definitions, parsing their commands.
+        if command.directive && command.name == "alias" {
+            let invalid = |e| error(format!("invalid %alias at line {}: {e}", command.line_number));
+            if command.args.is_empty() {
+                return Err(invalid("must be given as NAME=COMMAND".to_string()));
+            }
+            for arg in &command.args {
+                let Some(name) = &arg.key else {
+                    return Err(invalid("must be given as NAME=COMMAND".to_string()));
+                };
+                let alias = parse_expanded(arg.value.to_string()).map_err(invalid)?;
+                if aliases.contains_key(name.as_ref()) {
+                    return Err(invalid(format!("alias '{name}' already defined")));
+                }
+                aliases.insert(name.to_string(), alias);
+            }
+            continue;
+        }
+
+        // Record template definitions, and validate them by parsing them with
+        // placeholder values.
+        if command.directive && command.name == "template" {
+            let invalid =
+                |e| error(format!("invalid %template at line {}: {e}", command.line_number));
+            let [Argument { key: Some(name), value: template, .. }] = command.args.as_slice()
+            else {
+                return Err(invalid("must be given as NAME = \"COMMAND\"".to_string()));
+            };
+            let placeholders = template_placeholders(template).map_err(invalid)?;
+            let values = placeholders.iter().map(|p| (p.as_str(), "x")).collect();
+            expand_template(template, &values).map_err(invalid)?;
+            if templates.contains_key(name.as_ref()) {
+                return Err(invalid(format!("template '{name}' already defined")));
+            }
+            templates.insert(name.to_string(), (template.to_string(), placeholders));
+            continue;
+        }
+
+        if command.directive {
+            continue;
+        }
+
+        // Expand aliases, appending the invocation's arguments.
+        if let Some(alias) = aliases.get(command.name.as_ref()) {
+            let mut expanded = alias.clone();
+            expanded.args.append(&mut command.args);
+            merge_expanded(command, expanded);
+            continue;
+        }
+
+        // Expand template invocations.
+        let Some((template, placeholders)) =
+            command.name.strip_prefix('@').and_then(|name| templates.get(name))
+        else {
+            continue;
+        };
+        let invalid = |e| {
+            error(format!(
+                "invalid template invocation '{}' at line {}: {e}",
+                command.name, command.line_number
+            ))
+        };
+        let mut values = HashMap::new();
+        for arg in &command.args {
+            let Some(key) = &arg.key else {
+                return Err(invalid(format!("arguments must be KEY=VALUE, got '{}'", arg.value)));
+            };
+            if !placeholders.iter().any(|p| p == key) {
+                return Err(invalid(format!("unknown placeholder '{key}'")));
+            }
+            values.insert(key.as_ref(), arg.value.as_ref());
+        }
+        let expanded = expand_template(template, &values).map_err(invalid)?;
+        merge_expanded(command, expanded);
+    }
+    Ok(())
+}
+
+/// Replaces a template or alias invocation by the expanded command, merging
+/// the invocation's prefix, tags, and markers into it.
+fn merge_expanded<'a>(command: &mut Command<'a>, expanded: Command<'a>) {
+    let mut tags = expanded.tags;
+    tags.extend(command.tags.drain());
+    let mut tag_spans = expanded.tag_spans;
+    tag_spans.append(&mut command.tag_spans);
+    *command = Command {
+        name: expanded.name,
+        args: expanded.args,
+        prefix: command.prefix.take().or(expanded.prefix),
+        tags,
+        silent: command.silent || expanded.silent,
+        fail: command.fail || expanded.fail,
+        background: command.background || expanded.background,
+        line_number: command.line_number,
+        directive: false,
+        name_span: command.name_span.clone(),
+        prefix_span: command.prefix_span.clone(),
+        tag_spans,
+    };
+}
+
+/// Returns the names of the ${NAME} placeholders in a template, in order and
+/// without duplicates.
+fn template_placeholders(template: &str) -> Result<Vec<String>, String> {
+    let mut placeholders = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(end) = rest[start..].find('}') else {
+            return Err("unterminated placeholder".to_string());
+        };
+        let name = &rest[start + 2..start + end];
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(format!("invalid placeholder '{name}'"));
+        }
+        if !placeholders.iter().any(|p| p == name) {
+            placeholders.push(name.to_string());
+        }
+        rest = &rest[start + end + 1..];
+    }
+    Ok(placeholders)
+}
+
+/// Expands a template with the given placeholder values, and parses the
+/// resulting command. The strings and spans refer to the expanded text.
+fn expand_template<'a>(
+    template: &str,
+    values: &HashMap<&str, &str>,
+) -> Result<Command<'a>, String> {
+    // Substitute in a single pass, so values can't contain placeholders.
+    let mut text = String::with_capacity(template.len() + 1);
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let end = start + rest[start..].find('}').ok_or("unterminated placeholder")?;
+        let name = &rest[start + 2..end];
+        text.push_str(&rest[..start]);
+        text.push_str(values.get(name).ok_or(format!("missing placeholder '{name}'"))?);
+        rest = &rest[end + 1..];
+    }
+    text.push_str(rest);
+    parse_expanded(text)
+}
+
+/// Parses the expanded text of a template or alias as a single command. The
+/// strings and spans refer to the expanded text.
+fn parse_expanded<'a>(mut text: String) -> Result<Command<'a>, String> {
+    text.push('\n');
+    let (rest, (command, _)) =
+        command(Span::new(&text)).finish().map_err(|e| format!("parse error for {:?}", e.code))?;
+    if !rest.is_empty() {
+        return Err("must be a single command".to_string());
+    }
+    Ok(command.into_static())
+}
+
+/// Parses whitespace separating command tokens (the name, arguments, and
+/// tags). This can include line continuations, i.e. a \ followed by a line
+/// ending, which continues the command on the next line.
+fn token_space(input: Span) -> IResult<()> {
+    value((), many1_count(alt((space1, recognize(pair(char('\\'), line_ending))))))(input)
 }
 
 /// Parses a single command argument, consisting of an argument value and
-/// optionally a key separated by =.
-fn argument(input: Span) -> IResult<Argument> {
-    if let Ok((input, (key, value))) = separated_pair(string, tag("="), opt(string))(input) {
-        return Ok((input, Argument { key: Some(key), value: value.unwrap_or_default() }));
+/// optionally a key separated by =. If the value is a heredoc, its terminator
+/// is returned too, and the value must be parsed via heredoc_bodies() after the
+/// end of the line.
+fn argument<'a>(input: Span<'a>) -> IResult<'a, (Argument<'a>, Option<&'a str>)> {
+    // The value span of a heredoc is set by heredoc_bodies().
+    let heredoc_arg = |key: Option<(Span<'a>, Cow<'a, str>)>| {
+        let (key_span, key) = key.map(|(s, k)| (span_range(&s), k)).unzip();
+        let value_type = ValueType::String;
+        Argument { key, value: Cow::Borrowed(""), value_type, key_span, value_span: 0..0 }
+    };
+    if let Ok((input, (key, terminator))) =
+        separated_pair(consumed(string), tag("="), heredoc)(input)
+    {
+        return Ok((input, (heredoc_arg(Some(key)), Some(terminator))));
+    }
+    if let Ok((input, (key, value))) =
+        separated_pair(consumed(string), tag("="), json_literal(true))(input)
+    {
+        let arg = Argument {
+            key: Some(key.1),
+            value: Cow::Borrowed(value.fragment()),
+            value_type: ValueType::String,
+            key_span: Some(span_range(&key.0)),
+            value_span: span_range(&value),
+        };
+        return Ok((input, (arg, None)));
+    }
+    if let Ok((input, ((key_span, key), (value_span, value)))) =
+        separated_pair(consumed(string), tag("="), consumed(opt(value_string)))(input)
+    {
+        let value = value.unwrap_or_default();
+        let arg = Argument {
+            key: Some(key),
+            value_type: value_type(&value, &value_span),
+            value,
+            key_span: Some(span_range(&key_span)),
+            value_span: span_range(&value_span),
+        };
+        return Ok((input, (arg, None)));
+    }
+    if let Ok((input, terminator)) = heredoc(input) {
+        return Ok((input, (heredoc_arg(None), Some(terminator))));
+    }
+    if let Ok((input, value)) = json_literal(false)(input) {
+        let arg = Argument {
+            key: None,
+            value: Cow::Borrowed(value.fragment()),
+            value_type: ValueType::String,
+            key_span: None,
+            value_span: span_range(&value),
+        };
+        return Ok((input, (arg, None)));
+    }
+    let (input, (value_span, value)) = consumed(value_string)(input)?;
+    let arg = Argument {
+        key: None,
+        value_type: value_type(&value, &value_span),
+        value,
+        key_span: None,
+        value_span: span_range(&value_span),
+    };
+    Ok((input, (arg, None)))
+}
+
+/// Returns the type of a parsed string value, given its literal. Only unquoted
+/// strings, i.e. where the value equals the literal, are typed.
+fn value_type(value: &str, literal: &Span) -> ValueType {
+    match value == *literal.fragment() {
+        true => ValueType::infer(value),
+        false => ValueType::String,
+    }
+}
+
+/// Parses a JSON object or array literal argument value, returning it verbatim.
+/// It can span multiple lines. Arrays are only allowed if allow_array is true,
+/// i.e. for key=value arguments, since they would be ambiguous with tags.
+fn json_literal<'a>(allow_array: bool) -> impl FnMut(Span<'a>) -> IResult<'a, Span<'a>> {
+    move |input| match allow_array {
+        true => recognize(alt((json_object, json_array)))(input),
+        false => recognize(json_object)(input),
     }
-    let (input, value) = string(input)?;
-    Ok((input, Argument { key: None, value }))
 }
 
-/// Parses a list of []-delimited command tags separated by comma or whitespace.
-fn taglist(input: Span) -> IResult<HashSet<String>> {
+/// Parses a JSON value.
+fn json_value(input: Span) -> IResult<()> {
+    alt((
+        json_object,
+        json_array,
+        json_string,
+        json_number,
+        value((), alt((tag("true"), tag("false"), tag("null")))),
+    ))(input)
+}
+
+/// Parses a JSON object.
+fn json_object(input: Span) -> IResult<()> {
+    let member =
+        separated_pair(json_string, delimited(multispace0, char(':'), multispace0), json_value);
+    value(
+        (),
+        delimited(
+            pair(char('{'), multispace0),
+            opt(separated_list1(delimited(multispace0, char(','), multispace0), member)),
+            pair(multispace0, char('}')),
+        ),
+    )(input)
+}
+
+/// Parses a JSON array.
+fn json_array(input: Span) -> IResult<()> {
+    value(
+        (),
+        delimited(
+            pair(char('['), multispace0),
+            opt(separated_list1(delimited(multispace0, char(','), multispace0), json_value)),
+            pair(multispace0, char(']')),
+        ),
+    )(input)
+}
+
+/// Parses a JSON string, which can't contain control characters.
+fn json_string(input: Span) -> IResult<()> {
+    let escape = alt((
+        value((), one_of("\"\\/bfnrt")),
+        value((), preceded(char('u'), take_while_m_n(4, 4, |c: char| c.is_ascii_hexdigit()))),
+    ));
+    let character = verify(none_of("\"\\"), |c: &char| !c.is_control());
+    value(
+        (),
+        delimited(
+            char('"'),
+            many0_count(alt((value((), character), preceded(char('\\'), escape)))),
+            char('"'),
+        ),
+    )(input)
+}
+
+/// Parses a JSON number.
+fn json_number(input: Span) -> IResult<()> {
+    value(
+        (),
+        tuple((
+            opt(char('-')),
+            alt((tag("0"), recognize(pair(one_of("123456789"), digit0)))),
+            opt(pair(char('.'), digit1)),
+            opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+        )),
+    )(input)
+}
+
+/// Parses a heredoc argument marker <<TERMINATOR, returning the terminator. The
+/// terminator can only contain alphanumeric ASCII characters and _.
+fn heredoc<'a>(input: Span<'a>) -> IResult<'a, &'a str> {
+    let (input, terminator) = preceded(
+        tag("<<"),
+        recognize(pair(
+            alt((alphanumeric1, tag("_"))),
+            many0_count(alt((alphanumeric1, tag("_")))),
+        )),
+    )(input)?;
+    Ok((input, terminator.fragment()))
+}
+
+/// Parses the values of any heredoc arguments, in order, following the command
+/// line. Each value consists of the lines up to a line containing only the
+/// terminator (ignoring trailing whitespace), joined by \n without a trailing
+/// newline. The value borrows from the input unless it has \r\n line endings.
+fn heredoc_bodies<'a>(
+    mut input: Span<'a>,
+    args: Vec<(Argument<'a>, Option<&'a str>)>,
+) -> IResult<'a, Vec<Argument<'a>>> {
+    let mut result = Vec::with_capacity(args.len());
+    for (mut arg, terminator) in args {
+        if let Some(terminator) = terminator {
+            let (start, start_offset) = (*input.fragment(), input.location_offset());
+            let mut lines = Vec::new();
+            loop {
+                let (i, line) = terminated(not_line_ending, line_ending)(input)?;
+                input = i;
+                if line.trim_end() == terminator {
+                    break;
+                }
+                lines.push(line);
+            }
+            let end_offset = lines.last().map(|l| l.location_offset() + l.len());
+            let body = &start[..end_offset.unwrap_or(start_offset) - start_offset];
+            arg.value_span = start_offset..start_offset + body.len();
+            arg.value = match body.contains('\r') {
+                false => Cow::Borrowed(body),
+                true => {
+                    Cow::Owned(lines.iter().map(|l| *l.fragment()).collect::<Vec<_>>().join("\n"))
+                }
+            };
+        }
+        result.push(arg);
+    }
+    Ok((input, result))
+}
+
+/// Parses a list of []-delimited command tags separated by comma or whitespace,
+/// along with their spans.
+fn taglist(input: Span) -> IResult<Vec<(Range<usize>, Cow<str>)>> {
     let (input, tags) =
-        delimited(tag("["), separated_list1(one_of(", "), string), tag("]"))(input)?;
-    Ok((input, HashSet::from_iter(tags)))
+        delimited(tag("["), separated_list1(one_of(", "), consumed(command_tag)), tag("]"))(input)?;
+    Ok((input, tags.into_iter().map(|(span, tag)| (span_range(&span), tag)).collect()))
+}
+
+/// Parses a line of block tags, consisting only of a tag list and optionally a
+/// trailing comment.
+fn block_tags(input: Span) -> IResult<(BlockTags, Option<Span>)> {
+    let (input, tags) = delimited(space0, taglist, space0)(input)?;
+    let (input, comment) = terminated(opt(comment), line_ending)(input)?;
+    Ok((input, (tags, comment)))
+}
+
+/// Parses a single command tag, optionally as key=value. These are stored as a
+/// single key=value string, borrowed from the input if it's given literally.
+/// Like argument values, tags can contain :, e.g. [cfg:NAME].
+fn command_tag(input: Span) -> IResult<Cow<str>> {
+    let (input, (literal, (key, value))) =
+        consumed(pair(value_string, opt(preceded(tag("="), opt(value_string)))))(input)?;
+    let Some(value) = value else {
+        return Ok((input, key));
+    };
+    let value = value.unwrap_or_default();
+    match (&key, &value) {
+        (Cow::Borrowed(k), Cow::Borrowed(v)) if literal.len() == k.len() + v.len() + 1 => {
+            Ok((input, Cow::Borrowed(literal.fragment())))
+        }
+        _ => Ok((input, Cow::Owned(format!("{key}={value}")))),
+    }
 }
 
 /// Parses a command/output separator: --- followed by a line ending.
@@ -174,24 +781,74 @@ fn output(input: Span) -> IResult<Span> {
     recognize(many_till(anychar, pair(alt((line_ending, eof)), alt((line_ending, eof)))))(input)
 }
 
-/// Parses a string, both quoted (' or ") and unquoted.
-fn string(input: Span) -> IResult<String> {
-    alt((unquoted_string, quoted_string('\''), quoted_string('"')))(input)
+/// Parses a string, both quoted (' or "), triple-quoted ("""), raw (r' or
+/// r"), and unquoted.
+fn string(input: Span) -> IResult<Cow<str>> {
+    alt((
+        raw_string,
+        unquoted_string,
+        quoted_string('\''),
+        triple_quoted_string,
+        quoted_string('"'),
+    ))(input)
+}
+
+/// Parses an argument value string. Like string(), but unquoted strings can
+/// also contain :, see unquoted_value().
+fn value_string(input: Span) -> IResult<Cow<str>> {
+    let quoted = alt((quoted_string('\''), triple_quoted_string, quoted_string('"')));
+    alt((raw_string, unquoted_value, quoted))(input)
+}
+
+/// A raw string is prefixed by r and quoted using ' or ". It can contain
+/// anything but its quote character, and \ is taken literally.
+fn raw_string(input: Span) -> IResult<Cow<str>> {
+    let (input, string) = preceded(
+        char('r'),
+        alt((
+            delimited(char('\''), opt(is_not("\'")), char('\'')),
+            delimited(char('"'), opt(is_not("\"")), char('"')),
+        )),
+    )(input)?;
+    Ok((input, Cow::Borrowed(string.map(|s| *s.fragment()).unwrap_or_default())))
 }
 
 /// An unquoted string can't contain whitespace, and can only contain
-/// alphanumeric characters and some punctuation.
-fn unquoted_string(input: Span) -> IResult<String> {
+/// alphanumeric characters and some punctuation. It can start with a - or +
+/// sign, e.g. for negative numbers, or @, e.g. for fixture references.
+fn unquoted_string(input: Span) -> IResult<Cow<str>> {
+    let (input, string) = recognize(pair(unquoted_start, many0_count(unquoted_char)))(input)?;
+    Ok((input, Cow::Borrowed(string.fragment())))
+}
+
+/// An unquoted argument value or tag is an unquoted string that can also
+/// contain :, e.g. 1:10, but not end with it. Identifiers (prefixes, command names, and
+/// argument keys) can't contain :, since prefix:command is a prefix.
+fn unquoted_value(input: Span) -> IResult<Cow<str>> {
     let (input, string) = recognize(pair(
-        alt((alphanumeric1, tag("_"))),
-        many0_count(alt((alphanumeric1, tag("_"), tag("-"), tag("."), tag("/"), tag("@")))),
+        unquoted_start,
+        many0_count(alt((
+            unquoted_char,
+            recognize(pair(many1_count(char(':')), peek(unquoted_char))),
+        ))),
     ))(input)?;
-    Ok((input, string.to_string()))
+    Ok((input, Cow::Borrowed(string.fragment())))
+}
+
+/// Parses the start of an unquoted string.
+fn unquoted_start(input: Span) -> IResult<Span> {
+    alt((recognize(pair(opt(one_of("-+")), alt((alphanumeric1, tag("_"))))), tag("@")))(input)
+}
+
+/// Parses characters that can follow the start of an unquoted string, except :.
+fn unquoted_char(input: Span) -> IResult<Span> {
+    alt((alphanumeric1, recognize(one_of("_-./@*"))))(input)
 }
 
 /// A quoted string can contain anything, and respects common escape sequences.
-/// It can be quoted using ' or ".
-fn quoted_string(quote: char) -> impl FnMut(Span) -> IResult<String> {
+/// It can be quoted using ' or ". It is borrowed from the input unless it
+/// contains escape sequences.
+fn quoted_string<'a>(quote: char) -> impl FnMut(Span<'a>) -> IResult<'a, Cow<'a, str>> {
     move |input| {
         let q = match quote {
             '\'' | '\"' => quote.to_string(),
@@ -203,54 +860,88 @@ fn quoted_string(quote: char) -> impl FnMut(Span) -> IResult<String> {
         // character, special-case the empty quoted string.
         let (input, maybe_empty) = opt(tag(format!("{q}{q}").as_str()))(input)?;
         if maybe_empty.is_some() {
-            return Ok((input, String::new()));
+            return Ok((input, Cow::Borrowed("")));
+        }
+
+        // If there are no escape sequences, borrow the string.
+        let result: IResult<Span> =
+            delimited(tag(q), is_not(format!("\\{q}").as_str()), tag(q))(input);
+        if let Ok((input, string)) = result {
+            return Ok((input, Cow::Borrowed(*string.fragment())));
         }
 
         let result = delimited(
             tag(q),
-            escaped_transform(
-                is_not(format!("\\{q}").as_str()),
-                '\\',
-                alt((
-                    value('\'', tag("\'")),
-                    value('\"', tag("\"")),
-                    value('\\', tag("\\")),
-                    value('\0', tag("0")),
-                    value('\n', tag("n")),
-                    value('\r', tag("r")),
-                    value('\t', tag("t")),
-                    map_res(
-                        preceded(tag("x"), take(2usize)),
-                        |input: Span| match u8::from_str_radix(input.fragment(), 16) {
-                            Ok(byte) => Ok(char::from(byte)),
-                            Err(_) => Err(Error::new(input, ErrorKind::HexDigit)),
-                        },
-                    ),
-                    map_res(
-                        delimited(
-                            tag("u{"),
-                            take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit()),
-                            tag("}"),
-                        ),
-                        |input: Span| {
-                            let codepoint = u32::from_str_radix(input.fragment(), 16)
-                                .or(Err(Error::new(input, ErrorKind::HexDigit)))?;
-                            char::from_u32(codepoint).ok_or(Error::new(input, ErrorKind::Char))
-                        },
-                    ),
-                )),
-            ),
+            escaped_transform(is_not(format!("\\{q}").as_str()), '\\', escape_sequence),
             tag(q),
         )(input);
-        result
+        result.map(|(input, string)| (input, Cow::Owned(string)))
     }
 }
 
-/// Parses a line that only contains whitespace and/or a comment.
-fn empty_or_comment_line(input: Span) -> IResult<Span> {
-    verify(recognize(delimited(space0, opt(comment), alt((line_ending, eof)))), |line: &Span| {
-        !line.is_empty()
-    })(input)
+/// A triple-quoted string is quoted using """. It can span multiple lines and
+/// contain unescaped " quotes, and respects the same escape sequences as quoted
+/// strings. It ends at the first """, and is borrowed from the input unless it
+/// contains escape sequences.
+fn triple_quoted_string(input: Span) -> IResult<Cow<str>> {
+    let quote = "\"\"\"";
+    let result: IResult<Span> = delimited(tag(quote), take_until(quote), tag(quote))(input);
+    if let Ok((input, string)) = result {
+        if !string.contains('\\') {
+            return Ok((input, Cow::Borrowed(*string.fragment())));
+        }
+    }
+    // Anything but \ and the closing quote, including single " quotes. Once
+    // the opening quote is parsed, errors are reported at the invalid escape
+    // sequence instead of backtracking to parse "" as an empty quoted string.
+    let normal =
+        recognize(many1_count(alt((is_not("\\\""), terminated(tag("\""), not(tag("\"\"")))))));
+    let escaped = escaped_transform(normal, '\\', escape_sequence);
+    let (input, string) = preceded(tag(quote), cut(terminated(escaped, tag(quote))))(input)?;
+    Ok((input, Cow::Owned(string)))
+}
+
+/// Parses a string escape sequence following a \, returning the escaped
+/// character.
+fn escape_sequence(input: Span) -> IResult<char> {
+    alt((
+        value('\'', tag("\'")),
+        value('\"', tag("\"")),
+        value('\\', tag("\\")),
+        value('\0', tag("0")),
+        value('\n', tag("n")),
+        value('\r', tag("r")),
+        value('\t', tag("t")),
+        map_res(preceded(tag("x"), take(2usize)), |input: Span| {
+            match u8::from_str_radix(input.fragment(), 16) {
+                Ok(byte) => Ok(char::from(byte)),
+                Err(_) => Err(Error::new(input, ErrorKind::HexDigit)),
+            }
+        }),
+        map_res(
+            delimited(tag("u{"), take_while_m_n(1, 6, |c: char| c.is_ascii_hexdigit()), tag("}")),
+            |input: Span| {
+                let codepoint = u32::from_str_radix(input.fragment(), 16)
+                    .or(Err(Error::new(input, ErrorKind::HexDigit)))?;
+                char::from_u32(codepoint).ok_or(Error::new(input, ErrorKind::Char))
+            },
+        ),
+    ))(input)
+}
+
+/// Parses a line that only contains whitespace and/or a comment, returning the
+/// comment if any.
+fn empty_or_comment_line(input: Span) -> IResult<Option<Span>> {
+    let (input, (_, comment)) = verify(
+        consumed(delimited(space0, opt(comment), alt((line_ending, eof)))),
+        |(line, _): &(Span, _)| !line.is_empty(),
+    )(input)?;
+    Ok((input, comment))
+}
+
+/// Returns the byte range of a span in the parsed input.
+fn span_range(span: &Span) -> Range<usize> {
+    span.location_offset()..span.location_offset() + span.len()
 }
 
 /// Parses a # or // comment until the end of the line/file (not inclusive).
@@ -259,8 +950,13 @@ fn comment(input: Span) -> IResult<Span> {
 }
 
 /// Parses a raw line with optional \ line continuation escapes. Naïve but
-/// sufficient implementation that e.g. doesn't support \\ escapes.
-fn line_continuation(mut input: Span) -> IResult<String> {
+/// sufficient implementation that e.g. doesn't support \\ escapes. Borrows the
+/// line from the input if there is no line continuation.
+fn line_continuation(mut input: Span) -> IResult<Cow<str>> {
+    let (i, line) = terminated(not_line_ending, line_ending)(input)?;
+    if !line.ends_with('\\') {
+        return Ok((i, Cow::Borrowed(*line.fragment())));
+    }
     let mut result = String::new();
     loop {
         let (i, line) = terminated(not_line_ending, line_ending)(input)?;
@@ -272,6 +968,6 @@ fn line_continuation(mut input: Span) -> IResult<String> {
             result.pop();
             continue;
         }
-        return Ok((input, result));
+        return Ok((input, Cow::Owned(result)));
     }
 }
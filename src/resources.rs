@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::CancellationToken;
+
+/// The resources reserved by running scripts, shared by clones of the same
+/// [`RunOptions`](crate::RunOptions) such that the options' limits apply across
+/// all scripts run with them (e.g. tests run in parallel).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ResourcePool(Arc<PoolState>);
+
+#[derive(Debug, Default)]
+struct PoolState {
+    /// The resources reserved by running scripts, by name.
+    reserved: Mutex<BTreeMap<String, u64>>,
+    /// Notified when resources are released.
+    released: Condvar,
+}
+
+/// Resources reserved by a script via %resources, see
+/// [`RunOptions::resource_limit`](crate::RunOptions::resource_limit). They're
+/// released back to the pool when dropped.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    pool: ResourcePool,
+    resources: BTreeMap<String, u64>,
+}
+
+impl Reservation {
+    /// Reserves the requested resources from the pool under the given limits,
+    /// blocking until they're available or the token is cancelled. Resources
+    /// without a limit are unlimited, and requests exceeding a limit are
+    /// clamped to it, i.e. wait for exclusive use of the resource.
+    pub(crate) fn acquire(
+        pool: &ResourcePool,
+        requests: &BTreeMap<String, u64>,
+        limits: &BTreeMap<String, u64>,
+        token: &CancellationToken,
+    ) -> Result<Self, Box<dyn Error>> {
+        let requests: BTreeMap<String, u64> = requests
+            .iter()
+            .filter_map(|(name, amount)| {
+                let limit = limits.get(name)?;
+                Some((name.clone(), *amount.min(limit)))
+            })
+            .collect();
+        let pool = pool.clone();
+        if requests.is_empty() {
+            return Ok(Self { pool, resources: requests });
+        }
+
+        // A panicking script doesn't leave the reservations inconsistent, so
+        // ignore poisoning.
+        let mut reserved = pool.0.reserved.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            token.check()?;
+            let available = requests.iter().all(|(name, amount)| {
+                reserved.get(name).copied().unwrap_or(0) + amount <= limits[name]
+            });
+            if available {
+                for (name, amount) in &requests {
+                    *reserved.entry(name.clone()).or_default() += amount;
+                }
+                drop(reserved);
+                return Ok(Self { pool, resources: requests });
+            }
+            // Wake up periodically to check for cancellation.
+            reserved = pool
+                .0
+                .released
+                .wait_timeout(reserved, Duration::from_millis(10))
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.resources.is_empty() {
+            return;
+        }
+        let state = &self.pool.0;
+        let mut reserved = state.reserved.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, amount) in &self.resources {
+            if let Some(total) = reserved.get_mut(name) {
+                *total = total.saturating_sub(*amount);
+            }
+        }
+        state.released.notify_all();
+    }
+}
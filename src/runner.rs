@@ -1,8 +1,18 @@
-use crate::parser::parse;
-use crate::Command;
+use crate::command::{Block, BlockInfo};
+use crate::options::SharedReporter;
+use crate::output::Output;
+use crate::parser::{expand_templates, format_error, parse};
+use crate::resources::Reservation;
+use crate::setup_cache::{CachedSetup, SetupCache};
+use crate::{
+    borrowed, datagen, output, util, BinaryOutput, Bom, BudgetBreach, Command, ControlChars,
+    FinalNewline, InvalidUtf8, RunContext, RunOptions, ScratchDir, ScriptMetadata,
+};
 
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
-use std::io::Write as _;
+use std::ops::Range;
+use std::time::{Duration, Instant};
 
 /// Runs goldenscript commands, returning their output.
 pub trait Runner {
@@ -17,35 +27,194 @@ pub trait Runner {
     /// itself and return an `Ok` result with appropriate output.
     fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>>;
 
-    /// Called at the start of a goldenscript. Used e.g. for initial setup.
-    /// Can't return output, since it's not called in the context of a block.
-    fn start_script(&mut self) -> Result<(), Box<dyn Error>> {
-        Ok(())
+    /// Like [`Runner::run`], but also given the script's [`RunContext`], e.g.
+    /// to shuffle inputs deterministically, and returns structured [`Output`],
+    /// which goldenscript renders in a consistent format, e.g. as sections or
+    /// a table. The default implementation calls [`Runner::run`] and returns
+    /// its output as [`Output::Text`].
+    #[allow(unused_variables)]
+    fn run_ctx(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        self.run(command).map(Output::Text)
+    }
+
+    /// Runs an output-only snapshot script, given its entire input document
+    /// (e.g. a SQL file or a config), returning its output. Only used by
+    /// [`run_snapshot()`] and [`generate_snapshot()`], for systems whose
+    /// natural input is a single document rather than a sequence of commands.
+    /// The default implementation returns an error.
+    #[allow(unused_variables)]
+    fn run_raw(&mut self, input: &str) -> Result<String, Box<dyn Error>> {
+        Err("Runner::run_raw() not implemented".into())
+    }
+
+    /// Runs a batch of consecutive goldenscript commands from a block,
+    /// returning their outputs in order, or an error if any command fails.
+    /// Only used if [`Runner::batch_size`] is greater than 1.
+    ///
+    /// This can be used e.g. by runners for remote systems to amortize round
+    /// trips. The default implementation runs each command via
+    /// [`Runner::run_ctx`], falling back to [`Runner::unknown_command`].
+    /// Commands that are expected to fail (with `!`) are never batched, and
+    /// are always run individually via [`Runner::run_ctx`].
+    ///
+    /// The [`Runner::start_command`] hooks are called for all commands before
+    /// the batch is run, and the [`Runner::end_command`] hooks after.
+    fn run_batch(
+        &mut self,
+        commands: &[Command],
+        ctx: &mut RunContext,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        commands.iter().map(|command| run_ctx(self, command, ctx)).collect()
+    }
+
+    /// Spawns a background command, i.e. one suffixed with `&`, returning a
+    /// closure that runs it. The closure is called on a separate thread, while
+    /// the script continues with later commands, and its output is written by
+    /// a later `_wait` command. This allows scripting blocking operations,
+    /// e.g. lock waits or channel receives, that are unblocked by later
+    /// commands. The closure must own or share (e.g. via `Arc`) any state it
+    /// needs.
+    ///
+    /// Also used to run the commands of `[concurrent]` blocks, which requires
+    /// the runner to enable [`Capability::Spawn`] via
+    /// [`Runner::capabilities`].
+    ///
+    /// The command hooks aren't called for background commands. The default
+    /// implementation returns an error.
+    #[allow(unused_variables)]
+    fn spawn(&mut self, command: &Command) -> Result<Spawned, Box<dyn Error>> {
+        Err(format!("Runner::spawn() not implemented for command '{}'", command.name).into())
+    }
+
+    /// Saves a checkpoint of the runner's state with the given name, replacing
+    /// any existing checkpoint with that name. Called by the built-in
+    /// `_checkpoint NAME` command, such that a script can later restore it via
+    /// `_restore NAME` and run divergent scenarios from a common point, e.g.
+    /// after a shared setup. The [`RunContext`] random number generator,
+    /// virtual clock, and variables are also checkpointed. Also called with
+    /// the name `%branch` before the first `%branch` directive, to restore
+    /// before each later branch. The default implementation returns an error.
+    #[allow(unused_variables)]
+    fn checkpoint(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        Err("Runner::checkpoint() not implemented".into())
+    }
+
+    /// Restores the runner's state from a checkpoint with the given name, saved
+    /// via [`Runner::checkpoint`]. Called by the built-in `_restore NAME`
+    /// command and before each `%branch` but the first, only for existing
+    /// checkpoints. A checkpoint can be restored
+    /// multiple times. The default implementation returns an error.
+    #[allow(unused_variables)]
+    fn restore(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        Err("Runner::restore() not implemented".into())
+    }
+
+    /// Serializes the runner's state, to be restored via
+    /// [`Runner::restore_state`], possibly in a different runner instance.
+    /// Called after each `%setup` section when enabled via
+    /// [`RunOptions::cache_setup`], such that later scripts with the same
+    /// setup can skip it. The default implementation returns an error.
+    fn serialize_state(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Err("Runner::serialize_state() not implemented".into())
+    }
+
+    /// Restores the runner's state from [`Runner::serialize_state`], instead of
+    /// running a cached `%setup` section. Called after
+    /// [`Runner::start_script`]. The default implementation returns an error.
+    #[allow(unused_variables)]
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        Err("Runner::restore_state() not implemented".into())
+    }
+
+    /// Called when [`Runner::run`] or [`Runner::run_ctx`] returns an
+    /// [`UnknownCommand`] error, signalling that the runner doesn't recognize
+    /// the command. This allows layering runners, e.g. delegating unknown
+    /// commands to a base runner with built-in commands, without matching on
+    /// error messages. The default implementation returns an error.
+    fn unknown_command(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        Err(format!("unknown command '{}'", command.name).into())
+    }
+
+    /// Returns the maximum number of consecutive commands to run as a batch
+    /// via [`Runner::run_batch`]. Defaults to 1, i.e. no batching.
+    fn batch_size(&self) -> usize {
+        1
+    }
+
+    /// Returns the names of all commands the runner recognizes, if known. If
+    /// given, scripts are checked for unknown commands before they are run,
+    /// reporting all of them at once. See also [`check_commands()`], which
+    /// checks a set of scripts upfront. Defaults to `None`, i.e. no checks.
+    fn known_commands(&self) -> Option<Vec<&str>> {
+        None
+    }
+
+    /// Returns the built-in goldenscript features the runner consents to, see
+    /// [`Capability`]. Directives that use a capability the runner hasn't
+    /// enabled will error. Defaults to none.
+    fn capabilities(&self) -> HashSet<Capability> {
+        HashSet::new()
     }
 
-    /// Called at the end of a goldenscript. Used e.g. for state assertions.
-    /// Can't return output, since it's not called in the context of a block.
-    fn end_script(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Called at the start of a goldenscript, with the script's
+    /// [`RunContext`]. Used e.g. for initial setup, to configure the system
+    /// under test via the script's [`RunContext::metadata`], or to store
+    /// per-script state in its extensions. Can't return output, since it's not
+    /// called in the context of a block.
+    #[allow(unused_variables)]
+    fn start_script(&mut self, ctx: &mut RunContext) -> Result<(), Box<dyn Error>> {
         Ok(())
     }
 
-    /// Called at the start of a block. Used e.g. to output initial state.
-    /// Any output is prepended to the block's output.
-    fn start_block(&mut self) -> Result<String, Box<dyn Error>> {
+    /// Called at the end of a goldenscript, with the script's [`RunContext`].
+    /// Used e.g. for state assertions, or by runners simulating multiple
+    /// clients or nodes to verify via [`RunContext::prefixes`] that all of
+    /// them were properly shut down. Any output, e.g. a final state summary or
+    /// invariant report, is appended to the script after the last block, as
+    /// the output of a trailing `%end-script` block, which is added or updated
+    /// as needed.
+    #[allow(unused_variables)]
+    fn end_script(&mut self, ctx: &mut RunContext) -> Result<String, Box<dyn Error>> {
         Ok(String::new())
     }
 
-    /// Called at the end of a block. Used e.g. to output final state.
-    /// Any output is appended to the block's output.
-    fn end_block(&mut self) -> Result<String, Box<dyn Error>> {
+    /// Called at the start of a block, with information about the block such
+    /// as its tags and commands. Used e.g. to output initial state, or to reset
+    /// state for certain blocks. Any output is prepended to the block's output.
+    #[allow(unused_variables)]
+    fn start_block(
+        &mut self,
+        block: &BlockInfo,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
         Ok(String::new())
     }
 
+    /// Called at the end of a block, with information about the block. Used
+    /// e.g. to output final state, possibly as structured [`Output`] such as
+    /// sections. Any output is appended to the block's output.
+    #[allow(unused_variables)]
+    fn end_block(
+        &mut self,
+        block: &BlockInfo,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        Ok(Output::Text(String::new()))
+    }
+
     /// Called at the start of a command. Used e.g. for setup. Any output is
     /// prepended to the command's output, and is affected e.g. by the prefix
     /// and silencing of the command.
     #[allow(unused_variables)]
-    fn start_command(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+    fn start_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
         Ok(String::new())
     }
 
@@ -53,9 +222,395 @@ pub trait Runner {
     /// appended to the command's output, and is affected e.g. by the prefix and
     /// silencing of the command.
     #[allow(unused_variables)]
-    fn end_command(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+    fn end_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
         Ok(String::new())
     }
+
+    /// Called after the [`Runner::end_command`] hook, to check the runner's
+    /// invariants, e.g. that replicas are consistent. Violations should be
+    /// returned as an [`InvariantViolation`] error, which is rendered below
+    /// the command's output. Other errors abort the script. Not called if
+    /// [`Runner::end_command`] returned a violation. The default
+    /// implementation does nothing.
+    #[allow(unused_variables)]
+    fn check_invariants(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Post-processes a command's output, after it has run and any expected
+    /// failure has been formatted as output, but before the
+    /// [`Runner::end_command`] hook. Used e.g. to centrally redact timestamps,
+    /// pointers, or temporary paths. Not called for hook output. The default
+    /// implementation returns the output unchanged.
+    #[allow(unused_variables)]
+    fn process_output(
+        &mut self,
+        command: &Command,
+        output: String,
+    ) -> Result<String, Box<dyn Error>> {
+        Ok(output)
+    }
+}
+
+/// A background command returned by [`Runner::spawn`]. It's called on a
+/// separate thread, and returns the command's output or error.
+pub type Spawned = Box<dyn FnOnce() -> Result<String, Box<dyn Error + Send + Sync>> + Send>;
+
+/// The environment variable that sets the initial random seed of scripts.
+const SEED_ENV: &str = "GOLDENSCRIPT_SEED";
+
+/// The environment variable that sets the commands to run, see
+/// RunOptions::only_commands().
+const ONLY_COMMANDS_ENV: &str = "GOLDENSCRIPT_ONLY_COMMANDS";
+
+/// The environment variable that sets the tags of commands to skip, see
+/// RunOptions::skip_tags().
+const SKIP_TAGS_ENV: &str = "GOLDENSCRIPT_SKIP_TAGS";
+
+/// The built-in command that waits for background commands.
+const WAIT: &str = "_wait";
+
+/// The built-in command that advances the virtual clock.
+const ADVANCE_TIME: &str = "_advance_time";
+
+/// The built-in command that outputs the virtual clock's current time.
+const NOW: &str = "_now";
+
+/// The built-in command that checkpoints the runner's state.
+const CHECKPOINT: &str = "_checkpoint";
+
+/// The built-in command that restores the runner's state from a checkpoint.
+const RESTORE: &str = "_restore";
+
+/// The checkpoint name used for %branch, which can't collide with names given
+/// to _checkpoint since it's not a valid bare argument.
+const BRANCH_CHECKPOINT: &str = "%branch";
+
+/// All built-in commands, which aren't passed to the runner.
+const BUILTINS: &[&str] = &[WAIT, ADVANCE_TIME, NOW, CHECKPOINT, RESTORE];
+
+/// Optional built-in commands, enabled via RunOptions::builtin_commands().
+/// Otherwise, they're passed to the runner like any other command.
+const OPTIONAL_BUILTINS: &[&str] = &["_comment", "_env", "_set", "_skip", "_sleep"];
+
+/// A background command running on a separate thread.
+struct Background {
+    command: Command,
+    handle: std::thread::JoinHandle<Result<String, Box<dyn Error + Send + Sync>>>,
+}
+
+/// An error returned by [`Runner::run`] or [`Runner::run_ctx`] to signal that
+/// the runner doesn't recognize a command, in which case goldenscript calls
+/// [`Runner::unknown_command`] instead. For example, to layer a runner on top of a base runner:
+///
+/// ```
+/// # use std::error::Error;
+/// # use goldenscript::{Command, Runner, UnknownCommand};
+/// # #[derive(Default)]
+/// # struct BaseRunner;
+/// # impl Runner for BaseRunner {
+/// #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+/// # }
+/// struct ProjectRunner {
+///     base: BaseRunner,
+/// }
+///
+/// impl Runner for ProjectRunner {
+///     fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+///         match command.name.as_str() {
+///             "status" => Ok("ok".to_string()),
+///             _ => Err(UnknownCommand.into()),
+///         }
+///     }
+///
+///     fn unknown_command(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+///         self.base.run(command)
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UnknownCommand;
+
+impl std::fmt::Display for UnknownCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown command")
+    }
+}
+
+impl Error for UnknownCommand {}
+
+/// An invariant violation, returned as an error by the [`Runner::end_command`]
+/// or [`Runner::check_invariants`] hooks when the runner's state is
+/// inconsistent after a command. Rather than aborting the script, the
+/// violation is rendered below the command's output with an `invariant
+/// violated:` marker, followed by a line diff of the expected and actual state
+/// if given, and the command is considered failed. The violation
+/// thus shows up in the golden output diff, and satisfies `%expect-fail`.
+///
+/// ```
+/// # use std::error::Error;
+/// # use goldenscript::{Command, InvariantViolation, RunContext, Runner};
+/// # struct Replicas { leader: String, follower: String }
+/// impl Runner for Replicas {
+/// #   fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { Ok(String::new()) }
+///     fn check_invariants(
+///         &mut self,
+///         _: &Command,
+///         _: &mut RunContext,
+///     ) -> Result<(), Box<dyn Error>> {
+///         if self.leader != self.follower {
+///             let violation = InvariantViolation::new("replicas diverged")
+///                 .with_diff(&self.leader, &self.follower);
+///             return Err(violation.into());
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+///
+/// This renders e.g.:
+///
+/// ```text
+/// invariant violated: replicas diverged
+///   a=1
+/// - b=2
+/// + b=3
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvariantViolation {
+    /// The violation message.
+    message: String,
+    /// The expected and actual state, if given.
+    diff: Option<(String, String)>,
+}
+
+impl InvariantViolation {
+    /// Creates a new invariant violation with the given message.
+    pub fn new(message: impl Into<String>) -> Self {
+        Self { message: message.into(), diff: None }
+    }
+
+    /// Adds the expected and actual state, rendered as a line diff.
+    pub fn with_diff(mut self, expected: impl Into<String>, actual: impl Into<String>) -> Self {
+        self.diff = Some((expected.into(), actual.into()));
+        self
+    }
+
+    /// Returns the violation message.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Renders the violation as command output.
+    fn render(&self, eol: &str) -> String {
+        let mut output = format!("{self}{eol}");
+        if let Some((expected, actual)) = &self.diff {
+            output.push_str(&crate::diff::render(expected, actual, eol, true));
+        }
+        output
+    }
+}
+
+impl std::fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invariant violated: {}", self.message)
+    }
+}
+
+impl Error for InvariantViolation {}
+
+/// Runs a command via [`Runner::run_ctx`], rendering its output and
+/// falling back to [`Runner::unknown_command`] if the runner doesn't recognize
+/// it.
+pub(crate) fn run_ctx<R: Runner + ?Sized>(
+    runner: &mut R,
+    command: &Command,
+    ctx: &mut RunContext,
+) -> Result<String, Box<dyn Error>> {
+    match runner.run_ctx(command, ctx) {
+        Ok(output) => Ok(output.to_string()),
+        Err(e) if e.is::<UnknownCommand>() => runner.unknown_command(command),
+        Err(e) => Err(e),
+    }
+}
+
+/// A built-in goldenscript feature that can affect the runner, and thus must
+/// be enabled by the runner via [`Runner::capabilities`] before scripts can
+/// use it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum Capability {
+    /// Pausing script execution, via `%sleep DURATION`.
+    Sleep,
+    /// Setting and clearing environment variables, via the
+    /// `%env [KEY=VALUE...] [KEY...]` directive and the `_env` built-in
+    /// command.
+    Env,
+    /// Configuring failpoints in the runner under test. Not yet used by any
+    /// built-in feature.
+    Failpoints,
+    /// Starting, stopping, and signalling processes. Not yet used by any
+    /// built-in feature.
+    ProcControl,
+    /// Running commands on separate threads via [`Runner::spawn`], for
+    /// `[concurrent]` blocks. Scripts with `[concurrent]` blocks error before
+    /// running anything if the runner hasn't enabled it.
+    Spawn,
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Sleep => write!(f, "sleep"),
+            Self::Env => write!(f, "env"),
+            Self::Failpoints => write!(f, "failpoints"),
+            Self::ProcControl => write!(f, "proc-control"),
+            Self::Spawn => write!(f, "spawn"),
+        }
+    }
+}
+
+/// Validates the output of each block after it has run, e.g. to check
+/// invariants via an external model-based oracle, without modifying the
+/// runner. Attached to a run via [`RunOptions::validator`]. Errors fail the
+/// script.
+pub trait Validator {
+    /// Validates a block, given its input (the literal block text, including
+    /// comments) and its output. Called after the block's
+    /// [`Runner::end_block`] hook, in block order.
+    fn validate_block(&mut self, input: &str, output: &str) -> Result<(), Box<dyn Error>>;
+}
+
+/// Reviews golden output updates when recording goldenfiles via
+/// `UPDATE_GOLDENFILES=1`, e.g. to never change error lines automatically.
+/// Attached to a run via [`RunOptions::review_policy`].
+pub trait ReviewPolicy {
+    /// Reviews a block whose output changed, given its input (the literal
+    /// block text, including comments), its old output, and its new output.
+    /// The old output is empty for new blocks. Returning an error rejects the
+    /// update with the given reason, keeping the old output. Called after any
+    /// validators, in block order.
+    fn review_block(&mut self, input: &str, old: &str, new: &str) -> Result<(), String>;
+}
+
+/// Receives [`Notice`]s about a run, i.e. events that don't affect its result
+/// but may be of interest to the user, e.g. skipped blocks. Attached to a run
+/// via [`RunOptions::reporter`]. Without a reporter, notices are discarded.
+/// Implemented for closures, e.g. to print notices to stderr:
+///
+/// ```
+/// let options = goldenscript::RunOptions::new().reporter(|notice: &goldenscript::Notice| {
+///     eprintln!("{notice}")
+/// });
+/// ```
+pub trait Reporter {
+    /// Reports a notice.
+    fn report(&mut self, notice: &Notice);
+}
+
+impl<F: FnMut(&Notice)> Reporter for F {
+    fn report(&mut self, notice: &Notice) {
+        self(notice)
+    }
+}
+
+/// A notice about a run, passed to a [`Reporter`]. Displays as a
+/// human-readable message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Notice {
+    /// A block tagged `[skip]` or `[skip=REASON]` wasn't run, and kept its
+    /// golden output. The reason is empty if not given.
+    BlockSkipped { line_number: u32, reason: String },
+    /// The script was halted via `%halt` or [`RunContext::halt`] in the block
+    /// at the given line, with the given number of remaining blocks not run.
+    /// The reason is empty if not given.
+    ScriptHalted { line_number: u32, reason: String, remaining: usize },
+    /// The [`ReviewPolicy`] rejected the golden output update of the block at
+    /// the given line, which kept its old output.
+    UpdateRejected { line_number: u32, reason: String },
+    /// The script at the given path wasn't completed, because the run was
+    /// cancelled via [`RunOptions::cancellation_token`].
+    ScriptNotCompleted { path: std::path::PathBuf },
+    /// The script at the given path failed as expected, since it's marked via
+    /// [`RunOptions::expect_fail`]. The failure describes the error, panic, or
+    /// differing output.
+    ExpectedFailure { path: std::path::PathBuf, failure: String },
+    /// The script's output differs from its golden output, with the given
+    /// random seed (the initial seed or the last one chosen via `%seed auto`),
+    /// which reproduces it via `GOLDENSCRIPT_SEED`.
+    OutputDiffers { seed: u64 },
+    /// The script's scratch directory at the given path was kept after the
+    /// script failed, for inspection, see [`ScratchDir::KeepOnFailure`].
+    ScratchKept { path: std::path::PathBuf },
+    /// A `%seed auto` directive at the given line chose the given random seed,
+    /// since `GOLDENSCRIPT_SEED` wasn't set.
+    AutoSeed { line_number: u32, seed: u64 },
+    /// A `%teardown` section command failed while running the section after
+    /// the script failed. The section continues with the next command.
+    TeardownFailed { error: String },
+    /// A command exceeded its `[budget=DURATION]` tag, with
+    /// [`BudgetBreach::Warn`](crate::BudgetBreach::Warn). The message gives
+    /// the command, its duration, and the budget.
+    BudgetExceeded { line_number: u32, message: String },
+    /// A command exceeded its timeout (see [`RunOptions::command_timeout`])
+    /// and hasn't returned yet. Reported once when the timeout expires, and
+    /// again with the time waited if it hasn't returned after a grace period
+    /// of the same duration. The message gives the command and timeout.
+    CommandTimedOut { message: String, waited: Option<Duration> },
+}
+
+impl std::fmt::Display for Notice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BlockSkipped { line_number, reason } => {
+                write!(f, "skipped block at line {line_number}")?;
+                if !reason.is_empty() {
+                    write!(f, ": {reason}")?;
+                }
+                Ok(())
+            }
+            Self::ScriptHalted { line_number, reason, remaining } => {
+                write!(f, "script halted in block at line {line_number}")?;
+                if !reason.is_empty() {
+                    write!(f, ": {reason}")?;
+                }
+                write!(f, " ({remaining} blocks not executed)")
+            }
+            Self::UpdateRejected { line_number, reason } => {
+                write!(f, "update rejected for block at line {line_number}: {reason}")
+            }
+            Self::ScriptNotCompleted { path } => {
+                write!(f, "{}: not completed, run cancelled", path.display())
+            }
+            Self::ExpectedFailure { path, failure } => {
+                write!(f, "{}: expected failure: {failure}", path.display())
+            }
+            Self::OutputDiffers { seed } => {
+                write!(f, "script output differs with {SEED_ENV}={seed}")
+            }
+            Self::ScratchKept { path } => write!(f, "keeping scratch directory {}", path.display()),
+            Self::AutoSeed { line_number, seed } => {
+                write!(f, "%seed auto at line {line_number}: using seed {seed}")
+            }
+            Self::TeardownFailed { error } => write!(f, "teardown failed: {error}"),
+            Self::BudgetExceeded { message, .. } => write!(f, "warning: {message}"),
+            Self::CommandTimedOut { message, waited: None } => {
+                write!(f, "{message}, waiting for it to return")
+            }
+            Self::CommandTimedOut { message, waited: Some(waited) } => {
+                write!(f, "{message}, and hasn't returned after {waited:?}")
+            }
+        }
+    }
 }
 
 /// Runs a goldenscript at the given path.
@@ -65,6 +620,16 @@ pub trait Runner {
 /// `UPDATE_GOLDENFILES=1` is set, the new output file will replace the input
 /// file.
 pub fn run<R: Runner, P: AsRef<std::path::Path>>(runner: &mut R, path: P) -> std::io::Result<()> {
+    run_with(runner, path, &RunOptions::default())
+}
+
+/// Runs a goldenscript at the given path with the given options. Otherwise
+/// identical to [`run()`].
+pub fn run_with<R: Runner, P: AsRef<std::path::Path>>(
+    runner: &mut R,
+    path: P,
+    options: &RunOptions,
+) -> std::io::Result<()> {
     let path = path.as_ref();
     let Some(dir) = path.parent() else {
         return Err(std::io::Error::new(
@@ -79,292 +644,5786 @@ pub fn run<R: Runner, P: AsRef<std::path::Path>>(runner: &mut R, path: P) -> std
         ));
     };
 
-    let input = std::fs::read_to_string(dir.join(filename))?;
-    let output = generate(runner, &input)?;
+    let input = read_script(&dir.join(filename), options)?;
+    if options.expects_failure(path) {
+        return run_expect_fail(runner, path, &input, options);
+    }
+    let output = match &options.trace_dir {
+        Some(trace_dir) => generate_traced(runner, &input, options, &trace_dir.join(filename))?,
+        None => generate_with(runner, &input, options)?,
+    };
 
-    goldenfile::Mint::new(dir).new_goldenfile(filename)?.write_all(output.as_bytes())
+    check_output(options, &dir.join(filename), &input, &output)
 }
 
-/// Generates output for a goldenscript input, without comparing them.
-pub fn generate<R: Runner>(runner: &mut R, input: &str) -> std::io::Result<String> {
-    let mut output = String::with_capacity(input.len()); // common case: output == input
+/// Compares a script's output with its input, panicking with a diff if they
+/// differ. If the environment variable `UPDATE_GOLDENFILES=1` is set, the
+/// output is instead written to the script path in the options' storage.
+fn check_output(
+    options: &RunOptions,
+    path: &std::path::Path,
+    input: &str,
+    output: &str,
+) -> std::io::Result<()> {
+    if output == input {
+        return Ok(());
+    }
+    if std::env::var("UPDATE_GOLDENFILES").is_ok_and(|v| v == "1") {
+        return options.storage.write(path, output.as_bytes());
+    }
+    crate::diff::panic_diff(&path.display().to_string(), input, output)
+}
 
-    // Detect end-of-line format.
-    let eol = match input.find("\r\n") {
-        Some(_) => "\r\n",
-        None => "\n",
-    };
+/// Where [`run_str()`] writes the new output of an embedded script when
+/// `UPDATE_GOLDENFILES=1` is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateTarget<'a> {
+    /// Writes the output to the given path, typically the script's source file
+    /// that was embedded via `include_str!`. Relative paths are relative to the
+    /// working directory, i.e. the package root for `cargo test`.
+    Path(&'a str),
+    /// Doesn't write the output anywhere, so the script can't be updated.
+    None,
+}
 
-    // Parse the script.
-    let blocks = parse(input).map_err(|e| {
-        std::io::Error::new(
-            std::io::ErrorKind::InvalidInput,
-            format!(
-                "parse error at line {} column {} for {:?}:\n{}\n{}^",
-                e.input.location_line(),
-                e.input.get_column(),
-                e.code,
-                String::from_utf8_lossy(e.input.get_line_beginning()),
-                ' '.to_string().repeat(e.input.get_utf8_column() - 1)
-            ),
-        )
-    })?;
+/// Runs a goldenscript given as a string, e.g. embedded into the test binary
+/// via `include_str!`, such that the test can run without the script files
+/// (e.g. for `cargo publish`):
+///
+/// ```no_run
+/// # struct Runner;
+/// # use goldenscript::Command;
+/// # use std::error::Error;
+/// # impl goldenscript::Runner for Runner {
+/// #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+/// # }
+/// use goldenscript::UpdateTarget;
+///
+/// let script = include_str!("../tests/scripts/comments");
+/// goldenscript::run_str(&mut Runner, script, UpdateTarget::Path("tests/scripts/comments"))
+/// # .unwrap()
+/// ```
+///
+/// Panics if the script output differs from the input. Errors on IO, parser,
+/// or runner failure. If the environment variable `UPDATE_GOLDENFILES=1` is
+/// set, the new output is instead written to the update target, if any.
+pub fn run_str<R: Runner>(
+    runner: &mut R,
+    input: &str,
+    target: UpdateTarget,
+) -> std::io::Result<()> {
+    run_str_with(runner, input, target, &RunOptions::default())
+}
 
-    // Call the start_script() hook.
-    runner.start_script().map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::Other, format!("start_script failed: {e}"))
-    })?;
+/// Runs a goldenscript given as a string with the given options. Otherwise
+/// identical to [`run_str()`].
+pub fn run_str_with<R: Runner>(
+    runner: &mut R,
+    input: &str,
+    target: UpdateTarget,
+    options: &RunOptions,
+) -> std::io::Result<()> {
+    let output = generate_with(runner, input, options)?;
+    match target {
+        UpdateTarget::Path(path) => check_output(options, path.as_ref(), input, &output),
+        UpdateTarget::None if output == input => Ok(()),
+        UpdateTarget::None => crate::diff::panic_diff("embedded script", input, &output),
+    }
+}
 
-    for (i, block) in blocks.iter().enumerate() {
-        // There may be a trailing block with no commands if the script has bare
-        // comments at the end. If so, just retain its literal contents.
-        if block.commands.is_empty() {
-            output.push_str(&block.literal);
-            continue;
+/// Reads a script file from the options' storage, handling invalid UTF-8
+/// according to the options.
+pub(crate) fn read_script(path: &std::path::Path, options: &RunOptions) -> std::io::Result<String> {
+    let data = options.storage.read(path)?;
+    match String::from_utf8(data) {
+        Ok(input) => Ok(input),
+        Err(e) if options.invalid_utf8 == InvalidUtf8::Replace => {
+            Ok(String::from_utf8_lossy(e.as_bytes()).into_owned())
         }
+        Err(e) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: invalid UTF-8 at byte {}", path.display(), e.utf8_error().valid_up_to()),
+        )),
+    }
+}
 
-        // Process each block of commands and accumulate their output.
-        let mut block_output = String::new();
+/// Generates output for a script, writing an execution trace to the given path
+/// if the script errors, panics, or its output differs from the input (unless
+/// the output will be updated). Otherwise, removes any stale trace.
+fn generate_traced<R: Runner>(
+    runner: &mut R,
+    input: &str,
+    options: &RunOptions,
+    trace_path: &std::path::Path,
+) -> std::io::Result<String> {
+    let mut trace_path = trace_path.as_os_str().to_owned();
+    trace_path.push(".trace");
 
-        // Call the start_block() hook.
-        block_output.push_str(&ensure_eol(
-            runner.start_block().map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("start_block failed at line {}: {e}", block.line_number),
-                )
-            })?,
-            eol,
-        ));
+    let mut ctx = RunContext::with_trace();
+    let generate = std::panic::AssertUnwindSafe(|| generate_ctx(runner, input, options, &mut ctx));
+    let result = std::panic::catch_unwind(generate);
+    let updating = std::env::var("UPDATE_GOLDENFILES").is_ok_and(|v| v == "1");
+    let failed = match &result {
+        Ok(Ok(output)) => output != input && !updating,
+        Ok(Err(_)) | Err(_) => true,
+    };
 
-        for command in &block.commands {
-            let mut command_output = String::new();
+    let trace = ctx.take_trace().unwrap_or_default();
+    if failed {
+        if let Some(dir) = std::path::Path::new(&trace_path).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&trace_path, trace)?;
+    } else if let Err(e) = std::fs::remove_file(&trace_path) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    result.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
 
-            // Call the start_command() hook.
-            command_output.push_str(&ensure_eol(
-                runner.start_command(command).map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("start_command failed at line {}: {e}", command.line_number),
-                    )
-                })?,
-                eol,
-            ));
+/// Runs a script that is expected to fail, via [`RunOptions::expect_fail`].
+/// Reports a failure (error, panic, or differing output) to the reporter, and
+/// errors if the script passes. Never updates the script.
+fn run_expect_fail<R: Runner>(
+    runner: &mut R,
+    path: &std::path::Path,
+    input: &str,
+    options: &RunOptions,
+) -> std::io::Result<()> {
+    let generate = std::panic::AssertUnwindSafe(|| generate_with(runner, input, options));
+    let failure = match std::panic::catch_unwind(generate) {
+        Ok(Ok(output)) if output == input => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{}: expected failure, but the script passed", path.display()),
+            ))
+        }
+        Ok(Ok(_)) => "output differs".to_string(),
+        Ok(Err(e)) => e.to_string(),
+        Err(panic) => match panic_message(&*panic) {
+            Some(message) => format!("panicked: {message}"),
+            None => "panicked".to_string(),
+        },
+    };
+    options.report(Notice::ExpectedFailure { path: path.to_path_buf(), failure });
+    Ok(())
+}
 
-            // Execute the command. Handle panics and errors if requested. We
-            // assume the command is unwind-safe when handling panics, it is up
-            // to callers to manage this appropriately.
-            let run = std::panic::AssertUnwindSafe(|| runner.run(command));
-            command_output.push_str(&match std::panic::catch_unwind(run) {
-                // Unexpected success, error out.
-                Ok(Ok(output)) if command.fail => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!(
-                            "expected command '{}' to fail at line {}, succeeded with: {output}",
-                            command.name, command.line_number
-                        ),
-                    ))
-                }
+/// Runs an output-only snapshot script at the given path, passing its entire
+/// input document to [`Runner::run_raw`]. See [`generate_snapshot()`] for the
+/// file format. Otherwise identical to [`run()`], e.g. comparing and updating
+/// the output in the same way.
+pub fn run_snapshot<R: Runner>(
+    runner: &mut R,
+    path: impl AsRef<std::path::Path>,
+) -> std::io::Result<()> {
+    run_snapshot_with(runner, path, &RunOptions::default())
+}
 
-                // Expected success, output the result.
-                Ok(Ok(output)) => output,
+/// Runs an output-only snapshot script at the given path, reading and updating
+/// it via the storage in the given options. Otherwise identical to
+/// [`run_snapshot()`].
+pub fn run_snapshot_with<R: Runner>(
+    runner: &mut R,
+    path: impl AsRef<std::path::Path>,
+    options: &RunOptions,
+) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let (Some(dir), Some(filename)) = (path.parent(), path.file_name()) else {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid path '{path:?}'"),
+        ));
+    };
 
-                // Expected error, output it.
-                Ok(Err(e)) if command.fail => format!("Error: {e}"),
+    let input = read_script(&dir.join(filename), options)?;
+    let output = generate_snapshot(runner, &input)?;
+    check_output(options, &dir.join(filename), &input, &output)
+}
 
-                // Unexpected error, return it.
-                Ok(Err(e)) => {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!(
-                            "command '{}' failed at line {}: {e}",
-                            command.name, command.line_number
-                        ),
-                    ))
-                }
+/// Checks the goldenscripts at the given paths for commands that the runner
+/// doesn't recognize, as given by [`Runner::known_commands`], without running
+/// them. Returns an error listing all unknown commands across all scripts, e.g.
+/// to detect stale scripts in a test suite upfront. Does nothing if the runner
+/// doesn't know its commands.
+pub fn check_commands<R, P>(runner: &R, paths: impl IntoIterator<Item = P>) -> std::io::Result<()>
+where
+    R: Runner,
+    P: AsRef<std::path::Path>,
+{
+    check_commands_with(runner, paths, &RunOptions::default())
+}
 
-                // Expected panic, output it.
-                Err(panic) if command.fail => {
-                    let message = panic
-                        .downcast_ref::<&str>()
-                        .map(|s| s.to_string())
-                        .or_else(|| panic.downcast_ref::<String>().cloned())
-                        .unwrap_or_else(|| std::panic::resume_unwind(panic));
-                    format!("Panic: {message}")
-                }
+/// Checks the goldenscripts at the given paths for unknown commands, reading
+/// them via the storage in the given options. Otherwise identical to
+/// [`check_commands()`].
+pub fn check_commands_with<R, P>(
+    runner: &R,
+    paths: impl IntoIterator<Item = P>,
+    options: &RunOptions,
+) -> std::io::Result<()>
+where
+    R: Runner,
+    P: AsRef<std::path::Path>,
+{
+    let mut lines = Vec::new();
+    for path in paths {
+        let path = path.as_ref();
+        let input = read_script(path, options)?;
+        let mut blocks = parse(&input).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{}: parse error at line {}", path.display(), e.input.location_line()),
+            )
+        })?;
+        expand_templates(&mut blocks)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+        for command in unknown_commands(runner, &blocks, false) {
+            lines.push(format!("{}:{}: {}", path.display(), command.line_number, command.name));
+        }
+    }
+    if !lines.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("unknown commands:\n{}", lines.join("\n")),
+        ));
+    }
+    Ok(())
+}
 
-                // Unexpected panic, throw it.
-                Err(panic) => std::panic::resume_unwind(panic),
-            });
+/// Runs a goldenscript at the given path, using a new default-constructed
+/// runner. Otherwise identical to [`run()`], e.g.:
+///
+/// ```no_run
+/// # #[derive(Default)]
+/// # struct MyRunner;
+/// # use goldenscript::Command;
+/// # use std::error::Error;
+/// # impl goldenscript::Runner for MyRunner {
+/// #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+/// #         Ok(String::new())
+/// #     }
+/// # }
+/// goldenscript::run_default::<MyRunner>("tests/scripts/test")
+/// # .unwrap()
+/// ```
+pub fn run_default<R: Runner + Default>(path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    run(&mut R::default(), path)
+}
 
-            // Make sure the command output has a trailing newline, unless empty.
-            command_output = ensure_eol(command_output, eol);
+/// Runs a goldenscript at the given path, using the given closure to run
+/// commands. Useful for trivial runners that don't need state or hooks.
+/// Otherwise identical to [`run()`], e.g.:
+///
+/// ```no_run
+/// goldenscript::run_fn("tests/scripts/test", |command| Ok(command.name.to_uppercase()))
+/// # .unwrap()
+/// ```
+pub fn run_fn<F>(path: impl AsRef<std::path::Path>, f: F) -> std::io::Result<()>
+where
+    F: FnMut(&Command) -> Result<String, Box<dyn Error>>,
+{
+    run(&mut FnRunner::stateless(f), path)
+}
 
-            // Call the end_command() hook.
-            command_output.push_str(&ensure_eol(
-                runner.end_command(command).map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        format!("end_command failed at line {}: {e}", command.line_number),
-                    )
-                })?,
-                eol,
-            ));
+/// A hook closure for [`FnRunner`].
+type Hook<'a, S, T> = Box<dyn FnMut(&mut S) -> Result<T, Box<dyn Error>> + 'a>;
 
-            // Silence the output if requested.
-            if command.silent {
-                command_output = "".to_string();
-            }
+/// A block hook closure for [`FnRunner`].
+type BlockHook<'a, S> = Box<dyn FnMut(&mut S, &BlockInfo) -> Result<String, Box<dyn Error>> + 'a>;
 
-            // Prefix output lines if requested.
-            if let Some(prefix) = &command.prefix {
-                if !command_output.is_empty() {
-                    command_output = format!(
-                        "{prefix}: {}{eol}",
-                        command_output
-                            .strip_suffix(eol)
-                            .unwrap_or(command_output.as_str())
-                            .replace('\n', &format!("\n{prefix}: "))
-                    );
-                }
-            }
+/// A command hook closure for [`FnRunner`].
+type CommandHook<'a, S> = Box<dyn FnMut(&mut S, &Command) -> Result<String, Box<dyn Error>> + 'a>;
 
-            block_output.push_str(&command_output);
-        }
+/// The result of a [`FnRunner`] command closure.
+type CommandResult = Result<String, Box<dyn Error>>;
 
-        // Call the end_block() hook.
-        block_output.push_str(&ensure_eol(
-            runner.end_block().map_err(|e| {
-                std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("end_block failed at line {}: {e}", block.line_number),
-                )
-            })?,
-            eol,
-        ));
+/// A [`Runner`] that runs commands via a closure, optionally over some owned
+/// state. Hooks can optionally be given as closures too. Useful for ad hoc
+/// runners that don't warrant a separate type and trait implementation, e.g.:
+///
+/// ```no_run
+/// # use std::collections::HashMap;
+/// let mut runner = goldenscript::FnRunner::new(HashMap::new(), |map, command| {
+///     let key = command.args[0].value.clone();
+///     let value = map.entry(key).and_modify(|v| *v += 1).or_insert(1);
+///     Ok(format!("{value}"))
+/// })
+/// .on_end_block(|map, _| Ok(format!("keys={}", map.len())));
+///
+/// goldenscript::run(&mut runner, "tests/scripts/count")
+/// # .unwrap()
+/// ```
+///
+/// Closures without state can use [`FnRunner::stateless()`], since
+/// [`FnRunner::new()`] always takes the state as its first argument (and
+/// passes it to the closure). Alternatively, use [`run_fn()`]:
+///
+/// ```no_run
+/// let mut runner = goldenscript::FnRunner::stateless(|command| Ok(command.name.to_uppercase()));
+/// goldenscript::run(&mut runner, "tests/scripts/upper")
+/// # .unwrap()
+/// ```
+///
+/// Neither the command closure nor the hook closures need to be `'static`,
+/// i.e. they can borrow from the enclosing scope for the runner's lifetime
+/// `'a`.
+pub struct FnRunner<'a, S, F> {
+    state: S,
+    run: F,
+    start_script: Option<Hook<'a, S, ()>>,
+    end_script: Option<Hook<'a, S, ()>>,
+    start_block: Option<BlockHook<'a, S>>,
+    end_block: Option<BlockHook<'a, S>>,
+    start_command: Option<CommandHook<'a, S>>,
+    end_command: Option<CommandHook<'a, S>>,
+}
 
-        // If the block doesn't have any output, default to "ok".
-        if block_output.is_empty() {
-            block_output.push_str("ok\n")
-        }
+impl<'a> FnRunner<'a, (), ()> {
+    /// Creates a new runner without state, which runs commands via the given
+    /// closure.
+    pub fn stateless<F>(
+        mut run: F,
+    ) -> FnRunner<'a, (), impl FnMut(&mut (), &Command) -> CommandResult + 'a>
+    where
+        F: FnMut(&Command) -> Result<String, Box<dyn Error>> + 'a,
+    {
+        FnRunner::new((), move |_, command: &Command| run(command))
+    }
+}
 
-        // If the block output contains blank lines, use a > prefix for it.
-        //
-        // We'd be better off using regular expressions here, but don't want to
-        // add a dependency just for this.
-        if block_output.starts_with('\n')
-            || block_output.starts_with("\r\n")
-            || block_output.contains("\n\n")
-            || block_output.contains("\n\r\n")
-        {
-            block_output = format!("> {}", block_output.replace('\n', "\n> "));
-            // We guarantee above that block output ends with a newline, so we
-            // remove the "> " at the end of the output.
-            block_output.pop();
-            block_output.pop();
-        }
-
-        // Add the resulting block to the output. If this is not the last block,
-        // also add a newline separator.
-        output.push_str(&format!("{}---{eol}{}", block.literal, block_output));
-        if i < blocks.len() - 1 {
-            output.push_str(eol);
+impl<'a, S, F> FnRunner<'a, S, F>
+where
+    F: FnMut(&mut S, &Command) -> Result<String, Box<dyn Error>>,
+{
+    /// Creates a new runner with the given state, which runs commands via the
+    /// given closure.
+    pub fn new(state: S, run: F) -> Self {
+        Self {
+            state,
+            run,
+            start_script: None,
+            end_script: None,
+            start_block: None,
+            end_block: None,
+            start_command: None,
+            end_command: None,
         }
     }
 
-    // Call the end_script() hook.
-    runner.end_script().map_err(|e| {
-        std::io::Error::new(std::io::ErrorKind::Other, format!("end_script failed: {e}"))
-    })?;
+    /// Returns a reference to the runner state.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
 
-    Ok(output)
-}
+    /// Consumes the runner, returning its state.
+    pub fn into_state(self) -> S {
+        self.state
+    }
 
-/// Appends a newline if the string is not empty and doesn't already have one.
-fn ensure_eol(mut s: String, eol: &str) -> String {
-    if let Some(c) = s.chars().next_back() {
-        if c != '\n' {
-            s.push_str(eol)
-        }
+    /// Sets a closure for the [`Runner::start_script`] hook.
+    pub fn on_start_script<H>(mut self, hook: H) -> Self
+    where
+        H: FnMut(&mut S) -> Result<(), Box<dyn Error>> + 'a,
+    {
+        self.start_script = Some(Box::new(hook));
+        self
     }
-    s
-}
 
-// NB: most tests are done as goldenscripts under tests/.
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Sets a closure for the [`Runner::end_script`] hook.
+    pub fn on_end_script<H>(mut self, hook: H) -> Self
+    where
+        H: FnMut(&mut S) -> Result<(), Box<dyn Error>> + 'a,
+    {
+        self.end_script = Some(Box::new(hook));
+        self
+    }
 
-    /// A runner which simply counts the number of times its hooks are called.
+    /// Sets a closure for the [`Runner::start_block`] hook.
+    pub fn on_start_block<H>(mut self, hook: H) -> Self
+    where
+        H: FnMut(&mut S, &BlockInfo) -> Result<String, Box<dyn Error>> + 'a,
+    {
+        self.start_block = Some(Box::new(hook));
+        self
+    }
+
+    /// Sets a closure for the [`Runner::end_block`] hook.
+    pub fn on_end_block<H>(mut self, hook: H) -> Self
+    where
+        H: FnMut(&mut S, &BlockInfo) -> Result<String, Box<dyn Error>> + 'a,
+    {
+        self.end_block = Some(Box::new(hook));
+        self
+    }
+
+    /// Sets a closure for the [`Runner::start_command`] hook.
+    pub fn on_start_command<H>(mut self, hook: H) -> Self
+    where
+        H: FnMut(&mut S, &Command) -> Result<String, Box<dyn Error>> + 'a,
+    {
+        self.start_command = Some(Box::new(hook));
+        self
+    }
+
+    /// Sets a closure for the [`Runner::end_command`] hook.
+    pub fn on_end_command<H>(mut self, hook: H) -> Self
+    where
+        H: FnMut(&mut S, &Command) -> Result<String, Box<dyn Error>> + 'a,
+    {
+        self.end_command = Some(Box::new(hook));
+        self
+    }
+}
+
+impl<S, F> Runner for FnRunner<'_, S, F>
+where
+    F: FnMut(&mut S, &Command) -> Result<String, Box<dyn Error>>,
+{
+    fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        (self.run)(&mut self.state, command)
+    }
+
+    fn start_script(&mut self, _: &mut RunContext) -> Result<(), Box<dyn Error>> {
+        self.start_script.as_mut().map_or(Ok(()), |hook| hook(&mut self.state))
+    }
+
+    fn end_script(&mut self, _: &mut RunContext) -> Result<String, Box<dyn Error>> {
+        self.end_script.as_mut().map_or(Ok(()), |hook| hook(&mut self.state))?;
+        Ok(String::new())
+    }
+
+    fn start_block(
+        &mut self,
+        block: &BlockInfo,
+        _: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        self.start_block.as_mut().map_or(Ok(String::new()), |hook| hook(&mut self.state, block))
+    }
+
+    fn end_block(
+        &mut self,
+        block: &BlockInfo,
+        _: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        (self.end_block.as_mut().map_or(Ok(String::new()), |hook| hook(&mut self.state, block)))
+            .map(Output::Text)
+    }
+
+    fn start_command(
+        &mut self,
+        command: &Command,
+        _: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        self.start_command.as_mut().map_or(Ok(String::new()), |hook| hook(&mut self.state, command))
+    }
+
+    fn end_command(
+        &mut self,
+        command: &Command,
+        _: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        self.end_command.as_mut().map_or(Ok(String::new()), |hook| hook(&mut self.state, command))
+    }
+}
+
+/// A command handler closure for [`CommandRegistry`].
+type Handler<S> = Box<dyn FnMut(&Command, &mut S) -> Result<String, Box<dyn Error>>>;
+
+/// A [`Runner`] that dispatches commands to handler closures registered by
+/// command name, with a shared mutable state. Commands without a handler
+/// error with a list of the registered command names, e.g.:
+///
+/// ```no_run
+/// # use std::collections::HashMap;
+/// let mut runner = goldenscript::CommandRegistry::new(HashMap::new())
+///     .register("get", |command, map: &mut HashMap<String, String>| {
+///         let key = command.consume_args().require_pos("key")?.value.clone();
+///         Ok(format!("{:?}", map.get(&key)))
+///     })
+///     .register("put", |command, map| {
+///         let mut args = command.consume_args();
+///         let key = args.require_pos("key")?.value.clone();
+///         let value = args.require_pos("value")?.value.clone();
+///         args.reject_rest()?;
+///         map.insert(key, value);
+///         Ok(String::new())
+///     });
+///
+/// goldenscript::run(&mut runner, "tests/scripts/kv")
+/// # .unwrap()
+/// ```
+///
+/// Unknown commands are errors at runtime rather than checked upfront, such
+/// that they can be tested with `!`.
+pub struct CommandRegistry<S> {
+    state: S,
+    handlers: BTreeMap<String, Handler<S>>,
+}
+
+impl<S: Default> Default for CommandRegistry<S> {
+    fn default() -> Self {
+        Self::new(S::default())
+    }
+}
+
+impl<S> CommandRegistry<S> {
+    /// Creates a new registry with the given state and no handlers.
+    pub fn new(state: S) -> Self {
+        Self { state, handlers: BTreeMap::new() }
+    }
+
+    /// Registers a handler for the given command name, replacing any existing
+    /// handler for it. The handler is given the command and the shared state.
+    pub fn register<H>(mut self, name: &str, handler: H) -> Self
+    where
+        H: FnMut(&Command, &mut S) -> Result<String, Box<dyn Error>> + 'static,
+    {
+        self.handlers.insert(name.to_string(), Box::new(handler));
+        self
+    }
+
+    /// Returns the registered command names, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.handlers.keys().map(|name| name.as_str())
+    }
+
+    /// Returns a reference to the state.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Returns a mutable reference to the state.
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    /// Consumes the registry, returning its state.
+    pub fn into_state(self) -> S {
+        self.state
+    }
+}
+
+impl<S> Runner for CommandRegistry<S> {
+    fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        let Some(handler) = self.handlers.get_mut(&command.name) else {
+            return Err(UnknownCommand.into());
+        };
+        handler(command, &mut self.state)
+    }
+
+    fn unknown_command(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        let names: Vec<_> = self.handlers.keys().map(|name| name.as_str()).collect();
+        Err(match names.is_empty() {
+            true => format!("unknown command '{}', no commands registered", command.name),
+            false => {
+                format!("unknown command '{}', expected one of: {}", command.name, names.join(", "))
+            }
+        }
+        .into())
+    }
+}
+
+/// A runner factory for [`PrefixRouter`], given the command prefix.
+type PrefixFactory<R> = Box<dyn FnMut(&str) -> Result<R, Box<dyn Error>>>;
+
+/// A [`Runner`] that routes commands to a child runner by command prefix, e.g.
+/// to simulate multiple clients or nodes as distinct actors. Commands without
+/// a prefix are routed to the runner for the empty prefix `""`. Runners for
+/// unseen prefixes are created via the factory, if given, and otherwise error.
+///
+/// ```no_run
+/// # #[derive(Default)]
+/// # struct Client;
+/// # use goldenscript::Command;
+/// # use std::error::Error;
+/// # impl goldenscript::Runner for Client {
+/// #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+/// # }
+/// let mut router = goldenscript::PrefixRouter::new().factory(|_prefix| Ok(Client::default()));
+/// goldenscript::run(&mut router, "tests/scripts/clients")
+/// # .unwrap()
+/// ```
+///
+/// ```text
+/// client1: put foo=bar
+/// client2: get foo
+/// ---
+/// client1: ok
+/// client2: foo=bar
+/// ```
+///
+/// The start_command, run, spawn, end_command, check_invariants, and
+/// process_output calls are routed to the command's runner, and run_raw to
+/// the runner for the empty prefix. Batches are split into consecutive runs of
+/// commands with the same prefix, each passed to its runner's run_batch. The
+/// start_block and end_block hooks are called on all runners in prefix order,
+/// concatenating their output lines, and the end_script, checkpoint, and
+/// restore calls go to all runners. The start_script hook is called on
+/// runners as they're created, and on all existing runners at the start of a
+/// script.
+///
+/// The router's capabilities are those enabled by all existing runners, and
+/// its batch size is the smallest of theirs. Its known commands are those of
+/// all runners, or unknown if any runner doesn't know its commands or runners
+/// can be created via the factory. Its serialized state contains the state of
+/// each runner by prefix, and runners missing on restore are created via the
+/// factory.
+pub struct PrefixRouter<R> {
+    runners: BTreeMap<String, R>,
+    factory: Option<PrefixFactory<R>>,
+}
+
+impl<R> Default for PrefixRouter<R> {
+    fn default() -> Self {
+        Self { runners: BTreeMap::new(), factory: None }
+    }
+}
+
+impl<R: Runner> PrefixRouter<R> {
+    /// Creates a new router without any runners or factory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a runner for the given prefix, replacing any existing runner.
+    pub fn runner(mut self, prefix: &str, runner: R) -> Self {
+        self.runners.insert(prefix.to_string(), runner);
+        self
+    }
+
+    /// Sets a factory that creates runners for unseen prefixes, given the
+    /// prefix (empty for commands without a prefix).
+    pub fn factory<F>(mut self, factory: F) -> Self
+    where
+        F: FnMut(&str) -> Result<R, Box<dyn Error>> + 'static,
+    {
+        self.factory = Some(Box::new(factory));
+        self
+    }
+
+    /// Returns the runners, by prefix.
+    pub fn runners(&self) -> &BTreeMap<String, R> {
+        &self.runners
+    }
+
+    /// Consumes the router, returning its runners by prefix.
+    pub fn into_runners(self) -> BTreeMap<String, R> {
+        self.runners
+    }
+
+    /// Returns the runner for the command's prefix, creating it via the
+    /// factory if necessary and calling its start_script hook, with the
+    /// context if given or else a new one.
+    fn route(
+        &mut self,
+        command: &Command,
+        ctx: Option<&mut RunContext>,
+    ) -> Result<&mut R, Box<dyn Error>> {
+        self.prefix_runner(command.prefix.as_deref().unwrap_or_default(), ctx)
+    }
+
+    /// Returns the runner for the given prefix, creating it via the factory if
+    /// necessary and calling its start_script hook, with the context if given
+    /// or else a new one.
+    fn prefix_runner(
+        &mut self,
+        prefix: &str,
+        ctx: Option<&mut RunContext>,
+    ) -> Result<&mut R, Box<dyn Error>> {
+        if !self.runners.contains_key(prefix) {
+            let Some(factory) = self.factory.as_mut() else {
+                return Err(match prefix {
+                    "" => "no runner for commands without a prefix".into(),
+                    prefix => format!("unknown prefix '{prefix}'").into(),
+                });
+            };
+            let mut runner = factory(prefix)?;
+            match ctx {
+                Some(ctx) => runner.start_script(ctx)?,
+                None => runner.start_script(&mut RunContext::new())?,
+            }
+            self.runners.insert(prefix.to_string(), runner);
+        }
+        Ok(self.runners.get_mut(prefix).expect("runner not found"))
+    }
+
+    /// Calls a block or script hook on all runners, concatenating their output
+    /// as separate lines.
+    fn block_hook(
+        &mut self,
+        mut hook: impl FnMut(&mut R) -> Result<String, Box<dyn Error>>,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut output = String::new();
+        for runner in self.runners.values_mut() {
+            let runner_output = hook(runner)?;
+            output.push_str(&runner_output);
+            if !runner_output.is_empty() && !runner_output.ends_with('\n') {
+                output.push('\n');
+            }
+        }
+        Ok(output)
+    }
+}
+
+impl<R: Runner> Runner for PrefixRouter<R> {
+    fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        self.route(command, None)?.run(command)
+    }
+
+    fn run_ctx(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        self.route(command, Some(ctx))?.run_ctx(command, ctx)
+    }
+
+    fn run_raw(&mut self, input: &str) -> Result<String, Box<dyn Error>> {
+        self.prefix_runner("", None)?.run_raw(input)
+    }
+
+    fn run_batch(
+        &mut self,
+        commands: &[Command],
+        ctx: &mut RunContext,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut outputs = Vec::with_capacity(commands.len());
+        for batch in commands.chunk_by(|a, b| a.prefix == b.prefix) {
+            outputs.extend(self.route(&batch[0], Some(ctx))?.run_batch(batch, ctx)?);
+        }
+        Ok(outputs)
+    }
+
+    fn spawn(&mut self, command: &Command) -> Result<Spawned, Box<dyn Error>> {
+        self.route(command, None)?.spawn(command)
+    }
+
+    fn checkpoint(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.runners.values_mut().try_for_each(|runner| runner.checkpoint(name))
+    }
+
+    fn restore(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        self.runners.values_mut().try_for_each(|runner| runner.restore(name))
+    }
+
+    fn serialize_state(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        // Each runner's state is encoded as its length-prefixed prefix and
+        // state, with little-endian u64 lengths.
+        let mut state = Vec::new();
+        for (prefix, runner) in &mut self.runners {
+            let runner_state = runner.serialize_state()?;
+            for bytes in [prefix.as_bytes(), &runner_state] {
+                state.extend((bytes.len() as u64).to_le_bytes());
+                state.extend(bytes);
+            }
+        }
+        Ok(state)
+    }
+
+    fn restore_state(&mut self, mut state: &[u8]) -> Result<(), Box<dyn Error>> {
+        /// Splits off a length-prefixed byte string.
+        fn next<'a>(state: &mut &'a [u8]) -> Result<&'a [u8], Box<dyn Error>> {
+            let len = state.get(..8).ok_or("invalid router state")?;
+            let len = u64::from_le_bytes(len.try_into()?) as usize;
+            let bytes = state[8..].get(..len).ok_or("invalid router state")?;
+            *state = &state[8 + len..];
+            Ok(bytes)
+        }
+        while !state.is_empty() {
+            let prefix = std::str::from_utf8(next(&mut state)?)?;
+            self.prefix_runner(prefix, None)?.restore_state(next(&mut state)?)?;
+        }
+        Ok(())
+    }
+
+    fn unknown_command(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        self.route(command, None)?.unknown_command(command)
+    }
+
+    fn batch_size(&self) -> usize {
+        self.runners.values().map(|runner| runner.batch_size()).min().unwrap_or(1)
+    }
+
+    fn known_commands(&self) -> Option<Vec<&str>> {
+        if self.factory.is_some() {
+            return None;
+        }
+        let mut known = Vec::new();
+        for runner in self.runners.values() {
+            known.extend(runner.known_commands()?);
+        }
+        Some(known)
+    }
+
+    fn capabilities(&self) -> HashSet<Capability> {
+        let mut runners = self.runners.values();
+        let Some(first) = runners.next() else {
+            return HashSet::new();
+        };
+        runners.fold(first.capabilities(), |capabilities, runner| {
+            &capabilities & &runner.capabilities()
+        })
+    }
+
+    fn start_script(&mut self, ctx: &mut RunContext) -> Result<(), Box<dyn Error>> {
+        self.runners.values_mut().try_for_each(|runner| runner.start_script(ctx))
+    }
+
+    fn end_script(&mut self, ctx: &mut RunContext) -> Result<String, Box<dyn Error>> {
+        self.block_hook(|runner| runner.end_script(ctx))
+    }
+
+    fn start_block(
+        &mut self,
+        block: &BlockInfo,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        self.block_hook(|runner| runner.start_block(block, ctx))
+    }
+
+    fn end_block(
+        &mut self,
+        block: &BlockInfo,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        self.block_hook(|runner| runner.end_block(block, ctx).map(|output| output.to_string()))
+            .map(Output::Text)
+    }
+
+    fn start_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        // Routing errors are returned by run_ctx() instead, as command errors.
+        match self.route(command, Some(ctx)) {
+            Ok(runner) => runner.start_command(command, ctx),
+            Err(_) => Ok(String::new()),
+        }
+    }
+
+    fn end_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        let prefix = command.prefix.as_deref().unwrap_or_default();
+        match self.runners.get_mut(prefix) {
+            Some(runner) => runner.end_command(command, ctx),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn check_invariants(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<(), Box<dyn Error>> {
+        let prefix = command.prefix.as_deref().unwrap_or_default();
+        match self.runners.get_mut(prefix) {
+            Some(runner) => runner.check_invariants(command, ctx),
+            None => Ok(()),
+        }
+    }
+
+    fn process_output(
+        &mut self,
+        command: &Command,
+        output: String,
+    ) -> Result<String, Box<dyn Error>> {
+        let prefix = command.prefix.as_deref().unwrap_or_default();
+        match self.runners.get_mut(prefix) {
+            Some(runner) => runner.process_output(command, output),
+            None => Ok(output),
+        }
+    }
+}
+
+impl<R: Runner + ?Sized> Runner for Box<R> {
+    fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        (**self).run(command)
+    }
+
+    fn run_ctx(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        (**self).run_ctx(command, ctx)
+    }
+
+    fn run_raw(&mut self, input: &str) -> Result<String, Box<dyn Error>> {
+        (**self).run_raw(input)
+    }
+
+    fn run_batch(
+        &mut self,
+        commands: &[Command],
+        ctx: &mut RunContext,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        (**self).run_batch(commands, ctx)
+    }
+
+    fn spawn(&mut self, command: &Command) -> Result<Spawned, Box<dyn Error>> {
+        (**self).spawn(command)
+    }
+
+    fn checkpoint(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        (**self).checkpoint(name)
+    }
+
+    fn restore(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        (**self).restore(name)
+    }
+
+    fn serialize_state(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+        (**self).serialize_state()
+    }
+
+    fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+        (**self).restore_state(state)
+    }
+
+    fn unknown_command(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+        (**self).unknown_command(command)
+    }
+
+    fn batch_size(&self) -> usize {
+        (**self).batch_size()
+    }
+
+    fn known_commands(&self) -> Option<Vec<&str>> {
+        (**self).known_commands()
+    }
+
+    fn capabilities(&self) -> HashSet<Capability> {
+        (**self).capabilities()
+    }
+
+    fn start_script(&mut self, ctx: &mut RunContext) -> Result<(), Box<dyn Error>> {
+        (**self).start_script(ctx)
+    }
+
+    fn end_script(&mut self, ctx: &mut RunContext) -> Result<String, Box<dyn Error>> {
+        (**self).end_script(ctx)
+    }
+
+    fn start_block(
+        &mut self,
+        block: &BlockInfo,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        (**self).start_block(block, ctx)
+    }
+
+    fn end_block(
+        &mut self,
+        block: &BlockInfo,
+        ctx: &mut RunContext,
+    ) -> Result<Output, Box<dyn Error>> {
+        (**self).end_block(block, ctx)
+    }
+
+    fn start_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        (**self).start_command(command, ctx)
+    }
+
+    fn end_command(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        (**self).end_command(command, ctx)
+    }
+
+    fn check_invariants(
+        &mut self,
+        command: &Command,
+        ctx: &mut RunContext,
+    ) -> Result<(), Box<dyn Error>> {
+        (**self).check_invariants(command, ctx)
+    }
+
+    fn process_output(
+        &mut self,
+        command: &Command,
+        output: String,
+    ) -> Result<String, Box<dyn Error>> {
+        (**self).process_output(command, output)
+    }
+}
+
+/// A runner factory for [`RunnerRegistry`].
+type RunnerFactory = Box<dyn Fn() -> Box<dyn Runner>>;
+
+/// A registry of named runner factories, used by [`run_dir()`] to select a
+/// runner for each script via its `%runner NAME` header. This allows a single
+/// directory to mix scripts targeting different subsystems, e.g.:
+///
+/// ```no_run
+/// # #[derive(Default)]
+/// # struct KVRunner;
+/// # use goldenscript::Command;
+/// # use std::error::Error;
+/// # impl goldenscript::Runner for KVRunner {
+/// #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+/// # }
+/// # #[derive(Default)]
+/// # struct RaftRunner;
+/// # impl goldenscript::Runner for RaftRunner {
+/// #     fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> { todo!() }
+/// # }
+/// let registry = goldenscript::RunnerRegistry::new()
+///     .register("kv", KVRunner::default)
+///     .register("raft", RaftRunner::default);
+/// goldenscript::run_dir(&registry, "tests/scripts")
+/// # .unwrap()
+/// ```
+#[derive(Default)]
+pub struct RunnerRegistry {
+    factories: BTreeMap<String, RunnerFactory>,
+}
+
+impl RunnerRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a factory that creates a new runner for each script with the
+    /// given `%runner` name, replacing any existing factory with that name.
+    pub fn register<R, F>(mut self, name: &str, factory: F) -> Self
+    where
+        R: Runner + 'static,
+        F: Fn() -> R + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(move || Box::new(factory())));
+        self
+    }
+
+    /// Returns the registered runner names, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(|name| name.as_str())
+    }
+
+    /// Creates a new runner with the given name, if registered.
+    pub fn new_runner(&self, name: &str) -> Option<Box<dyn Runner>> {
+        self.factories.get(name).map(|factory| factory())
+    }
+}
+
+/// Runs all goldenscripts in the given directory and its subdirectories,
+/// skipping hidden files. Each script must have a `%runner NAME` directive
+/// before any commands, and is run with a new runner from the registry.
+/// Otherwise identical to [`run()`].
+///
+/// With the `manifest` crate feature, any `goldenscripts.toml` manifest in the
+/// directory is loaded, providing defaults for the options and the runner, see
+/// [`manifest`](crate::manifest).
+pub fn run_dir(registry: &RunnerRegistry, dir: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    run_dir_with(registry, dir, &RunOptions::default())
+}
+
+/// Runs all goldenscripts in the given directory with the given options.
+/// Otherwise identical to [`run_dir()`].
+pub fn run_dir_with(
+    registry: &RunnerRegistry,
+    dir: impl AsRef<std::path::Path>,
+    options: &RunOptions,
+) -> std::io::Result<()> {
+    // Apply any manifest in the directory.
+    #[cfg(feature = "manifest")]
+    let manifest = crate::manifest::Manifest::load(dir.as_ref())?.unwrap_or_default();
+    #[cfg(feature = "manifest")]
+    let (options, default_runner) = (&manifest.apply(options.clone()), manifest.runner());
+    #[cfg(not(feature = "manifest"))]
+    let default_runner = None;
+
+    let _signals = SignalGuard::install(options)?;
+    hold_shared_fixtures(options, || {
+        run_dir_scripts(registry, dir.as_ref(), options, default_runner)
+    })
+}
+
+/// Cancels the options' cancellation token on SIGINT or SIGTERM until dropped,
+/// if enabled via RunOptions::cancel_on_signal(). A second signal terminates
+/// the process with exit code 1.
+struct SignalGuard {
+    #[cfg(feature = "signals")]
+    ids: Vec<signal_hook::SigId>,
+}
+
+impl SignalGuard {
+    /// Installs the signal handlers, if enabled.
+    #[cfg(feature = "signals")]
+    fn install(options: &RunOptions) -> std::io::Result<Self> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        let mut guard = Self { ids: Vec::new() };
+        if !options.cancel_on_signal {
+            return Ok(guard);
+        }
+        let flag = options.cancellation_token.flag();
+        for signal in [SIGINT, SIGTERM] {
+            // The shutdown handler must be registered first, to see the flag
+            // before it's set by this signal.
+            guard.ids.push(signal_hook::flag::register_conditional_shutdown(
+                signal,
+                1,
+                flag.clone(),
+            )?);
+            guard.ids.push(signal_hook::flag::register(signal, flag.clone())?);
+        }
+        Ok(guard)
+    }
+
+    /// Signal handling requires the signals feature.
+    #[cfg(not(feature = "signals"))]
+    fn install(_: &RunOptions) -> std::io::Result<Self> {
+        Ok(Self {})
+    }
+}
+
+#[cfg(feature = "signals")]
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            signal_hook::low_level::unregister(id);
+        }
+    }
+}
+
+/// Runs the given scripts in order via the given closure, until the options'
+/// cancellation token is cancelled. On cancellation, the scripts that weren't
+/// completed are reported to the reporter, and the run fails with an Interrupted error
+/// giving the number of completed scripts.
+fn run_scripts(
+    scripts: &[std::path::PathBuf],
+    options: &RunOptions,
+    mut f: impl FnMut(&std::path::Path) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let token = &options.cancellation_token;
+    let cancelled = |completed: usize, message: String| {
+        for script in &scripts[completed..] {
+            options.report(Notice::ScriptNotCompleted { path: script.clone() });
+        }
+        std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            format!("{message} ({completed} of {} scripts completed)", scripts.len()),
+        )
+    };
+    for (i, script) in scripts.iter().enumerate() {
+        if token.is_cancelled() {
+            return Err(cancelled(i, "run cancelled".to_string()));
+        }
+        match f(script) {
+            Ok(()) => {}
+            Err(e) if token.is_cancelled() => return Err(cancelled(i, e.to_string())),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Calls the given closure while holding any shared fixtures, such that all
+/// scripts it runs share them.
+fn hold_shared_fixtures(
+    options: &RunOptions,
+    f: impl FnOnce() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    for fixture in &options.shared_fixtures {
+        fixture.0.acquire();
+    }
+    let result = f();
+    let mut released = Ok(());
+    for fixture in &options.shared_fixtures {
+        released = released.and(fixture.0.release(None));
+    }
+    result?;
+    released.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+}
+
+/// Runs all goldenscripts in the given directory, for run_dir_with(). Scripts
+/// without a %runner directive use the default runner, if given.
+fn run_dir_scripts(
+    registry: &RunnerRegistry,
+    dir: &std::path::Path,
+    options: &RunOptions,
+    default_runner: Option<&str>,
+) -> std::io::Result<()> {
+    let scripts: Vec<_> = util::find_scripts(dir)?.into_iter().map(|(_, path)| path).collect();
+    run_scripts(&scripts, options, |path| {
+        let input = read_script(path, options)?;
+        let blocks = parse(input.strip_prefix('\u{feff}').unwrap_or(&input)).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{}: parse error at line {}", path.display(), e.input.location_line()),
+            )
+        })?;
+        let name = blocks
+            .iter()
+            .flat_map(|block| &block.commands)
+            .find(|command| command.directive && command.name == "runner")
+            .and_then(|directive| directive.args.first())
+            .map(|arg| &*arg.value)
+            .or(default_runner);
+        let Some(name) = name else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{}: no %runner directive", path.display()),
+            ));
+        };
+        let Some(mut runner) = registry.new_runner(name) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{}: unknown runner '{name}'", path.display()),
+            ));
+        };
+        run_with(&mut runner, path, options)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+    })
+}
+
+/// Runs a suite of goldenscripts in order against a single runner, such that
+/// later scripts see the runner state left by earlier ones. This allows
+/// splitting a large end-to-end scenario into several reviewable scripts, each
+/// with its own golden output (i.e. each script is compared and updated like
+/// with [`run()`]).
+///
+/// The suite file lists the script paths relative to the suite file's
+/// directory, one per line. Blank lines and `#` comments are ignored. For
+/// example, `tests/suites/bank.suite`:
+///
+/// ```text
+/// # Open accounts, then transfer money between them.
+/// bank/open
+/// bank/transfer
+/// ```
+///
+/// Files with a `.suite` extension are skipped by [`run_dir()`], but the
+/// listed scripts aren't, so they should be kept in a separate directory.
+pub fn run_suite<R: Runner, P: AsRef<std::path::Path>>(
+    runner: &mut R,
+    path: P,
+) -> std::io::Result<()> {
+    run_suite_with(runner, path, &RunOptions::default())
+}
+
+/// Runs a suite of goldenscripts with the given options. Otherwise identical
+/// to [`run_suite()`].
+pub fn run_suite_with<R: Runner, P: AsRef<std::path::Path>>(
+    runner: &mut R,
+    path: P,
+    options: &RunOptions,
+) -> std::io::Result<()> {
+    let path = path.as_ref();
+    let scripts = read_suite(path)?;
+    let _signals = SignalGuard::install(options)?;
+    hold_shared_fixtures(options, || {
+        run_scripts(&scripts, options, |script| {
+            run_with(runner, script, options)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {e}", script.display())))
+        })
+    })
+}
+
+/// Reads a suite file, returning the script paths it lists. Errors if any of
+/// them don't exist, before running anything.
+fn read_suite(path: &std::path::Path) -> std::io::Result<Vec<std::path::PathBuf>> {
+    let dir = path.parent().unwrap_or(std::path::Path::new(""));
+    let mut scripts = Vec::new();
+    for (i, line) in std::fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let script = dir.join(line);
+        if !script.is_file() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} at line {}: script '{line}' not found", path.display(), i + 1),
+            ));
+        }
+        scripts.push(script);
+    }
+    Ok(scripts)
+}
+
+/// Generates output for a goldenscript input, without comparing them.
+pub fn generate<R: Runner>(runner: &mut R, input: &str) -> std::io::Result<String> {
+    generate_with(runner, input, &RunOptions::default())
+}
+
+/// Generates output for a goldenscript input with the given options, without
+/// comparing them.
+pub fn generate_with<R: Runner>(
+    runner: &mut R,
+    input: &str,
+    options: &RunOptions,
+) -> std::io::Result<String> {
+    generate_ctx(runner, input, options, &mut RunContext::new())
+}
+
+/// Generates output for a goldenscript input with the given options, using the
+/// given run context, e.g. with tracing enabled. If the script fails or panics
+/// before its %teardown section has started, the section's commands are run
+/// anyway. Shared fixtures and reserved resources are released afterwards, in
+/// any case. If an initial seed is given, failures report it.
+fn generate_ctx<R: Runner>(
+    runner: &mut R,
+    input: &str,
+    options: &RunOptions,
+    ctx: &mut RunContext,
+) -> std::io::Result<String> {
+    let seed = initial_seed(options)?;
+    if let Some(seed) = seed {
+        ctx.set_seed(seed);
+    }
+    ctx.acquire_shared_fixtures(&options.shared_fixtures);
+    let mut teardown = None;
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        generate_script(runner, input, options, ctx, &mut teardown)
+    }));
+    if !matches!(result, Ok(Ok(_))) {
+        if let Some(commands) = teardown {
+            run_teardown(runner, ctx, &commands, options);
+        }
+    }
+    let released = ctx.release_shared_fixtures();
+    ctx.release_reservation();
+
+    // Report the seed chosen by the last %seed auto, if any, or the initial
+    // seed, if given. Panics are propagated with the seed appended to their
+    // message.
+    let seed = ctx.auto_seeds().last().map(|(_, seed)| *seed).or(seed);
+    let result = result.unwrap_or_else(|panic| match (seed, panic_message(&*panic)) {
+        (Some(seed), Some(message)) => panic!("{message} ({SEED_ENV}={seed})"),
+        _ => std::panic::resume_unwind(panic),
+    });
+    let output = result
+        .and_then(|output| {
+            released.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+            Ok(output)
+        })
+        .map_err(|e| match seed {
+            Some(seed) => std::io::Error::new(e.kind(), format!("{e} ({SEED_ENV}={seed})")),
+            None => e,
+        })?;
+    if let Some(seed) = seed.filter(|_| output != input) {
+        options.report(Notice::OutputDiffers { seed });
+    }
+    Ok(output)
+}
+
+/// Returns the initial random seed for a script, if given via the
+/// GOLDENSCRIPT_SEED environment variable or the options, in that order.
+fn initial_seed(options: &RunOptions) -> std::io::Result<Option<u64>> {
+    Ok(env_seed()?.or(options.seed))
+}
+
+/// Returns the names of the commands to run, if limited via the
+/// GOLDENSCRIPT_ONLY_COMMANDS environment variable or the options, in that
+/// order.
+fn only_commands(options: &RunOptions) -> Option<BTreeSet<String>> {
+    env_list(ONLY_COMMANDS_ENV).or_else(|| options.only_commands.clone())
+}
+
+/// Returns the tags of commands to skip, via the GOLDENSCRIPT_SKIP_TAGS
+/// environment variable or the options, in that order.
+fn skip_tags(options: &RunOptions) -> BTreeSet<String> {
+    env_list(SKIP_TAGS_ENV).unwrap_or_else(|| options.skip_tags.clone())
+}
+
+/// Returns a comma-separated list from the given environment variable, if set
+/// and non-empty.
+fn env_list(name: &str) -> Option<BTreeSet<String>> {
+    let list = std::env::var(name).ok().filter(|list| !list.trim().is_empty())?;
+    Some(list.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).map(String::from).collect())
+}
+
+/// Returns the random seed given via the GOLDENSCRIPT_SEED environment
+/// variable, if any.
+fn env_seed() -> std::io::Result<Option<u64>> {
+    let Ok(value) = std::env::var(SEED_ENV) else {
+        return Ok(None);
+    };
+    value.trim().parse().map(Some).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid {SEED_ENV} '{value}': {e}"),
+        )
+    })
+}
+
+/// Generates output for a goldenscript input, for generate_ctx(). Sets the
+/// given teardown to the %teardown section's commands, if any, until the
+/// section starts running.
+fn generate_script<R: Runner>(
+    runner: &mut R,
+    input: &str,
+    options: &RunOptions,
+    ctx: &mut RunContext,
+    teardown: &mut Option<Vec<Command>>,
+) -> std::io::Result<String> {
+    let mut output = String::with_capacity(input.len()); // common case: output == input
+
+    // Strip any byte order mark, which is added back below if preserved.
+    let (input, bom) = match input.strip_prefix('\u{feff}') {
+        Some(_) if options.bom == Bom::Error => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "script starts with a byte order mark (BOM)",
+            ))
+        }
+        Some(input) => (input, options.bom == Bom::Preserve),
+        None => (input, false),
+    };
+
+    // Detect end-of-line format.
+    let eol = match input.find("\r\n") {
+        Some(_) => "\r\n",
+        None => "\n",
+    };
+
+    // Parse the script and any front matter, and expand command templates.
+    let mut parsed = parse(input).map_err(format_error)?;
+    ctx.set_metadata(ScriptMetadata::parse(input)?);
+    expand_templates(&mut parsed)?;
+
+    // Check for unknown commands before running anything.
+    let unknown = unknown_commands(runner, &parsed, options.builtin_commands);
+    if !unknown.is_empty() {
+        let lines: Vec<_> =
+            unknown.iter().map(|c| format!("line {}: {}", c.line_number, c.name)).collect();
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("unknown commands:\n{}", lines.join("\n")),
+        ));
+    }
+    // Keep the blocks' existing golden output, to retry commands until their
+    // output matches it.
+    let golden: Vec<String> =
+        parsed.iter().map(|b| golden_output(&input[b.output_span.clone()], eol)).collect();
+    // Keep the input ranges of each block's output, including its final line
+    // ending, to keep skipped blocks or the rest of the script verbatim.
+    let output_ranges: Vec<Range<usize>> = (parsed.iter().map(|b| &b.output_span))
+        .map(|span| match span.is_empty() {
+            true => span.clone(),
+            false => span.start..span.end + input[span.end..].find('\n').map_or(0, |i| i + 1),
+        })
+        .collect();
+    let blocks: Vec<Block> = parsed.into_iter().map(|b| b.into_owned()).collect();
+
+    // Resolve fixture references before running anything.
+    #[cfg(feature = "fixtures")]
+    let blocks = resolve_fixtures(options, blocks)?;
+
+    // Split off any trailing %end-script block, which is regenerated below.
+    let (mut blocks, end_block) = split_end_script(blocks);
+
+    // Set up any %setup and %teardown sections, and validate any branches.
+    *teardown = prepare_sections(&mut blocks)?;
+    check_branches(&blocks)?;
+    let capabilities = runner.capabilities();
+    check_concurrent(&blocks, &capabilities)?;
+
+    // Validate the commands against the schema before running anything.
+    if let Some(schema) = &options.schema {
+        let invalid: Vec<_> = (blocks.iter().flat_map(|b| &b.commands))
+            .filter_map(|c| {
+                let error = schema.validate(c).err()?;
+                Some(format!("line {}: {}: {error}", c.line_number, c.name))
+            })
+            .collect();
+        if !invalid.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid commands:\n{}", invalid.join("\n")),
+            ));
+        }
+    }
+
+    // Set up the run context and resource limits for the script.
+    ctx.set_cancellation_token(options.cancellation_token.child());
+
+    // Reserve any resources declared via %resources, waiting for them to
+    // become available. They're released by generate_ctx() once the script
+    // and any %teardown section have run.
+    let requests = resource_requests(&blocks)?;
+    let reservation = Reservation::acquire(
+        &options.resource_pool,
+        &requests,
+        &options.resource_limits,
+        ctx.cancellation_token(),
+    )
+    .map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::Other, format!("failed to reserve resources: {e}"))
+    })?;
+    ctx.set_reservation(reservation);
+    let mut limits = Limits::new();
+
+    // Create the scratch directory, if enabled. It's removed when the guard
+    // is dropped, unless kept on failure.
+    let mut scratch = Scratch::new(options)?;
+    if let Some(scratch) = &scratch {
+        ctx.set_scratch_dir(scratch.path.clone());
+    }
+
+    // Call the start_script() hook.
+    ctx.set_prefixes(command_prefixes(&blocks));
+    runner.start_script(ctx).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::Other, format!("start_script failed: {e}"))
+    })?;
+
+    let updating = std::env::var("UPDATE_GOLDENFILES").is_ok_and(|v| v == "1");
+    let pin_seeds = options.pin_auto_seeds || updating;
+
+    // Commands to skip if only some commands are run, or if they (or their
+    // block) have a skipped tag or a condition tag that doesn't match. Built-ins
+    // always run.
+    let only_commands = only_commands(options);
+    let skip_tags = skip_tags(options);
+    let skip_command = |block: &Block, command: &Command| {
+        (only_commands.as_ref().is_some_and(|only| !only.contains(&command.name))
+            || (command.tags.iter().chain(&block.tags))
+                .any(|tag| skip_tags.contains(tag) || !condition_matches(tag, options)))
+            && !BUILTINS.contains(&command.name.as_str())
+            && !(options.builtin_commands && OPTIONAL_BUILTINS.contains(&command.name.as_str()))
+    };
+    let mut background = Vec::new();
+    let mut watchdog = None;
+    let mut block_output = String::new();
+    // The previous block's full output, for [diff] blocks.
+    let mut previous_output = String::new();
+    let mut branched = false;
+    for (i, block) in blocks.iter().enumerate() {
+        // There may be a trailing block with no commands if the script has bare
+        // comments at the end. If so, just retain its literal contents.
+        if block.commands.is_empty() {
+            output.push_str(&block.literal);
+            continue;
+        }
+
+        // Once the %teardown section starts, it's no longer run on failure.
+        if is_section(block, "teardown") {
+            teardown.take();
+        }
+
+        // Blocks tagged [skip] or [skip=REASON] aren't run, and keep their
+        // golden output verbatim.
+        if let Some(reason) = skip_reason(block) {
+            let (line_number, reason) = (block.line_number, reason.to_string());
+            options.report(Notice::BlockSkipped { line_number, reason });
+            output.push_str(&block.literal);
+            output.push_str("---");
+            output.push_str(eol);
+            output.push_str(&input[output_ranges[i].clone()]);
+            if i < blocks.len() - 1 {
+                output.push_str(eol);
+            }
+            continue;
+        }
+
+        // If enabled, restore a cached %setup section instead of running it.
+        let setup_key = (options.setup_cache.as_ref())
+            .filter(|_| is_section(block, "setup") && is_cacheable_setup(block))
+            .map(|cache| {
+                let runner_type = std::any::type_name::<R>();
+                (cache, SetupCache::key(runner_type, &block.literal, ctx.seed(), eol))
+            });
+        if let Some(cached) = setup_key.and_then(|(cache, key)| cache.get(key)) {
+            runner.restore_state(&cached.state).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("restore_state failed at line {}: {e}", block.line_number),
+                )
+            })?;
+            ctx.restore_snapshot(&cached.ctx);
+            previous_output.clone_from(&cached.output);
+            ctx.trace(format_args!("line {}: restored cached setup", block.line_number));
+            output.push_str(&block.literal);
+            output.push_str("---");
+            output.push_str(eol);
+            push_block_output(&mut output, &cached.output);
+            if i < blocks.len() - 1 {
+                output.push_str(eol);
+            }
+            continue;
+        }
+
+        // Checkpoint the state at the first %branch, and restore it at later
+        // ones, such that each branch continues from the common prefix.
+        if is_section(block, "branch") {
+            start_branch(runner, ctx, &block.commands[0], &mut branched)?;
+        }
+
+        // Process each block of commands and accumulate their output, reusing
+        // the buffer across blocks. Sequence numbers restart in each block.
+        block_output.clear();
+        ctx.reset_seq();
+
+        // Call the start_block() hook.
+        let start_output = runner.start_block(&BlockInfo::new(block), ctx).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("start_block failed at line {}: {e}", block.line_number),
+            )
+        })?;
+        ctx.trace(format_args!("line {}: start_block: {start_output:?}", block.line_number));
+        block_output.push_str(&check_control_chars(
+            hook_output(start_output, eol, options),
+            options,
+            || format!("start_block at line {}", block.line_number),
+        )?);
+
+        // If the block is expected to fail via %expect-fail, any command may
+        // fail, and at least one must.
+        let expect_fail = block.commands.iter().any(|c| c.directive && c.name == "expect-fail");
+        let mut block_failed = false;
+
+        // Set if any commands were skipped via RunOptions::only_commands(),
+        // RunOptions::skip_tags(), or condition tags.
+        let mut filtered = false;
+
+        // Run the block's commands, repeating them for [repeat=N] blocks. The
+        // outputs are collapsed if identical, or numbered otherwise.
+        let repeat = repeat_count(BlockInfo::new(block).tag_value("repeat"), block.line_number)?;
+        let commands_start = block_output.len();
+        let mut repeated = Vec::with_capacity(repeat);
+        for _ in 0..repeat {
+            // Set by the _skip built-in, skipping the rest of the block.
+            let mut skipped = false;
+
+            // Run the block's commands, in batches if requested by the runner, and
+            // in dependency order if declared. Write the outputs in declaration
+            // order as they become available.
+            //
+            // In [concurrent] blocks, commands are instead spawned via
+            // Runner::spawn() as they're reached, and joined at the end of the
+            // block. Commands with dependencies are spawned once their
+            // dependencies have been joined, such that independent commands
+            // run concurrently.
+            let concurrent = block.tags.contains("concurrent");
+            let dependencies = match concurrent {
+                true => dependencies(&block.commands)?.unwrap_or_default(),
+                false => Vec::new(),
+            };
+            let mut spawned: Vec<(usize, Background)> = Vec::new();
+            let batch_size = match expect_fail
+                || concurrent
+                || block.commands.iter().any(|c| skip_command(block, c))
+            {
+                true => 1,
+                false => runner.batch_size().max(1),
+            };
+            let mut outputs: Vec<Option<String>> = vec![None; block.commands.len()];
+            let mut next_output = 0;
+            for indexes in plan_batches(&block.commands, batch_size)? {
+                if skipped || ctx.halted().is_some() {
+                    for index in indexes {
+                        outputs[index] = Some(String::new());
+                    }
+                    continue;
+                }
+
+                // Contiguous batches borrow the block's commands, others are
+                // cloned, since run_batch() takes a slice.
+                let owned: Vec<Command>;
+                let batch = match indexes.windows(2).all(|w| w[1] == w[0] + 1) {
+                    true => &block.commands[indexes[0]..=indexes[indexes.len() - 1]],
+                    false => {
+                        owned = indexes.iter().map(|&i| block.commands[i].clone()).collect();
+                        &owned
+                    }
+                };
+
+                // Don't run further commands if the script was cancelled, either
+                // via the options or because it exceeded its max_runtime. Run any
+                // pending %teardown section and call the end_script() hook first,
+                // to let the runner clean up e.g. processes and files.
+                if ctx.cancellation_token().is_cancelled() {
+                    if let Some(commands) = teardown.take() {
+                        run_teardown(runner, ctx, &commands, options);
+                    }
+                    end_script(runner, ctx)?;
+                    let line_number = batch[0].line_number;
+                    limits.check_runtime(line_number)?;
+                    let total = blocks.iter().filter(|b| !b.commands.is_empty()).count();
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!(
+                        "script cancelled at line {line_number} ({i} of {total} blocks completed)"
+                    ),
+                    ));
+                }
+
+                let batch_outputs = match batch {
+                    [directive] if directive.directive => {
+                        vec![run_directive(
+                            ctx,
+                            &mut limits,
+                            &capabilities,
+                            options,
+                            directive,
+                            eol,
+                        )?]
+                    }
+                    [command] if skip_command(block, command) => {
+                        filtered = true;
+                        vec![ensure_eol("(skipped)".to_string(), eol)]
+                    }
+                    [command] if command.background => {
+                        background.push(spawn_command(runner, ctx, command)?);
+                        vec![String::new()]
+                    }
+                    [command] if command.name == WAIT => {
+                        vec![wait_background(runner, ctx, command, &mut background, eol, options)?]
+                    }
+                    [command] if command.name == ADVANCE_TIME || command.name == NOW => {
+                        vec![run_clock_command(ctx, command, eol)?]
+                    }
+                    [command] if command.name == CHECKPOINT || command.name == RESTORE => {
+                        vec![run_checkpoint_command(runner, ctx, command)?]
+                    }
+                    [command]
+                        if options.builtin_commands
+                            && OPTIONAL_BUILTINS.contains(&command.name.as_str()) =>
+                    {
+                        skipped = command.name == "_skip";
+                        vec![run_builtin(ctx, &capabilities, command, eol)?]
+                    }
+                    [command] if concurrent => {
+                        let after = dependencies.get(indexes[0]).map_or(&[][..], Vec::as_slice);
+                        while let Some(pos) = spawned.iter().position(|(i, _)| after.contains(i)) {
+                            let (index, dependency) = spawned.remove(pos);
+                            outputs[index] = Some(join_background(runner, ctx, dependency, eol)?);
+                        }
+                        limits.record(batch)?;
+                        spawned.push((indexes[0], spawn_command(runner, ctx, command)?));
+                        continue;
+                    }
+                    [command] => {
+                        // Retried commands must match their expected output, i.e.
+                        // the golden output lines following the output of the
+                        // preceding commands, if that matches the golden output
+                        // so far. Otherwise, e.g. for new blocks or commands run
+                        // ahead of their dependents, only errors are retried.
+                        let expected = match indexes[0] == next_output {
+                            true => golden[i].strip_prefix(block_output.as_str()),
+                            false => None,
+                        };
+                        let matches = |(output, _): &(String, bool)| {
+                            let Some(expected) = expected.filter(|e| !e.is_empty()) else {
+                                return true;
+                            };
+                            let mut written = String::new();
+                            let write = write_command_output(
+                                &mut written,
+                                command,
+                                output.clone(),
+                                eol,
+                                options,
+                            );
+                            write.is_err() || expected.starts_with(&written)
+                        };
+                        let repeat =
+                            repeat_count(command.tag_value("repeat"), command.line_number)?;
+                        let timeout = command_timeout(command, options)?;
+                        let mut repeated = Vec::with_capacity(repeat);
+                        for _ in 0..repeat {
+                            let start = Instant::now();
+                            let (output, failed) = run_with_retry(ctx, command, matches, |ctx| {
+                                let name = || {
+                                    format!(
+                                        "command '{}' at line {}",
+                                        command.name, command.line_number
+                                    )
+                                };
+                                run_with_timeout(
+                                    ctx,
+                                    options,
+                                    &mut watchdog,
+                                    timeout,
+                                    name,
+                                    |ctx| {
+                                        run_command(runner, ctx, command, expect_fail, eol, options)
+                                    },
+                                )
+                            })?;
+                            check_budget(command, start.elapsed(), options)?;
+                            block_failed |= failed;
+                            repeated.push(output);
+                        }
+                        vec![combine_repeats(repeated, eol)]
+                    }
+                    // Batches have the combined timeout of their commands.
+                    batch => {
+                        let timeout = options.command_timeout.map(|t| t * batch.len() as u32);
+                        let name = || {
+                            let line_number = batch[0].line_number;
+                            format!("batch of {} commands at line {line_number}", batch.len())
+                        };
+                        run_with_timeout(ctx, options, &mut watchdog, timeout, name, |ctx| {
+                            run_batch(runner, ctx, batch, eol, options)
+                        })?
+                    }
+                };
+                if !batch[0].directive {
+                    limits.record(batch)?;
+                }
+                for (index, output) in indexes.into_iter().zip(batch_outputs) {
+                    outputs[index] = Some(output);
+                }
+                while let Some(output) = outputs.get_mut(next_output).and_then(Option::take) {
+                    let command = &block.commands[next_output];
+                    write_command_output(&mut block_output, command, output, eol, options)?;
+                    next_output += 1;
+                }
+            }
+
+            // Join any concurrent commands, and write the remaining outputs.
+            for (index, spawned) in spawned {
+                outputs[index] = Some(join_background(runner, ctx, spawned, eol)?);
+            }
+            while let Some(output) = outputs.get_mut(next_output).and_then(Option::take) {
+                let command = &block.commands[next_output];
+                write_command_output(&mut block_output, command, output, eol, options)?;
+                next_output += 1;
+            }
+
+            repeated.push(block_output.split_off(commands_start));
+            if ctx.halted().is_some() {
+                break;
+            }
+        }
+        block_output.push_str(&combine_repeats(repeated, eol));
+
+        if expect_fail && !block_failed && !filtered && ctx.halted().is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "expected block at line {} to fail, but all commands succeeded",
+                    block.line_number
+                ),
+            ));
+        }
+
+        // Call the end_block() hook.
+        let end_output = runner.end_block(&BlockInfo::new(block), ctx).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("end_block failed at line {}: {e}", block.line_number),
+            )
+        })?;
+        let end_output = end_output.to_string();
+        ctx.trace(format_args!("line {}: end_block: {end_output:?}", block.line_number));
+        let end_output = hook_output(end_output, eol, options);
+        block_output.push_str(&check_control_chars(end_output, options, || {
+            format!("end_block at line {}", block.line_number)
+        })?);
+
+        // For [diff] blocks, output the changed lines relative to the previous
+        // block's output instead, keeping the full output for the next block.
+        if block.tags.contains("diff") {
+            let mut diff = crate::diff::render(&previous_output, &block_output, eol, false);
+            if diff.is_empty() {
+                diff = ensure_eol("(no changes)".to_string(), eol);
+            }
+            previous_output = std::mem::replace(&mut block_output, diff);
+        } else {
+            previous_output.clone_from(&block_output);
+        }
+
+        // If the block doesn't have any output, default to "ok".
+        if block_output.is_empty() {
+            block_output.push_str("ok\n")
+        }
+
+        // Cache a %setup section's result, unless it was halted or left
+        // background commands running.
+        if let Some((cache, key)) = setup_key {
+            if ctx.halted().is_none() && background.is_empty() {
+                let state = runner.serialize_state().map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("serialize_state failed at line {}: {e}", block.line_number),
+                    )
+                })?;
+                let output = block_output.clone();
+                cache.insert(key, CachedSetup { state, ctx: ctx.snapshot(), output });
+            }
+        }
+
+        // Run any validators on the block.
+        for validator in &options.validators {
+            validator.validate_block(&block.literal, &block_output).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("validation failed for block at line {}: {e}", block.line_number),
+                )
+            })?;
+        }
+
+        // For [unordered] blocks, keep the golden output if it has the same
+        // lines in any order.
+        if block.tags.contains("unordered") && same_lines(&golden[i], &block_output) {
+            block_output.clone_from(&golden[i]);
+        }
+
+        // When recording, the review policy may reject output changes.
+        if updating {
+            review_update(options, block, &golden[i], &mut block_output);
+        }
+
+        // Add the resulting block to the output, writing directly into the
+        // output buffer to avoid intermediate allocations. When recording,
+        // pin any %seed auto directives to the chosen seed.
+        match pin_seeds {
+            true => output.push_str(&pin_auto_seeds(block, ctx.auto_seeds())),
+            false => output.push_str(&block.literal),
+        }
+        output.push_str("---");
+        output.push_str(eol);
+
+        // If the block output contains blank lines, use a > prefix for each
+        // line. We guarantee above that block output ends with a newline.
+        push_block_output(&mut output, &block_output);
+
+        // If the script was halted, keep the rest of it verbatim, including
+        // any %end-script block, and report the blocks that didn't run.
+        if let Some(reason) = ctx.halted() {
+            let remaining = blocks[i + 1..].iter().filter(|b| !b.commands.is_empty()).count();
+            let (line_number, reason) = (block.line_number, reason.to_string());
+            options.report(Notice::ScriptHalted { line_number, reason, remaining });
+            output.push_str(&input[output_ranges[i].end..]);
+            break;
+        }
+
+        // If this is not the last block, also add a newline separator.
+        if i < blocks.len() - 1 {
+            output.push_str(eol);
+        }
+    }
+
+    // If halted, run any pending %teardown section and wait for background
+    // commands, ignoring their output.
+    if ctx.halted().is_some() {
+        if let Some(commands) = teardown.take() {
+            run_teardown(runner, ctx, &commands, options);
+        }
+        for background in std::mem::take(&mut background) {
+            join_background(runner, ctx, background, eol)?;
+        }
+    }
+
+    // All background commands must have been waited for.
+    if !background.is_empty() {
+        let commands: Vec<_> = (background.iter().map(|b| &b.command))
+            .map(|c| format!("'{}' at line {}", c.name, c.line_number))
+            .collect();
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("background commands not waited for via {WAIT}: {}", commands.join(", ")),
+        ));
+    }
+
+    // Call the end_script() hook, and append any output as a trailing
+    // %end-script block.
+    let end_output = end_script(runner, ctx)?;
+    let end_output =
+        check_control_chars(ensure_eol(end_output, eol), options, || "end_script".to_string())?;
+    if !end_output.is_empty() && ctx.halted().is_none() {
+        if !output.is_empty() {
+            output.push_str(eol);
+        }
+        match &end_block {
+            Some(block) => output.push_str(&block.literal),
+            None => {
+                output.push_str("%end-script");
+                output.push_str(eol);
+            }
+        }
+        output.push_str("---");
+        output.push_str(eol);
+        push_block_output(&mut output, &end_output);
+    }
+
+    // Apply the final newline and BOM policies.
+    match options.final_newline {
+        FinalNewline::Keep => {}
+        FinalNewline::Always => output = ensure_eol(output, eol),
+        FinalNewline::Never => output.truncate(output.trim_end_matches(['\r', '\n']).len()),
+    }
+    if bom {
+        output.insert(0, '\u{feff}');
+    }
+
+    if let Some(scratch) = &mut scratch {
+        scratch.succeeded = true;
+    }
+    Ok(output)
+}
+
+/// Returns true if the strings have the same lines in any order, including
+/// duplicates.
+fn same_lines(a: &str, b: &str) -> bool {
+    let (mut a, mut b): (Vec<_>, Vec<_>) = (a.lines().collect(), b.lines().collect());
+    a.sort_unstable();
+    b.sort_unstable();
+    a == b
+}
+
+/// Reviews a block's output update via the review policy, if any. If rejected,
+/// reports it to the reporter and keeps the old output.
+fn review_update(options: &RunOptions, block: &Block, old: &str, new: &mut String) {
+    let Some(policy) = &options.review_policy else { return };
+    if old == new {
+        return;
+    }
+    if let Err(reason) = policy.review_block(&block.literal, old, new) {
+        options.report(Notice::UpdateRejected { line_number: block.line_number, reason });
+        old.clone_into(new);
+    }
+}
+
+/// Returns true if the block is a section of the given kind, i.e. setup or
+/// teardown, starting with the corresponding directive.
+fn is_section(block: &Block, kind: &str) -> bool {
+    block.commands.first().is_some_and(|c| c.directive && c.name == kind)
+}
+
+/// Returns the skip reason of a block tagged [skip] or [skip=REASON], if any.
+/// The reason is empty for [skip].
+fn skip_reason(block: &Block) -> Option<&str> {
+    (block.tags.iter()).find_map(|tag| match tag.as_str() {
+        "skip" => Some(""),
+        tag => tag.strip_prefix("skip="),
+    })
+}
+
+/// Returns true unless the tag is a condition tag that doesn't match the
+/// current platform or enabled conditions, i.e. `[unix]`, `[windows]`, or
+/// `[cfg:NAME]`, see [`RunOptions::cfg`].
+fn condition_matches(tag: &str, options: &RunOptions) -> bool {
+    match tag {
+        "unix" | "windows" => options.cfg_enabled(tag),
+        tag => match tag.strip_prefix("cfg:") {
+            Some(name) => options.cfg_enabled(name),
+            None => true,
+        },
+    }
+}
+
+/// Returns true if a %setup section can be cached, i.e. it doesn't contain
+/// other directives or background commands, which affect state that isn't
+/// cached.
+fn is_cacheable_setup(block: &Block) -> bool {
+    block.commands.iter().skip(1).all(|c| !c.directive && !c.background)
+}
+
+/// Validates any %setup and %teardown sections, which must be the first and
+/// last blocks respectively, starting with the directive. Silences the
+/// sections' commands unless the directive has the verbose flag. Returns the
+/// teardown section's commands, if any.
+fn prepare_sections(blocks: &mut [Block]) -> std::io::Result<Option<Vec<Command>>> {
+    let first = blocks.iter().position(|b| !b.commands.is_empty());
+    let last = blocks.iter().rposition(|b| !b.commands.is_empty());
+    let mut teardown = None;
+    for (i, block) in blocks.iter_mut().enumerate() {
+        let mut verbose = None;
+        for (j, command) in block.commands.iter().enumerate() {
+            let position = match command.name.as_str() {
+                "setup" if command.directive => (first, "first"),
+                "teardown" if command.directive => (last, "last"),
+                _ => continue,
+            };
+            let error = |message: String| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "directive %{} failed at line {}: {message}",
+                        command.name, command.line_number
+                    ),
+                )
+            };
+            if j > 0 || position.0 != Some(i) {
+                return Err(error(format!(
+                    "must be the first command in the {} block",
+                    position.1
+                )));
+            }
+            let mut args = command.consume_args();
+            verbose = Some(args.take_flag("verbose"));
+            args.reject_rest().map_err(|e| error(e.to_string()))?;
+        }
+        let Some(verbose) = verbose else {
+            continue;
+        };
+        if !verbose {
+            block.commands.iter_mut().filter(|c| !c.directive).for_each(|c| c.silent = true);
+        }
+        if is_section(block, "teardown") {
+            teardown = Some(block.commands.clone());
+        }
+    }
+    Ok(teardown)
+}
+
+/// Validates any %branch directives, which must be the first command in their
+/// block and take a unique branch name.
+fn check_branches(blocks: &[Block]) -> std::io::Result<()> {
+    let mut names = HashSet::new();
+    for (i, command) in blocks.iter().flat_map(|b| b.commands.iter().enumerate()) {
+        if !command.directive || command.name != "branch" {
+            continue;
+        }
+        let error = |message: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("directive %branch failed at line {}: {message}", command.line_number),
+            )
+        };
+        if i > 0 {
+            return Err(error("must be the first command in a block"));
+        }
+        let name = match command.args.as_slice() {
+            [arg] if arg.key.is_none() => &arg.value,
+            _ => return Err(error("takes a single name argument")),
+        };
+        if !names.insert(name) {
+            return Err(error(&format!("duplicate branch '{name}'")));
+        }
+    }
+    Ok(())
+}
+
+/// Starts a branch at a %branch directive. The first branch checkpoints the
+/// runner and context state, and later branches restore it.
+fn start_branch<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    directive: &Command,
+    branched: &mut bool,
+) -> std::io::Result<()> {
+    let result = match std::mem::replace(branched, true) {
+        false => runner.checkpoint(BRANCH_CHECKPOINT).map(|_| ctx.checkpoint(BRANCH_CHECKPOINT)),
+        true => runner.restore(BRANCH_CHECKPOINT).map(|_| ctx.restore(BRANCH_CHECKPOINT)),
+    };
+    ctx.trace(format_args!("line {}: %branch {}", directive.line_number, directive.args[0].value));
+    result.map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("directive %branch failed at line {}: {e}", directive.line_number),
+        )
+    })
+}
+
+/// Runs a %teardown section's commands after the script failed, ignoring their
+/// output. Failures are reported to the reporter, since the script already
+/// failed.
+fn run_teardown<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    commands: &[Command],
+    options: &RunOptions,
+) {
+    for command in commands.iter().filter(|c| !c.directive) {
+        let error = match run_command(runner, ctx, command, true, "\n", options) {
+            Ok((output, true)) if !command.fail => format!(
+                "command '{}' failed at line {}: {}",
+                command.name,
+                command.line_number,
+                output.trim_end()
+            ),
+            Ok(_) => continue,
+            Err(e) => e.to_string(),
+        };
+        options.report(Notice::TeardownFailed { error });
+    }
+}
+
+/// Splits off a trailing %end-script block, if any. It must be the last block,
+/// and contain only the %end-script directive. Other %end-script directives
+/// error when run.
+fn split_end_script(mut blocks: Vec<Block>) -> (Vec<Block>, Option<Block>) {
+    let is_end_script = |block: &Block| match block.commands.as_slice() {
+        [command] => command.directive && command.name == "end-script",
+        _ => false,
+    };
+    let end_block = match blocks.last() {
+        Some(block) if is_end_script(block) => blocks.pop(),
+        _ => None,
+    };
+    (blocks, end_block)
+}
+
+/// Generates output for an output-only snapshot script, without comparing
+/// them. The script consists of a single input document, which is passed to
+/// [`Runner::run_raw`] in its entirety, followed by a `---` separator line and
+/// the output, formatted as for a regular block:
+///
+/// ```text
+/// SELECT *
+/// FROM users
+/// ---
+/// Projection: *
+///   Scan: users
+/// ```
+///
+/// The input document ends at the first `---` line, and can't itself contain
+/// one. Any existing output after it is replaced. Comments and directives are
+/// not recognized. The [`Runner::start_script`] and [`Runner::end_script`]
+/// hooks are called before and after the document is run, with a new
+/// [`RunContext`], and any end_script output is ignored.
+pub fn generate_snapshot<R: Runner>(runner: &mut R, input: &str) -> std::io::Result<String> {
+    let eol = match input.find("\r\n") {
+        Some(_) => "\r\n",
+        None => "\n",
+    };
+
+    // Strip any existing output, from the first --- separator line.
+    let mut document = input;
+    let mut offset = 0;
+    for line in input.split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']) == "---" {
+            document = &input[..offset];
+            break;
+        }
+        offset += line.len();
+    }
+
+    let mut ctx = RunContext::new();
+    runner.start_script(&mut ctx).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::Other, format!("start_script failed: {e}"))
+    })?;
+    let mut document_output = runner.run_raw(document).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::Other, format!("run_raw failed: {e}"))
+    })?;
+    runner.end_script(&mut ctx).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::Other, format!("end_script failed: {e}"))
+    })?;
+
+    document_output = ensure_eol(document_output, eol);
+    if document_output.is_empty() {
+        document_output.push_str("ok\n");
+    }
+
+    let mut output = String::with_capacity(document.len() + document_output.len() + 5);
+    output.push_str(document);
+    if !document.is_empty() && !document.ends_with('\n') {
+        output.push_str(eol);
+    }
+    output.push_str("---");
+    output.push_str(eol);
+    push_block_output(&mut output, &document_output);
+    Ok(output)
+}
+
+/// Appends a block's output to the script output. If the block output
+/// contains blank lines, each line is prefixed with >. The block output must
+/// end with a newline.
+fn push_block_output(output: &mut String, block_output: &str) {
+    // We'd be better off using regular expressions here, but don't want to
+    // add a dependency just for this.
+    if block_output.starts_with('\n')
+        || block_output.starts_with("\r\n")
+        || block_output.contains("\n\n")
+        || block_output.contains("\n\r\n")
+    {
+        for line in block_output.split_inclusive('\n') {
+            output.push_str("> ");
+            output.push_str(line);
+        }
+    } else {
+        output.push_str(block_output);
+    }
+}
+
+/// Returns a block's golden output as the raw block output, i.e. removing any
+/// "> " line prefixes added by push_block_output() and adding back the final
+/// newline. Empty if the block has no output yet.
+fn golden_output(output: &str, eol: &str) -> String {
+    if output.is_empty() {
+        return String::new();
+    }
+    let mut golden: String = match output.starts_with('>') {
+        true => (output.split_inclusive('\n'))
+            .map(|line| line.strip_prefix("> ").or_else(|| line.strip_prefix('>')).unwrap_or(line))
+            .collect(),
+        false => output.to_string(),
+    };
+    golden.push_str(eol);
+    golden
+}
+
+/// Plans the execution of a block's commands as a sequence of batches of
+/// command indexes, with up to batch_size commands each. Directives, commands
+/// that may fail, background commands, built-ins, and commands with a budget,
+/// timeout, or retries are always run individually.
+///
+/// Commands are normally run in declaration order. If any command declares a
+/// dependency via an [after=@LABEL] tag, the commands are instead run in
+/// dependency order: each round batches all commands whose dependencies have
+/// completed, which the runner may run concurrently via Runner::run_batch().
+/// Directives act as barriers, running after all preceding commands and before
+/// all following commands. This only orders the commands, it doesn't run them
+/// concurrently: in [concurrent] blocks, where batch_size is 1, the caller
+/// spawns each command once its dependencies have been joined.
+fn plan_batches(commands: &[Command], batch_size: usize) -> std::io::Result<Vec<Vec<usize>>> {
+    let individual = |c: &Command| {
+        c.fail
+            || c.directive
+            || c.background
+            || BUILTINS.contains(&c.name.as_str())
+            || OPTIONAL_BUILTINS.contains(&c.name.as_str())
+            || ["budget", "timeout", "retry", "repeat"].iter().any(|tag| c.tag_value(tag).is_some())
+    };
+    let mut batches = Vec::new();
+    let mut batch_commands = |indexes: Vec<usize>| {
+        let mut batch = Vec::new();
+        for index in indexes {
+            if individual(&commands[index]) {
+                if !batch.is_empty() {
+                    batches.push(std::mem::take(&mut batch));
+                }
+                batches.push(vec![index]);
+                continue;
+            }
+            batch.push(index);
+            if batch.len() >= batch_size {
+                batches.push(std::mem::take(&mut batch));
+            }
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+    };
+
+    let Some(dependencies) = dependencies(commands)? else {
+        batch_commands((0..commands.len()).collect());
+        return Ok(batches);
+    };
+    let mut done = vec![false; commands.len()];
+    while let Some(pending) = done.iter().position(|d| !d) {
+        let ready: Vec<usize> = (0..commands.len())
+            .filter(|&i| !done[i] && dependencies[i].iter().all(|&d| done[d]))
+            .collect();
+        if ready.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("dependency cycle at line {}", commands[pending].line_number),
+            ));
+        }
+        ready.iter().for_each(|&i| done[i] = true);
+        batch_commands(ready);
+    }
+    Ok(batches)
+}
+
+/// Returns the dependencies of each command by index, as declared via [@LABEL]
+/// and [after=@LABEL] tags, or None if the commands don't declare any.
+fn dependencies(commands: &[Command]) -> std::io::Result<Option<Vec<Vec<usize>>>> {
+    if !commands.iter().any(|c| c.tag_value("after").is_some()) {
+        return Ok(None);
+    }
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+
+    let mut labels = HashMap::new();
+    for (index, command) in commands.iter().enumerate() {
+        for label in command.tags.iter().filter(|t| t.starts_with('@')) {
+            if labels.insert(label.as_str(), index).is_some() {
+                return Err(invalid(format!(
+                    "duplicate label '{label}' at line {}",
+                    command.line_number
+                )));
+            }
+        }
+    }
+
+    let mut dependencies = vec![Vec::new(); commands.len()];
+    for (index, command) in commands.iter().enumerate() {
+        for tag in &command.tags {
+            let Some(label) = tag.strip_prefix("after=") else {
+                continue;
+            };
+            let line_number = command.line_number;
+            if !label.starts_with('@') {
+                return Err(invalid(format!(
+                    "invalid dependency tag '{tag}' at line {line_number}, expected after=@LABEL"
+                )));
+            }
+            let Some(&dependency) = labels.get(label) else {
+                return Err(invalid(format!("unknown dependency '{tag}' at line {line_number}")));
+            };
+            dependencies[index].push(dependency);
+        }
+        if command.directive {
+            (0..index).for_each(|i| dependencies[index].push(i));
+            (index + 1..commands.len()).for_each(|i| dependencies[i].push(index));
+        }
+    }
+    Ok(Some(dependencies))
+}
+
+/// Replaces argument values that are fixture references with the fixture
+/// content, if a fixture store is given in the options.
+#[cfg(feature = "fixtures")]
+fn resolve_fixtures(options: &RunOptions, mut blocks: Vec<Block>) -> std::io::Result<Vec<Block>> {
+    let Some(store) = &options.fixtures else {
+        return Ok(blocks);
+    };
+    for command in blocks.iter_mut().flat_map(|b| &mut b.commands) {
+        for arg in &mut command.args {
+            if crate::fixtures::is_reference(&arg.value) {
+                arg.value = store.get_string(&arg.value).map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("line {}: {e}", command.line_number))
+                })?;
+            }
+        }
+    }
+    Ok(blocks)
+}
+
+/// Returns the distinct command prefixes in the given blocks, mapped to the
+/// number of commands using them.
+fn command_prefixes(blocks: &[Block]) -> BTreeMap<String, usize> {
+    let mut prefixes = BTreeMap::new();
+    for command in blocks.iter().flat_map(|b| &b.commands) {
+        if let Some(prefix) = &command.prefix {
+            *prefixes.entry(prefix.clone()).or_default() += 1;
+        }
+    }
+    prefixes
+}
+
+/// Calls the end_script() hook, returning its output.
+fn end_script<R: Runner>(runner: &mut R, ctx: &mut RunContext) -> std::io::Result<String> {
+    runner.end_script(ctx).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::Other, format!("end_script failed: {e}"))
+    })
+}
+
+/// Runs a single command, including its start_command() and end_command()
+/// hooks, and returns its output. Handles expected panics and errors.
+fn run_command<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    command: &Command,
+    may_fail: bool,
+    eol: &str,
+    options: &RunOptions,
+) -> std::io::Result<(String, bool)> {
+    // Call the start_command() hook.
+    let mut command_output = start_command(runner, ctx, command, eol, options)?;
+
+    // Execute the command. Handle panics and errors if requested, either via
+    // the command's ! marker or may_fail. We assume the command is unwind-safe
+    // when handling panics, it is up to callers to manage this appropriately.
+    let fail = command.fail || may_fail;
+    let mut failed = true;
+    let start = Instant::now();
+    let run = std::panic::AssertUnwindSafe(|| run_ctx(runner, command, ctx));
+    let result = std::panic::catch_unwind(run);
+    let elapsed = start.elapsed();
+    let output = match result {
+        // Unexpected success, error out.
+        Ok(Ok(output)) if command.fail => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "expected command '{}' to fail at line {}, succeeded with: {output}",
+                    command.name, command.line_number
+                ),
+            ))
+        }
+
+        // Expected success, output the result.
+        Ok(Ok(output)) => {
+            failed = false;
+            output
+        }
+
+        // Expected error, output it.
+        Ok(Err(e)) if fail => format!("Error: {e}"),
+
+        // Unexpected error, return it.
+        Ok(Err(e)) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("command '{}' failed at line {}: {e}", command.name, command.line_number),
+            ))
+        }
+
+        // Expected panic, output it.
+        Err(panic) if fail => {
+            let message =
+                panic_message(&*panic).unwrap_or_else(|| std::panic::resume_unwind(panic));
+            format!("Panic: {message}")
+        }
+
+        // Unexpected panic, throw it.
+        Err(panic) => std::panic::resume_unwind(panic),
+    };
+    ctx.trace(format_args!(
+        "line {}: run '{}' ({elapsed:?}): {output:?}",
+        command.line_number, command.name
+    ));
+    command_output.push_str(&process_output(runner, command, output)?);
+
+    // Make sure the command output has a trailing newline, unless empty.
+    command_output = ensure_eol(command_output, eol);
+
+    // Call the end_command() and check_invariants() hooks. An invariant
+    // violation fails the command.
+    let (end_output, violated) = end_command(runner, ctx, command, eol, options)?;
+    command_output.push_str(&end_output);
+
+    Ok((command_output, failed || violated))
+}
+
+/// Returns a panic's message, if it's a string.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> Option<String> {
+    (panic.downcast_ref::<&str>().map(|s| s.to_string()))
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+}
+
+/// Spawns a background command via Runner::spawn() on a separate thread.
+fn spawn_command<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    command: &Command,
+) -> std::io::Result<Background> {
+    let spawned = runner.spawn(command).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "command '{}' failed to spawn at line {}: {e}",
+                command.name, command.line_number
+            ),
+        )
+    })?;
+    ctx.trace(format_args!("line {}: spawn '{}'", command.line_number, command.name));
+    let handle = std::thread::Builder::new()
+        .name(format!("goldenscript-{}", command.name))
+        .spawn(spawned)?;
+    Ok(Background { command: command.clone(), handle })
+}
+
+/// Runs the built-in _wait command, which waits for all background commands
+/// to complete and returns their outputs in launch order.
+fn wait_background<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    wait: &Command,
+    background: &mut Vec<Background>,
+    eol: &str,
+    options: &RunOptions,
+) -> std::io::Result<String> {
+    if !wait.args.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{WAIT} takes no arguments at line {}", wait.line_number),
+        ));
+    }
+    let mut output = String::new();
+    for background in background.drain(..) {
+        let command = background.command.clone();
+        let command_output = join_background(runner, ctx, background, eol)?;
+        write_command_output(&mut output, &command, command_output, eol, options)?;
+    }
+    Ok(output)
+}
+
+/// Runs the built-in virtual clock commands: _advance_time DURATION advances
+/// the clock, and _now outputs its current time.
+fn run_clock_command(ctx: &RunContext, command: &Command, eol: &str) -> std::io::Result<String> {
+    let error = |message: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{message} at line {}", command.line_number),
+        )
+    };
+    match (command.name.as_str(), command.args.as_slice()) {
+        (NOW, []) => Ok(format!("{:?}{eol}", ctx.clock().now())),
+        (NOW, _) => Err(error(format!("{NOW} takes no arguments"))),
+        (_, [arg]) if arg.key.is_none() => {
+            let duration = arg.parse_duration().map_err(|e| {
+                let line_number = command.line_number;
+                let message = format!("invalid {ADVANCE_TIME} at line {line_number}: {e}");
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+            })?;
+            ctx.clock().advance(duration);
+            Ok(String::new())
+        }
+        (_, _) => Err(error(format!("{ADVANCE_TIME} takes a single duration argument"))),
+    }
+}
+
+/// Runs the built-in checkpoint commands: _checkpoint NAME saves a checkpoint
+/// of the runner and context state, and _restore NAME restores it.
+fn run_checkpoint_command<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    command: &Command,
+) -> std::io::Result<String> {
+    let name = match command.args.as_slice() {
+        [arg] if arg.key.is_none() => arg.value.as_str(),
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} takes a single name argument at line {}",
+                    command.name, command.line_number
+                ),
+            ))
+        }
+    };
+    let result = match command.name.as_str() {
+        CHECKPOINT => runner.checkpoint(name).map(|_| ctx.checkpoint(name)),
+        _ if !ctx.has_checkpoint(name) => Err(format!("unknown checkpoint '{name}'").into()),
+        _ => runner.restore(name).map(|_| ctx.restore(name)),
+    };
+    ctx.trace(format_args!("line {}: {} {name}", command.line_number, command.name));
+    result.map(|_| String::new()).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("command '{}' failed at line {}: {e}", command.name, command.line_number),
+        )
+    })
+}
+
+/// Runs an optional built-in command, see RunOptions::builtin_commands().
+fn run_builtin(
+    ctx: &mut RunContext,
+    capabilities: &HashSet<Capability>,
+    command: &Command,
+    eol: &str,
+) -> std::io::Result<String> {
+    let result = match command.name.as_str() {
+        "_comment" => Ok(String::new()),
+        "_env" => require_capability(capabilities, Capability::Env)
+            .and_then(|_| builtin_env(ctx, command)),
+        "_set" => builtin_set(ctx, command),
+        "_skip" => builtin_skip(command),
+        "_sleep" => directive_sleep(ctx, command),
+        name => panic!("unknown built-in command {name}"),
+    };
+    result.map(|output| ensure_eol(output, eol)).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("command '{}' failed at line {}: {e}", command.name, command.line_number),
+        )
+    })
+}
+
+/// _set [KEY=VALUE...] [KEY...]: sets the given script variables, available
+/// via RunContext::var(), and unsets the given bare keys.
+fn builtin_set(ctx: &mut RunContext, command: &Command) -> Result<String, Box<dyn Error>> {
+    for arg in &command.args {
+        match &arg.key {
+            Some(key) => ctx.set_var(key.clone(), Some(arg.value.clone())),
+            None => ctx.set_var(arg.value.clone(), None),
+        }
+    }
+    Ok(String::new())
+}
+
+/// _env [KEY=VALUE...] [KEY...]: sets the given script environment variables,
+/// available via RunContext::env(), and unsets the given bare keys. These
+/// don't affect the process environment, and are dropped at the end of the
+/// script. Requires Capability::Env.
+fn builtin_env(ctx: &mut RunContext, command: &Command) -> Result<String, Box<dyn Error>> {
+    for arg in &command.args {
+        match &arg.key {
+            Some(key) => ctx.set_env(key.clone(), Some(arg.value.clone())),
+            None => ctx.set_env(arg.value.clone(), None),
+        }
+    }
+    Ok(String::new())
+}
+
+/// _skip [REASON...]: skips the rest of the block, outputting the reason.
+fn builtin_skip(command: &Command) -> Result<String, Box<dyn Error>> {
+    if let Some(arg) = command.args.iter().find(|arg| arg.key.is_some()) {
+        return Err(format!("invalid argument '{}'", arg.name()).into());
+    }
+    let reason: Vec<_> = command.args.iter().map(|arg| arg.value.as_str()).collect();
+    match reason.is_empty() {
+        true => Ok("skipped".to_string()),
+        false => Ok(format!("skipped: {}", reason.join(" "))),
+    }
+}
+
+/// Waits for a background command to complete, returning its output.
+/// Failures are handled as for regular commands.
+fn join_background<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    background: Background,
+    eol: &str,
+) -> std::io::Result<String> {
+    let Background { command, handle } = background;
+    let start = Instant::now();
+    let output = match handle.join() {
+        Ok(Ok(output)) if command.fail => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!(
+                    "expected command '{}' to fail at line {}, succeeded with: {output}",
+                    command.name, command.line_number
+                ),
+            ))
+        }
+        Ok(Ok(output)) => output,
+        Ok(Err(e)) if command.fail => format!("Error: {e}"),
+        Ok(Err(e)) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("command '{}' failed at line {}: {e}", command.name, command.line_number),
+            ))
+        }
+        Err(panic) if command.fail => {
+            let message =
+                panic_message(&*panic).unwrap_or_else(|| std::panic::resume_unwind(panic));
+            format!("Panic: {message}")
+        }
+        Err(panic) => std::panic::resume_unwind(panic),
+    };
+    ctx.trace(format_args!(
+        "line {}: wait '{}' ({:?}): {output:?}",
+        command.line_number,
+        command.name,
+        start.elapsed()
+    ));
+    Ok(ensure_eol(process_output(runner, &command, output)?, eol))
+}
+
+/// Runs a batch of commands via Runner::run_batch(), returning their outputs.
+/// The start_command() hooks are called for all commands before the batch is
+/// run, and the end_command() hooks after. The commands can't expect failures.
+fn run_batch<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    commands: &[Command],
+    eol: &str,
+    options: &RunOptions,
+) -> std::io::Result<Vec<String>> {
+    let (first, last) = (&commands[0], &commands[commands.len() - 1]);
+
+    // Call the start_command() hooks.
+    let mut outputs = Vec::with_capacity(commands.len());
+    for command in commands {
+        outputs.push(start_command(runner, ctx, command, eol, options)?);
+    }
+
+    // Execute the batch. Since none of the commands are expected to fail, any
+    // panics are propagated.
+    let start = Instant::now();
+    let batch_outputs = runner.run_batch(commands, ctx).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "command batch failed at lines {}-{}: {e}",
+                first.line_number, last.line_number
+            ),
+        )
+    })?;
+    if batch_outputs.len() != commands.len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "command batch at lines {}-{} returned {} outputs for {} commands",
+                first.line_number,
+                last.line_number,
+                batch_outputs.len(),
+                commands.len()
+            ),
+        ));
+    }
+
+    ctx.trace(format_args!(
+        "lines {}-{}: run_batch ({:?})",
+        first.line_number,
+        last.line_number,
+        start.elapsed()
+    ));
+
+    // Append the command outputs and call the end_command() hooks.
+    for ((command, output), batch_output) in commands.iter().zip(&mut outputs).zip(batch_outputs) {
+        ctx.trace(format_args!(
+            "line {}: run '{}': {batch_output:?}",
+            command.line_number, command.name
+        ));
+        output.push_str(&ensure_eol(process_output(runner, command, batch_output)?, eol));
+        output.push_str(&end_command(runner, ctx, command, eol, options)?.0);
+    }
+
+    Ok(outputs)
+}
+
+/// The names of the supported % directives, handled by run_directive().
+#[cfg(feature = "lsp")]
+pub(crate) const DIRECTIVES: &[&str] = &[
+    "alias",
+    "branch",
+    "end-script",
+    "env",
+    "expect-fail",
+    "gen",
+    "halt",
+    "limits",
+    "resources",
+    "runner",
+    "seed",
+    "setup",
+    "sleep",
+    "teardown",
+    "template",
+];
+
+/// Runs a % directive, returning its output.
+fn run_directive(
+    ctx: &mut RunContext,
+    limits: &mut Limits,
+    capabilities: &HashSet<Capability>,
+    options: &RunOptions,
+    directive: &Command,
+    eol: &str,
+) -> std::io::Result<String> {
+    let result = match directive.name.as_str() {
+        "env" => require_capability(capabilities, Capability::Env)
+            .and_then(|_| directive_env(ctx, directive)),
+        "end-script" => Err("must be the only command in the last block".into()),
+        "expect-fail" => directive_expect_fail(directive),
+        "gen" => directive_gen(ctx, directive),
+        "halt" => directive_halt(ctx, directive),
+        "limits" => directive_limits(ctx, limits, directive),
+        "resources" => directive_resources(limits),
+        "runner" => directive_runner(limits, directive),
+        "seed" => directive_seed(ctx, options, directive),
+        "sleep" => require_capability(capabilities, Capability::Sleep)
+            .and_then(|_| directive_sleep(ctx, directive)),
+        // Sections and branches are handled by generate_with(), and templates
+        // and aliases are expanded when parsing the script.
+        "alias" | "branch" | "setup" | "teardown" | "template" => Ok(String::new()),
+        name => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown directive %{name} at line {}", directive.line_number),
+            ))
+        }
+    };
+    result.map(|output| ensure_eol(output, eol)).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("directive %{} failed at line {}: {e}", directive.name, directive.line_number),
+        )
+    })
+}
+
+/// Errors if the runner hasn't enabled the given capability.
+fn require_capability(
+    capabilities: &HashSet<Capability>,
+    capability: Capability,
+) -> Result<(), Box<dyn Error>> {
+    if !capabilities.contains(&capability) {
+        return Err(format!("runner does not enable the {capability} capability").into());
+    }
+    Ok(())
+}
+
+/// Checks that the runner enables Capability::Spawn if the script has any
+/// [concurrent] blocks, before running anything.
+fn check_concurrent(blocks: &[Block], capabilities: &HashSet<Capability>) -> std::io::Result<()> {
+    let Some(block) = blocks.iter().find(|b| b.tags.contains("concurrent")) else {
+        return Ok(());
+    };
+    require_capability(capabilities, Capability::Spawn).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("[concurrent] block at line {}: {e}", block.line_number),
+        )
+    })
+}
+
+/// %env [KEY=VALUE...] [KEY...]: sets the given script environment variables,
+/// and unsets the given bare keys, like the _env built-in. Requires
+/// Capability::Env.
+fn directive_env(ctx: &mut RunContext, directive: &Command) -> Result<String, Box<dyn Error>> {
+    builtin_env(ctx, directive)
+}
+
+/// %halt [REASON...]: halts the script after the directive, see
+/// RunContext::halt(). The reason is given as positional arguments.
+fn directive_halt(ctx: &mut RunContext, directive: &Command) -> Result<String, Box<dyn Error>> {
+    let mut args = directive.consume_args();
+    let reason: Vec<&str> = args.rest_pos().iter().map(|arg| &*arg.value).collect();
+    args.reject_rest()?;
+    ctx.halt(reason.join(" "));
+    Ok(String::new())
+}
+
+/// %expect-fail: expects the block to fail. This is handled by generate_with(),
+/// so the directive itself only validates its arguments.
+fn directive_expect_fail(directive: &Command) -> Result<String, Box<dyn Error>> {
+    directive.consume_args().reject_rest()?;
+    Ok(String::new())
+}
+
+/// %gen NAME KIND [ARGS...]: generates data of the given kind, storing it in
+/// the context as NAME. Outputs the data size and checksum.
+fn directive_gen(ctx: &mut RunContext, directive: &Command) -> Result<String, Box<dyn Error>> {
+    let mut args = directive.consume_args();
+    let name = args.require_pos("name")?.value.clone();
+    let kind = args.require_pos("kind")?.value.as_str();
+    let data = match kind {
+        "key" => {
+            let len = args.lookup_parse("len")?.unwrap_or(8);
+            args.reject_rest()?;
+            datagen::key(ctx, len)
+        }
+        "lorem" => {
+            let words = args.lookup_parse("words")?.unwrap_or(10);
+            args.reject_rest()?;
+            datagen::lorem(ctx, words)
+        }
+        "payload" => {
+            let size = args.require_key("size")?.parse_size()?;
+            args.reject_rest()?;
+            datagen::payload(ctx, usize::try_from(size)?)
+        }
+        kind => return Err(format!("invalid kind '{kind}'").into()),
+    };
+    let checksum = datagen::checksum(data.as_bytes());
+    let output = format!("{name}: {} bytes, checksum {checksum:08x}", data.len());
+    ctx.set_generated(name, data);
+    Ok(output)
+}
+
+/// %limits [max_commands=N] [max_runtime=DURATION]: sets script resource
+/// limits. Must be given before any commands are run. The context's
+/// cancellation token is cancelled once the max_runtime is exceeded.
+fn directive_limits(
+    ctx: &mut RunContext,
+    limits: &mut Limits,
+    directive: &Command,
+) -> Result<String, Box<dyn Error>> {
+    if limits.commands > 0 {
+        return Err("limits must be set before any commands are run".into());
+    }
+    let mut args = directive.consume_args();
+    if let Some(max_commands) = args.lookup_parse("max_commands")? {
+        limits.max_commands = Some(max_commands);
+    }
+    if let Some(arg) = args.lookup("max_runtime") {
+        limits.max_runtime = Some(util::parse_duration(&arg.value)?);
+    }
+    args.reject_rest()?;
+    if let Some(max_runtime) = limits.max_runtime {
+        let token = ctx.cancellation_token().child_with_deadline(limits.start + max_runtime);
+        ctx.set_cancellation_token(token);
+    }
+    Ok(String::new())
+}
+
+/// %resources [NAME=AMOUNT...] [NAME...]: declares the resources used by the
+/// script. These are reserved by generate_script() before running the script,
+/// so the directive itself only validates its position.
+fn directive_resources(limits: &Limits) -> Result<String, Box<dyn Error>> {
+    if limits.commands > 0 {
+        return Err("resources must be declared before any commands are run".into());
+    }
+    Ok(String::new())
+}
+
+/// Returns the resources declared by %resources directives, by name. Bare
+/// names request an amount of 1.
+fn resource_requests(blocks: &[Block]) -> std::io::Result<BTreeMap<String, u64>> {
+    let mut requests = BTreeMap::new();
+    let directives = blocks.iter().flat_map(|b| &b.commands);
+    for directive in directives.filter(|c| c.directive && c.name == "resources") {
+        for arg in &directive.args {
+            let (name, amount) = match &arg.key {
+                Some(key) => (
+                    key,
+                    arg.parse().map_err(|e| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "directive %resources failed at line {}: {e}",
+                                directive.line_number
+                            ),
+                        )
+                    })?,
+                ),
+                None => (&arg.value, 1),
+            };
+            *requests.entry(name.clone()).or_default() += amount;
+        }
+    }
+    Ok(requests)
+}
+
+/// %seed SEED|auto: seeds the context's random number generator. With auto,
+/// the seed is given by GOLDENSCRIPT_SEED if set, otherwise it's chosen at
+/// random and reported to the reporter.
+fn directive_seed(
+    ctx: &mut RunContext,
+    options: &RunOptions,
+    directive: &Command,
+) -> Result<String, Box<dyn Error>> {
+    let mut args = directive.consume_args();
+    let seed = match args.require_pos("seed")? {
+        arg if arg.value == "auto" => {
+            let seed = match env_seed()? {
+                Some(seed) => seed,
+                None => {
+                    use std::hash::{BuildHasher as _, Hasher as _};
+                    let seed =
+                        std::collections::hash_map::RandomState::new().build_hasher().finish();
+                    options.report(Notice::AutoSeed { line_number: directive.line_number, seed });
+                    seed
+                }
+            };
+            ctx.record_auto_seed(directive.line_number, seed);
+            seed
+        }
+        arg => arg.parse()?,
+    };
+    args.reject_rest()?;
+    ctx.set_seed(seed);
+    Ok(String::new())
+}
+
+/// %runner NAME: selects the runner for the script by name, via run_dir().
+/// This is handled by run_dir(), so the directive itself only validates its
+/// arguments and position, and is ignored when the script is run directly.
+fn directive_runner(limits: &Limits, directive: &Command) -> Result<String, Box<dyn Error>> {
+    if limits.commands > 0 {
+        return Err("runner must be given before any commands are run".into());
+    }
+    let mut args = directive.consume_args();
+    args.require_pos("name")?;
+    args.reject_rest()?;
+    Ok(String::new())
+}
+
+/// %sleep DURATION: pauses the script for the given duration, or until it's
+/// cancelled. Requires Capability::Sleep.
+fn directive_sleep(ctx: &RunContext, directive: &Command) -> Result<String, Box<dyn Error>> {
+    let mut args = directive.consume_args();
+    let duration = args.require_pos("duration")?.parse_duration()?;
+    args.reject_rest()?;
+    let token = ctx.cancellation_token();
+    _ = util::wait_until(duration, Duration::from_millis(10), || {
+        Ok(token.is_cancelled().then(String::new))
+    });
+    Ok(String::new())
+}
+
+/// A script's scratch directory, removed when dropped unless kept on failure.
+struct Scratch {
+    /// The scratch directory path.
+    path: std::path::PathBuf,
+    /// The scratch directory policy.
+    policy: ScratchDir,
+    /// Whether the script succeeded.
+    succeeded: bool,
+    /// The reporter to report kept directories to, if any.
+    reporter: Option<SharedReporter>,
+}
+
+impl Scratch {
+    /// Creates a new, empty scratch directory under the system temporary
+    /// directory, unless disabled by the options' policy.
+    fn new(options: &RunOptions) -> std::io::Result<Option<Self>> {
+        let policy = options.scratch_dir;
+        static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        if policy == ScratchDir::None {
+            return Ok(None);
+        }
+        let id = NEXT_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let name = format!("goldenscript-{}-{id}", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        std::fs::create_dir(&path).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("failed to create scratch directory {}: {e}", path.display()),
+            )
+        })?;
+        Ok(Some(Self { path, policy, succeeded: false, reporter: options.reporter.clone() }))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        if !self.succeeded && self.policy == ScratchDir::KeepOnFailure {
+            if let Some(reporter) = &self.reporter {
+                reporter.report(&Notice::ScratchKept { path: self.path.clone() });
+            }
+            return;
+        }
+        _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Script resource limits, set via the %limits directive.
+struct Limits {
+    /// The maximum number of commands to run.
+    max_commands: Option<usize>,
+    /// The maximum script runtime.
+    max_runtime: Option<Duration>,
+    /// The number of commands run so far.
+    commands: usize,
+    /// The script start time.
+    start: Instant,
+}
+
+impl Limits {
+    /// Creates new, unlimited limits for a script starting now.
+    fn new() -> Self {
+        Self { max_commands: None, max_runtime: None, commands: 0, start: Instant::now() }
+    }
+
+    /// Records that the given commands were run, erroring if a limit was
+    /// exceeded. The runtime is only checked after commands complete.
+    fn record(&mut self, commands: &[Command]) -> std::io::Result<()> {
+        self.commands += commands.len();
+        let line_number = commands.last().map(|c| c.line_number).unwrap_or_default();
+        if let Some(max) = self.max_commands.filter(|max| self.commands > *max) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("script exceeded max_commands={max} at line {line_number}"),
+            ));
+        }
+        self.check_runtime(line_number)
+    }
+
+    /// Errors if the script has exceeded its max_runtime, reporting the given
+    /// line number.
+    fn check_runtime(&self, line_number: u32) -> std::io::Result<()> {
+        if let Some(max) = self.max_runtime.filter(|max| self.start.elapsed() >= *max) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("script exceeded max_runtime={max:?} at line {line_number}"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the block's literal input, with any %seed auto directives replaced
+/// by the seeds chosen for them, given as line numbers and seeds.
+fn pin_auto_seeds(block: &Block, seeds: &[(u32, u64)]) -> String {
+    let mut literal = String::with_capacity(block.literal.len());
+    for (line_number, line) in (block.line_number..).zip(block.literal.split_inclusive('\n')) {
+        let seed = seeds.iter().find(|(l, _)| *l == line_number).map(|(_, seed)| seed);
+        match (seed, line.find("%seed")) {
+            (Some(seed), Some(pos)) => {
+                let (head, tail) = line.split_at(pos);
+                literal.push_str(head);
+                literal.push_str(&tail.replacen("auto", &seed.to_string(), 1));
+            }
+            _ => literal.push_str(line),
+        }
+    }
+    literal
+}
+
+/// Returns the commands in the given blocks that aren't known by the runner,
+/// if it knows its commands. Directives and built-in commands are ignored,
+/// including optional built-ins if enabled.
+fn unknown_commands<'a, R: Runner>(
+    runner: &R,
+    blocks: &'a [borrowed::Block<'a>],
+    optional_builtins: bool,
+) -> Vec<&'a borrowed::Command<'a>> {
+    let Some(known) = runner.known_commands() else {
+        return Vec::new();
+    };
+    blocks
+        .iter()
+        .flat_map(|b| &b.commands)
+        .filter(|c| !c.directive && !BUILTINS.contains(&c.name.as_ref()))
+        .filter(|c| !optional_builtins || !OPTIONAL_BUILTINS.contains(&c.name.as_ref()))
+        .filter(|c| !known.contains(&c.name.as_ref()))
+        .collect()
+}
+
+/// Calls the start_command() hook, returning its output, unless suppressed.
+fn start_command<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    command: &Command,
+    eol: &str,
+    options: &RunOptions,
+) -> std::io::Result<String> {
+    let output = runner.start_command(command, ctx).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("start_command failed at line {}: {e}", command.line_number),
+        )
+    })?;
+    ctx.trace(format_args!("line {}: start_command: {output:?}", command.line_number));
+    Ok(hook_output(output, eol, options))
+}
+
+/// Calls the end_command() hook, returning its output, unless suppressed, and
+/// then the check_invariants() hook. Any invariant violation returned by either
+/// is appended to the output, and true is returned.
+fn end_command<R: Runner>(
+    runner: &mut R,
+    ctx: &mut RunContext,
+    command: &Command,
+    eol: &str,
+    options: &RunOptions,
+) -> std::io::Result<(String, bool)> {
+    let hook_error = |hook: &str, e: Box<dyn Error>| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("{hook} failed at line {}: {e}", command.line_number),
+        )
+    };
+    let (output, mut violation) = match runner.end_command(command, ctx) {
+        Ok(output) => (output, None),
+        Err(e) => match e.downcast::<InvariantViolation>() {
+            Ok(violation) => (String::new(), Some(violation)),
+            Err(e) => return Err(hook_error("end_command", e)),
+        },
+    };
+    ctx.trace(format_args!("line {}: end_command: {output:?}", command.line_number));
+    if violation.is_none() {
+        if let Err(e) = runner.check_invariants(command, ctx) {
+            violation = Some(e.downcast().map_err(|e| hook_error("check_invariants", e))?);
+        }
+    }
+    let mut output = hook_output(output, eol, options);
+    if let Some(violation) = &violation {
+        ctx.trace(format_args!("line {}: {violation}", command.line_number));
+        output.push_str(&violation.render(eol));
+    }
+    Ok((output, violation.is_some()))
+}
+
+/// Returns a block or command hook's output with a trailing newline, or
+/// nothing if hook output is suppressed via RunOptions::suppress_hook_output().
+fn hook_output(output: String, eol: &str, options: &RunOptions) -> String {
+    match options.suppress_hook_output {
+        true => String::new(),
+        false => ensure_eol(output, eol),
+    }
+}
+
+/// Calls the process_output() hook, returning the processed output.
+fn process_output<R: Runner>(
+    runner: &mut R,
+    command: &Command,
+    output: String,
+) -> std::io::Result<String> {
+    runner.process_output(command, output).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "process_output failed for command '{}' at line {}: {e}",
+                command.name, command.line_number
+            ),
+        )
+    })
+}
+
+/// Checks a command's duration against its [budget=DURATION] tag, if any,
+/// applying the budget tolerance and breach policy.
+fn check_budget(command: &Command, elapsed: Duration, options: &RunOptions) -> std::io::Result<()> {
+    let Some(budget) = command.tag_value("budget") else {
+        return Ok(());
+    };
+    let duration = util::parse_duration(budget).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid budget tag at line {}: {e}", command.line_number),
+        )
+    })?;
+    let tolerance = options.budget_tolerance;
+    let allowed = Duration::try_from_secs_f64(duration.as_secs_f64() * (1.0 + tolerance))
+        .unwrap_or(Duration::MAX);
+    if elapsed <= allowed || options.budget_breach == BudgetBreach::Ignore {
+        return Ok(());
+    }
+
+    let mut message = format!(
+        "command '{}' at line {} took {elapsed:?}, exceeding budget {budget}",
+        command.name, command.line_number
+    );
+    if tolerance > 0.0 {
+        message.push_str(&format!(" with {}% tolerance", tolerance * 100.0));
+    }
+    match options.budget_breach {
+        BudgetBreach::Error => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, message)),
+        BudgetBreach::Warn => {
+            options.report(Notice::BudgetExceeded { line_number: command.line_number, message });
+            Ok(())
+        }
+        BudgetBreach::Ignore => Ok(()),
+    }
+}
+
+/// Runs a command via the given closure, retrying it according to its
+/// [retry=N] and [backoff=DURATION] tags, if any. The command is retried up
+/// to N times while it errors, or while its output doesn't match the golden
+/// output as determined by the matches closure. The backoff between attempts
+/// doubles after each retry. Timeouts and cancellation aren't retried. Returns
+/// the last attempt's result, so any mismatch shows up in the output.
+fn run_with_retry<T>(
+    ctx: &mut RunContext,
+    command: &Command,
+    matches: impl Fn(&T) -> bool,
+    mut f: impl FnMut(&mut RunContext) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let Some(retries) = command.tag_value("retry") else {
+        return f(ctx);
+    };
+    let invalid = |tag: &str, e: &dyn std::fmt::Display| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid {tag} tag at line {}: {e}", command.line_number),
+        )
+    };
+    let retries: u32 = retries.parse().map_err(|e| invalid("retry", &e))?;
+    let mut backoff = match command.tag_value("backoff") {
+        Some(backoff) => util::parse_duration(backoff).map_err(|e| invalid("backoff", &e))?,
+        None => Duration::ZERO,
+    };
+
+    let mut attempt = 0;
+    loop {
+        let result = f(ctx);
+        let done = match &result {
+            Ok(output) => matches(output),
+            Err(e) => e.kind() == std::io::ErrorKind::TimedOut,
+        };
+        if done || attempt >= retries || ctx.cancellation_token().is_cancelled() {
+            return result;
+        }
+        attempt += 1;
+        ctx.trace(format_args!(
+            "line {}: retry {attempt} of {retries} after {backoff:?}",
+            command.line_number
+        ));
+        std::thread::sleep(backoff);
+        backoff = backoff.saturating_mul(2);
+    }
+}
+
+/// Returns the number of times to run a command or block, given its
+/// [repeat=N] tag value, if any. Defaults to 1.
+fn repeat_count(repeat: Option<&str>, line_number: u32) -> std::io::Result<usize> {
+    let Some(repeat) = repeat else {
+        return Ok(1);
+    };
+    repeat.parse::<std::num::NonZeroUsize>().map(|n| n.get()).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid repeat tag at line {line_number}: {e}"),
+        )
+    })
+}
+
+/// Combines the outputs of a repeated command or block. If they're all
+/// identical, returns a single output, otherwise prefixes each output's lines
+/// with its repetition number, e.g. `2: `.
+fn combine_repeats(mut outputs: Vec<String>, eol: &str) -> String {
+    if outputs.windows(2).all(|w| w[0] == w[1]) {
+        return outputs.swap_remove(0);
+    }
+    let mut combined = String::new();
+    for (i, output) in outputs.iter().enumerate() {
+        let n = i + 1;
+        if output.is_empty() {
+            combined.push_str(&format!("{n}:{eol}"));
+        }
+        for line in output.lines() {
+            combined.push_str(format!("{n}: {line}").trim_end());
+            combined.push_str(eol);
+        }
+    }
+    combined
+}
+
+/// Returns a command's timeout from a [timeout=DURATION] tag or
+/// RunOptions::command_timeout, if any.
+fn command_timeout(command: &Command, options: &RunOptions) -> std::io::Result<Option<Duration>> {
+    match command.tag_value("timeout") {
+        Some(value) => Ok(Some(util::parse_duration(value).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid timeout tag at line {}: {e}", command.line_number),
+            )
+        })?)),
+        None => Ok(options.command_timeout),
+    }
+}
+
+/// Runs a command or batch via the given closure, with the given timeout if
+/// any. The context's cancellation token is cancelled when the timeout
+/// expires, and the script fails with a TimedOut error naming the commands
+/// once they return. If the options have a reporter, they're watched by the
+/// script's watchdog thread, which is spawned on first use.
+fn run_with_timeout<T>(
+    ctx: &mut RunContext,
+    options: &RunOptions,
+    watchdog: &mut Option<Watchdog>,
+    timeout: Option<Duration>,
+    name: impl FnOnce() -> String,
+    f: impl FnOnce(&mut RunContext) -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    let Some(timeout) = timeout else {
+        return f(ctx);
+    };
+
+    let message = format!("{} timed out after {timeout:?}", name());
+    let start = Instant::now();
+    let parent = ctx.cancellation_token().clone();
+    ctx.set_cancellation_token(parent.child_with_deadline(start + timeout));
+    // The watchdog only reports hung commands, so it's only needed with a
+    // reporter.
+    if let (None, Some(reporter)) = (&watchdog, &options.reporter) {
+        *watchdog = Some(Watchdog::spawn(reporter.clone())?);
+    }
+    if let Some(watchdog) = watchdog {
+        watchdog.watch(start + timeout, timeout, message.clone());
+    }
+
+    let result = f(ctx);
+    if let Some(watchdog) = watchdog {
+        watchdog.done();
+    }
+    ctx.set_cancellation_token(parent);
+    if start.elapsed() > timeout {
+        return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, message));
+    }
+    result
+}
+
+/// A watchdog thread for command timeouts, shared by all commands in a script.
+/// Commands can't be interrupted, so it reports a command on stderr when its
+/// timeout expires, and again if it still hasn't returned after a grace period
+/// of the same duration. It then detaches from the hung command rather than
+/// stalling or aborting the test process, and the script fails with a
+/// TimedOut error if the command eventually returns. The thread exits when the
+/// watchdog is dropped.
+struct Watchdog(std::sync::mpsc::Sender<Option<Watched>>);
+
+/// A command watched by the watchdog: its deadline, timeout, and message, and
+/// whether the grace period has started.
+type Watched = (Instant, Duration, String, bool);
+
+impl Watchdog {
+    /// Spawns a watchdog thread, reporting hung commands to the reporter.
+    fn spawn(reporter: SharedReporter) -> std::io::Result<Self> {
+        use std::sync::mpsc::RecvTimeoutError;
+        let (tx, rx) = std::sync::mpsc::channel::<Option<Watched>>();
+        std::thread::Builder::new().name("goldenscript-watchdog".to_string()).spawn(move || {
+            let mut watched: Option<Watched> = None;
+            loop {
+                let received = match &watched {
+                    Some((deadline, ..)) => {
+                        rx.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    }
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(next) => watched = next,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                    Err(RecvTimeoutError::Timeout) => match watched.take() {
+                        Some((deadline, timeout, message, false)) => {
+                            let notice =
+                                Notice::CommandTimedOut { message: message.clone(), waited: None };
+                            reporter.report(&notice);
+                            watched = Some((deadline + timeout, timeout, message, true));
+                        }
+                        Some((_, timeout, message, true)) => {
+                            let waited = Some(timeout * 2);
+                            reporter.report(&Notice::CommandTimedOut { message, waited });
+                        }
+                        None => {}
+                    },
+                }
+            }
+        })?;
+        Ok(Self(tx))
+    }
+
+    /// Watches a command with the given deadline and timeout.
+    fn watch(&self, deadline: Instant, timeout: Duration, message: String) {
+        // The thread only exits when the watchdog is dropped.
+        _ = self.0.send(Some((deadline, timeout, message, false)));
+    }
+
+    /// Stops watching the current command.
+    fn done(&self) {
+        _ = self.0.send(None);
+    }
+}
+
+/// Formats a command's output and appends it to the block output, handling
+/// silencing, output checks, wrapping, and prefixes.
+fn write_command_output(
+    block_output: &mut String,
+    command: &Command,
+    mut output: String,
+    eol: &str,
+    options: &RunOptions,
+) -> std::io::Result<()> {
+    // Silence the output if requested.
+    if command.silent {
+        output.clear();
+    }
+
+    // Normalize the output.
+    #[cfg(feature = "regex")]
+    for normalizer in &options.normalizers {
+        output = normalizer.apply(command, output);
+    }
+
+    // Check the output size.
+    if let Some(max) = options.max_output_size {
+        if output.len() > max {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "command '{}' at line {} output size {} exceeds maximum {max}",
+                    command.name,
+                    command.line_number,
+                    output.len()
+                ),
+            ));
+        }
+    }
+
+    // Check for binary output.
+    if options.binary_output != BinaryOutput::Allow && output::is_binary(&output) {
+        match options.binary_output {
+            BinaryOutput::Hexdump => output = output::hexdump(output.as_bytes()).replace('\n', eol),
+            BinaryOutput::Error | BinaryOutput::Allow => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "command '{}' at line {} output looks like binary data",
+                        command.name, command.line_number
+                    ),
+                ))
+            }
+        }
+    }
+
+    // Check for control characters.
+    output = check_control_chars(output, options, || {
+        format!("command '{}' at line {}", command.name, command.line_number)
+    })?;
+
+    // Wrap long lines if requested, either via a [wrap=WIDTH] tag or options.
+    let wrap = match command.tag_value("wrap") {
+        Some(width) => width.parse().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid wrap tag at line {}: {e}", command.line_number),
+            )
+        })?,
+        None => options.wrap.unwrap_or(0),
+    };
+    if wrap > 0 {
+        output = wrap_lines(&output, wrap, eol);
+    }
+
+    // Prefix output lines if requested, writing them directly to the block
+    // output.
+    match &command.prefix {
+        Some(prefix) if !output.is_empty() => {
+            let body = output.strip_suffix(eol).unwrap_or(output.as_str());
+            block_output.reserve(body.len() + (prefix.len() + 2) * body.lines().count());
+            for (i, line) in body.split('\n').enumerate() {
+                if i > 0 {
+                    block_output.push('\n');
+                }
+                block_output.push_str(prefix);
+                block_output.push_str(": ");
+                block_output.push_str(line);
+            }
+            block_output.push_str(eol);
+        }
+        _ => block_output.push_str(&output),
+    }
+
+    Ok(())
+}
+
+/// Applies the control character policy to the given output. The source
+/// closure describes the output source for error messages.
+fn check_control_chars(
+    output: String,
+    options: &RunOptions,
+    source: impl FnOnce() -> String,
+) -> std::io::Result<String> {
+    if options.control_chars == ControlChars::Allow {
+        return Ok(output);
+    }
+    let mut result = String::with_capacity(output.len());
+    let mut chars = output.chars().peekable();
+    while let Some(c) = chars.next() {
+        let is_control = match c {
+            '\n' | '\t' => false,
+            '\r' => chars.peek() != Some(&'\n'),
+            c => c.is_control(),
+        };
+        if !is_control {
+            result.push(c);
+            continue;
+        }
+        let escaped = format!("\\x{:02x}", c as u32);
+        match options.control_chars {
+            ControlChars::Escape => result.push_str(&escaped),
+            ControlChars::Error | ControlChars::Allow => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} output contains control character {escaped}", source()),
+                ))
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Hard-wraps lines longer than the given width (in characters) into chunks of
+/// the given width, ending all but the last chunk with a \ marker.
+fn wrap_lines(s: &str, width: usize, eol: &str) -> String {
+    let mut wrapped = String::with_capacity(s.len());
+    for line in s.split_inclusive('\n') {
+        let (content, ending) = match line.strip_suffix(eol) {
+            Some(content) => (content, eol),
+            None => (line, ""),
+        };
+        let chars: Vec<char> = content.chars().collect();
+        for (i, chunk) in chars.chunks(width).enumerate() {
+            if i > 0 {
+                wrapped.push('\\');
+                wrapped.push_str(eol);
+            }
+            wrapped.extend(chunk);
+        }
+        wrapped.push_str(ending);
+    }
+    wrapped
+}
+
+/// Appends a newline if the string is not empty and doesn't already have one.
+fn ensure_eol(mut s: String, eol: &str) -> String {
+    if let Some(c) = s.chars().next_back() {
+        if c != '\n' {
+            s.push_str(eol)
+        }
+    }
+    s
+}
+
+// NB: most tests are done as goldenscripts under tests/.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CancellationToken;
+
+    /// A runner which simply counts the number of times its hooks are called.
+    #[derive(Default)]
+    struct HookRunner {
+        start_script_count: usize,
+        end_script_count: usize,
+        start_block_count: usize,
+        end_block_count: usize,
+        start_command_count: usize,
+        end_command_count: usize,
+    }
+
+    impl Runner for HookRunner {
+        fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+            Ok(String::new())
+        }
+
+        fn start_script(&mut self, _: &mut RunContext) -> Result<(), Box<dyn Error>> {
+            self.start_script_count += 1;
+            Ok(())
+        }
+
+        fn end_script(&mut self, _: &mut RunContext) -> Result<String, Box<dyn Error>> {
+            self.end_script_count += 1;
+            Ok(String::new())
+        }
+
+        fn start_block(
+            &mut self,
+            _: &BlockInfo,
+            _: &mut RunContext,
+        ) -> Result<String, Box<dyn Error>> {
+            self.start_block_count += 1;
+            Ok(String::new())
+        }
+
+        fn end_block(
+            &mut self,
+            _: &BlockInfo,
+            _: &mut RunContext,
+        ) -> Result<Output, Box<dyn Error>> {
+            self.end_block_count += 1;
+            Ok(Output::Text(String::new()))
+        }
+
+        fn start_command(
+            &mut self,
+            _: &Command,
+            _: &mut RunContext,
+        ) -> Result<String, Box<dyn Error>> {
+            self.start_command_count += 1;
+            Ok(String::new())
+        }
+
+        fn end_command(
+            &mut self,
+            _: &Command,
+            _: &mut RunContext,
+        ) -> Result<String, Box<dyn Error>> {
+            self.end_command_count += 1;
+            Ok(String::new())
+        }
+    }
+
+    /// Tests that runner hooks are called as expected.
+    #[test]
+    fn hooks() {
+        let mut runner = HookRunner::default();
+        generate(
+            &mut runner,
+            r#"
+command
+---
+
+command
+command
+---
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(runner.start_script_count, 1);
+        assert_eq!(runner.end_script_count, 1);
+        assert_eq!(runner.start_block_count, 2);
+        assert_eq!(runner.end_block_count, 2);
+        assert_eq!(runner.start_command_count, 3);
+        assert_eq!(runner.end_command_count, 3);
+    }
+
+    /// A runner which records the command names of each batch.
     #[derive(Default)]
-    struct HookRunner {
-        start_script_count: usize,
-        end_script_count: usize,
-        start_block_count: usize,
-        end_block_count: usize,
-        start_command_count: usize,
-        end_command_count: usize,
+    struct BatchRunner {
+        batches: Vec<Vec<String>>,
+    }
+
+    impl Runner for BatchRunner {
+        fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+            self.batches.push(vec![command.name.clone()]);
+            match command.fail {
+                true => Err("failed".into()),
+                false => Ok(command.name.clone()),
+            }
+        }
+
+        fn run_batch(
+            &mut self,
+            commands: &[Command],
+            _: &mut RunContext,
+        ) -> Result<Vec<String>, Box<dyn Error>> {
+            let names: Vec<String> = commands.iter().map(|c| c.name.clone()).collect();
+            self.batches.push(names.clone());
+            Ok(names)
+        }
+
+        fn batch_size(&self) -> usize {
+            3
+        }
+    }
+
+    /// Tests that commands are batched as expected, with expected failures run
+    /// individually.
+    #[test]
+    fn batch() {
+        let mut runner = BatchRunner::default();
+        let output = generate(&mut runner, "a\nb\nc\nd\n!e\nf\n---\n\ng\n---\n").unwrap();
+
+        assert_eq!(
+            runner.batches,
+            vec![vec!["a", "b", "c"], vec!["d"], vec!["e"], vec!["f"], vec!["g"]]
+        );
+        assert_eq!(output, "a\nb\nc\nd\n!e\nf\n---\na\nb\nc\nd\nError: failed\nf\n\ng\n---\ng\n");
+    }
+
+    /// Tests that directives requiring a capability error unless the runner
+    /// enables it.
+    #[test]
+    fn capabilities() {
+        struct EnvRunner(HashSet<Capability>);
+        impl Runner for EnvRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                _: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                let value = ctx.env().get("GOLDENSCRIPT_TEST_ENV").map(String::as_str);
+                Ok(value.unwrap_or("unset").to_string().into())
+            }
+
+            fn capabilities(&self) -> HashSet<Capability> {
+                self.0.clone()
+            }
+        }
+
+        let mut runner = EnvRunner(HashSet::from([Capability::Sleep, Capability::Env]));
+        let input = "%sleep 1ms\n%env GOLDENSCRIPT_TEST_ENV=foo\nget\n\
+                     %env GOLDENSCRIPT_TEST_ENV\nget\n---\n";
+        assert_eq!(generate(&mut runner, input).unwrap(), format!("{input}foo\nunset\n"));
+
+        // The variables are scoped to the script, and don't leak into the
+        // process environment or later scripts.
+        let input = "%env GOLDENSCRIPT_TEST_ENV=foo\n---\n\nget\n---\n";
+        assert_eq!(
+            generate(&mut runner, input).unwrap(),
+            "%env GOLDENSCRIPT_TEST_ENV=foo\n---\nok\n\nget\n---\nfoo\n"
+        );
+        assert!(std::env::var("GOLDENSCRIPT_TEST_ENV").is_err());
+        assert_eq!(generate(&mut runner, "get\n---\n").unwrap(), "get\n---\nunset\n");
+
+        let mut runner = EnvRunner(HashSet::from([Capability::Env]));
+        assert_eq!(
+            generate(&mut runner, "%sleep 1ms\n---\n").unwrap_err().to_string(),
+            "directive %sleep failed at line 1: runner does not enable the sleep capability"
+        );
+    }
+
+    /// Tests that commands with dependencies are batched in dependency order,
+    /// with output in declaration order.
+    #[test]
+    fn dependencies() {
+        let mut runner = BatchRunner::default();
+        let input = "c [after=@b]\nb [@b after=@a]\nd\na [@a]\ne [after=@a]\n---\n";
+        let output = generate(&mut runner, input).unwrap();
+        assert_eq!(runner.batches, vec![vec!["d", "a"], vec!["b", "e"], vec!["c"]]);
+        assert_eq!(output, format!("{input}c\nb\nd\na\ne\n"));
+
+        // Directives are barriers.
+        let mut runner = BatchRunner::default();
+        let input = "b [after=@a]\na [@a]\n%seed 1\nc\nd [after=@c]\nc [@c]\n---\n";
+        let output = generate(&mut runner, input).unwrap();
+        assert_eq!(runner.batches, vec![vec!["a"], vec!["b"], vec!["c", "c"], vec!["d"]]);
+        assert_eq!(output, format!("{input}b\na\nc\nd\nc\n"));
+
+        // Invalid dependencies error before running anything.
+        let mut runner = BatchRunner::default();
+        assert!(generate(&mut runner, "a\nb [@b after=@c]\nc [@c after=@b]\n---\n").is_err());
+        assert!(runner.batches.is_empty());
+    }
+
+    /// Tests that UnknownCommand errors fall back to unknown_command(), also
+    /// when batching.
+    #[test]
+    fn unknown_command() {
+        /// Runs "a", and delegates other commands to the base runner, if any.
+        struct Layered {
+            base: Option<CommandRegistry<()>>,
+            batch_size: usize,
+        }
+
+        impl Runner for Layered {
+            fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "a" => Ok("layered a".to_string()),
+                    _ => Err(UnknownCommand.into()),
+                }
+            }
+
+            fn unknown_command(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+                match self.base.as_mut() {
+                    Some(base) => base.run(command),
+                    None => Err(format!("unknown command '{}'", command.name).into()),
+                }
+            }
+
+            fn batch_size(&self) -> usize {
+                self.batch_size
+            }
+        }
+
+        let input = "a\nb\nc\n---\n";
+        for batch_size in [1, 3] {
+            let base = CommandRegistry::new(())
+                .register("b", |_, _| Ok("base b".to_string()))
+                .register("c", |_, _| Ok("base c".to_string()));
+            let mut runner = Layered { base: Some(base), batch_size };
+            assert_eq!(
+                generate(&mut runner, input).unwrap(),
+                format!("{input}layered a\nbase b\nbase c\n")
+            );
+
+            let mut runner = Layered { base: None, batch_size };
+            assert_eq!(
+                generate(&mut runner, input).unwrap_err().to_string(),
+                match batch_size {
+                    1 => "command 'b' failed at line 2: unknown command 'b'",
+                    _ => "command batch failed at lines 1-3: unknown command 'b'",
+                }
+            );
+        }
+    }
+
+    /// Tests that process_output() is called on command output, including
+    /// expected failures and batches, but not hook output.
+    #[test]
+    fn process_output() {
+        /// Outputs a timestamp, which process_output() redacts.
+        struct TimeRunner {
+            batch_size: usize,
+        }
+
+        impl Runner for TimeRunner {
+            fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "error" => Err("failed at t=1".into()),
+                    name => Ok(format!("{name} at t=1")),
+                }
+            }
+
+            fn batch_size(&self) -> usize {
+                self.batch_size
+            }
+
+            fn end_command(
+                &mut self,
+                _: &Command,
+                _: &mut RunContext,
+            ) -> Result<String, Box<dyn Error>> {
+                Ok("end at t=1".to_string())
+            }
+
+            fn process_output(
+                &mut self,
+                command: &Command,
+                output: String,
+            ) -> Result<String, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "bad" => Err("bad output".into()),
+                    _ => Ok(output.replace("t=1", "t=<time>")),
+                }
+            }
+        }
+
+        for batch_size in [1, 2] {
+            let mut runner = TimeRunner { batch_size };
+            assert_eq!(
+                generate(&mut runner, "a\nb\n!error\n---\n").unwrap(),
+                "a\nb\n!error\n---\na at t=<time>\nend at t=1\nb at t=<time>\nend at t=1\n\
+                 Error: failed at t=<time>\nend at t=1\n"
+            );
+            assert_eq!(
+                generate(&mut runner, "bad\n---\n").unwrap_err().to_string(),
+                "process_output failed for command 'bad' at line 1: bad output"
+            );
+        }
+    }
+
+    /// Tests that scratch directories are created and removed according to
+    /// the policy.
+    #[test]
+    fn scratch_dir() {
+        /// Writes files to the scratch directory, and remembers its path.
+        #[derive(Default)]
+        struct ScratchRunner {
+            path: Option<std::path::PathBuf>,
+        }
+
+        impl Runner for ScratchRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                let Some(dir) = ctx.scratch_dir() else {
+                    return Ok("no scratch dir".into());
+                };
+                self.path = Some(dir.to_path_buf());
+                match command.name.as_str() {
+                    "fail" => Err("failed".into()),
+                    name => Ok(std::fs::write(dir.join(name), name).map(|_| String::new())?.into()),
+                }
+            }
+        }
+
+        let mut runner = ScratchRunner::default();
+        let options = RunOptions::new();
+        assert_eq!(
+            generate_with(&mut runner, "a\n---\n", &options).unwrap(),
+            "a\n---\nno scratch dir\n"
+        );
+
+        // Scratch directories are removed, also on failure.
+        let options = RunOptions::new().scratch_dir(ScratchDir::Remove);
+        let mut runner = ScratchRunner::default();
+        assert_eq!(generate_with(&mut runner, "a\n---\n", &options).unwrap(), "a\n---\nok\n");
+        assert!(!runner.path.unwrap().exists());
+
+        let mut runner = ScratchRunner::default();
+        assert!(generate_with(&mut runner, "a\nfail\n---\n", &options).is_err());
+        assert!(!runner.path.unwrap().exists());
+
+        // Scratch directories can be kept on failure, but not on success, and
+        // kept directories are reported. Each script gets a new directory.
+        let (options, notices) = collect_notices();
+        let options = options.scratch_dir(ScratchDir::KeepOnFailure);
+        let mut runner = ScratchRunner::default();
+        assert!(generate_with(&mut runner, "a\n---\n", &options).is_ok());
+        let path = runner.path.take().unwrap();
+        assert!(!path.exists());
+
+        assert!(generate_with(&mut runner, "a\nfail\n---\n", &options).is_err());
+        let kept = runner.path.take().unwrap();
+        assert_ne!(kept, path);
+        assert_eq!(std::fs::read_to_string(kept.join("a")).unwrap(), "a");
+        assert_eq!(*notices.lock().unwrap(), vec![Notice::ScratchKept { path: kept.clone() }]);
+        std::fs::remove_dir_all(kept).unwrap();
+    }
+
+    /// Tests that execution traces are written for failed scripts, and removed
+    /// once they pass.
+    #[cfg(feature = "regex")]
+    #[test]
+    fn trace() {
+        let dir = std::env::temp_dir().join(format!("goldenscript-trace-{}", std::process::id()));
+        let trace_path = dir.join("script.trace");
+        let mut runner = FnRunner::stateless(|c| match c.name.as_str() {
+            "fail" => Err("failed".into()),
+            name => Ok(format!("{name}\n")),
+        })
+        .on_start_block(|_, _| Ok("start".to_string()))
+        .on_end_command(|_, c| Ok(format!("end {}", c.name)));
+        let options = RunOptions::new().normalize(regex::Regex::new("a").unwrap(), "b");
+
+        let input = "a\n!fail\n---\n\nfail\n---\n";
+        let error = generate_traced(&mut runner, input, &options, &dir.join("script")).unwrap_err();
+        assert_eq!(error.to_string(), "command 'fail' failed at line 5: failed");
+        let trace = std::fs::read_to_string(&trace_path).unwrap();
+        let trace = regex::Regex::new(r"\(.*?s\)").unwrap().replace_all(&trace, "(<time>)");
+        assert_eq!(
+            trace,
+            "line 1: start_block: \"start\"\n\
+             line 1: start_command: \"\"\n\
+             line 1: run 'a' (<time>): \"a\\n\"\n\
+             line 1: end_command: \"end a\"\n\
+             line 2: start_command: \"\"\n\
+             line 2: run 'fail' (<time>): \"Error: failed\"\n\
+             line 2: end_command: \"end fail\"\n\
+             line 1: end_block: \"\"\n\
+             line 5: start_block: \"start\"\n\
+             line 5: start_command: \"\"\n"
+        );
+
+        let input = "a\n---\nstart\nb\nend b\n";
+        assert_eq!(
+            generate_traced(&mut runner, input, &options, &dir.join("script")).unwrap(),
+            input
+        );
+        assert!(!trace_path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Tests that end_script() output is appended as a trailing %end-script
+    /// block, which is updated or removed as needed.
+    #[test]
+    fn end_script_output() {
+        /// Outputs the number of commands run at the end of the script.
+        struct CountRunner(usize);
+
+        impl Runner for CountRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                self.0 += 1;
+                Ok(String::new())
+            }
+
+            fn end_script(&mut self, _: &mut RunContext) -> Result<String, Box<dyn Error>> {
+                match self.0 {
+                    0 => Ok(String::new()),
+                    n => Ok(format!("commands: {n}\n\nend")),
+                }
+            }
+        }
+
+        // The block is appended if missing, and stale output is replaced.
+        let expect = "a\n---\nok\n\n%end-script\n---\n> commands: 1\n> \n> end\n";
+        assert_eq!(generate(&mut CountRunner(0), "a\n---\n").unwrap(), expect);
+        let input = "a\n---\nok\n\n%end-script\n---\nstale\n";
+        assert_eq!(generate(&mut CountRunner(0), input).unwrap(), expect);
+
+        // Without output, the block is removed.
+        assert_eq!(generate(&mut CountRunner(0), "%end-script\n---\nstale\n").unwrap(), "");
+    }
+
+    /// Tests that snapshot scripts pass the document to run_raw(), replacing
+    /// any existing output.
+    #[test]
+    fn snapshot() {
+        struct Upper;
+
+        impl Runner for Upper {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_raw(&mut self, input: &str) -> Result<String, Box<dyn Error>> {
+                Ok(input.to_uppercase())
+            }
+        }
+
+        for (input, expect) in [
+            ("a\nb\n", "a\nb\n---\nA\nB\n"),
+            ("a\nb\n---\nold\n---\nold\n", "a\nb\n---\nA\nB\n"),
+            ("a", "a\n---\nA\n"),
+            ("a\r\n\r\nb\r\n---\r\nold\r\n", "a\r\n\r\nb\r\n---\r\n> A\r\n> \r\n> B\r\n"),
+            ("---\n", "---\nok\n"),
+        ] {
+            assert_eq!(generate_snapshot(&mut Upper, input).unwrap(), expect, "{input:?}");
+        }
+        assert_eq!(
+            generate_snapshot(&mut FnRunner::stateless(|_| Ok(String::new())), "a\n")
+                .unwrap_err()
+                .to_string(),
+            "run_raw failed: Runner::run_raw() not implemented"
+        );
+    }
+
+    /// Tests that validators are given each block's input and output, and
+    /// fail the script on errors.
+    #[test]
+    fn validator() {
+        use std::sync::{Arc, Mutex};
+
+        /// Records the blocks it's given, and errors on output "bad".
+        struct Recorder(Arc<Mutex<Vec<(String, String)>>>);
+
+        impl Validator for Recorder {
+            fn validate_block(&mut self, input: &str, output: &str) -> Result<(), Box<dyn Error>> {
+                self.0.lock().unwrap().push((input.to_string(), output.to_string()));
+                match output {
+                    "bad\n" => Err("bad output".into()),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let blocks = Arc::new(Mutex::new(Vec::new()));
+        let options = RunOptions::new().validator(Recorder(blocks.clone()));
+        let mut runner = FnRunner::stateless(|c| Ok(c.name.clone()));
+
+        let input = "# a\na\n---\n\nb\n---\n";
+        assert!(generate_with(&mut runner, input, &options).is_ok());
+        assert_eq!(
+            *blocks.lock().unwrap(),
+            vec![("# a\na\n".into(), "a\n".into()), ("b\n".into(), "b\n".into())]
+        );
+
+        let input = "a\n---\n\nbad\n---\n\nc\n---\n";
+        assert_eq!(
+            generate_with(&mut runner, input, &options).unwrap_err().to_string(),
+            "validation failed for block at line 4: bad output"
+        );
+        assert_eq!(blocks.lock().unwrap().len(), 4);
+    }
+
+    /// Tests that structured output from run_ctx() and end_block() is
+    /// rendered.
+    #[test]
+    fn structured_output() {
+        struct OutputRunner;
+
+        impl Runner for OutputRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                _: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "text" => Ok("text".into()),
+                    "table" => Ok(Output::Table {
+                        header: vec!["name".into(), "value".into()],
+                        rows: command
+                            .args
+                            .iter()
+                            .map(|a| vec![a.name().to_string(), a.value.clone()])
+                            .collect(),
+                    }),
+                    _ => Err(UnknownCommand.into()),
+                }
+            }
+
+            fn end_block(
+                &mut self,
+                block: &BlockInfo,
+                _: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                let commands = block.commands().map(|c| c.name.clone()).collect::<Vec<_>>();
+                Ok(Output::Sections(vec![
+                    ("line".into(), block.line_number().to_string()),
+                    ("commands".into(), commands.join("\n")),
+                ]))
+            }
+        }
+
+        let input = "text\ntable a=1 bcd=22\n---\n\n!foo\n---\n";
+        assert_eq!(
+            generate(&mut OutputRunner, input).unwrap(),
+            "text\ntable a=1 bcd=22\n---\ntext\nname  value\n----  -----\na     1\nbcd   22\n\
+             commands:\n  text\n  table\nline:\n  1\n\n\
+             !foo\n---\nError: unknown command 'foo'\ncommands:\n  foo\nline:\n  5\n"
+        );
+    }
+
+    /// Tests that end_script() is given the prefixes seen in the script via
+    /// the run context.
+    #[test]
+    fn end_script_prefixes() {
+        #[derive(Default)]
+        struct PrefixRunner {
+            prefixes: BTreeMap<String, usize>,
+        }
+
+        impl Runner for PrefixRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                Ok(String::new())
+            }
+
+            fn end_script(&mut self, ctx: &mut RunContext) -> Result<String, Box<dyn Error>> {
+                self.prefixes = ctx.prefixes().clone();
+                Ok(String::new())
+            }
+        }
+
+        let mut runner = PrefixRunner::default();
+        generate(&mut runner, "b: cmd\na: cmd\ncmd\n---\n\n(b: cmd)\nb: cmd\n---\n").unwrap();
+        assert_eq!(runner.prefixes, BTreeMap::from([("a".into(), 1), ("b".into(), 3)]));
+    }
+
+    /// Tests that Runner::known_commands() rejects scripts with unknown
+    /// commands before running them.
+    #[test]
+    fn known_commands() {
+        struct KnownRunner(Vec<String>);
+        impl Runner for KnownRunner {
+            fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+                self.0.push(command.name.clone());
+                Ok(String::new())
+            }
+
+            fn known_commands(&self) -> Option<Vec<&str>> {
+                Some(vec!["get", "set"])
+            }
+        }
+
+        let mut runner = KnownRunner(Vec::new());
+        generate(&mut runner, "set\n%seed 1\nget\n---\n").unwrap();
+        assert_eq!(runner.0, vec!["set", "get"]);
+
+        let mut runner = KnownRunner(Vec::new());
+        let input = "set\nfoo\n---\n\nget\na: bar\n---\n";
+        assert_eq!(
+            generate(&mut runner, input).unwrap_err().to_string(),
+            "unknown commands:\nline 2: foo\nline 6: bar"
+        );
+        assert!(runner.0.is_empty());
+    }
+
+    /// Tests that cancellation tokens are cancelled via the options and
+    /// max_runtime, and that scripts stop running commands once cancelled,
+    /// calling the end_script() hook before failing.
+    #[test]
+    fn cancellation() {
+        /// Cancels the given token on "cancel", and waits for cancellation on
+        /// "wait". Records whether end_script() was called.
+        struct CancelRunner {
+            token: CancellationToken,
+            ended: bool,
+        }
+        impl Runner for CancelRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "cancel" => self.token.cancel(),
+                    "wait" => {
+                        while !ctx.cancellation_token().is_cancelled() {
+                            std::thread::sleep(Duration::from_millis(1));
+                        }
+                    }
+                    _ => {}
+                }
+                ctx.cancellation_token().check()?;
+                Ok("ok".into())
+            }
+
+            fn end_script(&mut self, _: &mut RunContext) -> Result<String, Box<dyn Error>> {
+                self.ended = true;
+                Ok(String::new())
+            }
+        }
+
+        // Cancelling the options token fails the script before the next
+        // command, reporting the completed blocks. The runner observes the
+        // cancellation too.
+        let token = CancellationToken::new();
+        let options = RunOptions::new().cancellation_token(token.clone());
+        let mut runner = CancelRunner { token, ended: false };
+        let input = "a\n---\nok\n\n!cancel\nb\n---\n\nc\n---\n";
+        assert_eq!(
+            generate_with(&mut runner, input, &options).unwrap_err().to_string(),
+            "script cancelled at line 6 (1 of 3 blocks completed)"
+        );
+        assert!(runner.ended);
+
+        // Scripts are cancelled once they exceed max_runtime, and fail with a
+        // max_runtime error.
+        let options = RunOptions::new();
+        let mut runner = CancelRunner { token: CancellationToken::new(), ended: false };
+        let input = "%limits max_runtime=10ms\n!wait\nb\n---\n";
+        assert_eq!(
+            generate_with(&mut runner, input, &options).unwrap_err().to_string(),
+            "script exceeded max_runtime=10ms at line 2"
+        );
+    }
+
+    /// Tests [budget=DURATION] tags, with tolerance and breach policies.
+    #[test]
+    fn budget() {
+        /// Sleeps for the given number of milliseconds. Records the batch
+        /// sizes.
+        #[derive(Default)]
+        struct SleepRunner {
+            batches: Vec<usize>,
+        }
+        impl Runner for SleepRunner {
+            fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+                let millis = command.args.first().map(|a| a.parse()).transpose()?.unwrap_or(0);
+                std::thread::sleep(Duration::from_millis(millis));
+                Ok("ok".to_string())
+            }
+
+            fn run_batch(
+                &mut self,
+                commands: &[Command],
+                _: &mut RunContext,
+            ) -> Result<Vec<String>, Box<dyn Error>> {
+                self.batches.push(commands.len());
+                commands.iter().map(|c| self.run(c)).collect()
+            }
+
+            fn batch_size(&self) -> usize {
+                10
+            }
+        }
+
+        // Commands within budget succeed, and are run individually rather than
+        // batched. Only c and d are batched.
+        let mut runner = SleepRunner::default();
+        let input = "a\nb [budget=10s]\nc\nd\n---\n";
+        assert!(generate_with(&mut runner, input, &RunOptions::new()).is_ok());
+        assert_eq!(runner.batches, vec![2]);
+
+        // Commands exceeding their budget error by default.
+        let input = "sleep 20 [budget=5ms]\n---\n";
+        let err =
+            generate_with(&mut SleepRunner::default(), input, &RunOptions::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(err.to_string().starts_with("command 'sleep' at line 1 took "), "{err}");
+        assert!(err.to_string().ends_with(", exceeding budget 5ms"), "{err}");
+
+        let options = RunOptions::new().budget_tolerance(0.5);
+        let err = generate_with(&mut SleepRunner::default(), input, &options).unwrap_err();
+        assert!(err.to_string().ends_with(", exceeding budget 5ms with 50% tolerance"), "{err}");
+
+        // A sufficient tolerance or breach policy allows them.
+        let options = RunOptions::new().budget_tolerance(100.0);
+        assert!(generate_with(&mut SleepRunner::default(), input, &options).is_ok());
+        let (options, notices) = collect_notices();
+        let options = options.budget_breach(BudgetBreach::Warn);
+        assert!(generate_with(&mut SleepRunner::default(), input, &options).is_ok());
+        let notice = notices.lock().unwrap()[0].to_string();
+        assert!(notice.starts_with("warning: command 'sleep' at line 1 took "), "{notice}");
+        assert!(notice.ends_with(", exceeding budget 5ms"), "{notice}");
+        let options = RunOptions::new().budget_breach(BudgetBreach::Ignore);
+        assert!(generate_with(&mut SleepRunner::default(), input, &options).is_ok());
+
+        // Invalid budgets error even when ignored.
+        let input = "sleep [budget=5]\n---\n";
+        assert_eq!(
+            generate_with(&mut SleepRunner::default(), input, &options).unwrap_err().to_string(),
+            "invalid budget tag at line 1: invalid duration '5'"
+        );
+    }
+
+    /// Tests the BOM, final newline, and invalid UTF-8 policies.
+    #[test]
+    fn encoding() {
+        let mut runner = FnRunner::stateless(|c| Ok(format!("{}\n", c.name)));
+        let mut generate = |input: &str, options: RunOptions| {
+            generate_with(&mut runner, input, &options).map_err(|e| e.to_string())
+        };
+
+        // BOMs error by default, but can be stripped or preserved.
+        let input = "\u{feff}a\n---\n";
+        assert_eq!(
+            generate(input, RunOptions::new()),
+            Err("script starts with a byte order mark (BOM)".to_string())
+        );
+        assert_eq!(
+            generate(input, RunOptions::new().bom(Bom::Strip)),
+            Ok("a\n---\na\n".to_string())
+        );
+        assert_eq!(
+            generate(input, RunOptions::new().bom(Bom::Preserve)),
+            Ok("\u{feff}a\n---\na\n".to_string())
+        );
+
+        // Final newlines are kept by default, but can be added or removed.
+        let input = "a\n---\n\n# comment";
+        assert_eq!(generate(input, RunOptions::new()), Ok("a\n---\na\n\n# comment".to_string()));
+        assert_eq!(
+            generate(input, RunOptions::new().final_newline(FinalNewline::Always)),
+            Ok("a\n---\na\n\n# comment\n".to_string())
+        );
+        let options = RunOptions::new().final_newline(FinalNewline::Never);
+        assert_eq!(generate("a\n---\n", options.clone()), Ok("a\n---\na".to_string()));
+        assert_eq!(generate("a\n---\na", options), Ok("a\n---\na".to_string()));
+        let options = RunOptions::new().final_newline(FinalNewline::Always);
+        assert_eq!(generate("", options), Ok(String::new()));
+
+        // Invalid UTF-8 errors by default, but can be replaced.
+        let path = std::env::temp_dir().join(format!("goldenscript-utf8-{}", std::process::id()));
+        std::fs::write(&path, b"a \xff\n").unwrap();
+        let error = read_script(&path, &RunOptions::new()).unwrap_err();
+        assert_eq!(error.to_string(), format!("{}: invalid UTF-8 at byte 2", path.display()));
+        let options = RunOptions::new().invalid_utf8(InvalidUtf8::Replace);
+        assert_eq!(read_script(&path, &options).unwrap(), "a \u{fffd}\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Tests command timeouts via [timeout=DURATION] tags and options.
+    #[test]
+    fn timeout() {
+        /// Waits for cancellation on "wait", sleeps for 50ms ignoring
+        /// cancellation on "sleep", and errors if cancelled.
+        struct WaitRunner;
+        impl Runner for WaitRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                if command.name == "wait" {
+                    while !ctx.cancellation_token().is_cancelled() {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+                if command.name == "sleep" {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                ctx.cancellation_token().check()?;
+                Ok("ok".into())
+            }
+        }
+
+        // Commands within their timeout succeed, and the timeout doesn't
+        // affect later commands.
+        let options = RunOptions::new();
+        let input = "a [timeout=10s]\nb\n---\n";
+        assert_eq!(
+            generate_with(&mut WaitRunner, input, &options).unwrap(),
+            format!("{input}ok\nok\n")
+        );
+
+        // Commands exceeding their timeout are cancelled, and fail the script
+        // even if they're expected to fail.
+        let input = "a\n---\nok\n\n!wait [timeout=5ms]\n---\n";
+        let err = generate_with(&mut WaitRunner, input, &options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "command 'wait' at line 5 timed out after 5ms");
+
+        // Commands that don't return after their timeout are reported, and
+        // again after a grace period.
+        let (options, notices) = collect_notices();
+        let input = "sleep [timeout=5ms]\n---\n";
+        let err = generate_with(&mut WaitRunner, input, &options).unwrap_err();
+        assert_eq!(err.to_string(), "command 'sleep' at line 1 timed out after 5ms");
+        assert_eq!(
+            *notices.lock().unwrap(),
+            vec![
+                Notice::CommandTimedOut {
+                    message: "command 'sleep' at line 1 timed out after 5ms".to_string(),
+                    waited: None
+                },
+                Notice::CommandTimedOut {
+                    message: "command 'sleep' at line 1 timed out after 5ms".to_string(),
+                    waited: Some(Duration::from_millis(10))
+                },
+            ]
+        );
+
+        // The options set a default timeout, which tags override.
+        let options = RunOptions::new().command_timeout(Duration::from_millis(5));
+        let input = "a\nwait\n---\n";
+        assert_eq!(
+            generate_with(&mut WaitRunner, input, &options).unwrap_err().to_string(),
+            "command 'wait' at line 2 timed out after 5ms"
+        );
+        let input = "wait [timeout=10ms]\n---\n";
+        assert_eq!(
+            generate_with(&mut WaitRunner, input, &options).unwrap_err().to_string(),
+            "command 'wait' at line 1 timed out after 10ms"
+        );
+
+        // Batches have the combined timeout of their commands.
+        struct BatchRunner;
+        impl Runner for BatchRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                WaitRunner.run_ctx(command, ctx)
+            }
+
+            fn batch_size(&self) -> usize {
+                10
+            }
+        }
+        let input = "a
+wait
+---
+";
+        let err = generate_with(&mut BatchRunner, input, &options).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "batch of 2 commands at line 1 timed out after 10ms");
+    }
+
+    /// Tests command retries via [retry=N] and [backoff=DURATION] tags.
+    #[test]
+    fn retry() {
+        /// Counts the attempts of each command. "eventually" errors until the
+        /// third attempt, "fail" always errors, "blank" outputs a blank line
+        /// and the attempt, and others output the attempt.
+        #[derive(Default)]
+        struct RetryRunner {
+            attempts: HashMap<String, usize>,
+        }
+        impl Runner for RetryRunner {
+            fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+                let attempts = self.attempts.entry(command.name.clone()).or_default();
+                *attempts += 1;
+                match command.name.as_str() {
+                    "eventually" if *attempts < 3 => Err("not yet".into()),
+                    "fail" => Err("failed".into()),
+                    "blank" => Ok(format!("\n{attempts}")),
+                    _ => Ok(attempts.to_string()),
+                }
+            }
+        }
+
+        // Commands are retried until they succeed, with backoff.
+        let mut runner = RetryRunner::default();
+        let input = "eventually [retry=5 backoff=1ms]\n---\n";
+        assert_eq!(generate(&mut runner, input).unwrap(), format!("{input}3\n"));
+
+        // Without golden output, the first successful attempt is used. With
+        // golden output, commands are retried until the output matches it.
+        let mut runner = RetryRunner::default();
+        let input = "count [retry=5]\n---\n";
+        assert_eq!(generate(&mut runner, input).unwrap(), format!("{input}1\n"));
+        let mut runner = RetryRunner::default();
+        let input = "a\ncount [retry=5]\n---\n1\n3\n";
+        assert_eq!(generate(&mut runner, input).unwrap(), input);
+
+        // The output is compared by whole lines with the command's own expected
+        // output, following the preceding output. If the preceding output
+        // already differs, the expected output is unknown, so only errors are
+        // retried.
+        let mut runner = RetryRunner::default();
+        let input = "a\ncount [retry=5]\n---\n2\n3\n";
+        assert_eq!(generate(&mut runner, input).unwrap(), "a\ncount [retry=5]\n---\n1\n1\n");
+        let mut runner = RetryRunner::default();
+        let input = "count [retry=5]\na\n---\n12\n1\n";
+        assert_eq!(generate(&mut runner, input).unwrap(), "count [retry=5]\na\n---\n6\n1\n");
+
+        // If the output never matches, the last attempt is used.
+        let mut runner = RetryRunner::default();
+        let input = "count [retry=1]\n---\n3\n";
+        assert_eq!(generate(&mut runner, input).unwrap(), "count [retry=1]\n---\n2\n");
+
+        // Blank lines in the golden output are handled.
+        let mut runner = RetryRunner::default();
+        let input = "blank [retry=5]\n---\n> \n> 2\n";
+        assert_eq!(generate(&mut runner, input).unwrap(), input);
+
+        // Errors are returned once the retries are exhausted.
+        let mut runner = RetryRunner::default();
+        assert_eq!(
+            generate(&mut runner, "fail [retry=2]\n---\n").unwrap_err().to_string(),
+            "command 'fail' failed at line 1: failed"
+        );
+        assert_eq!(runner.attempts["fail"], 3);
+    }
+
+    /// Tests background commands and _wait.
+    #[test]
+    fn background() {
+        /// A lock that background "lock" commands wait for, released by
+        /// "unlock". "fail" errors in the background.
+        #[derive(Default)]
+        struct LockRunner {
+            locked: std::sync::Arc<(std::sync::Mutex<bool>, std::sync::Condvar)>,
+        }
+        impl Runner for LockRunner {
+            fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+                let (locked, cvar) = &*self.locked;
+                *locked.lock().unwrap() = command.name == "lock";
+                cvar.notify_all();
+                Ok(format!("{} ok", command.name))
+            }
+
+            fn spawn(&mut self, command: &Command) -> Result<Spawned, Box<dyn Error>> {
+                let locked = self.locked.clone();
+                match command.name.as_str() {
+                    "wait" => Ok(Box::new(move || {
+                        let (locked, cvar) = &*locked;
+                        let _guard = cvar.wait_while(locked.lock().unwrap(), |l| *l).unwrap();
+                        Ok("acquired".to_string())
+                    })),
+                    "fail" => Ok(Box::new(|| Err("failed".into()))),
+                    name => Err(format!("can't spawn {name}").into()),
+                }
+            }
+        }
+
+        // Background commands run while later commands unblock them, and
+        // their output is written by _wait in launch order, even across
+        // blocks.
+        let input = "lock\na: wait &\nb: wait &\n---\nlock ok\n\nunlock\n_wait\n---\n";
+        assert_eq!(
+            generate(&mut LockRunner::default(), input).unwrap(),
+            format!("{input}unlock ok\na: acquired\nb: acquired\n")
+        );
+
+        // Unexpected failures error.
+        assert_eq!(
+            generate(&mut LockRunner::default(), "fail &\n_wait\n---\n").unwrap_err().to_string(),
+            "command 'fail' failed at line 1: failed"
+        );
+        assert_eq!(
+            generate(&mut LockRunner::default(), "lock &\n---\n").unwrap_err().to_string(),
+            "command 'lock' failed to spawn at line 1: can't spawn lock"
+        );
     }
 
-    impl Runner for HookRunner {
-        fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
-            Ok(String::new())
+    /// Tests that %teardown sections are run when the script fails or panics
+    /// before them, but not again if they fail themselves.
+    #[test]
+    fn teardown_on_failure() {
+        let new_runner = || {
+            FnRunner::new(Vec::new(), |ran: &mut Vec<String>, command| {
+                ran.push(command.name.clone());
+                match command.name.as_str() {
+                    "error" => Err("failed".into()),
+                    "panic" => panic!("panicked"),
+                    _ => Ok(String::new()),
+                }
+            })
+        };
+
+        // The teardown runs after an error, and failing teardown commands are
+        // reported but don't stop it.
+        let mut runner = new_runner();
+        let (options, notices) = collect_notices();
+        let input = "%setup\na\n---\n\nerror\nb\n---\n\n%teardown\nerror\nc\n---\n";
+        assert!(generate_with(&mut runner, input, &options).is_err());
+        assert_eq!(runner.state(), &["a", "error", "error", "c"]);
+        assert_eq!(
+            notices.lock().unwrap().iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+            vec!["teardown failed: command 'error' failed at line 10: Error: failed"]
+        );
+
+        // The teardown runs after a panic, which is propagated.
+        let mut runner = new_runner();
+        let input = "panic\n---\n\n%teardown\nc\n---\n";
+        let run = std::panic::AssertUnwindSafe(|| generate(&mut runner, input));
+        assert!(std::panic::catch_unwind(run).is_err());
+        assert_eq!(runner.state(), &["panic", "c"]);
+
+        // A failing teardown isn't rerun.
+        let mut runner = new_runner();
+        let input = "a\n---\n\n%teardown\nerror\nc\n---\n";
+        assert!(generate(&mut runner, input).is_err());
+        assert_eq!(runner.state(), &["a", "error"]);
+    }
+
+    /// Tests that [concurrent] blocks run commands concurrently, and write
+    /// their output in input order.
+    #[test]
+    fn concurrent() {
+        /// Spawns commands that wait on a shared barrier for all commands in
+        /// the block, with a sleep given by the argument.
+        struct BarrierRunner {
+            barrier: std::sync::Arc<std::sync::Barrier>,
         }
+        impl Runner for BarrierRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
 
-        fn start_script(&mut self) -> Result<(), Box<dyn Error>> {
-            self.start_script_count += 1;
-            Ok(())
+            fn capabilities(&self) -> HashSet<Capability> {
+                HashSet::from([Capability::Spawn])
+            }
+
+            fn spawn(&mut self, command: &Command) -> Result<Spawned, Box<dyn Error>> {
+                let barrier = self.barrier.clone();
+                let sleep = command.args[0].parse()?;
+                let name = command.name.clone();
+                Ok(Box::new(move || {
+                    barrier.wait();
+                    std::thread::sleep(Duration::from_millis(sleep));
+                    match name.as_str() {
+                        "fail" => Err("failed".into()),
+                        name => Ok(name.to_string()),
+                    }
+                }))
+            }
         }
+        let new_runner =
+            |n| BarrierRunner { barrier: std::sync::Arc::new(std::sync::Barrier::new(n)) };
 
-        fn end_script(&mut self) -> Result<(), Box<dyn Error>> {
-            self.end_script_count += 1;
-            Ok(())
+        // The commands would deadlock if run sequentially, and the output is
+        // in input order even though later commands complete first.
+        let input = "[concurrent]\na 20\n%seed 1\nb 10\nc 0\n---\n";
+        assert_eq!(generate(&mut new_runner(3), input).unwrap(), format!("{input}a\nb\nc\n"));
+
+        // Failures are handled as for regular commands.
+        let input = "[concurrent]\na 0\n!fail 0\n---\n";
+        assert_eq!(
+            generate(&mut new_runner(2), input).unwrap(),
+            format!("{input}a\nError: failed\n")
+        );
+        assert_eq!(
+            generate(&mut new_runner(1), "[concurrent]\nfail 0\n---\n").unwrap_err().to_string(),
+            "command 'fail' failed at line 2: failed"
+        );
+
+        // Runners must enable the spawn capability, checked before running
+        // anything.
+        struct NoSpawnRunner;
+        impl Runner for NoSpawnRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
         }
+        assert_eq!(
+            generate(&mut NoSpawnRunner, "a\n---\n\n[concurrent]\nb\n---\n")
+                .unwrap_err()
+                .to_string(),
+            "[concurrent] block at line 4: runner does not enable the spawn capability"
+        );
+    }
 
-        fn start_block(&mut self) -> Result<String, Box<dyn Error>> {
-            self.start_block_count += 1;
-            Ok(String::new())
+    /// Tests that [concurrent] blocks with dependencies run independent
+    /// commands concurrently, and spawn dependent commands once their
+    /// dependencies have completed.
+    #[test]
+    fn concurrent_dependencies() {
+        use std::sync::{Arc, Barrier, Mutex};
+
+        /// Spawns commands that record their completion. Commands with a
+        /// "wait" argument first wait on a shared barrier and sleep. Commands
+        /// output the commands that completed before them.
+        struct DependencyRunner {
+            barrier: Arc<Barrier>,
+            completed: Arc<Mutex<Vec<String>>>,
         }
+        impl Runner for DependencyRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
 
-        fn end_block(&mut self) -> Result<String, Box<dyn Error>> {
-            self.end_block_count += 1;
-            Ok(String::new())
+            fn capabilities(&self) -> HashSet<Capability> {
+                HashSet::from([Capability::Spawn])
+            }
+
+            fn spawn(&mut self, command: &Command) -> Result<Spawned, Box<dyn Error>> {
+                let (barrier, completed) = (self.barrier.clone(), self.completed.clone());
+                let wait = command.args.iter().any(|a| a.value == "wait");
+                let name = command.name.clone();
+                Ok(Box::new(move || {
+                    if wait {
+                        barrier.wait();
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    let mut completed = completed.lock().unwrap();
+                    let output = format!("{name} after {:?}", completed);
+                    completed.push(name);
+                    Ok(output)
+                }))
+            }
         }
 
-        fn start_command(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
-            self.start_command_count += 1;
-            Ok(String::new())
+        // a and b would deadlock on the barrier if they didn't overlap, and c
+        // only runs once a has completed. b completes before or after a, so
+        // don't wait on it.
+        let mut runner =
+            DependencyRunner { barrier: Arc::new(Barrier::new(2)), completed: Arc::default() };
+        let input = "[concurrent]\nc [after=@a]\na wait [@a]\nb wait\n---\n";
+        let output = generate(&mut runner, input).unwrap();
+        let lines: Vec<&str> = output.strip_prefix(input).unwrap().lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("c after [") && lines[0].contains("\"a\""), "{output}");
+        assert!(lines[1].starts_with("a after [") && !lines[1].contains("\"c\""), "{output}");
+        assert!(lines[2].starts_with("b after ["), "{output}");
+    }
+
+    /// Tests that shared fixtures are set up on first use, shared by running
+    /// scripts, and torn down after the last script completes.
+    #[test]
+    fn shared_fixture() {
+        use crate::SharedFixture;
+        use std::sync::{Arc, Mutex};
+
+        /// A fixture which records its lifecycle events in a log.
+        struct Cluster {
+            id: usize,
+            log: Arc<Mutex<Vec<String>>>,
         }
+        /// Fetches the fixture, returning its ID.
+        struct FixtureRunner;
+        impl Runner for FixtureRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
 
-        fn end_command(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
-            self.end_command_count += 1;
-            Ok(String::new())
+            fn run_ctx(
+                &mut self,
+                _: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                let cluster = ctx.shared_fixture::<Cluster>()?;
+                let entry = format!("run {}", cluster.id);
+                cluster.log.lock().unwrap().push(entry);
+                Ok(cluster.id.to_string().into())
+            }
+        }
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let setup_log = log.clone();
+        let next_id = std::sync::atomic::AtomicUsize::new(1);
+        let fixture = SharedFixture::new(move || {
+            let id = next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            setup_log.lock().unwrap().push(format!("setup {id}"));
+            Ok(Cluster { id, log: setup_log.clone() })
+        })
+        .on_start_script(|cluster, namespace| {
+            cluster.log.lock().unwrap().push(format!("start {}", &namespace[..7]));
+            Ok(())
+        })
+        .on_end_script(|cluster, namespace| {
+            cluster.log.lock().unwrap().push(format!("end {}", &namespace[..7]));
+            Ok(())
+        })
+        .on_teardown(|cluster| {
+            cluster.log.lock().unwrap().push(format!("teardown {}", cluster.id));
+            Ok(())
+        });
+        let options = RunOptions::new().shared_fixture(&fixture);
+        let take_log = || std::mem::take(&mut *log.lock().unwrap());
+
+        // Scripts that don't fetch the fixture don't set it up.
+        assert_eq!(generate_with(&mut FixtureRunner, "", &options).unwrap(), "");
+        assert!(take_log().is_empty());
+
+        // Sequential scripts set up and tear down their own fixture, also when
+        // the script fails.
+        let input = "a\nb\n---\n1\n1\n";
+        assert_eq!(generate_with(&mut FixtureRunner, input, &options).unwrap(), input);
+        assert!(!fixture.is_set_up());
+        assert_eq!(
+            take_log(),
+            ["setup 1", "start script_", "run 1", "run 1", "end script_", "teardown 1"]
+        );
+        assert!(generate_with(&mut FixtureRunner, "a\n!b\n---\n", &options).is_err());
+        assert!(!fixture.is_set_up());
+        assert_eq!(
+            take_log(),
+            ["setup 2", "start script_", "run 2", "run 2", "end script_", "teardown 2"]
+        );
+
+        // While the fixture is in use, e.g. by concurrent scripts or a
+        // directory run, scripts share it.
+        options.shared_fixtures[0].0.acquire();
+        for _ in 0..2 {
+            let input = "a\n---\n3\n";
+            assert_eq!(generate_with(&mut FixtureRunner, input, &options).unwrap(), input);
         }
+        assert!(fixture.is_set_up());
+        options.shared_fixtures[0].0.release(None).unwrap();
+        assert!(!fixture.is_set_up());
+        assert_eq!(
+            take_log(),
+            [
+                "setup 3",
+                "start script_",
+                "run 3",
+                "end script_",
+                "start script_",
+                "run 3",
+                "end script_",
+                "teardown 3"
+            ]
+        );
+
+        // Runners error if the fixture isn't registered.
+        assert_eq!(
+            generate(&mut FixtureRunner, "a\n---\n").unwrap_err().to_string(),
+            "command 'a' failed at line 1: no shared fixture of type \
+             goldenscript::runner::tests::shared_fixture::Cluster registered"
+        );
     }
 
-    /// Tests that runner hooks are called as expected.
+    /// Tests the virtual clock and its built-in commands.
     #[test]
-    fn hooks() {
-        let mut runner = HookRunner::default();
-        generate(
-            &mut runner,
-            r#"
-command
----
+    fn virtual_clock() {
+        /// A key/value store whose keys expire after a TTL.
+        #[derive(Default)]
+        struct TTLRunner {
+            expires: HashMap<String, Duration>,
+        }
+        impl Runner for TTLRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
 
-command
-command
----
-"#,
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                let now = ctx.clock().now();
+                let key = command.args[0].value.clone();
+                match command.name.as_str() {
+                    "put" => {
+                        let ttl = command.args[1].parse_duration()?;
+                        self.expires.insert(key, now + ttl);
+                        Ok("ok".into())
+                    }
+                    "get" => match self.expires.get(&key) {
+                        Some(expires) if *expires > now => Ok("found".into()),
+                        _ => Ok("not found".into()),
+                    },
+                    name => Err(format!("unknown command {name}").into()),
+                }
+            }
+        }
+
+        let input =
+            "put foo 10s\n_advance_time 9s\nget foo\n_advance_time 1s\nget foo\n_now\n---\n\
+                     ok\nfound\nnot found\n10s\n";
+        assert_eq!(generate(&mut TTLRunner::default(), input).unwrap(), input);
+
+        // Each script has its own clock, and clones share the time.
+        let ctx = RunContext::new();
+        let clock = ctx.clock().clone();
+        assert_eq!(clock.advance(Duration::from_secs(3)), Duration::from_secs(3));
+        assert_eq!(ctx.clock().now(), Duration::from_secs(3));
+        assert_eq!(RunContext::new().clock().now(), Duration::ZERO);
+
+        // The time saturates.
+        clock.advance(Duration::MAX);
+        assert_eq!(clock.advance(Duration::MAX), Duration::from_nanos(u64::MAX));
+    }
+
+    /// Returns options with a reporter that collects notices, and the notices.
+    fn collect_notices() -> (RunOptions, std::sync::Arc<std::sync::Mutex<Vec<Notice>>>) {
+        let notices = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let options = RunOptions::new().reporter({
+            let notices = notices.clone();
+            move |notice: &Notice| notices.lock().unwrap().push(notice.clone())
+        });
+        (options, notices)
+    }
+
+    /// Tests that skipped blocks are reported.
+    #[test]
+    fn report_skipped() {
+        let (options, notices) = collect_notices();
+        let mut runner = FnRunner::stateless(|c| Ok(c.name.clone()));
+        let input = "[skip]\na\n---\nold\n\n[skip=flaky]\nb\n---\n\nc\n---\nc\n";
+        assert_eq!(generate_with(&mut runner, input, &options).unwrap(), input);
+        let notices = notices.lock().unwrap();
+        assert_eq!(
+            *notices,
+            vec![
+                Notice::BlockSkipped { line_number: 1, reason: String::new() },
+                Notice::BlockSkipped { line_number: 6, reason: "flaky".to_string() },
+            ]
+        );
+        assert_eq!(notices[1].to_string(), "skipped block at line 6: flaky");
+    }
+
+    /// Tests that halted scripts are reported, with the number of remaining
+    /// blocks.
+    #[test]
+    fn report_halted() {
+        let (options, notices) = collect_notices();
+        let mut runner = FnRunner::stateless(|c| Ok(c.name.clone()));
+        let input = "a\n%halt no network\n---\na\n\nb\n---\n\nc\n---\n";
+        assert_eq!(generate_with(&mut runner, input, &options).unwrap(), input);
+        let notices = notices.lock().unwrap();
+        assert_eq!(
+            *notices,
+            vec![Notice::ScriptHalted {
+                line_number: 1,
+                reason: "no network".to_string(),
+                remaining: 2
+            }]
+        );
+        assert_eq!(
+            notices[0].to_string(),
+            "script halted in block at line 1: no network (2 blocks not executed)"
+        );
+    }
+
+    /// Tests that %resources limits the resources used by concurrent scripts.
+    #[test]
+    fn resources() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+
+        /// Tracks the number of scripts running concurrently, and the maximum.
+        #[derive(Clone, Default)]
+        struct CountRunner {
+            running: Arc<AtomicU64>,
+            max: Arc<AtomicU64>,
+        }
+        impl Runner for CountRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max.fetch_max(running, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_millis(20));
+                self.running.fetch_sub(1, Ordering::SeqCst);
+                Ok(String::new())
+            }
+        }
+
+        // Run 4 scripts concurrently, using up to 3 CPUs and a port, under
+        // the given options. Returns the maximum concurrency.
+        let run = |options: RunOptions| {
+            let runner = CountRunner::default();
+            let scripts = ["%resources cpu=2 port\na\n---\nok\n", "%resources cpu=3\na\n---\nok\n"];
+            let threads: Vec<_> = (0..4)
+                .map(|i| {
+                    let (mut runner, options) = (runner.clone(), options.clone());
+                    std::thread::spawn(move || generate_with(&mut runner, scripts[i % 2], &options))
+                })
+                .collect();
+            for (i, thread) in threads.into_iter().enumerate() {
+                assert_eq!(thread.join().unwrap().unwrap(), scripts[i % 2]);
+            }
+            runner.max.load(Ordering::SeqCst)
+        };
+
+        // With limits, the scripts run one at a time, since there's room for
+        // neither two CPU-heavy scripts nor two ports. Requests exceeding the
+        // limit are clamped to it.
+        let options = RunOptions::new().resource_limit("cpu", 2).resource_limit("port", 1);
+        assert_eq!(run(options), 1);
+
+        // Without limits, the scripts all run concurrently.
+        assert_eq!(run(RunOptions::new()), 4);
+
+        // Waiting scripts can be cancelled.
+        let options = RunOptions::new().resource_limit("cpu", 1);
+        let reservation = Reservation::acquire(
+            &options.resource_pool,
+            &BTreeMap::from([("cpu".to_string(), 1)]),
+            &options.resource_limits,
+            &CancellationToken::new(),
         )
         .unwrap();
+        let token = CancellationToken::new();
+        token.cancel();
+        let options = options.cancellation_token(token);
+        assert_eq!(
+            generate_with(&mut CountRunner::default(), "%resources cpu\n---\n", &options)
+                .unwrap_err()
+                .to_string(),
+            "failed to reserve resources: cancelled"
+        );
 
-        assert_eq!(runner.start_script_count, 1);
-        assert_eq!(runner.end_script_count, 1);
-        assert_eq!(runner.start_block_count, 2);
-        assert_eq!(runner.end_block_count, 2);
-        assert_eq!(runner.start_command_count, 3);
-        assert_eq!(runner.end_command_count, 3);
+        // Independently created options don't share reservations.
+        let options = RunOptions::new().resource_limit("cpu", 1);
+        generate_with(&mut CountRunner::default(), "%resources cpu\n---\n", &options).unwrap();
+        drop(reservation);
+    }
+
+    /// Tests that the initial seed can be set via the options, and is reported
+    /// on failures.
+    #[test]
+    fn initial_seed() {
+        /// Outputs a random number, or errors for the fail command.
+        struct RandomRunner;
+        impl Runner for RandomRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "fail" => Err("failed".into()),
+                    "panic" => panic!("boom"),
+                    _ => Ok(ctx.random_below(1000).to_string().into()),
+                }
+            }
+        }
+
+        // The option seeds the generator like %seed, which can reseed it.
+        let seeded = generate(&mut RandomRunner, "%seed 7\na\n---\n").unwrap();
+        let options = RunOptions::new().seed(7);
+        let output = generate_with(&mut RandomRunner, "a\n---\n", &options).unwrap();
+        assert_eq!(output.lines().last(), seeded.lines().last());
+        assert_ne!(
+            output.lines().last(),
+            generate(&mut RandomRunner, "a\n---\n").unwrap().lines().last()
+        );
+        let reseeded = generate_with(&mut RandomRunner, "%seed 0\na\n---\n", &options).unwrap();
+        assert_eq!(
+            reseeded.lines().last(),
+            generate(&mut RandomRunner, "a\n---\n").unwrap().lines().last()
+        );
+
+        // Failures report the initial seed, but only if given.
+        assert_eq!(
+            generate_with(&mut RandomRunner, "%seed 1\nfail\n---\n", &options)
+                .unwrap_err()
+                .to_string(),
+            "command 'fail' failed at line 2: failed (GOLDENSCRIPT_SEED=7)"
+        );
+        assert_eq!(
+            generate(&mut RandomRunner, "fail\n---\n").unwrap_err().to_string(),
+            "command 'fail' failed at line 1: failed"
+        );
+        let panic = std::panic::catch_unwind(|| {
+            generate_with(&mut RandomRunner, "panic\n---\n", &RunOptions::new().seed(7))
+        })
+        .unwrap_err();
+        assert_eq!(panic_message(&*panic).unwrap(), "boom (GOLDENSCRIPT_SEED=7)");
+
+        // Differing output reports the seed.
+        let (options, notices) = collect_notices();
+        let options = options.seed(7);
+        generate_with(&mut RandomRunner, "a\n---\n", &options).unwrap();
+        assert_eq!(*notices.lock().unwrap(), vec![Notice::OutputDiffers { seed: 7 }]);
+    }
+
+    /// Tests the optional built-in commands.
+    #[test]
+    fn builtin_commands() {
+        /// Outputs the command name and the value of the script variable x,
+        /// or the script environment for the env command.
+        struct VarRunner;
+        impl Runner for VarRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                if command.name == "env" {
+                    return Ok(format!("{:?}", ctx.env()).into());
+                }
+                Ok(format!("{} x={}", command.name, ctx.var("x").unwrap_or("unset")).into())
+            }
+
+            fn known_commands(&self) -> Option<Vec<&str>> {
+                Some(vec!["a", "env"])
+            }
+
+            fn capabilities(&self) -> HashSet<Capability> {
+                HashSet::from([Capability::Env])
+            }
+        }
+
+        // When disabled, built-ins are passed to the runner.
+        assert_eq!(
+            generate(&mut VarRunner, "_set x=1\n---\n").unwrap_err().to_string(),
+            "unknown commands:\nline 1: _set"
+        );
+
+        // When enabled, they're handled by goldenscript.
+        let options = RunOptions::new().builtin_commands(true);
+        let input = "_comment ignored [tag]\n_set x=1 y=2\na\n_sleep 1ms\n_set x\na\n---\n\
+                     a x=1\na x=unset\n";
+        assert_eq!(generate_with(&mut VarRunner, input, &options).unwrap(), input);
+
+        // _env sets script environment variables, not process ones.
+        let input = "_env GOLDENSCRIPT_TEST_BUILTIN=1 a=2\n_env a\nenv\n---\n\
+                     {\"GOLDENSCRIPT_TEST_BUILTIN\": \"1\"}\n";
+        assert_eq!(generate_with(&mut VarRunner, input, &options).unwrap(), input);
+        assert!(std::env::var("GOLDENSCRIPT_TEST_BUILTIN").is_err());
+
+        // _skip skips the rest of the block, but not later blocks.
+        let input = "a\n_skip not supported\na\n---\na x=unset\nskipped: not supported\n\n\
+                     _skip\n---\nskipped\n\na\n---\na x=unset\n";
+        assert_eq!(generate_with(&mut VarRunner, input, &options).unwrap(), input);
+
+        // Errors are reported as command failures.
+        assert_eq!(
+            generate_with(&mut VarRunner, "_sleep foo\n---\n", &options).unwrap_err().to_string(),
+            "command '_sleep' failed at line 1: invalid duration 'foo'"
+        );
+        assert_eq!(
+            generate_with(&mut VarRunner, "_skip key=value\n---\n", &options)
+                .unwrap_err()
+                .to_string(),
+            "command '_skip' failed at line 1: invalid argument 'key'"
+        );
+
+        // _env requires the env capability.
+        assert_eq!(
+            generate_with(
+                &mut FnRunner::stateless(|_| Ok(String::new())),
+                "_env a=1\n---\n",
+                &options
+            )
+            .unwrap_err()
+            .to_string(),
+            "command '_env' failed at line 1: runner does not enable the env capability"
+        );
+    }
+
+    /// Tests that %seed auto picks a random seed, which is reported on failures
+    /// and pinned when recording.
+    #[test]
+    fn seed_auto() {
+        /// Outputs a random number, or errors for the fail command.
+        struct RandomRunner;
+        impl Runner for RandomRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "fail" => Err("failed".into()),
+                    _ => Ok(ctx.random().to_string().into()),
+                }
+            }
+        }
+
+        // Each run uses a different seed.
+        let input = "%seed auto\na\n---\n";
+        let a = generate(&mut RandomRunner, input).unwrap();
+        let b = generate(&mut RandomRunner, input).unwrap();
+        assert_ne!(a, b);
+        assert!(a.starts_with(input));
+
+        // When pinned, the directive is replaced by the chosen seed, which
+        // reproduces the output.
+        // The chosen seed is reported.
+        let input = "# comment\n%seed auto # auto\na\n---\n";
+        let (options, notices) = collect_notices();
+        let options = options.pin_auto_seeds(true);
+        let pinned = generate_with(&mut RandomRunner, input, &options).unwrap();
+        let seed: u64 = pinned.lines().nth(1).unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+        assert!(pinned.starts_with(&format!("# comment\n%seed {seed} # auto\na\n---\n")));
+        assert_eq!(notices.lock().unwrap()[0], Notice::AutoSeed { line_number: 2, seed });
+        assert_eq!(generate(&mut RandomRunner, &pinned).unwrap(), pinned);
+
+        // Failures report the chosen seed.
+        let error = generate(&mut RandomRunner, "%seed auto\nfail\n---\n").unwrap_err().to_string();
+        assert!(error.starts_with("command 'fail' failed at line 2: failed (GOLDENSCRIPT_SEED="));
+    }
+
+    /// Tests checkpointing and restoring runner and context state.
+    #[test]
+    fn checkpoint() {
+        /// A key/value store with checkpoints. Also outputs random numbers
+        /// and the virtual clock.
+        #[derive(Default)]
+        struct KVRunner {
+            data: BTreeMap<String, String>,
+            checkpoints: HashMap<String, BTreeMap<String, String>>,
+        }
+        impl Runner for KVRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "put" => {
+                        let arg = &command.args[0];
+                        self.data.insert(arg.name().to_string(), arg.value.clone());
+                        Ok(String::new().into())
+                    }
+                    "dump" => Ok(format!("{:?} {:?}", self.data, ctx.clock().now()).into()),
+                    "random" => Ok(ctx.random_below(1000).to_string().into()),
+                    name => Err(format!("unknown command {name}").into()),
+                }
+            }
+
+            fn checkpoint(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+                self.checkpoints.insert(name.to_string(), self.data.clone());
+                Ok(())
+            }
+
+            fn restore(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+                self.data.clone_from(&self.checkpoints[name]);
+                Ok(())
+            }
+        }
+
+        // Restoring a checkpoint resets the runner state, the virtual clock,
+        // and the random number generator, and can be done repeatedly.
+        let input = "put a=1\n_checkpoint base\nrandom\n---\n";
+        let output = generate(&mut KVRunner::default(), input).unwrap();
+        let random = output.lines().last().unwrap();
+        let input = format!(
+            "{output}\nput b=2\n_advance_time 1s\ndump\n_restore base\ndump\nrandom\n---\n\
+             {{\"a\": \"1\", \"b\": \"2\"}} 1s\n{{\"a\": \"1\"}} 0ns\n{random}\n\n\
+             put c=3\n_restore base\ndump\n---\n{{\"a\": \"1\"}} 0ns\n"
+        );
+        assert_eq!(generate(&mut KVRunner::default(), &input).unwrap(), input);
+
+        // Runner errors are reported.
+        struct FailRunner;
+        impl Runner for FailRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn checkpoint(&mut self, _: &str) -> Result<(), Box<dyn Error>> {
+                Ok(())
+            }
+            fn restore(&mut self, _: &str) -> Result<(), Box<dyn Error>> {
+                Err("restore failed".into())
+            }
+        }
+        assert_eq!(
+            generate(&mut FailRunner, "_checkpoint a\n_restore a\n---\n").unwrap_err().to_string(),
+            "command '_restore' failed at line 2: restore failed"
+        );
+    }
+
+    /// Tests %branch, which runs each branch from the state at the first one.
+    #[test]
+    fn branch() {
+        /// A key/value store with checkpoints.
+        #[derive(Default)]
+        struct KVRunner {
+            data: BTreeMap<String, String>,
+            checkpoints: HashMap<String, BTreeMap<String, String>>,
+        }
+        impl Runner for KVRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "put" => {
+                        let arg = &command.args[0];
+                        self.data.insert(arg.name().to_string(), arg.value.clone());
+                        Ok(String::new().into())
+                    }
+                    "dump" => Ok(format!("{:?} {:?}", self.data, ctx.clock().now()).into()),
+                    name => Err(format!("unknown command {name}").into()),
+                }
+            }
+
+            fn checkpoint(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+                self.checkpoints.insert(name.to_string(), self.data.clone());
+                Ok(())
+            }
+
+            fn restore(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+                self.data.clone_from(&self.checkpoints[name]);
+                Ok(())
+            }
+        }
+
+        // Each branch, including its later blocks, continues from the common
+        // prefix, and the runner sees the reserved checkpoint name.
+        let input = "put a=1\n---\nok\n\n\
+                     %branch commit\nput b=2\n_advance_time 1s\n---\nok\n\n\
+                     dump\n---\n{\"a\": \"1\", \"b\": \"2\"} 1s\n\n\
+                     %branch rollback\ndump\n---\n{\"a\": \"1\"} 0ns\n";
+        let mut runner = KVRunner::default();
+        assert_eq!(generate(&mut runner, input).unwrap(), input);
+        assert!(runner.checkpoints.contains_key(BRANCH_CHECKPOINT));
+
+        // Runners without checkpoint support error.
+        struct NoopRunner;
+        impl Runner for NoopRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+        }
+        assert_eq!(
+            generate(&mut NoopRunner, "%branch a\n---\n").unwrap_err().to_string(),
+            "directive %branch failed at line 1: Runner::checkpoint() not implemented"
+        );
+    }
+
+    /// Tests that the hooks share per-script state via the context's
+    /// extensions, including for runners created lazily by a PrefixRouter.
+    #[test]
+    fn context_extensions() {
+        /// Records the hook calls in the context extensions.
+        struct Calls(Vec<String>);
+
+        struct ExtRunner;
+        impl Runner for ExtRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                let calls = ctx.extensions_mut().get_mut::<Calls>().ok_or("no calls")?;
+                calls.0.push(command.name.clone());
+                Ok(calls.0.join(",").into())
+            }
+
+            fn start_script(&mut self, ctx: &mut RunContext) -> Result<(), Box<dyn Error>> {
+                ctx.extensions_mut().insert(Calls(vec!["start_script".into()]));
+                Ok(())
+            }
+
+            fn start_block(
+                &mut self,
+                _: &BlockInfo,
+                ctx: &mut RunContext,
+            ) -> Result<String, Box<dyn Error>> {
+                ctx.extensions_mut().get_or_insert_with(|| 0_u32);
+                *ctx.extensions_mut().get_mut::<u32>().unwrap() += 1;
+                Ok(String::new())
+            }
+
+            fn end_command(
+                &mut self,
+                _: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<String, Box<dyn Error>> {
+                let blocks = ctx.extensions().get::<u32>().copied().unwrap_or_default();
+                Ok(format!("blocks={blocks}"))
+            }
+
+            fn end_script(&mut self, ctx: &mut RunContext) -> Result<String, Box<dyn Error>> {
+                let calls = ctx.extensions_mut().remove::<Calls>().unwrap();
+                assert!(!ctx.extensions().contains::<Calls>());
+                Ok(format!("{} calls", calls.0.len()))
+            }
+        }
+
+        let input = "a\n---\nstart_script,a\nblocks=1\n\n\
+                     b\n---\nstart_script,a,b\nblocks=2\n\n\
+                     %end-script\n---\n3 calls\n";
+        assert_eq!(generate(&mut ExtRunner, input).unwrap(), input);
+
+        // The router's runner is created after the first start_block hook.
+        let mut router = PrefixRouter::new().factory(|_| Ok(ExtRunner));
+        let input = "x: a\n---\nx: start_script,a\nx: blocks=0\n\n%end-script\n---\n2 calls\n";
+        assert_eq!(generate(&mut router, input).unwrap(), input);
+    }
+
+    /// Tests that invariant violations from end_command() and
+    /// check_invariants() are rendered below the command output.
+    #[test]
+    fn invariant_violation() {
+        struct InvariantRunner;
+        impl Runner for InvariantRunner {
+            fn run(&mut self, command: &Command) -> Result<String, Box<dyn Error>> {
+                Ok(command.name.clone())
+            }
+
+            fn end_command(
+                &mut self,
+                command: &Command,
+                _: &mut RunContext,
+            ) -> Result<String, Box<dyn Error>> {
+                match command.name.as_str() {
+                    "end" => Err(InvariantViolation::new("end failed").into()),
+                    _ => Ok(String::new()),
+                }
+            }
+
+            fn check_invariants(
+                &mut self,
+                command: &Command,
+                _: &mut RunContext,
+            ) -> Result<(), Box<dyn Error>> {
+                match command.name.as_str() {
+                    "end" => panic!("check_invariants called after end_command violation"),
+                    "diverge" => Err(InvariantViolation::new("replicas diverged")
+                        .with_diff("a=1\nb=2\nc=3\n", "a=1\nb=3\nc=3\nd=4\n")
+                        .into()),
+                    "broken" => Err("broken".into()),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let input = "ok\nend\n---\nok\nend\ninvariant violated: end failed\n\n\
+                     diverge\n---\ndiverge\ninvariant violated: replicas diverged\n  \
+                     a=1\n- b=2\n+ b=3\n  c=3\n+ d=4\n";
+        assert_eq!(generate(&mut InvariantRunner, input).unwrap(), input);
+
+        // A violation satisfies %expect-fail.
+        let input = "%expect-fail\nend\n---\nend\ninvariant violated: end failed\n";
+        assert_eq!(generate(&mut InvariantRunner, input).unwrap(), input);
+
+        // Other errors abort the script.
+        assert_eq!(
+            generate(&mut InvariantRunner, "broken\n---\n").unwrap_err().to_string(),
+            "check_invariants failed at line 1: broken"
+        );
+    }
+
+    /// Tests script metadata given as front matter, which is available via the
+    /// run context, including in start_script().
+    #[test]
+    fn front_matter() {
+        #[derive(Default)]
+        struct MetadataRunner {
+            engine: Option<String>,
+        }
+        impl Runner for MetadataRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                _: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                let seed: Option<u64> = ctx.metadata().parse_value("seed")?;
+                Ok(format!("{:?} {seed:?}", self.engine).into())
+            }
+
+            fn start_script(&mut self, ctx: &mut RunContext) -> Result<(), Box<dyn Error>> {
+                self.engine = ctx.metadata().get("engine").map(|e| e.to_string());
+                Ok(())
+            }
+        }
+
+        let input = "---\nengine: memory\nseed: 7\n---\nget\n---\nSome(\"memory\") Some(7)\n";
+        assert_eq!(generate(&mut MetadataRunner::default(), input).unwrap(), input);
+
+        // Without front matter, the metadata is empty.
+        let input = "get\n---\nNone None\n";
+        assert_eq!(generate(&mut MetadataRunner::default(), input).unwrap(), input);
+
+        // Invalid values error when parsed.
+        let input = "---\nseed: x\n---\n!get\n---\n\
+                     Error: invalid seed 'x': invalid digit found in string\n";
+        assert_eq!(generate(&mut MetadataRunner::default(), input).unwrap(), input);
+    }
+
+    /// Tests review_update() with a ReviewPolicy, which can reject golden
+    /// output updates when recording. UPDATE_GOLDENFILES can't be set in
+    /// parallel tests, so this calls it directly.
+    #[test]
+    fn review_policy() {
+        use std::sync::{Arc, Mutex};
+
+        struct KeepErrors(Arc<Mutex<Vec<String>>>);
+        impl ReviewPolicy for KeepErrors {
+            fn review_block(&mut self, input: &str, old: &str, new: &str) -> Result<(), String> {
+                self.0.lock().unwrap().push(format!("{input:?} {old:?} {new:?}"));
+                match new.contains("Error:") {
+                    true => Err("new error".to_string()),
+                    false => Ok(()),
+                }
+            }
+        }
+
+        let reviews = Arc::new(Mutex::new(Vec::new()));
+        let (options, notices) = collect_notices();
+        let options = options.review_policy(KeepErrors(reviews.clone()));
+        let blocks = crate::parser::parse("# comment\nget\n---\nok\n").unwrap();
+        let block = blocks[0].clone().into_owned();
+
+        // Accepted updates use the new output.
+        let mut output = "value\n".to_string();
+        review_update(&options, &block, "ok\n", &mut output);
+        assert_eq!(output, "value\n");
+
+        // Rejected updates keep the old output, and are reported.
+        let mut output = "Error: boom\n".to_string();
+        review_update(&options, &block, "ok\n", &mut output);
+        assert_eq!(output, "ok\n");
+        assert_eq!(
+            notices.lock().unwrap().iter().map(|n| n.to_string()).collect::<Vec<_>>(),
+            vec!["update rejected for block at line 1: new error"]
+        );
+
+        // Unchanged output isn't reviewed.
+        review_update(&options, &block, "ok\n", &mut output);
+
+        assert_eq!(
+            *reviews.lock().unwrap(),
+            vec![
+                r##""# comment\nget\n" "ok\n" "value\n""##,
+                r##""# comment\nget\n" "ok\n" "Error: boom\n""##,
+            ]
+        );
+
+        // Without UPDATE_GOLDENFILES, the policy isn't used.
+        if std::env::var("UPDATE_GOLDENFILES").is_err() {
+            let mut runner = FnRunner::stateless(|_| Err("boom".into()));
+            let output = generate_with(&mut runner, "!get\n---\nok\n", &options).unwrap();
+            assert_eq!(output, "!get\n---\nError: boom\n");
+            assert_eq!(reviews.lock().unwrap().len(), 2);
+        }
+    }
+
+    /// Tests RunOptions::cache_setup(), which restores cached %setup sections
+    /// via Runner::serialize_state() and Runner::restore_state().
+    #[test]
+    fn cache_setup() {
+        #[derive(Default)]
+        struct StateRunner {
+            data: Vec<String>,
+            ran: Vec<String>,
+        }
+        impl Runner for StateRunner {
+            fn run(&mut self, _: &Command) -> Result<String, Box<dyn Error>> {
+                unreachable!()
+            }
+
+            fn run_ctx(
+                &mut self,
+                command: &Command,
+                ctx: &mut RunContext,
+            ) -> Result<Output, Box<dyn Error>> {
+                self.ran.push(command.name.clone());
+                match command.name.as_str() {
+                    "dump" => Ok(format!("{:?} {}", self.data, ctx.random_below(100)).into()),
+                    name => {
+                        // Advance the random number generator, which is cached.
+                        ctx.random();
+                        self.data.push(name.to_string());
+                        Ok(String::new().into())
+                    }
+                }
+            }
+
+            fn serialize_state(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
+                Ok(self.data.join(",").into_bytes())
+            }
+
+            fn restore_state(&mut self, state: &[u8]) -> Result<(), Box<dyn Error>> {
+                let state = std::str::from_utf8(state)?;
+                self.data = state.split(',').map(String::from).collect();
+                Ok(())
+            }
+        }
+
+        let options = RunOptions::new().cache_setup(true);
+        let input = "%setup verbose\na\nb\n---\nok\n\ndump\n---\n[\"a\", \"b\"] 79\n";
+
+        // The first script runs the setup, and later ones restore it, along with
+        // the context state. Clones of the options share the cache.
+        let mut runner = StateRunner::default();
+        assert_eq!(generate_with(&mut runner, input, &options).unwrap(), input);
+        assert_eq!(runner.ran, vec!["a", "b", "dump"]);
+
+        let mut runner = StateRunner::default();
+        assert_eq!(generate_with(&mut runner, input, &options.clone()).unwrap(), input);
+        assert_eq!(runner.ran, vec!["dump"]);
+
+        // A different setup, or setup with other directives, isn't cached.
+        let input = "%setup\na\n---\nok\n\ndump\n---\n[\"a\"] 0\n";
+        let mut runner = StateRunner::default();
+        assert_eq!(generate_with(&mut runner, input, &options).unwrap(), input);
+        assert_eq!(runner.ran, vec!["a", "dump"]);
+
+        let input = "%setup\n%seed 1\na\n---\nok\n";
+        for _ in 0..2 {
+            let mut runner = StateRunner::default();
+            assert_eq!(generate_with(&mut runner, input, &options).unwrap(), input);
+            assert_eq!(runner.ran, vec!["a"]);
+        }
+
+        // Without the cache, setup always runs.
+        let input = "%setup\na\n---\nok\n";
+        let mut runner = StateRunner::default();
+        assert_eq!(generate(&mut runner, input).unwrap(), input);
+        assert_eq!(runner.ran, vec!["a"]);
     }
 }
@@ -0,0 +1,429 @@
+//! Declarative command schemas, for validating commands before they're run.
+//!
+//! A [`Schema`] declares the expected signature of commands: their key/value
+//! arguments, positional argument arity, and value types. When given via
+//! [`RunOptions::schema`](crate::RunOptions::schema), all commands in a
+//! script are validated before any of them are run, and invalid commands are
+//! reported together with their line numbers, e.g.:
+//!
+//! ```text
+//! invalid commands:
+//! line 3: put: missing key 'value'
+//! line 7: get: expected 1 positional argument, got 2
+//! ```
+//!
+//! This enforces consistent command usage across large test suites, and gives
+//! uniform errors without the runner having to validate arguments itself.
+//!
+//! ```
+//! use goldenscript::schema::{CommandSchema, Schema};
+//! use goldenscript::ValueType;
+//!
+//! let schema = Schema::new()
+//!     .command(CommandSchema::new("get").positional(1..=1))
+//!     .command(
+//!         CommandSchema::new("put")
+//!             .positional(1..=1)
+//!             .required("value", ValueType::String)
+//!             .optional("ttl", ValueType::Integer),
+//!     );
+//! let options = goldenscript::RunOptions::new().schema(schema);
+//! ```
+//!
+//! For existing test suites, a draft schema can be inferred from the commands
+//! used in all scripts in a set of directories via [`Schema::infer_dirs`], and printed
+//! as Rust code via [`Schema::format_rust`]. The `goldenscript-schema` binary
+//! (with the `cli` feature) does this for the given directories. The draft
+//! only reflects observed usage, so it should be reviewed before use.
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+
+use crate::{Command, ValueType};
+
+/// A set of command schemas. Commands without a schema, and directives, are
+/// not validated.
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    commands: HashMap<String, CommandSchema>,
+}
+
+impl Schema {
+    /// Creates an empty schema.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a command schema, replacing any existing schema for the command.
+    pub fn command(mut self, schema: CommandSchema) -> Self {
+        self.commands.insert(schema.name.clone(), schema);
+        self
+    }
+
+    /// Validates a command against its schema, if any. Returns an error
+    /// listing all problems with the command, separated by "; ".
+    pub fn validate(&self, command: &Command) -> Result<(), Box<dyn Error>> {
+        match self.commands.get(&command.name) {
+            Some(schema) if !command.directive => schema.validate(command),
+            _ => Ok(()),
+        }
+    }
+
+    /// Infers a draft schema from the given commands, ignoring directives. For
+    /// each command, keys present in all uses are required and others
+    /// optional, the positional arity is the observed range, and value types
+    /// are the most specific type that fits all observed values (integers
+    /// widen to floats, and other mismatches to strings).
+    pub fn infer<'a>(commands: impl IntoIterator<Item = &'a Command>) -> Self {
+        let mut usages: BTreeMap<&str, Vec<&Command>> = BTreeMap::new();
+        for command in commands.into_iter().filter(|c| !c.directive) {
+            usages.entry(&command.name).or_default().push(command);
+        }
+
+        let mut schema = Self::new();
+        for (name, usages) in usages {
+            let mut command = CommandSchema::new(name);
+
+            // Infer key/value arguments, ordered by key.
+            let mut keys: BTreeMap<&str, (Option<ValueType>, usize)> = BTreeMap::new();
+            for usage in &usages {
+                for arg in &usage.args {
+                    let Some(key) = arg.key.as_deref() else {
+                        continue;
+                    };
+                    let (value_type, count) = keys.entry(key).or_default();
+                    *value_type = Some(widen(*value_type, arg.value_type));
+                    *count += 1;
+                }
+            }
+            for (key, (value_type, count)) in keys {
+                let value_type = value_type.unwrap_or(ValueType::String);
+                command = match count >= usages.len() {
+                    true => command.required(key, value_type),
+                    false => command.optional(key, value_type),
+                };
+            }
+
+            // Infer positional arguments.
+            let mut positional_type = None;
+            let (mut min, mut max) = (usize::MAX, 0);
+            for usage in &usages {
+                let positional: Vec<_> = usage.args.iter().filter(|a| a.key.is_none()).collect();
+                min = min.min(positional.len());
+                max = max.max(positional.len());
+                for arg in positional {
+                    positional_type = Some(widen(positional_type, arg.value_type));
+                }
+            }
+            command = command.positional(min..=max);
+            if let Some(value_type) = positional_type {
+                command = command.positional_type(value_type);
+            }
+
+            schema = schema.command(command);
+        }
+        schema
+    }
+
+    /// Infers a draft schema from all goldenscripts in the given directories
+    /// and their subdirectories, skipping hidden files. See [`Schema::infer`].
+    pub fn infer_dirs(dirs: impl IntoIterator<Item = impl AsRef<Path>>) -> std::io::Result<Self> {
+        let mut commands = Vec::new();
+        for dir in dirs {
+            for (_, path) in crate::util::find_scripts(dir.as_ref())? {
+                let input = std::fs::read_to_string(&path)?;
+                let blocks = crate::parse_borrowed(&input).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("{}: {e}", path.display()),
+                    )
+                })?;
+                commands.extend(blocks.into_iter().flat_map(|b| b.into_owned().commands));
+            }
+        }
+        Ok(Self::infer(&commands))
+    }
+
+    /// Formats the schema as Rust code that constructs it, ordered by command
+    /// name, e.g. to bootstrap a hand-maintained schema from an inferred one.
+    pub fn format_rust(&self) -> String {
+        let mut commands: Vec<_> = self.commands.values().collect();
+        commands.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut output = "Schema::new()".to_string();
+        for command in commands {
+            let mut methods = Vec::new();
+            match command.positional {
+                (0, Some(0)) => {}
+                (min, Some(max)) => methods.push(format!(".positional({min}..={max})")),
+                (min, None) => methods.push(format!(".positional({min}..)")),
+            }
+            if command.positional_type != ValueType::String {
+                methods.push(format!(".positional_type(ValueType::{:?})", command.positional_type));
+            }
+            for (key, value_type, required) in &command.keys {
+                let method = if *required { "required" } else { "optional" };
+                methods.push(format!(".{method}({key:?}, ValueType::{value_type:?})"));
+            }
+
+            let new = format!("CommandSchema::new({:?})", command.name);
+            // Commands with keys are formatted across multiple lines.
+            match command.keys.is_empty() {
+                true => write!(output, "\n    .command({new}{})", methods.concat()).unwrap(),
+                false => {
+                    write!(output, "\n    .command(\n        {new}").unwrap();
+                    for method in methods {
+                        write!(output, "\n            {method}").unwrap();
+                    }
+                    output.push_str(",\n    )");
+                }
+            }
+        }
+        output.push('\n');
+        output
+    }
+}
+
+/// The expected signature of a command. By default, a command takes no
+/// arguments.
+#[derive(Clone, Debug)]
+pub struct CommandSchema {
+    name: String,
+    /// Key/value arguments, as key, type, and whether they're required.
+    keys: Vec<(String, ValueType, bool)>,
+    /// The minimum and maximum number of positional arguments.
+    positional: (usize, Option<usize>),
+    /// The type of positional arguments.
+    positional_type: ValueType,
+}
+
+impl CommandSchema {
+    /// Creates a schema for the command with the given name.
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            keys: Vec::new(),
+            positional: (0, Some(0)),
+            positional_type: ValueType::String,
+        }
+    }
+
+    /// Adds a required key/value argument of the given type.
+    pub fn required(mut self, key: &str, value_type: ValueType) -> Self {
+        self.keys.push((key.to_string(), value_type, true));
+        self
+    }
+
+    /// Adds an optional key/value argument of the given type.
+    pub fn optional(mut self, key: &str, value_type: ValueType) -> Self {
+        self.keys.push((key.to_string(), value_type, false));
+        self
+    }
+
+    /// Sets the allowed number of positional arguments, e.g. `1..=2` or `1..`.
+    pub fn positional(mut self, arity: impl RangeBounds<usize>) -> Self {
+        let min = match arity.start_bound() {
+            Bound::Included(&n) => n,
+            Bound::Excluded(&n) => n + 1,
+            Bound::Unbounded => 0,
+        };
+        let max = match arity.end_bound() {
+            Bound::Included(&n) => Some(n),
+            Bound::Excluded(&n) => Some(n.saturating_sub(1)),
+            Bound::Unbounded => None,
+        };
+        self.positional = (min, max);
+        self
+    }
+
+    /// Sets the type of positional arguments. Defaults to strings.
+    pub fn positional_type(mut self, value_type: ValueType) -> Self {
+        self.positional_type = value_type;
+        self
+    }
+
+    /// Validates a command against the schema.
+    fn validate(&self, command: &Command) -> Result<(), Box<dyn Error>> {
+        let mut errors = Vec::new();
+
+        // Check key/value arguments.
+        for arg in &command.args {
+            let Some(key) = arg.key.as_deref() else {
+                continue;
+            };
+            match self.keys.iter().find(|(k, _, _)| k == key) {
+                Some((_, value_type, _)) if !is_type(&arg.value, *value_type) => errors.push(
+                    format!("invalid {key} '{}', expected {}", arg.value, type_name(*value_type)),
+                ),
+                Some(_) => {}
+                None => errors.push(format!("unknown key '{key}'")),
+            }
+        }
+        for (key, _, _) in self.keys.iter().filter(|(_, _, required)| *required) {
+            if !command.args.iter().any(|a| a.key.as_deref() == Some(key)) {
+                errors.push(format!("missing key '{key}'"));
+            }
+        }
+
+        // Check positional arguments.
+        let positional: Vec<_> = command.args.iter().filter(|a| a.key.is_none()).collect();
+        let (min, max) = self.positional;
+        if positional.len() < min || max.is_some_and(|max| positional.len() > max) {
+            let expected = match max {
+                Some(max) if max == min => format!("{min}"),
+                Some(max) => format!("{min} to {max}"),
+                None => format!("at least {min}"),
+            };
+            let plural = if max.unwrap_or(min) == 1 { "" } else { "s" };
+            errors.push(format!(
+                "expected {expected} positional argument{plural}, got {}",
+                positional.len()
+            ));
+        }
+        for arg in positional.iter().filter(|a| !is_type(&a.value, self.positional_type)) {
+            let expected = type_name(self.positional_type);
+            errors.push(format!("invalid argument '{}', expected {expected}", arg.value));
+        }
+
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors.join("; ").into()),
+        }
+    }
+}
+
+/// Widens an inferred value type to also fit the given value type.
+fn widen(inferred: Option<ValueType>, value_type: ValueType) -> ValueType {
+    match (inferred, value_type) {
+        (None, value_type) => value_type,
+        (Some(inferred), value_type) if inferred == value_type => inferred,
+        (Some(ValueType::Integer), ValueType::Float)
+        | (Some(ValueType::Float), ValueType::Integer) => ValueType::Float,
+        (Some(_), _) => ValueType::String,
+    }
+}
+
+/// Returns true if the value can be parsed as the given type. Strings accept
+/// any value, and floats accept integers.
+fn is_type(value: &str, value_type: ValueType) -> bool {
+    match (value_type, ValueType::infer(value)) {
+        (ValueType::String, _) => true,
+        (ValueType::Float, ValueType::Integer) => true,
+        (expect, actual) => expect == actual,
+    }
+}
+
+/// Returns a human-readable name for a value type.
+fn type_name(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::String => "string",
+        ValueType::Integer => "integer",
+        ValueType::Float => "float",
+        ValueType::Boolean => "boolean",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a schema for the tests.
+    fn schema() -> Schema {
+        Schema::new()
+            .command(CommandSchema::new("get").positional(1..=1))
+            .command(
+                CommandSchema::new("put")
+                    .positional(1..)
+                    .required("value", ValueType::String)
+                    .optional("ttl", ValueType::Integer)
+                    .optional("ratio", ValueType::Float),
+            )
+            .command(CommandSchema::new("del").positional(..3).positional_type(ValueType::Integer))
+    }
+
+    /// Tests command validation.
+    #[test]
+    fn validate() {
+        let schema = schema();
+        for (input, expect) in [
+            ("get a", Ok(())),
+            ("put a b value=1 ttl=10 ratio=1", Ok(())),
+            ("del", Ok(())),
+            ("del 1 2", Ok(())),
+            ("other a b=c", Ok(())),
+            ("get", Err("expected 1 positional argument, got 0")),
+            ("get a b", Err("expected 1 positional argument, got 2")),
+            ("put value=1", Err("expected at least 1 positional argument, got 0")),
+            ("put a", Err("missing key 'value'")),
+            (
+                "put a value=1 ttl=x foo=bar",
+                Err("invalid ttl 'x', expected integer; unknown key 'foo'"),
+            ),
+            ("put a value=1 ratio=true", Err("invalid ratio 'true', expected float")),
+            ("del 1 2 3", Err("expected 0 to 2 positional arguments, got 3")),
+            ("del a", Err("invalid argument 'a', expected integer")),
+        ] {
+            let command = crate::parser::parse_command(&format!("{input}\n")).unwrap();
+            let result = schema.validate(&command).map_err(|e| e.to_string());
+            assert_eq!(result, expect.map_err(|e| e.to_string()), "{input}");
+        }
+    }
+
+    /// Tests schema inference and formatting.
+    #[test]
+    fn infer() {
+        let input = "get a\nget b\nput a value=1 ttl=10\nput b value=x ratio=1\nput c 'd' value=y ratio=0.5\n\
+                     del 1\ndel 2 3.5\nping\n%seed 1\n---\n";
+        let commands: Vec<_> =
+            crate::parse_borrowed(input).unwrap().remove(0).into_owned().commands;
+        let schema = Schema::infer(&commands);
+        assert_eq!(
+            schema.format_rust(),
+            r#"Schema::new()
+    .command(CommandSchema::new("del").positional(1..=2).positional_type(ValueType::Float))
+    .command(CommandSchema::new("get").positional(1..=1))
+    .command(CommandSchema::new("ping"))
+    .command(
+        CommandSchema::new("put")
+            .positional(1..=2)
+            .optional("ratio", ValueType::Float)
+            .optional("ttl", ValueType::Integer)
+            .required("value", ValueType::String),
+    )
+"#
+        );
+
+        // The inferred schema accepts all commands it was inferred from.
+        for command in &commands {
+            assert!(schema.validate(command).is_ok(), "{command:?}");
+        }
+    }
+
+    /// Tests that RunOptions::schema() validates all commands before running
+    /// them.
+    #[test]
+    fn run_options() {
+        let mut runner = crate::FnRunner::new(0, |count: &mut usize, _| {
+            *count += 1;
+            Ok(String::new())
+        });
+        let options = crate::RunOptions::new().schema(schema());
+
+        let input = "get a\n%seed 1\nput a value=1\n---\n";
+        assert!(crate::generate_with(&mut runner, input, &options).is_ok());
+        assert_eq!(runner.state(), &2);
+
+        let input = "get a\nget\n---\n\nput a b value=x ttl=y\n---\n";
+        let error = crate::generate_with(&mut runner, input, &options).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(
+            error.to_string(),
+            "invalid commands:\nline 2: get: expected 1 positional argument, got 0\n\
+             line 5: put: invalid ttl 'y', expected integer"
+        );
+        assert_eq!(runner.state(), &2);
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash as _, Hasher as _};
+use std::sync::{Arc, Mutex};
+
+use crate::context::Checkpoint;
+
+/// A cache of runner states after %setup sections, keyed by a hash of the
+/// section, see [`RunOptions::cache_setup`](crate::RunOptions::cache_setup).
+/// Shared between clones of the options.
+#[derive(Clone, Default)]
+pub(crate) struct SetupCache(Arc<Mutex<HashMap<u64, CachedSetup>>>);
+
+/// The cached result of running a %setup section.
+#[derive(Clone)]
+pub(crate) struct CachedSetup {
+    /// The runner state, via Runner::serialize_state().
+    pub(crate) state: Vec<u8>,
+    /// The run context state.
+    pub(crate) ctx: Checkpoint,
+    /// The block output.
+    pub(crate) output: String,
+}
+
+impl SetupCache {
+    /// Returns the cache key for a %setup section, given the runner type, the
+    /// section's literal text, the random seed it runs with, and the script's
+    /// line endings.
+    pub(crate) fn key(runner: &str, literal: &str, seed: u64, eol: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (runner, literal, seed, eol).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Fetches a cached %setup section, if any.
+    pub(crate) fn get(&self, key: u64) -> Option<CachedSetup> {
+        self.lock().get(&key).cloned()
+    }
+
+    /// Caches a %setup section.
+    pub(crate) fn insert(&self, key: u64, setup: CachedSetup) {
+        self.lock().insert(key, setup);
+    }
+
+    /// Locks the cache. It's never left inconsistent, so ignore poisoning.
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, CachedSetup>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl std::fmt::Debug for SetupCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SetupCache({} entries)", self.lock().len())
+    }
+}
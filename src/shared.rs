@@ -0,0 +1,235 @@
+use std::any::{Any, TypeId};
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+/// A fixture setup function.
+type Setup<T> = dyn Fn() -> Result<T, Box<dyn Error>> + Send + Sync;
+
+/// A fixture teardown function.
+type Teardown<T> = dyn Fn(&T) -> Result<(), Box<dyn Error>> + Send + Sync;
+
+/// A fixture script hook, given the script's namespace.
+type ScriptHook<T> = dyn Fn(&T, &str) -> Result<(), Box<dyn Error>> + Send + Sync;
+
+/// An expensive resource shared by multiple scripts, e.g. a test cluster that
+/// takes seconds to start. Registered via
+/// [`RunOptions::shared_fixture`](crate::RunOptions::shared_fixture), and
+/// fetched by runners via
+/// [`RunContext::shared_fixture`](crate::RunContext::shared_fixture).
+///
+/// The fixture is set up lazily when a script first fetches it, and is shared
+/// by all scripts that run while it's set up. It's reference counted, and torn
+/// down after the last running script using it completes. Scripts run via
+/// [`run_dir_with()`](crate::run_dir_with) all share the same fixture, as do
+/// scripts run concurrently, e.g. as separate tests.
+///
+/// To preserve isolation between scripts, the
+/// [`SharedFixture::on_start_script`] and [`SharedFixture::on_end_script`]
+/// hooks are called with a unique namespace for each script that uses the
+/// fixture, also available via
+/// [`RunContext::namespace`](crate::RunContext::namespace). For example, they
+/// can create and drop a database with that name, which the runner then uses.
+///
+/// Clones share the same fixture state, so hooks should be configured before
+/// cloning it.
+///
+/// ```
+/// # struct Cluster;
+/// # impl Cluster {
+/// #     fn start() -> Result<Self, Box<dyn std::error::Error>> { Ok(Self) }
+/// #     fn stop(&self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+/// #     fn create_database(&self, _: &str) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
+/// # }
+/// let cluster = goldenscript::SharedFixture::new(Cluster::start)
+///     .on_start_script(|cluster, namespace| cluster.create_database(namespace))
+///     .on_teardown(|cluster| cluster.stop());
+/// let options = goldenscript::RunOptions::new().shared_fixture(&cluster);
+/// ```
+pub struct SharedFixture<T> {
+    /// The fixture state, shared between clones.
+    state: Arc<Mutex<State<T>>>,
+    /// Sets up the fixture.
+    setup: Arc<Setup<T>>,
+    /// Tears down the fixture.
+    teardown: Option<Arc<Teardown<T>>>,
+    /// Called the first time a script fetches the fixture.
+    start_script: Option<Arc<ScriptHook<T>>>,
+    /// Called when a script that fetched the fixture completes.
+    end_script: Option<Arc<ScriptHook<T>>>,
+}
+
+/// The state of a shared fixture.
+struct State<T> {
+    /// The fixture, if set up.
+    value: Option<Arc<T>>,
+    /// The number of running scripts (or directory runs) using the fixture.
+    users: usize,
+    /// The namespaces of running scripts that have fetched the fixture.
+    namespaces: HashSet<String>,
+}
+
+impl<T: Send + Sync + 'static> SharedFixture<T> {
+    /// Creates a new shared fixture, which is set up with the given function
+    /// when first used. By default, it's torn down by dropping it.
+    pub fn new<F>(setup: F) -> Self
+    where
+        F: Fn() -> Result<T, Box<dyn Error>> + Send + Sync + 'static,
+    {
+        let state = State { value: None, users: 0, namespaces: HashSet::new() };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            setup: Arc::new(setup),
+            teardown: None,
+            start_script: None,
+            end_script: None,
+        }
+    }
+
+    /// Calls the given function to tear down the fixture after the last script
+    /// using it has completed, before dropping it. Errors fail the last script.
+    pub fn on_teardown<F>(mut self, teardown: F) -> Self
+    where
+        F: Fn(&T) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+    {
+        self.teardown = Some(Arc::new(teardown));
+        self
+    }
+
+    /// Calls the given function with the script's namespace the first time a
+    /// script fetches the fixture, e.g. to create a per-script database.
+    /// Errors are returned to the runner when fetching the fixture.
+    pub fn on_start_script<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&T, &str) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+    {
+        self.start_script = Some(Arc::new(hook));
+        self
+    }
+
+    /// Calls the given function with the script's namespace when a script that
+    /// fetched the fixture completes, even if it failed, e.g. to drop its
+    /// database. Errors fail the script.
+    pub fn on_end_script<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&T, &str) -> Result<(), Box<dyn Error>> + Send + Sync + 'static,
+    {
+        self.end_script = Some(Arc::new(hook));
+        self
+    }
+
+    /// Returns true if the fixture is currently set up.
+    pub fn is_set_up(&self) -> bool {
+        self.lock().value.is_some()
+    }
+
+    /// Locks the fixture state. A panicking hook is reported by the caller, so
+    /// ignore poisoning.
+    fn lock(&self) -> std::sync::MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl<T> Clone for SharedFixture<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            setup: self.setup.clone(),
+            teardown: self.teardown.clone(),
+            start_script: self.start_script.clone(),
+            end_script: self.end_script.clone(),
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for SharedFixture<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SharedFixture<{}>", std::any::type_name::<T>())
+    }
+}
+
+/// A type-erased shared fixture, for use in options and run contexts.
+pub(crate) trait AnyFixture: Send + Sync {
+    /// Returns the fixture's value type.
+    fn value_type(&self) -> TypeId;
+
+    /// Registers a user of the fixture, which must later call release().
+    fn acquire(&self);
+
+    /// Returns the fixture, setting it up if necessary, and calling the
+    /// start_script hook if this is the namespace's first fetch.
+    fn get(&self, namespace: &str) -> Result<Arc<dyn Any + Send + Sync>, Box<dyn Error>>;
+
+    /// Releases a user of the fixture, calling the end_script hook for the
+    /// namespace (if any) and tearing the fixture down if this was the last
+    /// user.
+    fn release(&self, namespace: Option<&str>) -> Result<(), Box<dyn Error>>;
+}
+
+impl<T: Send + Sync + 'static> AnyFixture for SharedFixture<T> {
+    fn value_type(&self) -> TypeId {
+        TypeId::of::<T>()
+    }
+
+    fn acquire(&self) {
+        self.lock().users += 1;
+    }
+
+    fn get(&self, namespace: &str) -> Result<Arc<dyn Any + Send + Sync>, Box<dyn Error>> {
+        // Hold the lock while setting up, such that concurrent scripts wait
+        // for the same fixture.
+        let mut state = self.lock();
+        let value = match &state.value {
+            Some(value) => value.clone(),
+            None => {
+                let value = Arc::new(
+                    (self.setup)().map_err(|e| format!("shared fixture setup failed: {e}"))?,
+                );
+                state.value = Some(value.clone());
+                value
+            }
+        };
+        if !state.namespaces.contains(namespace) {
+            if let Some(hook) = &self.start_script {
+                hook(&value, namespace)
+                    .map_err(|e| format!("shared fixture start_script hook failed: {e}"))?;
+            }
+            state.namespaces.insert(namespace.to_string());
+        }
+        Ok(value)
+    }
+
+    fn release(&self, namespace: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let mut state = self.lock();
+        let mut result = Ok(());
+        if let Some(namespace) = namespace.filter(|ns| state.namespaces.remove(*ns)) {
+            if let (Some(hook), Some(value)) = (&self.end_script, &state.value) {
+                result = hook(value, namespace)
+                    .map_err(|e| format!("shared fixture end_script hook failed: {e}").into());
+            }
+        }
+        state.users = state.users.saturating_sub(1);
+        if state.users == 0 {
+            state.namespaces.clear();
+            if let Some(value) = state.value.take() {
+                if let Some(teardown) = &self.teardown {
+                    let teardown = teardown(&value)
+                        .map_err(|e| format!("shared fixture teardown failed: {e}").into());
+                    result = result.and(teardown);
+                }
+            }
+        }
+        result
+    }
+}
+
+/// A registered shared fixture, see
+/// [`RunOptions::shared_fixture`](crate::RunOptions::shared_fixture).
+#[derive(Clone)]
+pub(crate) struct AnySharedFixture(pub(crate) Arc<dyn AnyFixture>);
+
+impl std::fmt::Debug for AnySharedFixture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedFixture")
+    }
+}
@@ -0,0 +1,244 @@
+//! Statistics about goldenscripts, for test suite maintenance and planning.
+//!
+//! The statistics are also available via the `goldenscript-stats` command-line
+//! tool, which requires the `cli` crate feature. It scans a directory for
+//! scripts and prints per-script statistics as a table, or as JSON with
+//! `--json`:
+//!
+//! ```sh
+//! $ cargo run --features cli --bin goldenscript-stats -- tests/scripts
+//! ```
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::util::json_quote;
+
+/// Statistics about a single goldenscript, or a set of scripts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ScriptStats {
+    /// The number of blocks with commands.
+    pub blocks: usize,
+    /// The number of commands, excluding directives.
+    pub commands: usize,
+    /// The number of directives.
+    pub directives: usize,
+    /// The distinct command names.
+    pub command_names: BTreeSet<String>,
+    /// The distinct command tags.
+    pub tags: BTreeSet<String>,
+    /// The number of output lines.
+    pub output_lines: usize,
+    /// The number of output bytes, excluding trailing line endings.
+    pub output_bytes: usize,
+}
+
+impl ScriptStats {
+    /// Computes statistics for the given goldenscript, without running it.
+    pub fn new(input: &str) -> std::io::Result<Self> {
+        let mut stats = Self::default();
+        for block in crate::parse_borrowed(input)? {
+            if block.commands.is_empty() {
+                continue;
+            }
+            stats.blocks += 1;
+            for command in &block.commands {
+                if command.directive {
+                    stats.directives += 1;
+                    continue;
+                }
+                stats.commands += 1;
+                stats.command_names.insert(command.name.to_string());
+                stats.tags.extend(command.tags.iter().map(|t| t.to_string()));
+            }
+            let output = &input[block.output_span];
+            stats.output_lines += output.lines().count();
+            stats.output_bytes += output.len();
+        }
+        Ok(stats)
+    }
+
+    /// Adds the statistics of another script, e.g. to compute totals.
+    pub fn add(&mut self, other: &ScriptStats) {
+        self.blocks += other.blocks;
+        self.commands += other.commands;
+        self.directives += other.directives;
+        self.command_names.extend(other.command_names.iter().cloned());
+        self.tags.extend(other.tags.iter().cloned());
+        self.output_lines += other.output_lines;
+        self.output_bytes += other.output_bytes;
+    }
+}
+
+/// Computes statistics for all goldenscripts in the given directory and its
+/// subdirectories, ordered by path. Hidden files and directories are skipped.
+/// Returns an error if a script fails to parse.
+pub fn scan_dir(dir: &Path) -> std::io::Result<Vec<(String, ScriptStats)>> {
+    let mut scripts = Vec::new();
+    for (name, path) in crate::util::find_scripts(dir)? {
+        let input = std::fs::read_to_string(&path)?;
+        let stats = ScriptStats::new(&input).map_err(|e| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{name}: {e}"))
+        })?;
+        scripts.push((name, stats));
+    }
+    Ok(scripts)
+}
+
+/// The table and JSON columns, as header and JSON key.
+const COLUMNS: [(&str, &str); 7] = [
+    ("blocks", "blocks"),
+    ("commands", "commands"),
+    ("directives", "directives"),
+    ("names", "command_names"),
+    ("tags", "tags"),
+    ("lines", "output_lines"),
+    ("bytes", "output_bytes"),
+];
+
+/// Returns the column values for the given stats.
+fn columns(stats: &ScriptStats) -> [usize; 7] {
+    [
+        stats.blocks,
+        stats.commands,
+        stats.directives,
+        stats.command_names.len(),
+        stats.tags.len(),
+        stats.output_lines,
+        stats.output_bytes,
+    ]
+}
+
+/// Formats script statistics as a plain text table, with a total row. Command
+/// names and tags are given as distinct counts.
+pub fn format_table(scripts: &[(String, ScriptStats)]) -> String {
+    let mut total = ScriptStats::default();
+    scripts.iter().for_each(|(_, stats)| total.add(stats));
+
+    let mut rows = vec![std::iter::once("script".to_string())
+        .chain(COLUMNS.iter().map(|(header, _)| header.to_string()))
+        .collect::<Vec<_>>()];
+    for (name, stats) in scripts.iter().chain([&("total".to_string(), total)]) {
+        let values = columns(stats).map(|v| v.to_string());
+        rows.push(std::iter::once(name.clone()).chain(values).collect());
+    }
+
+    let mut widths = vec![0; COLUMNS.len() + 1];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in rows {
+        let mut line = format!("{:<1$}", row[0], widths[0]);
+        for (cell, width) in row.iter().zip(&widths).skip(1) {
+            line.push_str(&format!("  {cell:>width$}"));
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Formats script statistics as a JSON object keyed by script name, with the
+/// distinct command names and tags as arrays.
+pub fn format_json(scripts: &[(String, ScriptStats)]) -> String {
+    let list = |set: &BTreeSet<String>| {
+        format!("[{}]", set.iter().map(|s| json_quote(s)).collect::<Vec<_>>().join(", "))
+    };
+    let mut entries = Vec::new();
+    for (name, stats) in scripts {
+        let mut fields = Vec::new();
+        for ((_, key), value) in COLUMNS.iter().zip(columns(stats)) {
+            match *key {
+                "command_names" => {
+                    fields.push(format!("\"{key}\": {}", list(&stats.command_names)))
+                }
+                "tags" => fields.push(format!("\"{key}\": {}", list(&stats.tags))),
+                _ => fields.push(format!("\"{key}\": {value}")),
+            }
+        }
+        entries.push(format!("  {}: {{ {} }}", json_quote(name), fields.join(", ")));
+    }
+    match entries.is_empty() {
+        true => "{}\n".to_string(),
+        false => format!("{{\n{}\n}}\n", entries.join(",\n")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = r#"# A comment.
+put a=1
+get a [tag]
+---
+ok
+a=1
+
+%seed 7
+put b=2 [tag,other]
+---
+ok
+
+# Trailing comment.
+"#;
+
+    /// Tests ScriptStats computation.
+    #[test]
+    fn script_stats() {
+        let stats = ScriptStats::new(SCRIPT).unwrap();
+        assert_eq!(stats.blocks, 2);
+        assert_eq!(stats.commands, 3);
+        assert_eq!(stats.directives, 1);
+        assert_eq!(stats.command_names, ["get", "put"].map(String::from).into());
+        assert_eq!(stats.tags, ["other", "tag"].map(String::from).into());
+        assert_eq!(stats.output_lines, 3);
+        assert_eq!(stats.output_bytes, 8);
+
+        let mut total = stats.clone();
+        total.add(&ScriptStats::new("del a\n---\nok\n").unwrap());
+        assert_eq!(total.blocks, 3);
+        assert_eq!(total.command_names.len(), 3);
+
+        assert!(ScriptStats::new("put\n").is_err());
+    }
+
+    /// Tests table and JSON formatting.
+    #[test]
+    fn format() {
+        let scripts = vec![
+            ("script".to_string(), ScriptStats::new(SCRIPT).unwrap()),
+            ("dir/a".to_string(), ScriptStats::new("del a\n---\nok\n").unwrap()),
+        ];
+        assert_eq!(
+            format_table(&scripts),
+            r#"script  blocks  commands  directives  names  tags  lines  bytes
+script       2         3           1      2     2      3      8
+dir/a        1         1           0      1     0      1      2
+total        3         4           1      3     2      4     10
+"#
+        );
+        assert_eq!(
+            format_json(&scripts),
+            r#"{
+  "script": { "blocks": 2, "commands": 3, "directives": 1, "command_names": ["get", "put"], "tags": ["other", "tag"], "output_lines": 3, "output_bytes": 8 },
+  "dir/a": { "blocks": 1, "commands": 1, "directives": 0, "command_names": ["del"], "tags": [], "output_lines": 1, "output_bytes": 2 }
+}
+"#
+        );
+        assert_eq!(format_json(&[]), "{}\n");
+    }
+
+    /// Tests scanning the test scripts directory.
+    #[test]
+    fn scan_test_scripts() {
+        let scripts = scan_dir(Path::new("tests/scripts")).unwrap();
+        assert!(scripts.iter().any(|(name, _)| name == "strings"));
+        assert!(scripts.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+}
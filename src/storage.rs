@@ -0,0 +1,87 @@
+//! Pluggable storage backends for golden files.
+//!
+//! All golden file reads and writes, e.g. by [`run()`](crate::run),
+//! [`run_str()`](crate::run_str), and [`run_snapshot()`](crate::run_snapshot),
+//! go through a [`Storage`] backend. By default, this is a [`FileStorage`]
+//! using the filesystem. Via
+//! [`RunOptions::storage`](crate::RunOptions::storage), a different
+//! [`Storage`] can be used instead, e.g. a [`MemoryStorage`] in tests, or a
+//! remote object store for huge recorded corpora that are kept out of version
+//! control. Scripts are compared and updated the same way regardless of the
+//! storage: the test panics with a diff if the output differs, unless
+//! `UPDATE_GOLDENFILES=1` is set, in which case the output is written back.
+//!
+//! Only the script files themselves are stored in the backend. Directories
+//! given to [`run_dir()`](crate::run_dir) and suite files given to
+//! [`run_suite()`](crate::run_suite) are still read from the filesystem, and
+//! execution traces are written to it.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A golden file storage backend, see the [module documentation](self).
+pub trait Storage: Send + Sync {
+    /// Reads the file at the given path. Errors with
+    /// [`std::io::ErrorKind::NotFound`] if it doesn't exist.
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>>;
+
+    /// Writes the file at the given path, replacing any existing file.
+    fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()>;
+}
+
+/// Stores golden files on the filesystem. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileStorage;
+
+impl Storage for FileStorage {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        std::fs::write(path, data)
+    }
+}
+
+/// Stores golden files in memory, e.g. for tests. Clones share the same files.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorage(Arc<Mutex<BTreeMap<PathBuf, Vec<u8>>>>);
+
+impl MemoryStorage {
+    /// Creates a new, empty memory storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the file at the given path, if any.
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        self.lock().get(path.as_ref()).cloned()
+    }
+
+    /// Inserts a file at the given path, replacing any existing file.
+    pub fn insert(&self, path: impl Into<PathBuf>, data: impl Into<Vec<u8>>) {
+        self.lock().insert(path.into(), data.into());
+    }
+
+    /// Locks the files. They're never left inconsistent, so ignore poisoning.
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<PathBuf, Vec<u8>>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, path: &Path) -> std::io::Result<Vec<u8>> {
+        self.get(path).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} not found in memory storage", path.display()),
+            )
+        })
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        self.insert(path, data);
+        Ok(())
+    }
+}
@@ -0,0 +1,223 @@
+//! Utilities for implementing runners.
+
+use std::error::Error;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+/// Polls the given closure every interval until it returns `Some(output)`,
+/// returning the output, or errors if the timeout expires first. The closure
+/// is always called at least once, and its errors are returned immediately.
+///
+/// This is useful for runners implementing wait-style commands, e.g. waiting
+/// for a cluster to converge, and ensures consistent timeout errors:
+///
+/// ```
+/// # use std::time::Duration;
+/// let mut attempts = 0;
+/// let output = goldenscript::util::wait_until(
+///     Duration::from_secs(1),
+///     Duration::from_millis(1),
+///     || {
+///         attempts += 1;
+///         Ok((attempts == 3).then(|| format!("converged after {attempts} attempts")))
+///     },
+/// );
+/// assert_eq!(output.unwrap(), "converged after 3 attempts");
+/// ```
+pub fn wait_until<F>(
+    timeout: Duration,
+    interval: Duration,
+    mut f: F,
+) -> Result<String, Box<dyn Error>>
+where
+    F: FnMut() -> Result<Option<String>, Box<dyn Error>>,
+{
+    let start = Instant::now();
+    loop {
+        if let Some(output) = f()? {
+            return Ok(output);
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            return Err(format!("timed out after {timeout:?}").into());
+        }
+        std::thread::sleep(interval.min(timeout - elapsed));
+    }
+}
+
+/// Parses a duration given as an integer with a unit suffix: ms, s, m, or h
+/// (e.g. `500ms` or `30s`). Also available as
+/// [`Argument::parse_duration()`](crate::Argument::parse_duration).
+///
+/// ```
+/// # use std::time::Duration;
+/// assert_eq!(goldenscript::util::parse_duration("30s")?, Duration::from_secs(30));
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn parse_duration(s: &str) -> Result<Duration, Box<dyn Error>> {
+    let invalid = || format!("invalid duration '{s}'");
+    let split = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(invalid)?;
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 3600)),
+        _ => Err(invalid().into()),
+    }
+}
+
+/// Parses a byte size given as an integer with an optional unit suffix: B,
+/// decimal KB, MB, GB, or TB (powers of 1000), or binary KiB, MiB, GiB, or TiB
+/// (powers of 1024). Units are case-sensitive. Also available as
+/// [`Argument::parse_size()`](crate::Argument::parse_size).
+///
+/// ```
+/// assert_eq!(goldenscript::util::parse_size("4MB")?, 4_000_000);
+/// assert_eq!(goldenscript::util::parse_size("4MiB")?, 4 * 1024 * 1024);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn parse_size(s: &str) -> Result<u64, Box<dyn Error>> {
+    let invalid = || format!("invalid size '{s}'");
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let multiplier: u64 = match unit {
+        "" | "B" => 1,
+        "KB" => 1000,
+        "MB" => 1000_u64.pow(2),
+        "GB" => 1000_u64.pow(3),
+        "TB" => 1000_u64.pow(4),
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return Err(invalid().into()),
+    };
+    value.checked_mul(multiplier).ok_or_else(|| format!("size '{s}' is too large").into())
+}
+
+/// Quotes a string as a JSON string.
+pub(crate) fn json_quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The file name of suite manifests, see [`manifest`](crate::manifest).
+pub(crate) const MANIFEST_FILE: &str = "goldenscripts.toml";
+
+/// The file extension of suite files, see crate::run_suite().
+pub(crate) const SUITE_EXTENSION: &str = "suite";
+
+/// Finds all goldenscripts in the given directory and its subdirectories, as
+/// names relative to the directory and paths, ordered by name. Hidden files
+/// and directories, suite manifests, and suite files are skipped.
+pub(crate) fn find_scripts(
+    dir: &std::path::Path,
+) -> std::io::Result<Vec<(String, std::path::PathBuf)>> {
+    fn find(
+        root: &std::path::Path,
+        dir: &std::path::Path,
+        scripts: &mut Vec<(String, std::path::PathBuf)>,
+    ) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.file_name().is_some_and(|n| n.to_string_lossy().starts_with('.')) {
+                continue;
+            }
+            if path.file_name().is_some_and(|n| n == MANIFEST_FILE) {
+                continue;
+            }
+            if path.extension().is_some_and(|ext| ext == SUITE_EXTENSION) {
+                continue;
+            }
+            if path.is_dir() {
+                find(root, &path, scripts)?;
+                continue;
+            }
+            let name = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().to_string();
+            scripts.push((name, path));
+        }
+        Ok(())
+    }
+
+    let mut scripts = Vec::new();
+    find(dir, dir, &mut scripts)?;
+    scripts.sort();
+    Ok(scripts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tests wait_until() timeouts and errors.
+    #[test]
+    fn wait_until_timeout() {
+        let mut calls = 0;
+        let result = wait_until(Duration::from_millis(10), Duration::from_millis(1), || {
+            calls += 1;
+            Ok(None)
+        });
+        assert_eq!(result.unwrap_err().to_string(), "timed out after 10ms");
+        assert!(calls > 1);
+
+        let result = wait_until(Duration::ZERO, Duration::from_millis(1), || Err("failed".into()));
+        assert_eq!(result.unwrap_err().to_string(), "failed");
+
+        let result = wait_until(Duration::ZERO, Duration::ZERO, || Ok(Some("done".to_string())));
+        assert_eq!(result.unwrap(), "done");
+    }
+
+    /// Tests parse_duration().
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        for invalid in ["", "30", "s", "1.5s", "-1s", "1d"] {
+            assert_eq!(
+                parse_duration(invalid).unwrap_err().to_string(),
+                format!("invalid duration '{invalid}'")
+            );
+        }
+    }
+
+    /// Tests parse_size().
+    #[test]
+    fn parse_size_units() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("512B").unwrap(), 512);
+        assert_eq!(parse_size("4KB").unwrap(), 4_000);
+        assert_eq!(parse_size("4MB").unwrap(), 4_000_000);
+        assert_eq!(parse_size("4GB").unwrap(), 4_000_000_000);
+        assert_eq!(parse_size("4TB").unwrap(), 4_000_000_000_000);
+        assert_eq!(parse_size("4KiB").unwrap(), 4 << 10);
+        assert_eq!(parse_size("4MiB").unwrap(), 4 << 20);
+        assert_eq!(parse_size("4GiB").unwrap(), 4 << 30);
+        assert_eq!(parse_size("4TiB").unwrap(), 4 << 40);
+        for invalid in ["", "MB", "4mb", "4 MB", "1.5MB", "-1", "4PB"] {
+            assert_eq!(
+                parse_size(invalid).unwrap_err().to_string(),
+                format!("invalid size '{invalid}'")
+            );
+        }
+        assert_eq!(
+            parse_size("99999999TiB").unwrap_err().to_string(),
+            "size '99999999TiB' is too large"
+        );
+    }
+}
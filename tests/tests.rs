@@ -1,5 +1,6 @@
 #![warn(clippy::all)]
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Write as _;
 use std::io::Write as _;
@@ -12,6 +13,14 @@ fn test_goldenscript(path: &std::path::Path) {
     goldenscript::run(&mut DebugRunner::new(), path).expect("runner failed")
 }
 
+// Run output-only snapshot scripts in tests/raw, which debug-print the lines of
+// the input document.
+test_each_path! { in "tests/raw" as raw => test_raw }
+
+fn test_raw(path: &std::path::Path) {
+    goldenscript::run_snapshot(&mut DebugRunner::new(), path).expect("runner failed")
+}
+
 // Run goldenscripts in tests/generate with output in a separate file. This is
 // particularly useful for parser tests where output hasn't yet been generated.
 test_each_path! { for ["in", "out"] in "tests/generate" as generate => test_generate }
@@ -57,8 +66,12 @@ fn test_error([in_path, out_path]: [&std::path::Path; 2]) {
 ///
 /// _echo: prints back the arguments, space-separated
 /// _error: errors with the given string
+/// _generated: prints the data generated by %gen with the given name
+/// _halt: halts the script via the run context, with the given reason
 /// _panic: panics with the given string
+/// _seq: prints each argument labeled with the run context's next sequence number
 /// _set: sets various options
+/// _shuffle: prints back the arguments, shuffled by the run context
 ///
 ///   - prefix=<string>: printed immediately before the command output
 ///   - suffix=<string>: printed immediately after the command output
@@ -66,9 +79,16 @@ fn test_error([in_path, out_path]: [&std::path::Path; 2]) {
 ///   - start_command=<string>: printed at the start of a command
 ///   - end_block=<string>: printed at the end of a block
 ///   - end_command=<string>: printed at the end of a command
+///   - end_script=<string>: printed in a trailing %end-script block
+///   - hide_block_tags=<bool>: don't print the block info of tagged blocks
+///
+/// Blocks with tags print the block info at the start of the block, unless
+/// hidden via _set.
 ///
 /// If a command is expected to fail via !, the parsed command string is
-/// returned as an error.
+/// returned as an error. Background commands debug-print the parsed command
+/// from the background thread. Output-only snapshot scripts debug-print each
+/// line of the input document.
 #[derive(Default)]
 struct DebugRunner {
     prefix: String,
@@ -77,6 +97,8 @@ struct DebugRunner {
     end_block: String,
     start_command: String,
     end_command: String,
+    end_script: String,
+    hide_block_tags: bool,
 }
 
 impl DebugRunner {
@@ -86,6 +108,10 @@ impl DebugRunner {
 }
 
 impl goldenscript::Runner for DebugRunner {
+    fn run_raw(&mut self, input: &str) -> Result<String, Box<dyn Error>> {
+        Ok(input.lines().map(|line| format!("{line:?}\n")).collect())
+    }
+
     fn run(&mut self, command: &goldenscript::Command) -> Result<String, Box<dyn Error>> {
         // Process commands.
         let output = match command.name.as_str() {
@@ -117,6 +143,8 @@ impl goldenscript::Runner for DebugRunner {
                         Some("end_block") => self.end_block = arg.value.clone(),
                         Some("start_command") => self.start_command = arg.value.clone(),
                         Some("end_command") => self.end_command = arg.value.clone(),
+                        Some("end_script") => self.end_script = arg.value.clone(),
+                        Some("hide_block_tags") => self.hide_block_tags = arg.parse()?,
                         Some(key) => return Err(format!("unknown argument key {key}").into()),
                         None => return Err("argument must have a key".into()),
                     }
@@ -132,21 +160,96 @@ impl goldenscript::Runner for DebugRunner {
         Ok(format!("{}{output}{}", self.prefix, self.suffix))
     }
 
-    fn start_block(&mut self) -> Result<String, Box<dyn Error>> {
-        Ok(self.start_block.clone())
+    fn run_ctx(
+        &mut self,
+        command: &goldenscript::Command,
+        ctx: &mut goldenscript::RunContext,
+    ) -> Result<goldenscript::output::Output, Box<dyn Error>> {
+        // Process commands that use the run context.
+        let output = match command.name.as_str() {
+            "_halt" => {
+                ctx.halt(command.args.first().map(|a| a.value.as_str()).unwrap_or_default());
+                String::new()
+            }
+
+            "_generated" => {
+                let name = command.args.first().map(|a| a.value.as_str()).unwrap_or_default();
+                ctx.generated(name).ok_or(format!("no generated data {name}"))?.to_string()
+            }
+
+            "_seq" => command
+                .args
+                .iter()
+                .map(|a| format!("{}: {}", ctx.next_seq(), a.value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+
+            "_shuffle" => {
+                let mut values: Vec<&str> = command.args.iter().map(|a| a.value.as_str()).collect();
+                ctx.shuffle(&mut values);
+                values.join(" ")
+            }
+
+            _ => return self.run(command).map(Into::into),
+        };
+
+        Ok(format!("{}{output}{}", self.prefix, self.suffix).into())
     }
 
-    fn end_block(&mut self) -> Result<String, Box<dyn Error>> {
-        Ok(self.end_block.clone())
+    fn start_block(
+        &mut self,
+        block: &goldenscript::BlockInfo,
+        _: &mut goldenscript::RunContext,
+    ) -> Result<String, Box<dyn Error>> {
+        let mut output = self.start_block.clone();
+        if !block.tags().is_empty() && !self.hide_block_tags {
+            let mut tags: Vec<_> = block.tags().iter().collect();
+            tags.sort();
+            let commands: Vec<_> = block.commands().map(|c| c.name.as_str()).collect();
+            write!(output, "block at line {}: tags={tags:?} ", block.line_number())?;
+            writeln!(output, "commands={commands:?}")?;
+        }
+        Ok(output)
     }
 
-    fn start_command(&mut self, _: &goldenscript::Command) -> Result<String, Box<dyn Error>> {
+    fn end_block(
+        &mut self,
+        _: &goldenscript::BlockInfo,
+        _: &mut goldenscript::RunContext,
+    ) -> Result<goldenscript::output::Output, Box<dyn Error>> {
+        Ok(self.end_block.clone().into())
+    }
+
+    fn start_command(
+        &mut self,
+        _: &goldenscript::Command,
+        _: &mut goldenscript::RunContext,
+    ) -> Result<String, Box<dyn Error>> {
         Ok(self.start_command.clone())
     }
 
-    fn end_command(&mut self, _: &goldenscript::Command) -> Result<String, Box<dyn Error>> {
+    fn end_command(
+        &mut self,
+        _: &goldenscript::Command,
+        _: &mut goldenscript::RunContext,
+    ) -> Result<String, Box<dyn Error>> {
         Ok(self.end_command.clone())
     }
+
+    fn end_script(&mut self, _: &mut goldenscript::RunContext) -> Result<String, Box<dyn Error>> {
+        Ok(self.end_script.clone())
+    }
+
+    fn spawn(
+        &mut self,
+        command: &goldenscript::Command,
+    ) -> Result<goldenscript::Spawned, Box<dyn Error>> {
+        let command = command.clone();
+        Ok(Box::new(move || match command.fail {
+            true => Err(format!("{command:?}").into()),
+            false => Ok(format!("{command:?}")),
+        }))
+    }
 }
 
 /// A runner for BTreeMap tests. This is used as a documentation example.
@@ -201,3 +304,724 @@ fn btreemap() {
     goldenscript::run(&mut BTreeMapRunner::default(), "tests/btreemap")
         .expect("goldenscript failed")
 }
+
+/// run_default() should construct and use a default runner.
+#[test]
+fn btreemap_default() {
+    goldenscript::run_default::<BTreeMapRunner>("tests/btreemap").expect("goldenscript failed")
+}
+
+/// run_fn() should use the closure to run commands.
+#[test]
+fn run_fn() {
+    goldenscript::run_fn("tests/run_fn", |command| {
+        Ok(format!("{} args={}", command.name, command.args.len()))
+    })
+    .expect("goldenscript failed")
+}
+
+/// FnRunner::stateless() should run commands via a stateless closure.
+#[test]
+fn fn_runner_stateless() {
+    let mut runner = goldenscript::FnRunner::stateless(|command| {
+        Ok(format!("{} args={}", command.name, command.args.len()))
+    });
+    goldenscript::run(&mut runner, "tests/run_fn").expect("goldenscript failed")
+}
+
+/// FnRunner should run commands and hooks via closures over its state. The
+/// closures can borrow from the enclosing scope.
+#[test]
+fn fn_runner() {
+    let start = String::from("start");
+    let mut runner = goldenscript::FnRunner::new(HashMap::new(), |counts, command| {
+        let count = counts.entry(command.name.clone()).or_insert(0);
+        *count += 1;
+        Ok(format!("{}={count}", command.name))
+    })
+    .on_start_command(|_, command| Ok(format!("{start} {}", command.name)))
+    .on_end_block(|counts, _| Ok(format!("names={}", counts.len())));
+
+    goldenscript::run(&mut runner, "tests/fn_runner").expect("goldenscript failed");
+    assert_eq!(runner.into_state().len(), 3);
+}
+
+/// CommandRegistry should dispatch commands to handlers by name, with shared
+/// state, and error on unknown commands.
+#[test]
+fn command_registry() {
+    let mut runner = goldenscript::CommandRegistry::<HashMap<String, String>>::default()
+        .register("get", |command, map| {
+            let key = &command.consume_args().require_pos("key")?.value;
+            Ok(format!("{key}={:?}", map.get(key)))
+        })
+        .register("put", |command, map| {
+            let mut args = command.consume_args();
+            let key = args.require_pos("key")?.value.clone();
+            let value = args.require_pos("value")?.value.clone();
+            map.insert(key, value);
+            Ok(String::new())
+        })
+        .register("del", |command, map| {
+            map.remove(&command.consume_args().require_pos("key")?.value);
+            Ok(String::new())
+        });
+    assert_eq!(runner.names().collect::<Vec<_>>(), vec!["del", "get", "put"]);
+
+    goldenscript::run(&mut runner, "tests/command_registry").expect("goldenscript failed");
+    assert_eq!(runner.into_state().len(), 1);
+}
+
+/// PrefixRouter should route commands to a runner per prefix, creating them
+/// via the factory, and error on unknown prefixes without a factory.
+#[test]
+fn prefix_router() {
+    let mut router = goldenscript::PrefixRouter::new().factory(|prefix| {
+        let prefix = prefix.to_string();
+        Ok(goldenscript::FnRunner::new(0, move |count, _| {
+            *count += 1;
+            Ok(format!("{prefix:?} #{count}"))
+        })
+        .on_end_block(|count, _| Ok(format!("count={count}"))))
+    });
+    goldenscript::run(&mut router, "tests/prefix_router").expect("goldenscript failed");
+    let counts: Vec<_> =
+        router.into_runners().into_iter().map(|(p, r)| (p, r.into_state())).collect();
+    assert_eq!(counts, vec![("".into(), 1), ("a".into(), 2), ("b".into(), 2)]);
+
+    let mut router = goldenscript::PrefixRouter::new()
+        .runner("a", goldenscript::FnRunner::stateless(|_| Ok("a".into())));
+    let output = goldenscript::generate(&mut router, "a: foo\nb: !foo\n!foo\n---\n").unwrap();
+    assert_eq!(
+        output,
+        "a: foo\nb: !foo\n!foo\n---\na: a\nb: Error: unknown prefix 'b'\n\
+         Error: no runner for commands without a prefix\n"
+    );
+}
+
+/// PrefixRouter should forward capabilities, known commands, and batches to
+/// its runners.
+#[test]
+fn prefix_router_forwarding() {
+    use goldenscript::Capability;
+    use std::collections::HashSet;
+
+    /// A runner with the given known commands, capabilities, and batch size,
+    /// which outputs the command names and the size of their batch.
+    struct ActorRunner {
+        commands: Vec<&'static str>,
+        capabilities: HashSet<Capability>,
+        batch_size: usize,
+    }
+    impl goldenscript::Runner for ActorRunner {
+        fn run(&mut self, command: &goldenscript::Command) -> Result<String, Box<dyn Error>> {
+            Ok(command.name.clone())
+        }
+
+        fn run_batch(
+            &mut self,
+            commands: &[goldenscript::Command],
+            _: &mut goldenscript::RunContext,
+        ) -> Result<Vec<String>, Box<dyn Error>> {
+            Ok(commands
+                .iter()
+                .map(|c| format!("{} (batch of {})", c.name, commands.len()))
+                .collect())
+        }
+
+        fn batch_size(&self) -> usize {
+            self.batch_size
+        }
+
+        fn known_commands(&self) -> Option<Vec<&str>> {
+            Some(self.commands.clone())
+        }
+
+        fn capabilities(&self) -> HashSet<Capability> {
+            self.capabilities.clone()
+        }
+    }
+
+    let new_router = |b_capabilities: &[Capability]| {
+        let all = HashSet::from([Capability::Sleep, Capability::Env]);
+        goldenscript::PrefixRouter::new()
+            .runner(
+                "a",
+                ActorRunner { commands: vec!["get", "put"], capabilities: all, batch_size: 3 },
+            )
+            .runner(
+                "b",
+                ActorRunner {
+                    commands: vec!["put"],
+                    capabilities: b_capabilities.iter().copied().collect(),
+                    batch_size: 2,
+                },
+            )
+    };
+    let mut router = new_router(&[Capability::Sleep, Capability::Env]);
+    goldenscript::run(&mut router, "tests/prefix_router_forwarding").expect("goldenscript failed");
+
+    // Capabilities must be enabled by all runners.
+    let mut router = new_router(&[Capability::Env]);
+    assert_eq!(
+        goldenscript::generate(&mut router, "%sleep 1ms\n---\n").unwrap_err().to_string(),
+        "directive %sleep failed at line 1: runner does not enable the sleep capability"
+    );
+
+    // Commands must be known by any runner.
+    assert_eq!(
+        goldenscript::generate(&mut router, "a: put\nb: del\n---\n").unwrap_err().to_string(),
+        "unknown commands:\nline 2: del"
+    );
+}
+
+/// run_dir() should run each script with the runner given by its %runner
+/// directive, and error on missing or unknown runners.
+#[test]
+fn run_dir() {
+    let registry = goldenscript::RunnerRegistry::new()
+        .register("debug", DebugRunner::new)
+        .register("upper", || goldenscript::FnRunner::stateless(|c| Ok(c.name.to_uppercase())));
+    assert_eq!(registry.names().collect::<Vec<_>>(), vec!["debug", "upper"]);
+    goldenscript::run_dir(&registry, "tests/runners").expect("goldenscript failed");
+
+    let dir = std::env::temp_dir().join(format!("goldenscript-run-dir-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("script");
+
+    std::fs::write(&script, "%runner other\nfoo\n---\n").unwrap();
+    let error = goldenscript::run_dir(&registry, &dir).unwrap_err();
+    assert_eq!(error.to_string(), format!("{}: unknown runner 'other'", script.display()));
+
+    std::fs::write(&script, "foo\n---\n").unwrap();
+    let error = goldenscript::run_dir(&registry, &dir).unwrap_err();
+    assert_eq!(error.to_string(), format!("{}: no %runner directive", script.display()));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// run_dir() should apply any goldenscripts.toml manifest in the directory.
+#[cfg(feature = "manifest")]
+#[test]
+fn run_dir_manifest() {
+    let registry = goldenscript::RunnerRegistry::new()
+        .register("upper", || goldenscript::FnRunner::stateless(|c| Ok(c.name.to_uppercase())));
+
+    let dir = std::env::temp_dir().join(format!("goldenscript-manifest-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let manifest = dir.join("goldenscripts.toml");
+
+    // The manifest gives the default runner and options, and isn't run as a
+    // script.
+    std::fs::write(&manifest, "runner = 'upper'\nwrap = 4\n").unwrap();
+    std::fs::write(dir.join("script"), "foobar\n---\nFOOB\\\nAR\n").unwrap();
+    goldenscript::run_dir(&registry, &dir).expect("goldenscript failed");
+
+    std::fs::write(&manifest, "separator = '==='\n").unwrap();
+    let error = goldenscript::run_dir(&registry, &dir).unwrap_err();
+    assert_eq!(error.to_string(), format!("{}: unknown key 'separator'", manifest.display()));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// run_suite() should run the listed scripts in order against the same runner,
+/// and error on missing scripts before running anything.
+#[test]
+fn run_suite() {
+    goldenscript::run_suite(&mut DebugRunner::new(), "tests/suites/prefix.suite")
+        .expect("goldenscript failed");
+
+    // Running a script on its own uses a fresh runner without the prefix.
+    let input = std::fs::read_to_string("tests/suites/prefix/use").unwrap();
+    let output = goldenscript::generate(&mut DebugRunner::new(), &input).unwrap();
+    assert!(output.ends_with("---\nbar\n"), "{output}");
+
+    let dir = std::env::temp_dir().join(format!("goldenscript-suite-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let suite = dir.join("test.suite");
+    std::fs::write(&suite, "# comment\n\nmissing\n").unwrap();
+    let error = goldenscript::run_suite(&mut DebugRunner::new(), &suite).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        format!("{} at line 3: script 'missing' not found", suite.display())
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// run_suite_with() should stop on SIGTERM with cancel_on_signal(), running
+/// the in-flight script's teardown and end_script(), and report the completed
+/// scripts.
+#[cfg(all(feature = "signals", unix))]
+#[test]
+fn run_suite_signal() {
+    /// Logs the commands and end_script() calls. On "signal", sends SIGTERM to
+    /// the process and waits for the run to be cancelled.
+    struct SignalRunner {
+        token: goldenscript::CancellationToken,
+        log: Vec<String>,
+    }
+
+    impl goldenscript::Runner for SignalRunner {
+        fn run(&mut self, command: &goldenscript::Command) -> Result<String, Box<dyn Error>> {
+            self.log.push(command.name.clone());
+            if command.name == "signal" {
+                let pid = std::process::id().to_string();
+                std::process::Command::new("kill").args(["-TERM", &pid]).status()?;
+                while !self.token.is_cancelled() {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+            }
+            Ok(String::new())
+        }
+
+        fn end_script(
+            &mut self,
+            _: &mut goldenscript::RunContext,
+        ) -> Result<String, Box<dyn Error>> {
+            self.log.push("end_script".to_string());
+            Ok(String::new())
+        }
+    }
+
+    let dir = std::env::temp_dir().join(format!("goldenscript-signal-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let suite = dir.join("test.suite");
+    std::fs::write(&suite, "a\nb\nc\n").unwrap();
+    std::fs::write(dir.join("a"), "a\n---\nok\n").unwrap();
+    std::fs::write(dir.join("b"), "signal\nb\n---\n\n%teardown\nteardown\n---\n").unwrap();
+    std::fs::write(dir.join("c"), "c\n---\nok\n").unwrap();
+
+    let token = goldenscript::CancellationToken::new();
+    let notices = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let options = goldenscript::RunOptions::new()
+        .cancellation_token(token.clone())
+        .cancel_on_signal(true)
+        .reporter({
+            let notices = notices.clone();
+            move |notice: &goldenscript::Notice| notices.lock().unwrap().push(notice.to_string())
+        });
+    let mut runner = SignalRunner { token, log: Vec::new() };
+    let error = goldenscript::run_suite_with(&mut runner, &suite, &options).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::Interrupted);
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "{}: script cancelled at line 2 (0 of 2 blocks completed) (1 of 3 scripts completed)",
+            dir.join("b").display()
+        )
+    );
+    assert_eq!(runner.log, ["a", "end_script", "signal", "teardown", "end_script"]);
+    assert_eq!(
+        *notices.lock().unwrap(),
+        ["b", "c"].map(|s| format!("{}: not completed, run cancelled", dir.join(s).display()))
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// run_str() should run embedded scripts, and panic with a diff if they differ
+/// from the output.
+#[test]
+fn run_str() {
+    use goldenscript::UpdateTarget;
+
+    let script = include_str!("scripts/comments");
+    goldenscript::run_str(
+        &mut DebugRunner::new(),
+        script,
+        UpdateTarget::Path("tests/scripts/comments"),
+    )
+    .expect("goldenscript failed");
+
+    let run = std::panic::AssertUnwindSafe(|| {
+        goldenscript::run_str(&mut DebugRunner::new(), "_echo foo\n---\nbar\n", UpdateTarget::None)
+    });
+    let panic = std::panic::catch_unwind(run).expect_err("script didn't panic");
+    let message = panic.downcast_ref::<String>().expect("no panic message");
+    assert_eq!(
+        message,
+        "embedded script: output differs, 1 changed line: 1 other:\n\
+         \x20 _echo foo\n  ---\n- bar\n+ foo\n"
+    );
+}
+
+/// Scripts can be read from a storage backend other than the filesystem.
+#[test]
+fn storage() {
+    use goldenscript::storage::MemoryStorage;
+
+    let storage = MemoryStorage::new();
+    storage.insert("memory/passing", "_echo foo\n---\nfoo\n");
+    storage.insert("memory/failing", "_echo foo\n---\nbar\n");
+    let options = goldenscript::RunOptions::new().storage(storage.clone());
+
+    goldenscript::run_with(&mut DebugRunner::new(), "memory/passing", &options)
+        .expect("goldenscript failed");
+
+    // Outdated scripts panic, or are updated with UPDATE_GOLDENFILES=1.
+    if std::env::var("UPDATE_GOLDENFILES").is_ok_and(|v| v == "1") {
+        goldenscript::run_with(&mut DebugRunner::new(), "memory/failing", &options)
+            .expect("goldenscript failed");
+        assert_eq!(storage.get("memory/failing").unwrap(), b"_echo foo\n---\nfoo\n");
+    } else {
+        let run = std::panic::AssertUnwindSafe(|| {
+            goldenscript::run_with(&mut DebugRunner::new(), "memory/failing", &options)
+        });
+        let panic = std::panic::catch_unwind(run).expect_err("script didn't panic");
+        let message = panic.downcast_ref::<String>().expect("no panic message");
+        assert!(message.starts_with("memory/failing: output differs"), "{message}");
+    }
+
+    let error = goldenscript::run_with(&mut DebugRunner::new(), "memory/missing", &options)
+        .expect_err("missing script didn't error");
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+
+    // Snapshot scripts and command checks also read from the storage.
+    storage.insert("memory/snapshot", "foo\n---\n\"foo\"\n");
+    goldenscript::run_snapshot_with(&mut DebugRunner::new(), "memory/snapshot", &options)
+        .expect("goldenscript failed");
+    goldenscript::check_commands_with(&DebugRunner::new(), ["memory/passing"], &options)
+        .expect("check failed");
+    let error =
+        goldenscript::check_commands_with(&DebugRunner::new(), ["memory/missing"], &options)
+            .expect_err("missing script didn't error");
+    assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+}
+
+/// Scripts marked via RunOptions::expect_fail() should report failures as
+/// expected, and error if they pass.
+#[test]
+fn expect_fail() {
+    let notices = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let reported = notices.clone();
+    let options = goldenscript::RunOptions::new()
+        .expect_fail([
+            "expect_fail/error",
+            "expect_fail/outdated",
+            "expect_fail/panic",
+            "tests/expect_fail/passing",
+        ])
+        .reporter(move |notice: &goldenscript::Notice| {
+            reported.lock().unwrap().push(notice.clone())
+        });
+    for name in ["error", "outdated", "panic"] {
+        let path = format!("tests/expect_fail/{name}");
+        let input = std::fs::read_to_string(&path).unwrap();
+        goldenscript::run_with(&mut DebugRunner::new(), &path, &options)
+            .expect("expected failure errored");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), input, "script was updated");
+        let notice = notices.lock().unwrap().pop().expect("no notice reported");
+        assert!(
+            matches!(&notice, goldenscript::Notice::ExpectedFailure { path: p, .. }
+                if p.ends_with(name)),
+            "unexpected notice {notice:?}"
+        );
+    }
+
+    let error =
+        goldenscript::run_with(&mut DebugRunner::new(), "tests/expect_fail/passing", &options)
+            .expect_err("passing script succeeded");
+    assert_eq!(
+        error.to_string(),
+        "tests/expect_fail/passing: expected failure, but the script passed"
+    );
+
+    // The script passes without the option.
+    goldenscript::run(&mut DebugRunner::new(), "tests/expect_fail/passing").expect("runner failed");
+}
+
+/// RunOptions::wrap() should wrap all command output, unless overridden by tags.
+#[test]
+fn option_wrap() {
+    let options = goldenscript::RunOptions::new().wrap(3);
+    goldenscript::run_with(&mut DebugRunner::new(), "tests/options/wrap", &options)
+        .expect("goldenscript failed")
+}
+
+/// Normalizers should apply to all output, or only to commands in scope.
+#[cfg(feature = "regex")]
+#[test]
+fn option_normalize() {
+    use goldenscript::Scope;
+    use regex::Regex;
+
+    let options = goldenscript::RunOptions::new()
+        .normalize(Regex::new(r"id=\d+").unwrap(), "id=<id>")
+        .normalize_for("_error", Regex::new(r"t=(\d+)ms").unwrap(), "t=<$1>")
+        .normalize_for(Scope::Prefix("a".into()), Regex::new("t=").unwrap(), "time=")
+        .normalize_for(Scope::Tag("x".into()), Regex::new("ms").unwrap(), "µs");
+    goldenscript::run_with(&mut DebugRunner::new(), "tests/options/normalize", &options)
+        .expect("goldenscript failed")
+}
+
+/// RunOptions::control_chars() should allow control characters by default, and
+/// can escape them or error.
+#[test]
+fn option_control_chars() {
+    use goldenscript::ControlChars;
+
+    let input = "_echo \"a\\x07b\"\n---\n";
+    let output = goldenscript::generate(&mut DebugRunner::new(), input).unwrap();
+    assert_eq!(output, format!("{input}a\x07b\n"));
+
+    let options = goldenscript::RunOptions::new().control_chars(ControlChars::Escape);
+    goldenscript::run_with(&mut DebugRunner::new(), "tests/options/control_chars", &options)
+        .expect("goldenscript failed");
+
+    let options = goldenscript::RunOptions::new().control_chars(ControlChars::Error);
+    let error = goldenscript::generate_with(&mut DebugRunner::new(), input, &options).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "command '_echo' at line 1 output contains control character \\x07"
+    );
+}
+
+/// RunOptions::binary_output() should allow binary output by default, and can
+/// render it as a hexdump or error. max_output_size() limits the output size.
+#[test]
+fn option_binary_output() {
+    use goldenscript::BinaryOutput;
+
+    let input = "_echo text\n_echo \"ab\\x00c\"\n---\n";
+    let output = goldenscript::generate(&mut DebugRunner::new(), input).unwrap();
+    assert_eq!(output, format!("{input}text\nab\0c\n"));
+
+    let options = goldenscript::RunOptions::new().binary_output(BinaryOutput::Hexdump);
+    goldenscript::run_with(&mut DebugRunner::new(), "tests/options/binary_output", &options)
+        .expect("goldenscript failed");
+
+    let options = goldenscript::RunOptions::new().binary_output(BinaryOutput::Error);
+    let error = goldenscript::generate_with(&mut DebugRunner::new(), input, &options).unwrap_err();
+    assert_eq!(error.to_string(), "command '_echo' at line 2 output looks like binary data");
+
+    // The size includes the trailing newline.
+    let options = goldenscript::RunOptions::new().max_output_size(5);
+    let input = "_echo text\n---\n";
+    assert!(goldenscript::generate_with(&mut DebugRunner::new(), input, &options).is_ok());
+    let input = "_echo texts\n---\n";
+    let error = goldenscript::generate_with(&mut DebugRunner::new(), input, &options).unwrap_err();
+    assert_eq!(error.to_string(), "command '_echo' at line 1 output size 6 exceeds maximum 5");
+}
+
+/// RunOptions::only_commands() should skip other commands.
+#[test]
+fn option_only_commands() {
+    let options = goldenscript::RunOptions::new().only_commands(["_echo"]);
+    goldenscript::run_with(&mut DebugRunner::new(), "tests/options/only_commands", &options)
+        .expect("goldenscript failed")
+}
+
+/// RunOptions::skip_tags() should skip commands with the given tags.
+#[test]
+fn option_skip_tags() {
+    let options = goldenscript::RunOptions::new().skip_tags(["slow", "flaky"]);
+    goldenscript::run_with(&mut DebugRunner::new(), "tests/options/skip_tags", &options)
+        .expect("goldenscript failed")
+}
+
+/// Condition tags should skip commands on other platforms or conditions.
+#[cfg(target_os = "linux")]
+#[test]
+fn option_cfg() {
+    let options = goldenscript::RunOptions::new().cfg("feature-x");
+    goldenscript::run_with(&mut DebugRunner::new(), "tests/options/condition_tags", &options)
+        .expect("goldenscript failed")
+}
+
+/// RunOptions::suppress_hook_output() should drop hook output, but still call
+/// the hooks.
+#[test]
+fn option_suppress_hook_output() {
+    let mut runner = goldenscript::FnRunner::new(0, |calls, command| {
+        *calls += 1;
+        Ok(command.name.clone())
+    })
+    .on_start_block(|calls, _| {
+        *calls += 1;
+        Ok("start_block".to_string())
+    })
+    .on_end_block(|calls, _| {
+        *calls += 1;
+        Ok("end_block".to_string())
+    })
+    .on_start_command(|calls, _| {
+        *calls += 1;
+        Ok("start_command".to_string())
+    })
+    .on_end_command(|calls, _| {
+        *calls += 1;
+        Ok("end_command".to_string())
+    });
+    let options = goldenscript::RunOptions::new().suppress_hook_output(true);
+    goldenscript::run_with(&mut runner, "tests/options/suppress_hook_output", &options)
+        .expect("goldenscript failed");
+    assert_eq!(runner.into_state(), 8);
+}
+
+/// AsyncRunner should run scripts via run_async(), awaiting spawned tasks.
+#[cfg(feature = "tokio")]
+#[tokio::test(flavor = "multi_thread")]
+async fn async_runner() {
+    #[derive(Default)]
+    struct AsyncRunner {
+        blocks: usize,
+    }
+
+    impl goldenscript::AsyncRunner for AsyncRunner {
+        async fn run(&mut self, command: &goldenscript::Command) -> Result<String, Box<dyn Error>> {
+            let mut args = command.consume_args();
+            let output = match command.name.as_str() {
+                "double" => {
+                    let value: i64 = args.next_pos().ok_or("value not given")?.parse()?;
+                    tokio::spawn(async move { value * 2 }).await?.to_string()
+                }
+                name => return Err(format!("unknown command {name}").into()),
+            };
+            args.reject_rest()?;
+            Ok(output)
+        }
+
+        async fn end_block(
+            &mut self,
+            _: &goldenscript::BlockInfo<'_>,
+            _: &mut goldenscript::RunContext,
+        ) -> Result<goldenscript::output::Output, Box<dyn Error>> {
+            self.blocks += 1;
+            Ok(format!("blocks: {}", self.blocks).into())
+        }
+    }
+
+    goldenscript::run_async(&mut AsyncRunner::default(), "tests/async_runner")
+        .await
+        .expect("goldenscript failed");
+}
+
+/// FromCommand should be derivable for enums, parsing commands and arguments.
+#[cfg(feature = "derive")]
+#[test]
+fn derive_from_command() {
+    use goldenscript::FromCommand;
+
+    #[allow(dead_code)] // only Debug-printed
+    #[derive(Debug, FromCommand)]
+    enum TestCommand {
+        Reset,
+        Get(String, Option<u64>),
+        PutAll {
+            #[command(positional)]
+            prefix: String,
+            #[command(positional)]
+            keys: Vec<String>,
+            value: u64,
+            ttl: Option<u64>,
+            tags: Vec<String>,
+        },
+        #[command(name = "kind")]
+        Type {
+            r#type: char,
+        },
+    }
+
+    let mut runner = goldenscript::FnRunner::stateless(|command| {
+        Ok(format!("{:?}", TestCommand::from_command(command)?))
+    });
+    assert_eq!(TestCommand::command_names(), &["reset", "get", "put_all", "kind"]);
+    goldenscript::run(&mut runner, "tests/derive").expect("goldenscript failed");
+}
+
+/// parse_borrowed() should borrow strings from the input unless they contain
+/// escapes or continuations.
+#[test]
+fn parse_borrowed() {
+    use std::borrow::Cow;
+
+    let input = "p: cmd 'quoted' \"esc\\n\" key=value <<EOF [tag k=v]\nline 1\nline 2\nEOF\n\
+                 > literal \\\ncontinued\n---\nok\n";
+    let blocks = goldenscript::parse_borrowed(input).expect("parse failed");
+    assert_eq!(blocks.len(), 1);
+    assert_eq!(blocks[0].line_number, 1);
+    assert_eq!(blocks[0].literal, &input[..input.find("---").unwrap()]);
+
+    let [command, literal] = blocks[0].commands.as_slice() else { panic!("expected 2 commands") };
+    assert!(matches!(command.name, Cow::Borrowed("cmd")));
+    assert!(matches!(command.prefix, Some(Cow::Borrowed("p"))));
+    assert!(matches!(command.args[0].value, Cow::Borrowed("quoted")));
+    assert!(matches!(&command.args[1].value, Cow::Owned(s) if s == "esc\n"));
+    assert!(matches!(command.args[2].key, Some(Cow::Borrowed("key"))));
+    assert!(matches!(command.args[2].value, Cow::Borrowed("value")));
+    assert!(matches!(command.args[3].value, Cow::Borrowed("line 1\nline 2")));
+    assert!(command.tags.iter().all(|t| matches!(t, Cow::Borrowed(_))));
+    assert!(matches!(&literal.name, Cow::Owned(s) if s == "literal continued"));
+    assert!(!command.directive);
+
+    // Spans point to the syntax elements in the input.
+    assert_eq!(&input[command.name_span.clone()], "cmd");
+    assert_eq!(&input[command.prefix_span.clone().unwrap()], "p");
+    assert_eq!(&input[command.args[1].value_span.clone()], "\"esc\\n\"");
+    assert_eq!(&input[command.args[2].key_span.clone().unwrap()], "key");
+    assert_eq!(&input[command.args[3].value_span.clone()], "line 1\nline 2");
+    assert_eq!(&input[command.tag_spans[1].clone()], "k=v");
+    assert_eq!(&input[literal.name_span.clone()], "literal \\\ncontinued");
+    assert_eq!(&input[blocks[0].output_span.clone()], "ok");
+
+    // Converting to an owned command retains the contents.
+    let owned = command.clone().into_owned();
+    assert_eq!(owned.name, "cmd");
+    assert_eq!(owned.args[3].value, "line 1\nline 2");
+    assert_eq!(owned.tag_value("k"), Some("v"));
+
+    // Parse errors are returned as InvalidInput.
+    let err = goldenscript::parse_borrowed("cmd\n").expect_err("parse succeeded");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+/// check_commands() should report unknown commands across all scripts.
+#[test]
+fn check_commands() {
+    struct KnownRunner;
+    impl goldenscript::Runner for KnownRunner {
+        fn run(&mut self, _: &goldenscript::Command) -> Result<String, Box<dyn Error>> {
+            Ok(String::new())
+        }
+
+        fn known_commands(&self) -> Option<Vec<&str>> {
+            Some(vec!["get", "set"])
+        }
+    }
+
+    goldenscript::check_commands(&KnownRunner, ["tests/known_commands/valid"])
+        .expect("valid script failed");
+    let err = goldenscript::check_commands(
+        &KnownRunner,
+        ["tests/known_commands/valid", "tests/known_commands/stale"],
+    )
+    .expect_err("stale script succeeded");
+    assert_eq!(
+        err.to_string(),
+        "unknown commands:\n\
+         tests/known_commands/stale:4: delete\n\
+         tests/known_commands/stale:5: scan"
+    );
+}
+
+/// assert_insta_snapshot!() should record the output as an insta snapshot.
+#[cfg(feature = "insta")]
+#[test]
+fn insta() {
+    goldenscript::assert_insta_snapshot!(&mut DebugRunner::new(), "tests/insta/example")
+}
+
+/// The TextMate grammar should match the checked-in grammar file, which can
+/// be used directly by editors. Run with UPDATE_GOLDENFILES=1 to update it.
+#[test]
+fn grammar_textmate() {
+    let mut mint = goldenfile::Mint::new("grammar");
+    let mut f =
+        mint.new_goldenfile("goldenscript.tmLanguage.json").expect("failed to create goldenfile");
+    f.write_all(goldenscript::grammar::textmate().as_bytes()).expect("failed to write goldenfile");
+}